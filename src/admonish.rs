@@ -0,0 +1,190 @@
+//! Compatibility with mdbook-admonish, whose ```` ```admonish ```` blocks
+//! pandoc would otherwise treat as code, leaving the citations inside them
+//! unrendered.
+//!
+//! The opening and closing fences are swapped for placeholder paragraphs
+//! so pandoc processes the block's body as ordinary markdown, then put back.
+
+use mdbook::errors::Error;
+
+use crate::error::CiteprocError;
+
+const PREFIX: &str = "CITEPROCADMONISH";
+const SUFFIX: &str = "X";
+
+/// A chapter with its admonish fences taken out.
+#[derive(Debug)]
+pub struct Protected {
+    pub content: String,
+    fences: Vec<String>,
+    /// The opening fences which had a blank line put before them.
+    padded: Vec<usize>,
+}
+
+/// Replaces the fences of every admonish block in `content` with
+/// placeholders.
+pub fn protect(content: &str) -> Protected {
+    let mut protected = Protected {
+        content: String::with_capacity(content.len()),
+        fences: Vec::new(),
+        padded: Vec::new(),
+    };
+    // The fence of the admonish block we're in, and of any other code
+    // block (which may be nested inside one).
+    let mut admonish: Option<(char, usize)> = None;
+    let mut code: Option<(char, usize)> = None;
+
+    for line in content.split_inclusive('\n') {
+        let trimmed = line.trim_start_matches(' ');
+        let fence = fence(trimmed);
+
+        if let Some(open) = code {
+            if fence.is_some_and(|f| closes(f, open, trimmed)) {
+                code = None;
+            }
+            protected.content.push_str(line);
+            continue;
+        }
+        if let (Some(open), Some(f)) = (admonish, fence) {
+            if closes(f, open, trimmed) {
+                admonish = None;
+                let placeholder = protected.stash(line);
+                protected
+                    .content
+                    .push_str(&format!("\n{placeholder}{}", newline(line)));
+                continue;
+            }
+        }
+        match fence {
+            Some(f) if admonish.is_none() && is_admonish(trimmed, f) => {
+                admonish = Some(f);
+                if !protected.content.is_empty() && !protected.content.ends_with("\n\n") {
+                    protected.padded.push(protected.fences.len());
+                    protected.content.push('\n');
+                }
+                let placeholder = protected.stash(line);
+                protected.content.push_str(&placeholder);
+                if line.ends_with('\n') {
+                    protected.content.push_str("\n\n");
+                }
+            }
+            Some(f) => {
+                code = Some(f);
+                protected.content.push_str(line);
+            }
+            None => protected.content.push_str(line),
+        }
+    }
+    protected
+}
+
+impl Protected {
+    fn stash(&mut self, line: &str) -> String {
+        let placeholder = format!("{PREFIX}{}{SUFFIX}", self.fences.len());
+        self.fences.push(line.trim_end_matches('\n').to_string());
+        placeholder
+    }
+
+    /// Puts the fences back into pandoc's `output`, along with the blank
+    /// lines `protect` added around them.
+    pub fn restore(&self, chapter_name: &str, output: &str) -> Result<String, Error> {
+        let mut restored = output.to_string();
+        for (index, fence) in self.fences.iter().enumerate() {
+            let placeholder = format!("{PREFIX}{index}{SUFFIX}");
+            let Some(start) = restored.find(&placeholder) else {
+                return Err(CiteprocError::pandoc_failed(format!(
+                    "pandoc dropped an admonish block fence from chapter \"{chapter_name}\": {fence}"
+                )));
+            };
+            let mut end = start + placeholder.len();
+            let mut start = start;
+            // Blocks don't nest, so opening and closing fences alternate.
+            let opening = index % 2 == 0;
+            if opening && restored[end..].starts_with("\n\n") {
+                end += 1;
+            }
+            let padded = match opening {
+                true => self.padded.contains(&index) && restored[..start].ends_with('\n'),
+                false => restored[..start].ends_with("\n\n"),
+            };
+            if padded {
+                start -= 1;
+            }
+            restored.replace_range(start..end, fence);
+        }
+        Ok(restored)
+    }
+}
+
+/// The line ending of `line`, which the last line of a chapter may not
+/// have.
+fn newline(line: &str) -> &str {
+    match line.ends_with('\n') {
+        true => "\n",
+        false => "",
+    }
+}
+
+/// The character and length of the code fence `line` starts with.
+fn fence(line: &str) -> Option<(char, usize)> {
+    let c = line.chars().next().filter(|c| *c == '`' || *c == '~')?;
+    let len = line.chars().take_while(|x| *x == c).count();
+    (len >= 3).then_some((c, len))
+}
+
+fn closes(fence: (char, usize), open: (char, usize), line: &str) -> bool {
+    fence.0 == open.0 && fence.1 >= open.1 && line.trim_end().len() == fence.1
+}
+
+fn is_admonish(line: &str, fence: (char, usize)) -> bool {
+    let info = line[fence.1..].trim();
+    info == "admonish" || info.starts_with("admonish ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exposes_admonish_bodies() {
+        let content = "```admonish info title=\"Sources\"\nSee @smith.\n```\n";
+        let protected = protect(content);
+        assert_eq!(
+            protected.content,
+            "CITEPROCADMONISH0X\n\nSee @smith.\n\nCITEPROCADMONISH1X\n"
+        );
+        assert_eq!(
+            protected.restore("test", &protected.content).unwrap(),
+            content
+        );
+    }
+
+    #[test]
+    fn nested_and_ordinary_code_is_untouched() {
+        let content = "````admonish\n```rust\n@not_a_citation\n```\n````\n\n```\nadmonish\n```\n";
+        let protected = protect(content);
+        assert_eq!(
+            protected.content,
+            "CITEPROCADMONISH0X\n\n```rust\n@not_a_citation\n```\n\nCITEPROCADMONISH1X\n\n```\nadmonish\n```\n"
+        );
+        assert_eq!(
+            protected.restore("test", &protected.content).unwrap(),
+            content
+        );
+    }
+
+    #[test]
+    fn keeps_blank_lines_as_they_were() {
+        for content in [
+            "```admonish\nText\n```",
+            "Text\n\n```admonish",
+            "\nText\n```admonish\nText\n```\n",
+        ] {
+            let protected = protect(content);
+            assert_eq!(
+                protected.restore("test", &protected.content).unwrap(),
+                content
+            );
+        }
+    }
+}
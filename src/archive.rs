@@ -0,0 +1,142 @@
+//! Archived copies of web references from the Wayback Machine, linked from
+//! the bibliography so it stays usable when sources disappear.
+//!
+//! Lookups are kept in `wayback.json` in the cache root, including URLs
+//! with no snapshot, so each URL is only asked about once (or once per
+//! `remote-cache-days`).
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::Duration;
+
+use mdbook::errors::Error;
+use serde_json::Value;
+
+use crate::bibliography::{Entry, Library};
+use crate::cache::Remote;
+use crate::http;
+
+const API: &str = "https://archive.org/wayback/available";
+
+/// The closest snapshot of each of `keys` which is a web entry with a URL,
+/// by key. Lookups older than `ttl` are made again, as is every lookup
+/// with `refresh`; offline, whatever is known is used.
+pub fn snapshots<'k>(
+    library: &Library,
+    keys: impl IntoIterator<Item = &'k str>,
+    cache_root: &Path,
+    ttl: Option<Duration>,
+    refresh: bool,
+) -> Result<BTreeMap<String, String>, Error> {
+    let ttl = ttl.filter(|_| !http::is_offline());
+    let mut known = Remote::<Option<String>>::open(cache_root.join("wayback.json"), ttl);
+
+    let mut snapshots = BTreeMap::new();
+    for key in keys {
+        let Some(entry) = library.get(key).filter(|entry| entry.kind == "webpage") else {
+            continue;
+        };
+        let Some(url) = entry.fields.get("url") else {
+            continue;
+        };
+        let snapshot = match known.get(url).filter(|_| !refresh) {
+            Some(snapshot) => snapshot.clone(),
+            None => match lookup(url, timestamp(entry).as_deref()) {
+                Ok(snapshot) => {
+                    known.insert(url.clone(), snapshot.clone());
+                    snapshot
+                }
+                Err(e) => {
+                    eprintln!("Warning: {e}; {key} won't have an archived link");
+                    continue;
+                }
+            },
+        };
+        if let Some(snapshot) = snapshot {
+            snapshots.insert(key.to_string(), snapshot);
+        }
+    }
+
+    known.save()?;
+    Ok(snapshots)
+}
+
+/// Asks the Wayback Machine for the snapshot of `url` closest to
+/// `timestamp` (`YYYYMMDD`), or the latest one.
+fn lookup(url: &str, timestamp: Option<&str>) -> Result<Option<String>, Error> {
+    let mut request = format!("{API}?url={}", encode(url));
+    if let Some(timestamp) = timestamp {
+        request += &format!("&timestamp={timestamp}");
+    }
+    let response: Value = serde_json::from_slice(&http::get(&request)?)
+        .map_err(|e| Error::msg(format!("invalid JSON response from {API}: {e}")))?;
+    let closest = &response["archived_snapshots"]["closest"];
+    if closest["available"] != Value::Bool(true) {
+        return Ok(None);
+    }
+    Ok(closest["url"]
+        .as_str()
+        .map(|snapshot| snapshot.replacen("http://", "https://", 1)))
+}
+
+/// When the entry was accessed as a Wayback timestamp, so the snapshot is
+/// of the page that was cited.
+fn timestamp(entry: &Entry) -> Option<String> {
+    let date = entry
+        .fields
+        .get("urldate")
+        .or_else(|| entry.fields.get("accessed"))?;
+    let digits: String = date.chars().filter(char::is_ascii_digit).collect();
+    (digits.len() >= 4).then_some(digits)
+}
+
+/// Percent-encodes `value` for a query string.
+pub fn encode(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                out.push(byte as char)
+            }
+            _ => out += &format!("%{byte:02X}"),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uses_known_snapshots() {
+        let dir = std::env::temp_dir().join(format!("citeproc-wayback-{}", std::process::id()));
+        let mut known = Remote::open(dir.join("wayback.json"), None);
+        known.insert(
+            "https://x.org".into(),
+            Some("https://web.archive.org/web/2020/https://x.org".to_string()),
+        );
+        known.insert("https://gone.org".into(), None);
+        known.save().unwrap();
+        let web = |key: &str, url: &str| Entry {
+            key: key.into(),
+            kind: "webpage".into(),
+            fields: BTreeMap::from([("url".to_string(), url.to_string())]),
+        };
+        let library = Library::new([web("x", "https://x.org"), web("gone", "https://gone.org")]);
+
+        let snapshots = snapshots(&library, ["x", "gone", "missing"], &dir, None, false).unwrap();
+        assert_eq!(
+            snapshots,
+            BTreeMap::from([(
+                "x".to_string(),
+                "https://web.archive.org/web/2020/https://x.org".to_string()
+            )])
+        );
+        assert_eq!(
+            encode("https://x.org/?a=1"),
+            "https%3A%2F%2Fx.org%2F%3Fa%3D1"
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
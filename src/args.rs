@@ -0,0 +1,267 @@
+//! Pandoc's command line, built from parts rather than strings: [`Format`]
+//! for the reader and writer with their extensions, and [`PandocArgs`] for
+//! everything else.
+
+use std::fmt;
+
+/// A pandoc format with extensions turned on or off, e.g.
+/// `markdown_strict+footnotes-raw_html`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Format {
+    pub name: String,
+    /// In the order they're applied, so a later one wins.
+    pub extensions: Vec<Extension>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Extension {
+    pub name: String,
+    pub enabled: bool,
+}
+
+impl Format {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            extensions: Vec::new(),
+        }
+    }
+
+    /// Reads a format as pandoc takes it, `name+on-off`.
+    pub fn parse(spec: &str) -> Self {
+        let name_end = spec.find(['+', '-']).unwrap_or(spec.len());
+        let mut format = Self::new(&spec[..name_end]);
+        format.toggle_all(&spec[name_end..]);
+        format
+    }
+
+    pub fn enable(&mut self, extension: &str) {
+        self.set(extension, true);
+    }
+
+    pub fn disable(&mut self, extension: &str) {
+        self.set(extension, false);
+    }
+
+    pub fn set(&mut self, extension: &str, enabled: bool) {
+        self.extensions.push(Extension {
+            name: extension.to_string(),
+            enabled,
+        });
+    }
+
+    /// Applies toggles written like `+footnotes-raw_html`.
+    pub fn toggle_all(&mut self, toggles: &str) {
+        let mut rest = toggles;
+        while let Some(sign) = rest.chars().next() {
+            let end = rest[1..].find(['+', '-']).map_or(rest.len(), |i| i + 1);
+            if end > 1 {
+                self.set(&rest[1..end], sign == '+');
+            }
+            rest = &rest[end..];
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.name)?;
+        for extension in &self.extensions {
+            let sign = if extension.enabled { '+' } else { '-' };
+            write!(f, "{sign}{}", extension.name)?;
+        }
+        Ok(())
+    }
+}
+
+/// Something pandoc runs over the document after reading it, in the order
+/// given on the command line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Filter {
+    Citeproc,
+    Lua(String),
+    Json(String),
+}
+
+/// The arguments for one pandoc run.
+///
+/// ```
+/// use mdbook_citeproc::args::{Format, PandocArgs};
+///
+/// let args = PandocArgs::new(Format::parse("markdown_strict+citations"), Format::new("plain"))
+///     .metadata("lang", "en-GB")
+///     .bibliography("refs.bib")
+///     .citeproc()
+///     .into_args();
+/// assert_eq!(
+///     args,
+///     [
+///         "--from=markdown_strict+citations",
+///         "--to=plain",
+///         "--metadata=lang:en-GB",
+///         "--bibliography=refs.bib",
+///         "--citeproc",
+///     ]
+/// );
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PandocArgs {
+    reader: Format,
+    writer: Format,
+    /// Keys with their values, or `None` for `true`.
+    metadata: Vec<(String, Option<String>)>,
+    options: Vec<String>,
+    metadata_files: Vec<String>,
+    csl: Option<String>,
+    bibliographies: Vec<String>,
+    filters: Vec<Filter>,
+}
+
+impl PandocArgs {
+    pub fn new(reader: Format, writer: Format) -> Self {
+        Self {
+            reader,
+            writer,
+            metadata: Vec::new(),
+            options: Vec::new(),
+            metadata_files: Vec::new(),
+            csl: None,
+            bibliographies: Vec::new(),
+            filters: Vec::new(),
+        }
+    }
+
+    pub fn metadata(mut self, key: &str, value: &str) -> Self {
+        self.metadata
+            .push((key.to_string(), Some(value.to_string())));
+        self
+    }
+
+    /// Sets the metadata `key` to `true`.
+    pub fn metadata_flag(mut self, key: &str) -> Self {
+        self.metadata.push((key.to_string(), None));
+        self
+    }
+
+    pub fn metadata_file(mut self, path: &str) -> Self {
+        self.metadata_files.push(path.to_string());
+        self
+    }
+
+    /// Any other option, e.g. `--markdown-headings=atx`, given as is.
+    pub fn option(mut self, option: impl Into<String>) -> Self {
+        self.options.push(option.into());
+        self
+    }
+
+    pub fn options(mut self, options: impl IntoIterator<Item = String>) -> Self {
+        self.options.extend(options);
+        self
+    }
+
+    pub fn csl(mut self, style: &str) -> Self {
+        self.csl = Some(style.to_string());
+        self
+    }
+
+    pub fn bibliography(mut self, path: &str) -> Self {
+        self.bibliographies.push(path.to_string());
+        self
+    }
+
+    /// Renders citations, after any filters added before.
+    pub fn citeproc(mut self) -> Self {
+        self.filters.push(Filter::Citeproc);
+        self
+    }
+
+    pub fn lua_filter(mut self, path: &str) -> Self {
+        self.filters.push(Filter::Lua(path.to_string()));
+        self
+    }
+
+    /// A JSON filter: a program which reads and writes pandoc's AST.
+    pub fn filter(mut self, program: &str) -> Self {
+        self.filters.push(Filter::Json(program.to_string()));
+        self
+    }
+
+    pub fn into_args(self) -> Vec<String> {
+        let mut args = vec![
+            format!("--from={}", self.reader),
+            format!("--to={}", self.writer),
+        ];
+        args.extend(self.metadata.iter().map(|(key, value)| match value {
+            Some(value) => format!("--metadata={key}:{value}"),
+            None => format!("--metadata={key}"),
+        }));
+        args.extend(self.options);
+        args.extend(
+            self.metadata_files
+                .iter()
+                .map(|path| format!("--metadata-file={path}")),
+        );
+        args.extend(self.csl.iter().map(|style| format!("--csl={style}")));
+        args.extend(
+            self.bibliographies
+                .iter()
+                .map(|path| format!("--bibliography={path}")),
+        );
+        args.extend(self.filters.iter().map(|filter| match filter {
+            Filter::Citeproc => "--citeproc".to_string(),
+            Filter::Lua(path) => format!("--lua-filter={path}"),
+            Filter::Json(program) => format!("--filter={program}"),
+        }));
+        args
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_round_trip() {
+        let format = Format::parse("markdown_strict+footnotes-raw_html+raw_html");
+        assert_eq!(format.name, "markdown_strict");
+        assert_eq!(format.extensions.len(), 3);
+        assert!(!format.extensions[1].enabled);
+        assert_eq!(
+            format.to_string(),
+            "markdown_strict+footnotes-raw_html+raw_html"
+        );
+        assert_eq!(Format::parse("typst").to_string(), "typst");
+
+        let mut format = Format::new("gfm");
+        format.disable("smart");
+        format.toggle_all("+emoji-");
+        assert_eq!(format.to_string(), "gfm-smart+emoji");
+    }
+
+    #[test]
+    fn filters_run_in_the_order_given() {
+        let args = PandocArgs::new(Format::new("markdown"), Format::new("markdown"))
+            .lua_filter("before.lua")
+            .citeproc()
+            .filter("pandoc-crossref")
+            .metadata_flag("link-citations")
+            .option("--markdown-headings=atx")
+            .metadata_file("nocite.yaml")
+            .csl("style.csl")
+            .into_args();
+        assert_eq!(
+            args,
+            [
+                "--from=markdown",
+                "--to=markdown",
+                "--metadata=link-citations",
+                "--markdown-headings=atx",
+                "--metadata-file=nocite.yaml",
+                "--csl=style.csl",
+                "--lua-filter=before.lua",
+                "--citeproc",
+                "--filter=pandoc-crossref",
+            ]
+        );
+    }
+}
@@ -0,0 +1,94 @@
+//! Audit mode: reports changes pandoc made to a chapter which have nothing
+//! to do with citations, such as reflowed paragraphs or rewritten tables.
+
+use similar::{DiffOp, TextDiff};
+
+/// A change to a chapter outside of any citation or bibliography.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Modification {
+    /// The 1-based line in the chapter's source where the change starts.
+    pub line: usize,
+    pub before: Vec<String>,
+    pub after: Vec<String>,
+}
+
+/// Diffs `input` against pandoc's `output`, ignoring lines which contain
+/// citations (they're supposed to change), the generated bibliography and
+/// changes to blank lines only.
+pub fn audit(input: &str, output: &str) -> Vec<Modification> {
+    let output = strip_bibliography(output);
+    let diff = TextDiff::from_lines(input, output.as_str());
+    let old: Vec<&str> = diff.old_slices().to_vec();
+    let new: Vec<&str> = diff.new_slices().to_vec();
+
+    let mut modifications = Vec::new();
+    for op in diff.ops() {
+        let (old_range, new_range) = match *op {
+            DiffOp::Equal { .. } => continue,
+            DiffOp::Delete {
+                old_index, old_len, ..
+            } => (old_index..old_index + old_len, 0..0),
+            DiffOp::Insert {
+                new_index, new_len, ..
+            } => (0..0, new_index..new_index + new_len),
+            DiffOp::Replace {
+                old_index,
+                old_len,
+                new_index,
+                new_len,
+            } => (
+                old_index..old_index + old_len,
+                new_index..new_index + new_len,
+            ),
+        };
+        let before: Vec<&str> = old[old_range.clone()].to_vec();
+        let after: Vec<&str> = new[new_range].to_vec();
+
+        if before.iter().any(|line| contains_citation(line)) {
+            continue;
+        }
+        let blank = |line: &&str| line.trim().is_empty();
+        if before.iter().all(blank) && after.iter().all(blank) {
+            continue;
+        }
+        modifications.push(Modification {
+            line: old_range.start + 1,
+            before: before.iter().map(|l| l.trim_end().to_string()).collect(),
+            after: after.iter().map(|l| l.trim_end().to_string()).collect(),
+        });
+    }
+    modifications
+}
+
+/// Whether `line` contains a pandoc citation (`@key` not preceded by a word
+/// character, so email addresses don't count).
+pub fn contains_citation(line: &str) -> bool {
+    let bytes = line.as_bytes();
+    bytes.iter().enumerate().any(|(i, &b)| {
+        b == b'@'
+            && (i == 0 || !(bytes[i - 1].is_ascii_alphanumeric() || bytes[i - 1] == b'.'))
+            && bytes
+                .get(i + 1)
+                .is_some_and(|c| c.is_ascii_alphanumeric() || *c == b'_' || *c == b'{')
+    })
+}
+
+/// Removes the `<div id="refs">` block citeproc generates.
+fn strip_bibliography(output: &str) -> String {
+    let mut stripped = String::with_capacity(output.len());
+    let mut depth = 0usize;
+    for line in output.split_inclusive('\n') {
+        if depth == 0 && line.contains("id=\"refs\"") {
+            depth = 1;
+            depth = depth.saturating_sub(line.matches("</div>").count());
+            continue;
+        }
+        if depth > 0 {
+            depth += line.matches("<div").count();
+            depth = depth.saturating_sub(line.matches("</div>").count());
+            continue;
+        }
+        stripped.push_str(line);
+    }
+    stripped
+}
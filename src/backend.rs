@@ -0,0 +1,536 @@
+//! Citation backends: the things which actually turn a chapter's markdown
+//! into markdown with rendered citations.
+//!
+//! The preprocessor picks one from the `backend` option, but any
+//! [`CitationBackend`] can be plugged in through [`Pandoc::backend`].
+//!
+//! [`Pandoc::backend`]: crate::Pandoc::backend
+
+use std::collections::BTreeMap;
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use base64::Engine as _;
+use mdbook::errors::Error;
+use serde_json::{json, Value};
+
+use crate::args::PandocArgs;
+use crate::citation;
+use crate::compat::{self, PandocVersion};
+use crate::config::{BackendKind, Config};
+use crate::subprocess::{self, path_arg, TempDir};
+use crate::{http, install};
+
+/// What a backend produced for one chapter.
+#[derive(Debug, Clone, Default)]
+pub struct Output {
+    pub stdout: Vec<u8>,
+    /// Diagnostics, in pandoc's `[WARNING] ...` format.
+    pub stderr: String,
+    /// `None` on success, otherwise a short description of the failure such
+    /// as an exit status.
+    pub failure: Option<String>,
+}
+
+/// What a backend is able to honor, so `check` can flag configurations it
+/// would silently get wrong.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Whether citations can be resolved at all.
+    pub citeproc: bool,
+    /// Whether arbitrary CSL style files can be used.
+    pub custom_styles: bool,
+    /// Whether note (footnote based) CSL styles are rendered correctly.
+    pub note_styles: bool,
+    /// The locales which can be rendered, or `None` for any.
+    pub locales: Option<Vec<String>>,
+    /// Whether files outside of the book root can be read.
+    pub reads_outside_root: bool,
+    /// The version of pandoc doing the converting, when it's known.
+    pub pandoc_version: Option<PandocVersion>,
+}
+
+impl Default for Capabilities {
+    /// Everything pandoc itself supports.
+    fn default() -> Self {
+        Self {
+            citeproc: true,
+            custom_styles: true,
+            note_styles: true,
+            locales: None,
+            reads_outside_root: true,
+            pandoc_version: None,
+        }
+    }
+}
+
+/// Something which renders citations. Several chapters are converted at a
+/// time, so it's shared between threads.
+pub trait CitationBackend: Sync {
+    fn name(&self) -> &str;
+
+    fn capabilities(&self) -> Capabilities;
+
+    /// Converts one chapter's `input` as described by `config`.
+    fn convert(&self, config: &Config, input: &str) -> Result<Output, Error>;
+}
+
+/// Selects the backend configured in `config`.
+///
+/// With `download` unset, `auto-install-pandoc` never hits the network; the
+/// pinned pandoc is assumed to be where it would be installed.
+pub fn select(
+    config: &Config,
+    root: &Path,
+    download: bool,
+) -> Result<Box<dyn CitationBackend + Send>, Error> {
+    match &config.backend {
+        BackendKind::Pandoc if config.auto_install_pandoc => {
+            let dir = config.cache_root.join("pandoc");
+            let binary = if download {
+                install::ensure_pandoc(&dir, config.pandoc_sha256.as_deref())?
+            } else {
+                install::installed_path(&dir)
+            };
+            let version = PandocVersion::parse(install::PANDOC_VERSION);
+            Ok(Box::new(
+                PandocSubprocess::new(vec![binary.into()])
+                    .with_version(version)
+                    .in_root(root),
+            ))
+        }
+        BackendKind::Pandoc => Ok(Box::new(
+            PandocSubprocess::new(config.pandoc_command.iter().map(OsString::from).collect())
+                .in_root(root),
+        )),
+        BackendKind::PandocServer(url) => Ok(Box::new(PandocServer::new(url.clone(), root))),
+        BackendKind::Wasm(module) => wasm(&root.join(module), root),
+    }
+}
+
+#[cfg(feature = "wasm")]
+fn wasm(module: &Path, root: &Path) -> Result<Box<dyn CitationBackend + Send>, Error> {
+    Ok(Box::new(crate::wasm::WasmPandoc::new(module, root)?))
+}
+
+#[cfg(not(feature = "wasm"))]
+fn wasm(_module: &Path, _root: &Path) -> Result<Box<dyn CitationBackend + Send>, Error> {
+    Err(crate::error::CiteprocError::config(
+        "wasm-module requires mdbook-citeproc to be built with the `wasm` feature",
+    ))
+}
+
+/// The arguments current pandoc is invoked with for every chapter.
+pub fn pandoc_args(config: &Config) -> Vec<String> {
+    pandoc_args_for(config, None)
+}
+
+/// The arguments pandoc `version` is invoked with for every chapter, or
+/// current pandoc's for `None`. See [`compat`] for what differs.
+pub fn pandoc_args_for(config: &Config, version: Option<&PandocVersion>) -> Vec<String> {
+    let mut args = PandocArgs::new(config.from.clone(), config.to.clone());
+    if let Some(locale) = &config.locale {
+        args = args.metadata("lang", locale);
+    }
+    for (key, value) in &config.metadata {
+        args = args.metadata(key, value);
+    }
+    if let Some(style) = config.heading_style {
+        args = args.options(compat::heading_args(style, version));
+    }
+    if config.sandbox.enabled {
+        args = args.option("--sandbox");
+    }
+    if let Some(file) = &config.metadata_file {
+        args = args.metadata_file(&path_arg(file));
+    }
+    if let Some(bibliography_config) = &config.bibliography {
+        args = args
+            .csl(&bibliography_config.bibliography_style)
+            .bibliography(&bibliography_config.bibliography);
+        for path in &config.extra_bibliographies {
+            args = args.bibliography(&path_arg(path));
+        }
+        args = args
+            .metadata_flag("link-citations")
+            .metadata_flag("link-bibliography")
+            .citeproc();
+    }
+    args.into_args()
+}
+
+/// Runs pandoc (or a compatible program) as a subprocess per chapter.
+pub struct PandocSubprocess {
+    /// The program followed by any leading arguments.
+    command: Vec<OsString>,
+    /// Asked for the first time it's needed.
+    version: OnceLock<Option<PandocVersion>>,
+    /// The book root, which bibliography and style paths are relative to.
+    root: PathBuf,
+}
+
+impl PandocSubprocess {
+    pub fn new(command: Vec<OsString>) -> Self {
+        assert!(!command.is_empty(), "pandoc command must not be empty");
+        Self {
+            command,
+            version: OnceLock::new(),
+            root: PathBuf::new(),
+        }
+    }
+
+    /// Resolves bibliography and style paths against `root` rather than
+    /// the current directory, which (e.g. for the daemon) needn't be it.
+    pub fn in_root(mut self, root: &Path) -> Self {
+        self.root = root.to_path_buf();
+        self
+    }
+
+    /// Assumes `command` runs pandoc `version` rather than asking it.
+    pub fn with_version(self, version: Option<PandocVersion>) -> Self {
+        let _ = self.version.set(version);
+        self
+    }
+
+    /// The version `pandoc --version` reports, if it's pandoc at all.
+    pub fn version(&self) -> Option<&PandocVersion> {
+        self.version
+            .get_or_init(|| compat::detect(&self.command))
+            .as_ref()
+    }
+}
+
+impl CitationBackend for PandocSubprocess {
+    fn name(&self) -> &str {
+        "pandoc"
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            pandoc_version: self.version().cloned(),
+            ..Capabilities::default()
+        }
+    }
+
+    fn convert(&self, config: &Config, input: &str) -> Result<Output, Error> {
+        let version = self.version();
+        if let Some(version) = version {
+            compat::check(config, version)?;
+        }
+        let mut command = subprocess::command(&self.command)?;
+        if let Some(dir) = &config.sandbox.working_dir {
+            fs::create_dir_all(dir)
+                .map_err(|e| Error::msg(format!("failed to create {}: {e}", dir.display())))?;
+            command.current_dir(dir);
+        }
+        let mut args = pandoc_args_for(&anchored(config, &self.root)?, version);
+        if config.sandbox.clear_env {
+            subprocess::clear_env(&mut command, &config.sandbox.pass_env);
+        }
+        // A large chapter goes through files, removed along with their
+        // directory once pandoc is done.
+        let handoff = match config.tempfile_threshold {
+            Some(threshold) if input.len() as u64 >= threshold => {
+                Some(TempDir::new().map_err(|e| {
+                    Error::msg(format!("failed to create a temporary directory: {e}"))
+                })?)
+            }
+            _ => None,
+        };
+        let stdin = match &handoff {
+            Some(dir) => {
+                let chapter = dir.path().join("chapter");
+                fs::write(&chapter, input).map_err(|e| {
+                    Error::msg(format!("failed to write {}: {e}", chapter.display()))
+                })?;
+                args.push(format!("--output={}", path_arg(&dir.path().join("output"))));
+                args.push(path_arg(&chapter).into_owned());
+                ""
+            }
+            None => input,
+        };
+        if env::var_os("MDBOOK_CITEPROC_DEBUG").is_some() {
+            eprintln!("Running {}", subprocess::display(&self.command, &args));
+        }
+        command.args(args);
+        let output = subprocess::run(&mut command, stdin, config.pandoc_limits)?;
+        let stdout = match &handoff {
+            Some(dir) if output.status.success() => {
+                let path = dir.path().join("output");
+                fs::read(&path)
+                    .map_err(|e| Error::msg(format!("failed to read {}: {e}", path.display())))?
+            }
+            _ => output.stdout,
+        };
+        Ok(Output {
+            failure: (!output.status.success()).then(|| output.status.to_string()),
+            stdout,
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        })
+    }
+}
+
+/// `config` with the files pandoc reads as absolute paths, the
+/// bibliography and style resolved against `root`, so they're found
+/// wherever pandoc runs: from the sandbox's working directory, or a daemon
+/// started outside the book.
+fn anchored(config: &Config, root: &Path) -> Result<Config, Error> {
+    let absolute = |path: &Path| {
+        std::path::absolute(path)
+            .map_err(|e| Error::msg(format!("failed to resolve {}: {e}", path.display())))
+    };
+    let mut config = config.clone();
+    if let Some(bibliography) = &mut config.bibliography {
+        bibliography.bibliography =
+            path_arg(&absolute(&root.join(&bibliography.bibliography))?).into_owned();
+        // Builds fetch styles at URLs first (see `style::fetch_remote`),
+        // but one given to the backend directly is left to pandoc.
+        if !bibliography.bibliography_style.contains("://") {
+            bibliography.bibliography_style =
+                path_arg(&absolute(&root.join(&bibliography.bibliography_style))?).into_owned();
+        }
+    }
+    for path in config
+        .extra_bibliographies
+        .iter_mut()
+        .chain(&mut config.metadata_file)
+    {
+        *path = absolute(path)?;
+    }
+    Ok(config)
+}
+
+/// Sends chapters to a running `pandoc-server` over HTTP.
+///
+/// The server can't see our filesystem, so the bibliography and style are
+/// uploaded alongside every chapter.
+pub struct PandocServer {
+    url: String,
+    root: PathBuf,
+}
+
+impl PandocServer {
+    pub fn new(url: String, root: &Path) -> Self {
+        Self {
+            url,
+            root: root.to_path_buf(),
+        }
+    }
+
+    fn request(&self, config: &Config, input: &str) -> Result<Value, Error> {
+        let mut metadata = serde_json::Map::new();
+        if let Some(locale) = &config.locale {
+            metadata.insert("lang".into(), json!(locale));
+        }
+        for (key, value) in &config.metadata {
+            metadata.insert(key.clone(), json!(value));
+        }
+        if !config.nocite.is_empty() {
+            metadata.insert("nocite".into(), json!(citation::nocite(&config.nocite)));
+        }
+        let mut request = json!({
+            "text": input,
+            "from": config.from.to_string(),
+            "to": config.to.to_string(),
+        });
+        if let Some(style) = config.heading_style {
+            request["markdown-headings"] = json!(compat::heading_style(style));
+        }
+
+        if let Some(bibliography) = &config.bibliography {
+            metadata.insert("link-citations".into(), json!(true));
+            metadata.insert("link-bibliography".into(), json!(true));
+            let mut files = BTreeMap::new();
+            let mut bibliographies = vec![bibliography.bibliography.clone()];
+            bibliographies.extend(
+                config
+                    .extra_bibliographies
+                    .iter()
+                    .map(|path| path.display().to_string()),
+            );
+            for path in bibliographies
+                .iter()
+                .chain([&bibliography.bibliography_style])
+            {
+                let contents = fs::read(self.root.join(path))?;
+                files.insert(
+                    path.clone(),
+                    base64::engine::general_purpose::STANDARD.encode(contents),
+                );
+            }
+            request["citeproc"] = json!(true);
+            request["bibliography"] = json!(bibliographies);
+            request["csl"] = json!(bibliography.bibliography_style);
+            request["files"] = json!(files);
+        }
+        request["metadata"] = Value::Object(metadata);
+        Ok(request)
+    }
+}
+
+impl CitationBackend for PandocServer {
+    fn name(&self) -> &str {
+        "pandoc-server"
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::default()
+    }
+
+    fn convert(&self, config: &Config, input: &str) -> Result<Output, Error> {
+        let response = match http::post_json(&self.url, &self.request(config, input)?) {
+            Ok(response) => response,
+            Err(e) => {
+                return Ok(Output {
+                    failure: Some(e.to_string()),
+                    ..Output::default()
+                })
+            }
+        };
+        let output = response["output"].as_str().unwrap_or_default();
+        let stdout = if response["base64"].as_bool() == Some(true) {
+            base64::engine::general_purpose::STANDARD.decode(output)?
+        } else {
+            output.as_bytes().to_vec()
+        };
+        let stderr = response["messages"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|message| {
+                format!(
+                    "[{}] {}\n",
+                    message["verbosity"].as_str().unwrap_or("INFO"),
+                    message["message"].as_str().unwrap_or_default()
+                )
+            })
+            .collect();
+        Ok(Output {
+            stdout,
+            stderr,
+            failure: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(toml: &str) -> Config {
+        Config::from_table(&toml::from_str(toml).unwrap(), Path::new(".")).unwrap()
+    }
+
+    #[test]
+    fn builds_the_exact_arguments() {
+        let mut config = config(
+            r#"
+            citations = "transpile"
+            footnotes = "transpile"
+            bibliography = "refs.bib"
+            csl = "style.csl"
+            heading-style = "atx"
+            "#,
+        );
+        config.extra_bibliographies = vec![PathBuf::from("inline.json")];
+        config.metadata_file = Some(PathBuf::from("nocite.yaml"));
+        assert_eq!(
+            pandoc_args(&config),
+            [
+                "--from=markdown_strict+citations+footnotes+fenced_divs",
+                "--to=markdown_strict-citations-footnotes-fenced_divs",
+                "--metadata=link-citations",
+                "--metadata=link-bibliography",
+                "--markdown-headings=atx",
+                "--metadata-file=nocite.yaml",
+                "--csl=style.csl",
+                "--bibliography=refs.bib",
+                "--bibliography=inline.json",
+                "--citeproc",
+            ]
+        );
+
+        let old = PandocVersion::parse("2.11.1").unwrap();
+        let args = pandoc_args_for(&config, Some(&old));
+        assert!(args.contains(&"--atx-headers".to_string()));
+        assert!(!args
+            .iter()
+            .any(|arg| arg.starts_with("--markdown-headings")));
+    }
+
+    #[test]
+    fn preserved_citations_are_left_to_the_writer() {
+        let mut config = config("citations = \"preserve\"\nlocale = \"de-DE\"");
+        config.metadata.insert("title".into(), "Über".into());
+        assert_eq!(
+            pandoc_args(&config),
+            [
+                "--from=markdown_strict+citations",
+                "--to=markdown_strict+citations",
+                "--metadata=lang:de-DE",
+                "--metadata=title:Über",
+            ]
+        );
+    }
+
+    #[test]
+    fn sandboxed_pandoc_is_given_absolute_paths() {
+        let mut config = config(
+            r#"
+            citations = "transpile"
+            bibliography = "refs.bib"
+            csl = "https://example.org/style.csl"
+            sandbox = true
+            pandoc-working-dir = "jail"
+            "#,
+        );
+        config.metadata_file = Some(PathBuf::from("nocite.yaml"));
+        assert!(pandoc_args(&config).contains(&"--sandbox".to_string()));
+        assert_eq!(config.sandbox.working_dir, Some(PathBuf::from("./jail")));
+
+        let anchored = anchored(&config, Path::new("book")).unwrap();
+        let bibliography = anchored.bibliography.unwrap();
+        assert!(Path::new(&bibliography.bibliography).is_absolute());
+        assert_eq!(
+            bibliography.bibliography_style,
+            "https://example.org/style.csl"
+        );
+        assert!(anchored.metadata_file.unwrap().is_absolute());
+
+        let old = PandocVersion::parse("2.14.2").unwrap();
+        assert!(compat::check(&config, &old).is_err());
+    }
+
+    /// A fake pandoc which uppercases its input file into `--output`, and
+    /// says on stderr where the input was.
+    #[cfg(unix)]
+    #[test]
+    fn hands_large_chapters_over_in_files() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("citeproc-handoff-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let pandoc = dir.join("pandoc");
+        fs::write(
+            &pandoc,
+            "#!/bin/sh\nfor arg; do case $arg in\n\
+             --output=*) out=${arg#--output=};;\n--*) ;;\n*) in=$arg;;\nesac; done\n\
+             echo \"$in\" >&2\ntr a-z A-Z < \"$in\" > \"$out\"\n",
+        )
+        .unwrap();
+        fs::set_permissions(&pandoc, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let config = config("tempfile-threshold = 4");
+        let backend = PandocSubprocess::new(vec![pandoc.into()]).with_version(None);
+        let output = backend.convert(&config, "large chapter\n").unwrap();
+        assert_eq!(output.failure, None);
+        assert_eq!(output.stdout, b"LARGE CHAPTER\n");
+        let chapter = Path::new(output.stderr.trim());
+        assert!(chapter.is_absolute());
+        assert!(!chapter.parent().unwrap().exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
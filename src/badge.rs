@@ -0,0 +1,96 @@
+//! `citation-count`: a line under each chapter's title saying how many
+//! works it cites, e.g. "12 references", for course materials where the
+//! reading load matters.
+
+/// The line `citation-count = true` gives. `{count}` is the number of
+/// works and `{s}` an "s" unless it's one.
+pub const DEFAULT_TEMPLATE: &str = "{count} reference{s}";
+
+/// `template` for a chapter citing `count` works.
+pub fn render(template: &str, count: usize) -> String {
+    template
+        .replace("{count}", &count.to_string())
+        .replace("{s}", if count == 1 { "" } else { "s" })
+}
+
+/// `content` with `line` under its first heading, or at the top if it has
+/// none. HTML gets a paragraph it can style as a badge, other renderers
+/// an emphasized line.
+pub fn insert(content: &str, line: &str, renderer: &str) -> String {
+    let badge = match renderer {
+        "html" => format!("<p class=\"citation-count\">{line}</p>\n\n"),
+        _ => format!("*{line}*\n\n"),
+    };
+    let lines: Vec<&str> = content.split_inclusive('\n').collect();
+    let mut fence: Option<&str> = None;
+    let mut at = None;
+    for (i, text) in lines.iter().enumerate() {
+        let trimmed = text.trim();
+        if let Some(marker) = fence {
+            if trimmed.starts_with(marker) {
+                fence = None;
+            }
+            continue;
+        }
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            fence = Some(&trimmed[..3]);
+            continue;
+        }
+        let atx = trimmed.trim_start_matches('#');
+        if trimmed.starts_with('#') && (atx.is_empty() || atx.starts_with(' ')) {
+            at = Some(i + 1);
+            break;
+        }
+        let underline = lines.get(i + 1).map_or("", |next| next.trim());
+        if !trimmed.is_empty()
+            && !underline.is_empty()
+            && (underline.chars().all(|c| c == '=') || underline.chars().all(|c| c == '-'))
+        {
+            at = Some(i + 2);
+            break;
+        }
+    }
+    let Some(at) = at else {
+        return format!("{badge}{content}");
+    };
+    let mut out: String = lines[..at].concat();
+    if !out.ends_with('\n') {
+        out.push('\n');
+    }
+    out.push('\n');
+    out.push_str(badge.trim_end());
+    out.push('\n');
+    let rest = lines[at..].concat();
+    if !rest.is_empty() && !rest.starts_with('\n') {
+        out.push('\n');
+    }
+    out.push_str(&rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn goes_under_the_title() {
+        assert_eq!(render(DEFAULT_TEMPLATE, 1), "1 reference");
+        assert_eq!(render("Reading: {count}", 12), "Reading: 12");
+        assert_eq!(
+            insert("# Sources\n\nText.\n", "2 references", "html"),
+            "# Sources\n\n<p class=\"citation-count\">2 references</p>\n\nText.\n"
+        );
+        assert_eq!(
+            insert(
+                "```\n# not a title\n```\n\nSources\n=======\nText.\n",
+                "2 references",
+                "latex"
+            ),
+            "```\n# not a title\n```\n\nSources\n=======\n\n*2 references*\n\nText.\n"
+        );
+        assert_eq!(
+            insert("Text.\n", "1 reference", "typst"),
+            "*1 reference*\n\nText.\n"
+        );
+    }
+}
@@ -0,0 +1,1165 @@
+//! Up-front validation of bibliography files.
+//!
+//! Pandoc's own errors for a malformed bibliography are hard to act on,
+//! especially mid-edit under `mdbook serve`. Checking the syntax ourselves
+//! lets us point at the exact file, line and entry. The same parser reads
+//! entries for the features which need more than pandoc's rendering.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{CiteprocError, ErrorKind};
+
+/// A syntax error in a bibliography file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BibliographyError {
+    pub file: PathBuf,
+    pub line: usize,
+    /// The key of the entry the error was found in, if known.
+    pub key: Option<String>,
+    pub message: String,
+}
+
+impl fmt::Display for BibliographyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: ", self.file.display(), self.line)?;
+        if let Some(key) = &self.key {
+            write!(f, "in entry `{key}`: ")?;
+        }
+        f.write_str(&self.message)
+    }
+}
+
+impl From<BibliographyError> for mdbook::errors::Error {
+    fn from(e: BibliographyError) -> Self {
+        CiteprocError::new(ErrorKind::Bibliography, e.to_string()).into()
+    }
+}
+
+/// A bibliography entry, as far as citeproc needs to know about it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    pub key: String,
+    /// The CSL item type, e.g. `book` or `article-journal`.
+    pub kind: String,
+    /// The entry's fields by lowercased name, with BibTeX's delimiters and
+    /// `@string` macros resolved.
+    pub fields: BTreeMap<String, String>,
+}
+
+impl Entry {
+    /// The entry's `annote` (or biblatex `annotation`) field, without
+    /// BibTeX's protective braces.
+    pub fn annotation(&self) -> Option<String> {
+        let annotation = self
+            .fields
+            .get("annote")
+            .or_else(|| self.fields.get("annotation"))?;
+        Some(without_braces(annotation))
+    }
+
+    /// The entry's `keywords`, which BibTeX separates with commas and
+    /// CSL-JSON sometimes with semicolons.
+    pub fn keywords(&self) -> impl Iterator<Item = &str> {
+        self.fields
+            .get("keywords")
+            .into_iter()
+            .flat_map(|keywords| keywords.split([',', ';']))
+            .map(str::trim)
+            .filter(|keyword| !keyword.is_empty())
+    }
+
+    /// The entry as a flat CSL-JSON object: BibTeX fields are renamed to
+    /// the CSL variables pandoc maps them to, and lose their braces.
+    pub fn csl_json(&self) -> serde_json::Value {
+        let mut object = serde_json::Map::new();
+        object.insert("id".into(), self.key.clone().into());
+        object.insert("type".into(), self.kind.clone().into());
+        for (field, value) in &self.fields {
+            let name = csl_variable(field);
+            // A field which already has the CSL name wins over one mapped
+            // to it.
+            if name != field && self.fields.contains_key(name) {
+                continue;
+            }
+            object.insert(name.to_string(), without_braces(value).into());
+        }
+        serde_json::Value::Object(object)
+    }
+}
+
+/// `value` without BibTeX's protective braces.
+fn without_braces(value: &str) -> String {
+    value.replace(['{', '}'], "")
+}
+
+/// The CSL variable a (lowercased) field name corresponds to.
+fn csl_variable(field: &str) -> &str {
+    match field {
+        "journal" | "journaltitle" | "booktitle" => "container-title",
+        "address" | "location" => "publisher-place",
+        "year" | "date" => "issued",
+        "urldate" => "accessed",
+        "pages" => "page",
+        "number" => "issue",
+        "annotation" => "annote",
+        "doi" => "DOI",
+        "url" => "URL",
+        "isbn" => "ISBN",
+        "issn" => "ISSN",
+        "pmid" => "PMID",
+        field => field,
+    }
+}
+
+/// A field's value as CSL-JSON, where dates are objects and names are
+/// lists of them.
+fn csl_value(variable: &str, value: &str) -> serde_json::Value {
+    match variable {
+        "accessed" | "available-date" | "event-date" | "issued" | "original-date" | "submitted" => {
+            serde_json::json!({ "raw": value })
+        }
+        variable if NAME_FIELDS.contains(&variable) => {
+            names(value).iter().map(Name::csl_json).collect()
+        }
+        _ => value.into(),
+    }
+}
+
+/// The fields, BibTeX's and CSL's, which hold lists of names.
+pub const NAME_FIELDS: &[&str] = &[
+    "author",
+    "bookauthor",
+    "collection-editor",
+    "composer",
+    "container-author",
+    "director",
+    "editor",
+    "illustrator",
+    "interviewer",
+    "original-author",
+    "recipient",
+    "reviewed-author",
+    "translator",
+];
+
+/// Lowercase words which end a name in BibTeX's `First Last` form but
+/// aren't its family name.
+const SUFFIXES: &[&str] = &["jr", "jr.", "sr", "sr.", "ii", "iii", "iv"];
+
+/// One name from a BibTeX name list, in CSL's parts.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Name {
+    pub family: String,
+    pub given: String,
+    /// The lowercase words before the family name, e.g. `van` in
+    /// `Ludwig van Beethoven`.
+    pub particle: String,
+    /// E.g. `Jr.`.
+    pub suffix: String,
+    /// The whole name, for one which is braced, like
+    /// `{World Health Organization}`, and so isn't split up.
+    pub literal: Option<String>,
+}
+
+impl Name {
+    /// The name as a CSL-JSON name object.
+    pub fn csl_json(&self) -> serde_json::Value {
+        let mut object = serde_json::Map::new();
+        if let Some(literal) = &self.literal {
+            object.insert("literal".into(), literal.clone().into());
+            return serde_json::Value::Object(object);
+        }
+        for (part, value) in [
+            ("family", &self.family),
+            ("given", &self.given),
+            ("non-dropping-particle", &self.particle),
+            ("suffix", &self.suffix),
+        ] {
+            if !value.is_empty() {
+                object.insert(part.into(), value.clone().into());
+            }
+        }
+        serde_json::Value::Object(object)
+    }
+}
+
+/// The names in a BibTeX name list, like `author`'s, separated by `and`.
+/// Braces keep what's in them together, so `{Procter and Gamble}` is one
+/// name, and one wholly in braces is a literal, as BibTeX has it. Names are
+/// written `First von Last`, `von Last, First` or `von Last, Jr, First`; a
+/// `Jr.` or `III` ending the first form is taken as a suffix too.
+pub fn names(value: &str) -> Vec<Name> {
+    split_names(value).into_iter().map(name).collect()
+}
+
+/// `value`'s names as BibTeX has them written, split at each `and` outside
+/// braces.
+pub fn split_names(value: &str) -> Vec<&str> {
+    let mut names = Vec::new();
+    let mut depth = 0usize;
+    let mut start = 0;
+    let bytes = value.as_bytes();
+    for (i, byte) in bytes.iter().enumerate() {
+        match byte {
+            b'{' => depth += 1,
+            b'}' => depth = depth.saturating_sub(1),
+            _ if depth == 0 && i >= start => {
+                let word = value[i..].get(..5).is_some_and(|w| {
+                    let w = w.as_bytes();
+                    w[0].is_ascii_whitespace()
+                        && w[1..4].eq_ignore_ascii_case(b"and")
+                        && w[4].is_ascii_whitespace()
+                });
+                if word {
+                    names.push(value[start..i].trim());
+                    start = i + 5;
+                }
+            }
+            _ => {}
+        }
+    }
+    names.push(value[start..].trim());
+    names.retain(|name| !name.is_empty());
+    names
+}
+
+fn name(text: &str) -> Name {
+    if let Some(inner) = text.strip_prefix('{').and_then(|t| t.strip_suffix('}')) {
+        if closing_brace(text) == Some(text.len() - 1) {
+            return Name {
+                literal: Some(without_braces(inner)),
+                ..Name::default()
+            };
+        }
+    }
+    let parts = split_outside_braces(text, |c| c == ',');
+    let joined = |words: &[&str]| without_braces(&words.join(" "));
+    match parts.as_slice() {
+        [whole] => {
+            let mut words = split_outside_braces(whole, char::is_whitespace);
+            let mut suffix = "";
+            if words.len() > 2 && SUFFIXES.contains(&words[words.len() - 1].to_lowercase().as_str())
+            {
+                suffix = words.pop().unwrap_or_default();
+            }
+            // `von` starts at the first lowercase word, and runs to the
+            // last lowercase one before the last word.
+            let last = words.len().saturating_sub(1);
+            let von_start = words[..last].iter().position(|w| is_lowercase(w));
+            let (given, von, family) = match von_start {
+                Some(start) => {
+                    let end = (start..last)
+                        .rev()
+                        .find(|&i| is_lowercase(words[i]))
+                        .map_or(start, |i| i + 1);
+                    (&words[..start], &words[start..end], &words[end..])
+                }
+                None => (&words[..last], &[][..], &words[last..]),
+            };
+            Name {
+                family: joined(family),
+                given: joined(given),
+                particle: joined(von),
+                suffix: without_braces(suffix),
+                literal: None,
+            }
+        }
+        [last, rest @ ..] => {
+            let words = split_outside_braces(last, char::is_whitespace);
+            let family_start = words
+                .iter()
+                .rposition(|w| is_lowercase(w))
+                .map_or(0, |i| i + 1)
+                .min(words.len().saturating_sub(1));
+            let (suffix, given) = match rest {
+                [suffix, given, ..] => (*suffix, *given),
+                [given] => ("", *given),
+                [] => ("", ""),
+            };
+            Name {
+                family: joined(&words[family_start..]),
+                given: without_braces(given),
+                particle: joined(&words[..family_start]),
+                suffix: without_braces(suffix),
+                literal: None,
+            }
+        }
+        [] => Name::default(),
+    }
+}
+
+/// Where the brace opening `text` closes.
+fn closing_brace(text: &str) -> Option<usize> {
+    let mut depth = 0;
+    for (i, c) in text.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// `text` split where `split` matches outside braces, trimmed and without
+/// empty pieces.
+fn split_outside_braces(text: &str, split: impl Fn(char) -> bool) -> Vec<&str> {
+    let mut pieces = Vec::new();
+    let mut depth = 0usize;
+    let mut start = 0;
+    for (i, c) in text.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' => depth = depth.saturating_sub(1),
+            c if depth == 0 && split(c) => {
+                pieces.push(text[start..i].trim());
+                start = i + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+    pieces.push(text[start..].trim());
+    pieces.retain(|piece| !piece.is_empty());
+    pieces
+}
+
+/// Whether a name's word is a `von` particle: its first letter outside
+/// braces is lowercase.
+fn is_lowercase(word: &str) -> bool {
+    let mut depth = 0usize;
+    for c in word.chars() {
+        match c {
+            '{' => depth += 1,
+            '}' => depth = depth.saturating_sub(1),
+            c if depth == 0 && c.is_alphabetic() => return c.is_lowercase(),
+            _ => {}
+        }
+    }
+    false
+}
+
+/// The entries of a bibliography, by key.
+#[derive(Debug, Clone, Default)]
+pub struct Library {
+    entries: HashMap<String, Entry>,
+}
+
+impl Library {
+    pub fn new(entries: impl IntoIterator<Item = Entry>) -> Self {
+        Self {
+            entries: entries
+                .into_iter()
+                .map(|entry| (entry.key.clone(), entry))
+                .collect(),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Entry> {
+        self.entries.get(key)
+    }
+
+    /// Every entry, ordered by key.
+    pub fn entries(&self) -> Vec<&Entry> {
+        let mut entries: Vec<&Entry> = self.entries.values().collect();
+        entries.sort_by(|a, b| a.key.cmp(&b.key));
+        entries
+    }
+}
+
+/// An entry for the same work as an earlier one, under another key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Duplicate {
+    pub key: String,
+    /// The earlier entry's key.
+    pub of: String,
+    /// What gives them away: `"DOI"` or `"title and year"`.
+    pub by: &'static str,
+}
+
+/// The entries in `entries` for the same work as an earlier one, e.g. when
+/// a `.bib` file and a Zotero export both have it: ones with the same DOI,
+/// or, unless both have a DOI, the same title and year. Titles are compared
+/// by their letters and digits alone, ignoring case.
+pub fn duplicates(entries: &[Entry]) -> Vec<Duplicate> {
+    let mut dois: HashMap<String, &str> = HashMap::new();
+    let mut titles: HashMap<(String, String), (&str, bool)> = HashMap::new();
+    let mut duplicates = Vec::new();
+    for entry in entries {
+        let doi = entry.fields.get("doi").map(|doi| normalized_doi(doi));
+        let title = entry.fields.get("title").map(|title| {
+            title
+                .chars()
+                .filter(|c| c.is_alphanumeric())
+                .flat_map(char::to_lowercase)
+                .collect::<String>()
+        });
+        let work = title
+            .filter(|title| !title.is_empty())
+            .zip(entry_year(entry));
+
+        let same = match (&doi, &work) {
+            (Some(doi), _) if dois.contains_key(doi) => Some((dois[doi], "DOI")),
+            (_, Some(work)) => titles
+                .get(work)
+                .filter(|(_, has_doi)| !(*has_doi && doi.is_some()))
+                .map(|(key, _)| (*key, "title and year")),
+            _ => None,
+        };
+        let canonical = match same {
+            Some((of, by)) => {
+                if of != entry.key {
+                    duplicates.push(Duplicate {
+                        key: entry.key.clone(),
+                        of: of.to_string(),
+                        by,
+                    });
+                }
+                of
+            }
+            None => &entry.key,
+        };
+        if let Some(doi) = doi {
+            dois.entry(doi).or_insert(canonical);
+        }
+        if let Some(work) = work {
+            titles
+                .entry(work)
+                .or_insert((canonical, entry.fields.contains_key("doi")));
+        }
+    }
+    duplicates
+}
+
+/// `doi` without a resolver's URL or a `doi:` prefix, and lowercased, as
+/// DOIs are case-insensitive.
+fn normalized_doi(doi: &str) -> String {
+    let doi = doi.trim().to_lowercase();
+    let doi = [
+        "https://doi.org/",
+        "http://doi.org/",
+        "https://dx.doi.org/",
+        "http://dx.doi.org/",
+        "doi:",
+    ]
+    .iter()
+    .find_map(|prefix| doi.strip_prefix(prefix))
+    .unwrap_or(&doi);
+    doi.trim().to_string()
+}
+
+/// The year an entry was published, from its `year` field or the start of
+/// its date.
+fn entry_year(entry: &Entry) -> Option<String> {
+    ["year", "date", "issued"]
+        .iter()
+        .filter_map(|field| entry.fields.get(*field))
+        .find_map(|value| {
+            let start = value.find(|c: char| c.is_ascii_digit())?;
+            let year: String = value[start..]
+                .chars()
+                .take_while(char::is_ascii_digit)
+                .collect();
+            (year.len() == 4).then_some(year)
+        })
+}
+
+/// Checks the syntax of the bibliography at `path`.
+///
+/// BibTeX/BibLaTeX and CSL-JSON files are checked; other formats are left to
+/// pandoc.
+pub fn validate(path: &Path) -> Result<(), BibliographyError> {
+    load(path).map(drop)
+}
+
+/// Reads the entries of the bibliography at `path`.
+///
+/// Only BibTeX/BibLaTeX and CSL-JSON files are read; other formats load as
+/// empty.
+pub fn load(path: &Path) -> Result<Vec<Entry>, BibliographyError> {
+    let error = |line, message: String| BibliographyError {
+        file: path.to_path_buf(),
+        line,
+        key: None,
+        message,
+    };
+    let contents =
+        fs::read_to_string(path).map_err(|e| error(0, format!("cannot be read: {e}")))?;
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("bib" | "bibtex") => {
+            BibParser::new(&contents)
+                .parse()
+                .map_err(|(line, key, message)| BibliographyError {
+                    key,
+                    ..error(line, message)
+                })
+        }
+        Some("json") => serde_json::from_str::<serde_json::Value>(&contents)
+            .map(|json| csl_entries(&json))
+            .map_err(|e| error(e.line(), e.to_string())),
+        _ => Ok(Vec::new()),
+    }
+}
+
+/// Rewrites the bibliography at `path` entry by entry: `edit` can change
+/// an entry's fields, or return `false` to leave it out. Entries it doesn't
+/// change are copied verbatim.
+///
+/// Returns `None` for formats other than BibTeX/BibLaTeX and CSL-JSON.
+pub fn rewrite(
+    path: &Path,
+    mut edit: impl FnMut(&mut Entry) -> bool,
+) -> Result<Option<String>, BibliographyError> {
+    let error = |line, message: String| BibliographyError {
+        file: path.to_path_buf(),
+        line,
+        key: None,
+        message,
+    };
+    let contents =
+        fs::read_to_string(path).map_err(|e| error(0, format!("cannot be read: {e}")))?;
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("bib" | "bibtex") => {
+            let items =
+                BibParser::new(&contents)
+                    .parse_items()
+                    .map_err(|(line, key, message)| BibliographyError {
+                        key,
+                        ..error(line, message)
+                    })?;
+            let mut out = String::with_capacity(contents.len());
+            let mut last = 0;
+            for item in items {
+                out += &contents[last..item.span.start];
+                last = item.span.end;
+                let raw = &contents[item.span];
+                let Some(mut entry) = item.entry else {
+                    out += raw;
+                    continue;
+                };
+                let original = entry.clone();
+                if !edit(&mut entry) {
+                    continue;
+                }
+                if entry == original {
+                    out += raw;
+                } else {
+                    out += &to_bibtex(&item.bibtex_type, &entry);
+                }
+            }
+            out += &contents[last..];
+            Ok(Some(out))
+        }
+        Some("json") => {
+            let json = serde_json::from_str::<serde_json::Value>(&contents)
+                .map_err(|e| error(e.line(), e.to_string()))?;
+            let items = json.as_array().cloned().unwrap_or_default();
+            let mut out = Vec::with_capacity(items.len());
+            for mut item in items {
+                let Some(mut entry) = csl_entry(&item) else {
+                    out.push(item);
+                    continue;
+                };
+                let original = entry.clone();
+                if !edit(&mut entry) {
+                    continue;
+                }
+                if let Some(object) = item.as_object_mut() {
+                    for (name, value) in &entry.fields {
+                        if original.fields.get(name) != Some(value) {
+                            let variable = csl_variable(name);
+                            object.insert(variable.to_string(), csl_value(variable, value));
+                        }
+                    }
+                    if entry.kind != original.kind {
+                        object.insert("type".into(), entry.kind.clone().into());
+                    }
+                }
+                out.push(item);
+            }
+            let json = serde_json::to_string_pretty(&out).map_err(|e| error(0, e.to_string()))?;
+            Ok(Some(json))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// `entry` written back out as BibTeX.
+fn to_bibtex(bibtex_type: &str, entry: &Entry) -> String {
+    let mut out = format!("@{bibtex_type}{{{},\n", entry.key);
+    for (name, value) in &entry.fields {
+        out += &format!("  {name} = {{{value}}},\n");
+    }
+    out += "}";
+    out
+}
+
+/// The entries of a CSL-JSON bibliography, with names and dates flattened
+/// to strings the way BibTeX writes them.
+fn csl_entries(json: &serde_json::Value) -> Vec<Entry> {
+    let items = json.as_array().map(Vec::as_slice).unwrap_or_default();
+    items.iter().filter_map(csl_entry).collect()
+}
+
+fn csl_entry(item: &serde_json::Value) -> Option<Entry> {
+    use serde_json::Value;
+
+    let item = item.as_object()?;
+    let key = match item.get("id")? {
+        Value::String(id) => id.clone(),
+        id => id.to_string(),
+    };
+    let mut fields = BTreeMap::new();
+    for (name, value) in item {
+        let value = match value {
+            Value::String(value) => value.clone(),
+            Value::Number(value) => value.to_string(),
+            Value::Array(names) => names
+                .iter()
+                .filter_map(csl_name)
+                .collect::<Vec<_>>()
+                .join(" and "),
+            Value::Object(date) => match csl_date(date) {
+                Some(date) => date,
+                None => continue,
+            },
+            _ => continue,
+        };
+        fields.insert(name.to_lowercase(), value);
+    }
+    let kind = fields.remove("type").unwrap_or_else(|| "article".into());
+    fields.remove("id");
+    Some(Entry { key, kind, fields })
+}
+
+/// A CSL-JSON name as BibTeX writes it, `von Family, Jr, Given`.
+fn csl_name(name: &serde_json::Value) -> Option<String> {
+    let part = |part| name.get(part).and_then(|p| p.as_str());
+    let family = match (part("family"), part("non-dropping-particle")) {
+        (Some(family), Some(particle)) => format!("{particle} {family}"),
+        (Some(family), None) => family.to_string(),
+        (None, _) => return part("literal").map(|literal| format!("{{{literal}}}")),
+    };
+    match (part("given"), part("suffix")) {
+        (Some(given), Some(suffix)) => Some(format!("{family}, {suffix}, {given}")),
+        (Some(given), None) => Some(format!("{family}, {given}")),
+        (None, _) => Some(family),
+    }
+}
+
+/// A CSL-JSON date as `YYYY-MM-DD`, or however much of it there is.
+fn csl_date(date: &serde_json::Map<String, serde_json::Value>) -> Option<String> {
+    if let Some(parts) = date
+        .get("date-parts")
+        .and_then(|parts| parts.get(0))
+        .and_then(|parts| parts.as_array())
+    {
+        let parts: Vec<String> = parts.iter().map(|part| part.to_string()).collect();
+        return Some(parts.join("-"));
+    }
+    ["literal", "raw"]
+        .iter()
+        .find_map(|key| date.get(*key).and_then(|d| d.as_str()).map(str::to_string))
+}
+
+/// The CSL item type pandoc maps a BibTeX entry type to.
+fn csl_type(bibtex: &str) -> &'static str {
+    match bibtex {
+        "article" => "article-journal",
+        "book" | "mvbook" | "manual" | "proceedings" | "mvproceedings" | "collection" => "book",
+        "booklet" => "pamphlet",
+        "inbook" | "incollection" | "bookinbook" | "suppbook" | "suppcollection" => "chapter",
+        "inproceedings" | "conference" => "paper-conference",
+        "phdthesis" | "mastersthesis" | "thesis" => "thesis",
+        "techreport" | "report" => "report",
+        "online" | "www" | "electronic" => "webpage",
+        "unpublished" => "manuscript",
+        "patent" => "patent",
+        "dataset" => "dataset",
+        "software" => "software",
+        "periodical" => "periodical",
+        _ => "document",
+    }
+}
+
+/// One `@` item in a BibTeX file.
+struct Item {
+    /// The lowercased BibTeX type, e.g. `inproceedings` or `string`.
+    bibtex_type: String,
+    entry: Option<Entry>,
+    /// Where the item is in the file, from its `@` to its closing brace.
+    span: Range<usize>,
+}
+
+/// A line number, the key of the entry being parsed and a message.
+type SyntaxError = (usize, Option<String>, String);
+
+/// A minimal BibTeX parser. It reads entries as flat maps of strings and
+/// reports exactly where the structure breaks.
+struct BibParser<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+    /// The byte offset of `chars`.
+    pos: usize,
+    line: usize,
+    key: Option<String>,
+    /// `@string` macros, including the predefined month abbreviations.
+    strings: HashMap<String, String>,
+}
+
+const MONTHS: [&str; 12] = [
+    "January",
+    "February",
+    "March",
+    "April",
+    "May",
+    "June",
+    "July",
+    "August",
+    "September",
+    "October",
+    "November",
+    "December",
+];
+
+impl<'a> BibParser<'a> {
+    fn new(contents: &'a str) -> Self {
+        let strings = MONTHS
+            .iter()
+            .map(|month| (month[..3].to_lowercase(), month.to_string()))
+            .collect();
+        Self {
+            chars: contents.chars().peekable(),
+            pos: 0,
+            line: 1,
+            key: None,
+            strings,
+        }
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.chars.next();
+        match c {
+            Some('\n') => self.line += 1,
+            None => return None,
+            _ => {}
+        }
+        self.pos += c.map_or(0, char::len_utf8);
+        c
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.chars.peek().is_some_and(|c| c.is_whitespace()) {
+            self.next();
+        }
+    }
+
+    fn error<T>(&self, message: impl Into<String>) -> Result<T, SyntaxError> {
+        Err((self.line, self.key.clone(), message.into()))
+    }
+
+    fn identifier(&mut self) -> String {
+        let mut ident = String::new();
+        while let Some(&c) = self.chars.peek() {
+            if c.is_alphanumeric() || "_-:.+/'".contains(c) {
+                ident.push(c);
+                self.next();
+            } else {
+                break;
+            }
+        }
+        ident
+    }
+
+    fn parse(self) -> Result<Vec<Entry>, SyntaxError> {
+        Ok(self
+            .parse_items()?
+            .into_iter()
+            .filter_map(|item| item.entry)
+            .collect())
+    }
+
+    /// Parses every `@` item, entries or not, along with where it is.
+    fn parse_items(mut self) -> Result<Vec<Item>, SyntaxError> {
+        let mut items = Vec::new();
+        // Anything outside of an entry is a comment in BibTeX.
+        while let Some(c) = self.next() {
+            if c == '@' {
+                let start = self.pos - 1;
+                let (bibtex_type, entry) = self.entry()?;
+                items.push(Item {
+                    bibtex_type,
+                    entry,
+                    span: start..self.pos,
+                });
+            }
+        }
+        Ok(items)
+    }
+
+    /// Parses the item after an `@`, returning its type and, unless it's
+    /// a comment, preamble or macro, the entry.
+    fn entry(&mut self) -> Result<(String, Option<Entry>), SyntaxError> {
+        self.key = None;
+        let start = self.line;
+        self.skip_whitespace();
+        let kind = self.identifier().to_lowercase();
+        if kind.is_empty() {
+            return self.error("expected an entry type after `@`");
+        }
+        self.skip_whitespace();
+        let close = match self.next() {
+            Some('{') => '}',
+            Some('(') => ')',
+            _ => return self.error(format!("expected `{{` after `@{kind}`")),
+        };
+
+        match kind.as_str() {
+            "comment" => {
+                self.balanced(close, start)?;
+                return Ok((kind, None));
+            }
+            "preamble" => {
+                self.value(start)?;
+                self.skip_whitespace();
+                self.close(close, start)?;
+                return Ok((kind, None));
+            }
+            "string" => {
+                let strings = self.fields(close, start)?;
+                self.strings.extend(strings);
+                return Ok((kind, None));
+            }
+            _ => {}
+        }
+
+        self.skip_whitespace();
+        let key: String = {
+            let mut key = String::new();
+            while let Some(&c) = self.chars.peek() {
+                if c == ',' || c == close || c.is_whitespace() {
+                    break;
+                }
+                key.push(c);
+                self.next();
+            }
+            key
+        };
+        if key.is_empty() {
+            return self.error(format!("`@{kind}` entry has no citation key"));
+        }
+        self.key = Some(key.clone());
+        self.skip_whitespace();
+        let fields = match self.next() {
+            Some(',') => self.fields(close, start)?,
+            Some(c) if c == close => BTreeMap::new(),
+            _ => return self.error("expected `,` after the citation key"),
+        };
+        let entry = Entry {
+            key,
+            kind: csl_type(&kind).to_string(),
+            fields,
+        };
+        Ok((kind, Some(entry)))
+    }
+
+    /// Parses `name = value` pairs up to and including the closing delimiter.
+    fn fields(
+        &mut self,
+        close: char,
+        start: usize,
+    ) -> Result<BTreeMap<String, String>, SyntaxError> {
+        let mut fields = BTreeMap::new();
+        loop {
+            self.skip_whitespace();
+            if self.chars.peek() == Some(&close) {
+                self.next();
+                return Ok(fields);
+            }
+            if self.chars.peek().is_none() {
+                return self.unterminated(start);
+            }
+            let name = self.identifier();
+            if name.is_empty() {
+                let found = self.chars.peek().copied().unwrap_or(' ');
+                return self.error(format!("expected a field name, found `{found}`"));
+            }
+            let field_line = self.line;
+            self.skip_whitespace();
+            if self.next() != Some('=') {
+                return self.error(format!("expected `=` after field `{name}`"));
+            }
+            let value = self.value(start)?;
+            self.skip_whitespace();
+            match self.chars.peek() {
+                Some(',') => {
+                    self.next();
+                }
+                Some(&c) if c == close => {}
+                None => return self.unterminated(start),
+                // The value swallowed the rest of the entry, so the braces
+                // inside it don't balance.
+                Some('@') => {
+                    return Err((
+                        field_line,
+                        self.key.clone(),
+                        format!("unbalanced braces in field `{name}`"),
+                    ))
+                }
+                Some(&c) => {
+                    return self.error(format!(
+                        "expected `,` after field `{name}`, found `{c}` (missing comma?)"
+                    ))
+                }
+            }
+            fields.insert(name.to_lowercase(), value);
+        }
+    }
+
+    /// Parses a field value, including `#` concatenations.
+    fn value(&mut self, start: usize) -> Result<String, SyntaxError> {
+        let mut value = String::new();
+        loop {
+            self.skip_whitespace();
+            match self.chars.peek() {
+                Some('{') => {
+                    self.next();
+                    value += &self.balanced('}', start)?;
+                }
+                Some('"') => {
+                    self.next();
+                    value += &self.quoted(start)?;
+                }
+                Some(_) => {
+                    let ident = self.identifier();
+                    if ident.is_empty() {
+                        return self.error("expected a field value");
+                    }
+                    // A bare word is a macro, or a number.
+                    match self.strings.get(&ident.to_lowercase()) {
+                        Some(expansion) => value += expansion,
+                        None => value += &ident,
+                    }
+                }
+                None => return self.unterminated(start),
+            }
+            self.skip_whitespace();
+            if self.chars.peek() == Some(&'#') {
+                self.next();
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    /// Consumes up to and including the `close` matching an already
+    /// consumed opening brace, and returns what was in between.
+    fn balanced(&mut self, close: char, start: usize) -> Result<String, SyntaxError> {
+        let mut text = String::new();
+        let mut depth = 0usize;
+        let mut line_start = false;
+        while let Some(c) = self.next() {
+            match c {
+                '{' => depth += 1,
+                '}' if depth > 0 => depth -= 1,
+                c if c == close && depth == 0 => return Ok(text),
+                // An `@` at the start of a line is almost certainly the next
+                // entry, so report the imbalance here rather than at EOF.
+                '@' if line_start => {
+                    return Err((
+                        start,
+                        self.key.clone(),
+                        format!(
+                            "unbalanced braces: entry is still open when the next one starts on line {}",
+                            self.line
+                        ),
+                    ))
+                }
+                _ => {}
+            }
+            text.push(c);
+            line_start = c == '\n';
+        }
+        self.unterminated(start)
+    }
+
+    fn quoted(&mut self, start: usize) -> Result<String, SyntaxError> {
+        let mut text = String::new();
+        let mut depth = 0usize;
+        while let Some(c) = self.next() {
+            match c {
+                '{' => depth += 1,
+                '}' if depth == 0 => return self.error("unbalanced `}` in quoted value"),
+                '}' => depth -= 1,
+                '"' if depth == 0 => return Ok(text),
+                _ => {}
+            }
+            text.push(c);
+        }
+        self.unterminated(start)
+    }
+
+    fn close(&mut self, close: char, start: usize) -> Result<(), SyntaxError> {
+        match self.next() {
+            Some(c) if c == close => Ok(()),
+            None => self.unterminated(start),
+            Some(c) => self.error(format!("expected `{close}`, found `{c}`")),
+        }
+    }
+
+    fn unterminated<T>(&self, start: usize) -> Result<T, SyntaxError> {
+        Err((
+            start,
+            self.key.clone(),
+            "entry is never closed (unbalanced braces?)".to_string(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(contents: &str) -> Vec<Entry> {
+        BibParser::new(contents).parse().unwrap()
+    }
+
+    #[test]
+    fn reads_bibtex_entries() {
+        let entries = parse(
+            "@string{pub = \"MIT Press\"}\n\
+             @Book{knuth1984,\n  Title = {The {TeX}book},\n  publisher = pub # \", Cambridge\",\n  month = jan,\n  year = 1984\n}\n\
+             @comment{ignored}\n\
+             @inproceedings{smith, title = \"Citations\"}\n",
+        );
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].key, "knuth1984");
+        assert_eq!(entries[0].kind, "book");
+        assert_eq!(entries[0].fields["title"], "The {TeX}book");
+        assert_eq!(entries[0].fields["publisher"], "MIT Press, Cambridge");
+        assert_eq!(entries[0].fields["month"], "January");
+        assert_eq!(entries[0].fields["year"], "1984");
+        assert_eq!(entries[1].kind, "paper-conference");
+        assert_eq!(entries[0].annotation(), None);
+        let json = entries[1].csl_json();
+        assert_eq!(json["type"], "paper-conference");
+        assert_eq!(json["title"], "Citations");
+    }
+
+    #[test]
+    fn rewrites_bibtex_entries() {
+        let dir = std::env::temp_dir().join(format!("citeproc-rewrite-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("refs.bib");
+        fs::write(
+            &path,
+            "% shared\n@string{j = {J}}\n@article{a, journal = j}\n@book{b,\n title = {B}}\n@misc{c}\n",
+        )
+        .unwrap();
+        let rewritten = rewrite(&path, |entry| {
+            if entry.key == "b" {
+                entry.fields.insert("title".into(), "Better".into());
+            }
+            entry.key != "c"
+        })
+        .unwrap()
+        .unwrap();
+        assert_eq!(
+            rewritten,
+            "% shared\n@string{j = {J}}\n@article{a, journal = j}\n@book{b,\n  title = {Better},\n}\n\n"
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reads_csl_json_entries() {
+        let json = serde_json::json!([
+            {"id": "smith", "type": "webpage", "title": "A page", "issued": {"date-parts": [[2020]]},
+             "author": [{"family": "Smith", "given": "Jo"}, {"literal": "ACME"}]},
+            {"id": 7, "title": "No type", "annote": "Read {this} first."},
+        ]);
+        let entries = csl_entries(&json);
+        assert_eq!(entries[0].key, "smith");
+        assert_eq!(entries[0].kind, "webpage");
+        assert_eq!(entries[0].fields["issued"], "2020");
+        assert_eq!(entries[0].fields["author"], "Smith, Jo and {ACME}");
+        assert_eq!(entries[1].key, "7");
+        assert_eq!(entries[1].kind, "article");
+        assert_eq!(entries[1].annotation().as_deref(), Some("Read this first."));
+    }
+
+    #[test]
+    fn finds_the_same_work_under_other_keys() {
+        let mut entries = parse(
+            "@article{smith2020, title = {Citations in {Practice}}, year = 2020,\n\
+               doi = {10.1000/XYZ}}\n\
+             @book{adams2018, title = {A Book}, year = 2018}\n\
+             @article{preprint, title = {Citations in Practice}, year = 2020, doi = {10.1000/arxiv}}\n",
+        );
+        let json = serde_json::json!([
+            {"id": "Smith2020Citations", "title": "Citations in practice",
+             "DOI": "https://doi.org/10.1000/xyz", "issued": {"date-parts": [[2021]]}},
+            {"id": "adamsBook2018", "title": "A book.", "issued": {"date-parts": [[2018, 5]]}},
+            {"id": "adams2018", "title": "A Book", "issued": {"date-parts": [[2018]]}},
+            {"id": "adamsBook2019", "title": "A Book", "issued": {"date-parts": [[2019]]}},
+        ]);
+        entries.extend(csl_entries(&json));
+        let duplicates = duplicates(&entries);
+        let found: Vec<(&str, &str, &str)> = duplicates
+            .iter()
+            .map(|d| (d.key.as_str(), d.of.as_str(), d.by))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                ("Smith2020Citations", "smith2020", "DOI"),
+                ("adamsBook2018", "adams2018", "title and year"),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_bibtex_names() {
+        let parsed = names(
+            "{World Health Organization} and Ludwig van Beethoven and \
+             de la Fontaine, Jean and King, Jr., Martin Luther and \
+             Martin Luther King Jr. and {Procter and Gamble} and Jane {de Souza}",
+        );
+        let parts: Vec<(&str, &str, &str, &str)> = parsed
+            .iter()
+            .map(|n| {
+                (
+                    n.literal.as_deref().unwrap_or(&n.family),
+                    n.given.as_str(),
+                    n.particle.as_str(),
+                    n.suffix.as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            parts,
+            [
+                ("World Health Organization", "", "", ""),
+                ("Beethoven", "Ludwig", "van", ""),
+                ("Fontaine", "Jean", "de la", ""),
+                ("King", "Martin Luther", "", "Jr."),
+                ("King", "Martin Luther", "", "Jr."),
+                ("Procter and Gamble", "", "", ""),
+                ("de Souza", "Jane", "", ""),
+            ]
+        );
+        assert_eq!(
+            parsed[1].csl_json(),
+            serde_json::json!({"family": "Beethoven", "given": "Ludwig", "non-dropping-particle": "van"})
+        );
+    }
+}
@@ -0,0 +1,135 @@
+//! Tolerance for chapters brought over from bookdown, which still have its
+//! `\@ref(fig:plot)` cross-references and `(ref:label)` text references in
+//! them. Pandoc knows neither, and escapes or unescapes them on the way
+//! through, so they're hidden from it and put back as they were written,
+//! for whoever finishes the migration to replace them in their own time.
+
+use mdbook::errors::Error;
+
+use crate::error::CiteprocError;
+
+const PREFIX: &str = "CITEPROCBOOKDOWN";
+const SUFFIX: &str = "X";
+
+/// The project file which turns this on by default.
+pub const PROJECT: &str = "_bookdown.yml";
+
+/// A chapter with its bookdown references taken out.
+#[derive(Debug)]
+pub struct Protected {
+    pub content: String,
+    references: Vec<String>,
+}
+
+/// Replaces each bookdown reference outside code in `content` with a
+/// placeholder.
+pub fn protect(content: &str) -> Protected {
+    let mut protected = Protected {
+        content: String::with_capacity(content.len()),
+        references: Vec::new(),
+    };
+    let mut fence: Option<(char, usize)> = None;
+    for line in content.split_inclusive('\n') {
+        let trimmed = line.trim_start_matches(' ');
+        let c = trimmed.chars().next().filter(|c| *c == '`' || *c == '~');
+        let run = c.map_or(0, |c| trimmed.chars().take_while(|x| *x == c).count());
+        match (fence, c) {
+            (Some((open, len)), Some(c)) if c == open && run >= len => {
+                fence = None;
+                protected.content.push_str(line);
+                continue;
+            }
+            (Some(_), _) => {
+                protected.content.push_str(line);
+                continue;
+            }
+            (None, Some(c)) if run >= 3 => {
+                fence = Some((c, run));
+                protected.content.push_str(line);
+                continue;
+            }
+            _ => {}
+        }
+        protected.protect_line(line);
+    }
+    protected
+}
+
+impl Protected {
+    fn protect_line(&mut self, line: &str) {
+        let mut rest = line;
+        while let Some(start) = next_reference(rest) {
+            let end = match rest[start..].find(')') {
+                Some(close) => start + close + 1,
+                None => break,
+            };
+            let placeholder = format!("{PREFIX}{}{SUFFIX}", self.references.len());
+            self.content.push_str(&rest[..start]);
+            self.content.push_str(&placeholder);
+            self.references.push(rest[start..end].to_string());
+            rest = &rest[end..];
+        }
+        self.content.push_str(rest);
+    }
+
+    /// Puts the references back into pandoc's `output`.
+    pub fn restore(&self, chapter_name: &str, output: &str) -> Result<String, Error> {
+        let mut restored = output.to_string();
+        for (index, reference) in self.references.iter().enumerate() {
+            let placeholder = format!("{PREFIX}{index}{SUFFIX}");
+            let Some(start) = restored.find(&placeholder) else {
+                return Err(CiteprocError::pandoc_failed(format!(
+                    "pandoc dropped a bookdown reference from chapter \"{chapter_name}\": {reference}"
+                )));
+            };
+            restored.replace_range(start..start + placeholder.len(), reference);
+        }
+        Ok(restored)
+    }
+}
+
+/// Where the first `\@ref(` or `(ref:` outside a code span in `line` is.
+fn next_reference(line: &str) -> Option<usize> {
+    let bytes = line.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'`' => {
+                let ticks = bytes[i..].iter().take_while(|b| **b == b'`').count();
+                let close = line[i + ticks..].find(&"`".repeat(ticks));
+                i = close.map_or(i + ticks, |end| i + ticks + end + ticks);
+                continue;
+            }
+            b'\\' if line[i..].starts_with("\\@ref(") => return Some(i),
+            b'(' if line[i..].starts_with("(ref:") => return Some(i),
+            _ => {}
+        }
+        i += 1;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hides_bookdown_references_outside_code() {
+        let content = "(ref:cap) A *caption*.\n\n\
+                       As \\@ref(fig:plot) shows [@smith], see `\\@ref(x)`.\n\
+                       ```\n(ref:code)\n```\n";
+        let protected = protect(content);
+        assert_eq!(
+            protected.content,
+            "CITEPROCBOOKDOWN0X A *caption*.\n\n\
+             As CITEPROCBOOKDOWN1X shows [@smith], see `\\@ref(x)`.\n\
+             ```\n(ref:code)\n```\n"
+        );
+        let output =
+            "CITEPROCBOOKDOWN0X A *caption*.\n\nAs CITEPROCBOOKDOWN1X shows (Smith 2020).\n";
+        assert_eq!(
+            protected.restore("Intro", output).unwrap(),
+            "(ref:cap) A *caption*.\n\nAs \\@ref(fig:plot) shows (Smith 2020).\n"
+        );
+    }
+}
@@ -0,0 +1,547 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use mdbook::errors::Error;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::config::CacheBackend;
+#[cfg(not(feature = "sqlite"))]
+use crate::error::CiteprocError;
+
+/// Hex encoded SHA-256 of `data`.
+pub fn sha256_hex(data: impl AsRef<[u8]>) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+/// Writes `contents` to a file in `dir` named by its hash, unless it's
+/// already there, and returns the path. For files pandoc reads which are
+/// generated from the configuration, so repeated builds reuse them.
+pub fn store(dir: &Path, extension: &str, contents: &str) -> io::Result<PathBuf> {
+    let path = dir.join(format!("{}.{extension}", &sha256_hex(contents)[..16]));
+    if path.exists() {
+        touch(&path);
+    } else {
+        fs::create_dir_all(dir)?;
+        write_atomic(&path, contents)?;
+    }
+    Ok(path)
+}
+
+/// Marks `path` as used just now, for [`gc`](crate::gc).
+fn touch(path: &Path) {
+    let _ = File::options()
+        .write(true)
+        .open(path)
+        .and_then(|file| file.set_modified(SystemTime::now()));
+}
+
+/// Writes `contents` to `path` through a temporary file beside it, so a
+/// build interrupted halfway leaves the old file or the new one, never
+/// part of one.
+pub fn write_atomic(path: &Path, contents: impl AsRef<[u8]>) -> io::Result<()> {
+    static WRITES: AtomicUsize = AtomicUsize::new(0);
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let temporary = path.with_file_name(format!(
+        ".{name}.{}.{}.tmp",
+        process::id(),
+        WRITES.fetch_add(1, Ordering::Relaxed)
+    ));
+    let written = File::create(&temporary).and_then(|mut file| {
+        file.write_all(contents.as_ref())?;
+        file.sync_all()
+    });
+    let result = written.and_then(|()| fs::rename(&temporary, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&temporary);
+    }
+    result
+}
+
+/// Removes what writes interrupted before their rename left in `dir`.
+fn remove_temporaries(dir: &Path) {
+    for file in fs::read_dir(dir).into_iter().flatten().flatten() {
+        let path = file.path();
+        if path.extension().is_some_and(|extension| extension == "tmp") {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// What remote services answered, kept in a JSON file in the cache root
+/// by what was asked (e.g. a URL) along with when.
+///
+/// Answers older than the time to live are treated as missing, so
+/// corrections upstream eventually arrive; without one they're kept for
+/// good, so builds stay reproducible.
+#[derive(Debug)]
+pub struct Remote<T> {
+    path: PathBuf,
+    ttl: Option<Duration>,
+    entries: BTreeMap<String, Fetched<T>>,
+    changed: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Fetched<T> {
+    value: T,
+    /// When, in seconds since the Unix epoch.
+    fetched: u64,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+impl<T: Serialize + DeserializeOwned> Remote<T> {
+    /// Reads the answers in `path`. A file which can't be read is treated
+    /// as empty.
+    pub fn open(path: PathBuf, ttl: Option<Duration>) -> Self {
+        let entries = fs::read(&path)
+            .ok()
+            .and_then(|contents| serde_json::from_slice(&contents).ok())
+            .unwrap_or_default();
+        Self {
+            path,
+            ttl,
+            entries,
+            changed: false,
+        }
+    }
+
+    /// The answer for `key`, unless there is none or it has expired.
+    pub fn get(&self, key: &str) -> Option<&T> {
+        let fetched = self.entries.get(key)?;
+        let age = Duration::from_secs(now().saturating_sub(fetched.fetched));
+        match self.ttl {
+            Some(ttl) if age >= ttl => None,
+            _ => Some(&fetched.value),
+        }
+    }
+
+    pub fn insert(&mut self, key: String, value: T) {
+        let fetched = Fetched {
+            value,
+            fetched: now(),
+        };
+        self.entries.insert(key, fetched);
+        self.changed = true;
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        self.changed |= self.entries.remove(key).is_some();
+    }
+
+    /// Writes the answers back, if any changed.
+    pub fn save(&self) -> Result<(), Error> {
+        if !self.changed {
+            return Ok(());
+        }
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        write_atomic(&self.path, serde_json::to_vec_pretty(&self.entries)?)?;
+        Ok(())
+    }
+}
+
+/// Fingerprint of a file the whole book depends on.
+///
+/// A missing file gets a fixed fingerprint rather than an error, so
+/// creating it later still invalidates the cache.
+pub fn file_fingerprint(path: &Path) -> String {
+    match fs::read(path) {
+        Ok(contents) => sha256_hex(contents),
+        Err(_) => "missing".to_string(),
+    }
+}
+
+/// Fingerprints of everything besides the chapter text which influences
+/// pandoc's output: bibliography, style, locale and configuration.
+///
+/// If any of them changes every cached chapter is stale.
+pub type Dependencies = BTreeMap<String, String>;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Metadata {
+    dependencies: Dependencies,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    input: String,
+    output: String,
+    /// The hash of `output`, to tell an entry which was damaged on disk.
+    /// Entries from before it was recorded have none, and are discarded.
+    #[serde(default)]
+    checksum: String,
+}
+
+impl Entry {
+    fn new(input: &str, output: &str) -> Self {
+        Self {
+            input: sha256_hex(input),
+            output: output.to_string(),
+            checksum: sha256_hex(output),
+        }
+    }
+
+    fn is_intact(&self) -> bool {
+        self.checksum == sha256_hex(&self.output)
+    }
+}
+
+/// The in-memory counterpart of [`Cache`], used by the daemon to keep
+/// processed chapters around between rebuilds.
+#[derive(Debug, Default)]
+pub struct MemoryCache {
+    dependencies: Dependencies,
+    entries: HashMap<String, Entry>,
+}
+
+impl MemoryCache {
+    /// Discards every entry if `dependencies` changed since the last build.
+    pub fn validate(&mut self, dependencies: &Dependencies) {
+        if &self.dependencies != dependencies {
+            self.entries.clear();
+            self.dependencies = dependencies.clone();
+        }
+    }
+
+    pub fn get(&self, key: &str, input: &str) -> Option<String> {
+        let entry = self.entries.get(key)?;
+        (entry.input == sha256_hex(input)).then(|| entry.output.clone())
+    }
+
+    /// Returns the last output for chapter `key`, whatever it was produced from.
+    pub fn get_stale(&self, key: &str) -> Option<String> {
+        self.entries.get(key).map(|entry| entry.output.clone())
+    }
+
+    pub fn put(&mut self, key: &str, input: &str, output: &str) {
+        self.entries
+            .insert(key.to_string(), Entry::new(input, output));
+    }
+}
+
+/// A cache of processed chapters, so `mdbook serve` only reruns pandoc on
+/// the chapters which were actually edited.
+pub struct Cache {
+    store: Store,
+}
+
+enum Store {
+    /// `metadata.json` and a JSON file per chapter in `chapters/`.
+    Files(PathBuf),
+    /// `cache.sqlite3`, for books with so many chapters that a file each
+    /// gets slow.
+    #[cfg(feature = "sqlite")]
+    Sqlite(rusqlite::Connection),
+}
+
+impl Cache {
+    /// Opens the cache in `dir`, discarding every cached chapter if the
+    /// recorded dependencies don't match `dependencies`.
+    pub fn open(
+        dir: PathBuf,
+        dependencies: &Dependencies,
+        backend: CacheBackend,
+    ) -> Result<Self, Error> {
+        let cache = Self::open_stale(dir, backend)?;
+        match &cache.store {
+            Store::Files(dir) => {
+                let metadata_path = dir.join("metadata.json");
+                let metadata: Metadata = fs::read(&metadata_path)
+                    .ok()
+                    .and_then(|data| serde_json::from_slice(&data).ok())
+                    .unwrap_or_default();
+
+                if &metadata.dependencies != dependencies {
+                    let chapters_dir = dir.join("chapters");
+                    match fs::remove_dir_all(&chapters_dir) {
+                        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                        _ => {}
+                    }
+                    fs::create_dir_all(chapters_dir)?;
+                    let metadata = Metadata {
+                        dependencies: dependencies.clone(),
+                    };
+                    write_atomic(&metadata_path, serde_json::to_vec_pretty(&metadata)?)?;
+                }
+                remove_temporaries(dir);
+                remove_temporaries(&dir.join("chapters"));
+            }
+            #[cfg(feature = "sqlite")]
+            Store::Sqlite(db) => sqlite::validate(db, dependencies)?,
+        }
+        Ok(cache)
+    }
+
+    /// Opens the cache in `dir` without checking or updating its recorded
+    /// dependencies, to serve the last good build when the current inputs
+    /// are broken.
+    pub fn open_stale(dir: PathBuf, backend: CacheBackend) -> Result<Self, Error> {
+        let store = match backend {
+            CacheBackend::Files => Store::Files(dir),
+            CacheBackend::Sqlite => open_sqlite(&dir)?,
+        };
+        Ok(Self { store })
+    }
+
+    fn entry(&self, key: &str) -> Option<Entry> {
+        match &self.store {
+            Store::Files(dir) => {
+                let path = entry_path(dir, key);
+                let data = fs::read(&path).ok()?;
+                match serde_json::from_slice::<Entry>(&data) {
+                    Ok(entry) if entry.is_intact() => {
+                        touch(&path);
+                        Some(entry)
+                    }
+                    // Rerunning the chapter replaces it.
+                    _ => {
+                        let _ = fs::remove_file(path);
+                        None
+                    }
+                }
+            }
+            #[cfg(feature = "sqlite")]
+            Store::Sqlite(db) => sqlite::get(db, key),
+        }
+    }
+
+    /// Returns the cached output for chapter `key` if it was produced from
+    /// exactly `input`.
+    pub fn get(&self, key: &str, input: &str) -> Option<String> {
+        let entry = self.entry(key)?;
+        (entry.input == sha256_hex(input)).then_some(entry.output)
+    }
+
+    /// Returns the last output cached for chapter `key`, whatever it was
+    /// produced from.
+    pub fn get_stale(&self, key: &str) -> Option<String> {
+        Some(self.entry(key)?.output)
+    }
+
+    /// Records `output` as the result of processing `input` for chapter `key`.
+    pub fn put(&self, key: &str, input: &str, output: &str) -> Result<(), Error> {
+        let entry = Entry::new(input, output);
+        match &self.store {
+            Store::Files(dir) => {
+                write_atomic(&entry_path(dir, key), serde_json::to_vec(&entry)?)?;
+            }
+            #[cfg(feature = "sqlite")]
+            Store::Sqlite(db) => sqlite::put(db, key, &entry)?,
+        }
+        Ok(())
+    }
+}
+
+fn entry_path(dir: &Path, key: &str) -> PathBuf {
+    dir.join("chapters")
+        .join(format!("{}.json", sha256_hex(key)))
+}
+
+#[cfg(feature = "sqlite")]
+fn open_sqlite(dir: &Path) -> Result<Store, Error> {
+    Ok(Store::Sqlite(sqlite::open(dir)?))
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn open_sqlite(_dir: &Path) -> Result<Store, Error> {
+    Err(CiteprocError::config(
+        "cache-backend = \"sqlite\" requires mdbook-citeproc to be built with the `sqlite` feature",
+    ))
+}
+
+/// The SQLite cache: a `metadata` table holding the recorded dependencies
+/// and a `chapters` table keyed like the chapter files.
+#[cfg(feature = "sqlite")]
+mod sqlite {
+    use std::fs;
+    use std::path::Path;
+
+    use mdbook::errors::Error;
+    use rusqlite::{params, Connection, OptionalExtension};
+
+    use super::{sha256_hex, Dependencies, Entry, Metadata};
+
+    const SCHEMA: &str = "
+        CREATE TABLE IF NOT EXISTS metadata (name TEXT PRIMARY KEY, value TEXT NOT NULL);
+        CREATE TABLE IF NOT EXISTS chapters (
+            key TEXT PRIMARY KEY,
+            input TEXT NOT NULL,
+            output TEXT NOT NULL
+        );
+    ";
+
+    /// Opens `cache.sqlite3` in `dir`, moving a file cache already there
+    /// into it.
+    pub fn open(dir: &Path) -> Result<Connection, Error> {
+        fs::create_dir_all(dir)?;
+        let path = dir.join("cache.sqlite3");
+        let new = !path.exists();
+        let mut db = Connection::open(&path)
+            .map_err(|e| Error::msg(format!("can't open {}: {e}", path.display())))?;
+        db.pragma_update(None, "journal_mode", "WAL")?;
+        db.execute_batch(SCHEMA)?;
+        if new {
+            migrate(&mut db, dir)?;
+        }
+        Ok(db)
+    }
+
+    /// Imports `metadata.json` and `chapters/` and removes them, so
+    /// switching backends doesn't cost a full rebuild.
+    fn migrate(db: &mut Connection, dir: &Path) -> Result<(), Error> {
+        let metadata_path = dir.join("metadata.json");
+        let Some(metadata) = fs::read(&metadata_path)
+            .ok()
+            .and_then(|data| serde_json::from_slice::<Metadata>(&data).ok())
+        else {
+            return Ok(());
+        };
+        let chapters_dir = dir.join("chapters");
+        let transaction = db.transaction()?;
+        transaction.execute(
+            "INSERT OR REPLACE INTO metadata (name, value) VALUES ('dependencies', ?1)",
+            params![serde_json::to_string(&metadata.dependencies)?],
+        )?;
+        for file in fs::read_dir(&chapters_dir).into_iter().flatten().flatten() {
+            let path = file.path();
+            let Some(hash) = path.file_stem().and_then(|stem| stem.to_str()) else {
+                continue;
+            };
+            let Some(entry) = fs::read(&path)
+                .ok()
+                .and_then(|data| serde_json::from_slice::<Entry>(&data).ok())
+                .filter(Entry::is_intact)
+            else {
+                continue;
+            };
+            transaction.execute(
+                "INSERT OR REPLACE INTO chapters (key, input, output) VALUES (?1, ?2, ?3)",
+                params![hash, entry.input, entry.output],
+            )?;
+        }
+        transaction.commit()?;
+        let _ = fs::remove_dir_all(chapters_dir);
+        let _ = fs::remove_file(metadata_path);
+        Ok(())
+    }
+
+    /// Discards every chapter if the recorded dependencies don't match
+    /// `dependencies`.
+    pub fn validate(db: &Connection, dependencies: &Dependencies) -> Result<(), Error> {
+        let current = serde_json::to_string(dependencies)?;
+        let recorded: Option<String> = db
+            .query_row(
+                "SELECT value FROM metadata WHERE name = 'dependencies'",
+                [],
+                |row| row.get(0),
+            )
+            .optional()?;
+        if recorded.as_deref() != Some(current.as_str()) {
+            db.execute_batch("DELETE FROM chapters")?;
+            db.execute(
+                "INSERT OR REPLACE INTO metadata (name, value) VALUES ('dependencies', ?1)",
+                params![current],
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn get(db: &Connection, key: &str) -> Option<Entry> {
+        db.query_row(
+            "SELECT input, output FROM chapters WHERE key = ?1",
+            params![sha256_hex(key)],
+            |row| {
+                let output: String = row.get(1)?;
+                Ok(Entry {
+                    input: row.get(0)?,
+                    // SQLite's transactions keep rows whole.
+                    checksum: sha256_hex(&output),
+                    output,
+                })
+            },
+        )
+        .ok()
+    }
+
+    pub fn put(db: &Connection, key: &str, entry: &Entry) -> Result<(), Error> {
+        db.execute(
+            "INSERT OR REPLACE INTO chapters (key, input, output) VALUES (?1, ?2, ?3)",
+            params![sha256_hex(key), entry.input, entry.output],
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remote_answers_expire() {
+        let path =
+            std::env::temp_dir().join(format!("citeproc-remote-{}.json", std::process::id()));
+        let mut remote = Remote::open(path.clone(), None);
+        remote.insert("https://x.org".into(), 1);
+        remote.save().unwrap();
+
+        assert_eq!(
+            Remote::<u8>::open(path.clone(), None).get("https://x.org"),
+            Some(&1)
+        );
+        let expired = Remote::<u8>::open(path.clone(), Some(Duration::ZERO));
+        assert_eq!(expired.get("https://x.org"), None);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn damaged_entries_are_discarded() {
+        let dir = std::env::temp_dir().join(format!("citeproc-damaged-{}", std::process::id()));
+        let dependencies = Dependencies::from([("style".to_string(), "1".to_string())]);
+        let cache = Cache::open(dir.clone(), &dependencies, CacheBackend::Files).unwrap();
+        cache.put("one", "input", "output").unwrap();
+        assert_eq!(cache.get("one", "input").as_deref(), Some("output"));
+
+        let path = entry_path(&dir, "one");
+        let damaged = fs::read_to_string(&path)
+            .unwrap()
+            .replace("output", "outpu");
+        fs::write(&path, damaged).unwrap();
+        fs::write(dir.join("chapters/.x.json.1.0.tmp"), "{").unwrap();
+        let cache = Cache::open(dir.clone(), &dependencies, CacheBackend::Files).unwrap();
+        assert!(!dir.join("chapters/.x.json.1.0.tmp").exists());
+        assert_eq!(cache.get_stale("one"), None);
+        assert!(!path.exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn sqlite_cache_takes_over_the_file_cache() {
+        let dir = std::env::temp_dir().join(format!("citeproc-sqlite-{}", std::process::id()));
+        let dependencies = Dependencies::from([("style".to_string(), "1".to_string())]);
+        let files = Cache::open(dir.clone(), &dependencies, CacheBackend::Files).unwrap();
+        files.put("one", "input", "output").unwrap();
+
+        let db = Cache::open(dir.clone(), &dependencies, CacheBackend::Sqlite).unwrap();
+        assert_eq!(db.get("one", "input").as_deref(), Some("output"));
+        assert!(!dir.join("chapters").exists());
+
+        let changed = Dependencies::from([("style".to_string(), "2".to_string())]);
+        let db = Cache::open(dir.clone(), &changed, CacheBackend::Sqlite).unwrap();
+        assert_eq!(db.get_stale("one"), None);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,137 @@
+//! Titles recased for `title-case`, since CSL styles can title-case a
+//! title but not sentence-case one, and a bibliography collected over the
+//! years has both.
+//!
+//! Braced text is left as it is, as BibTeX protects it, and so is any word
+//! with a capital past its first letter, like `DNA` or `iPhone`, which is
+//! most likely a name or an acronym.
+
+use crate::config::TitleCase;
+
+/// Words title case leaves lowercase, except first and last.
+const SMALL_WORDS: &[&str] = &[
+    "a", "an", "and", "as", "at", "but", "by", "for", "from", "in", "into", "nor", "of", "on",
+    "or", "over", "the", "to", "up", "via", "with",
+];
+
+/// `title` in the `case` asked for.
+pub fn recase(title: &str, case: TitleCase) -> String {
+    if case == TitleCase::Preserve {
+        return title.to_string();
+    }
+    let words = words(title);
+    let mut out = String::with_capacity(title.len());
+    let mut starts_sentence = true;
+    for (i, (space, word)) in words.iter().enumerate() {
+        out.push_str(space);
+        let last = i + 1 == words.len();
+        out.push_str(&recase_word(word, case, starts_sentence, last));
+        starts_sentence = word.ends_with([':', '?', '!', '.']);
+    }
+    out
+}
+
+/// `text` as words outside braces, each with the whitespace before it.
+fn words(text: &str) -> Vec<(&str, &str)> {
+    let mut words = Vec::new();
+    let mut depth = 0usize;
+    let mut space_start = 0;
+    let mut word_start = None;
+    for (i, c) in text.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+        if c.is_whitespace() && depth == 0 {
+            if let Some(start) = word_start.take() {
+                words.push((&text[space_start..start], &text[start..i]));
+                space_start = i;
+            }
+        } else if word_start.is_none() {
+            word_start = Some(i);
+        }
+    }
+    match word_start {
+        Some(start) => words.push((&text[space_start..start], &text[start..])),
+        None if space_start < text.len() => words.push((&text[space_start..], "")),
+        None => {}
+    }
+    words
+}
+
+fn recase_word(word: &str, case: TitleCase, first: bool, last: bool) -> String {
+    // The letters outside braces, which are the ones to change.
+    let mut depth = 0usize;
+    let letters: Vec<(usize, char)> = word
+        .char_indices()
+        .filter(|&(_, c)| {
+            match c {
+                '{' => depth += 1,
+                '}' => depth = depth.saturating_sub(1),
+                _ => return depth == 0 && c.is_alphabetic(),
+            }
+            false
+        })
+        .collect();
+    let protected = letters.is_empty()
+        || word
+            .trim_start_matches(|c: char| !c.is_alphanumeric() && c != '{')
+            .starts_with('{')
+        || letters.iter().skip(1).any(|(_, c)| c.is_uppercase());
+    if protected {
+        return word.to_string();
+    }
+    let bare: String = letters
+        .iter()
+        .map(|(_, c)| *c)
+        .collect::<String>()
+        .to_lowercase();
+    let capitalize = match case {
+        TitleCase::Preserve => return word.to_string(),
+        TitleCase::Sentence => first,
+        TitleCase::Title => first || last || !SMALL_WORDS.contains(&bare.as_str()),
+    };
+    let mut out = String::with_capacity(word.len());
+    let mut letters = letters.iter().peekable();
+    for (i, c) in word.char_indices() {
+        match letters.peek() {
+            Some(&&(at, _)) if at == i => {
+                let is_first = out.chars().all(|c| !c.is_alphabetic());
+                if capitalize && is_first {
+                    out.extend(c.to_uppercase());
+                } else if case == TitleCase::Sentence {
+                    out.extend(c.to_lowercase());
+                } else {
+                    out.push(c);
+                }
+                letters.next();
+            }
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recases_outside_braces() {
+        let title = "The {TeX}book: A Guide to DNA and {Rust} for iPhone Users";
+        assert_eq!(
+            recase(title, TitleCase::Sentence),
+            "The {TeX}book: A guide to DNA and {Rust} for iPhone users"
+        );
+        assert_eq!(
+            recase(
+                "citations in practice: a survey of {mdBook} use",
+                TitleCase::Title
+            ),
+            "Citations in Practice: A Survey of {mdBook} Use"
+        );
+        assert_eq!(recase(title, TitleCase::Preserve), title);
+        assert_eq!(recase("  (the end) ", TitleCase::Title), "  (The End) ");
+    }
+}
@@ -0,0 +1,387 @@
+//! `mdbook-citeproc check`: validates a book's configuration without
+//! building it, and with `--preview` shows how its citations render.
+
+use std::fs;
+use std::path::{Component, Path};
+
+use mdbook::book::Book;
+use mdbook::errors::Error;
+use mdbook::{BookItem, MDBook};
+
+use crate::args::Format;
+use crate::backend::{self, Capabilities, CitationBackend};
+use crate::bibliography::{self, Library};
+use crate::citation;
+use crate::compat;
+use crate::config::{Config, PandocSetting};
+use crate::error::CiteprocError;
+use crate::http;
+use crate::lint;
+use crate::model::BookCitations;
+use crate::orcid;
+use crate::ordering;
+use crate::quarto;
+use crate::style;
+use crate::template::EntryTemplate;
+use crate::wiki_links;
+
+/// Loads `[preprocessor.citeproc]` from the `book.toml` in `root`, with
+/// the profile [`PROFILE_VAR`] selects if it's set.
+///
+/// [`PROFILE_VAR`]: crate::config::PROFILE_VAR
+pub fn load_config(root: &Path) -> Result<Config, Error> {
+    load(root, None).map(|(_, config)| config)
+}
+
+/// Like [`load_config`], but with the profile for `renderer` applied too.
+pub fn load_config_for(root: &Path, renderer: &str) -> Result<Config, Error> {
+    load(root, Some(renderer)).map(|(_, config)| config)
+}
+
+fn load(root: &Path, renderer: Option<&str>) -> Result<(mdbook::Config, Config), Error> {
+    let mut book_config = mdbook::Config::from_disk(root.join("book.toml"))
+        .map_err(|e| CiteprocError::config(format!("failed to read book.toml: {e}")))?;
+    // As mdbook would for a build, so a translation's `MDBOOK_BOOK__LANGUAGE`
+    // applies.
+    book_config.update_from_env();
+    let table = book_config
+        .get_preprocessor("citeproc")
+        .ok_or_else(|| CiteprocError::config("No config table for citeproc preprocessor"))?;
+    let mut config = Config::from_table_for(table, root, renderer)?;
+    config.resolve_admonish(&book_config);
+    if let Some(language) = &book_config.book.language {
+        config.set_language(language, root);
+    }
+    Ok((book_config, config))
+}
+
+/// Checks the book in `root`, returning warnings for anything which will
+/// build but not as configured. Hard problems, like a malformed
+/// bibliography, are errors.
+pub fn check(root: &Path) -> Result<Vec<String>, Error> {
+    let (book_config, mut config) = load(root, None)?;
+    http::configure_network(&config)?;
+    if let Some(remote) = &config.remote_bibliography {
+        remote.fetch(config.remote_ttl, false)?;
+    }
+    style::fetch_remote(&mut config, http::is_offline())?;
+    if let Some(bibliography) = &config.bibliography {
+        bibliography::validate(&root.join(&bibliography.bibliography))?;
+    }
+    if let Some(template) = &config.entry_template {
+        EntryTemplate::load(template)?;
+    }
+    let backend = backend::select(&config, root, false)?;
+    let capabilities = backend.capabilities();
+    if let Some(version) = &capabilities.pandoc_version {
+        compat::check(&config, version)?;
+    }
+    let mut warnings = capability_warnings(&config, root, backend.name(), &capabilities);
+    warnings.extend(extension_warnings(&config, root));
+    warnings.extend(ordering::warnings(&book_config, &config));
+    if let Some(bibliography) = &config.bibliography {
+        let mut entries = bibliography::load(&root.join(&bibliography.bibliography))?;
+        for extra in &config.extra_bibliographies {
+            entries.extend(bibliography::load(extra)?);
+        }
+        let mut book = MDBook::load(root)?;
+        if config.wiki_links {
+            wiki_links::normalize_book(&mut book.book);
+        }
+        let mut citations = BookCitations::collect(&book.book);
+        if config.quarto {
+            quarto::skip_crossrefs(&mut citations);
+        }
+        let keys = citations.keys();
+        if config.orcid_names {
+            warnings.extend(orcid::names(&entries).conflicts);
+        }
+        warnings.extend(lint::check(&config, root, &Library::new(entries), &keys)?);
+    }
+    Ok(warnings)
+}
+
+/// Starts each citation's paragraph in a preview, so it can be found in
+/// pandoc's output.
+const PREVIEW_PREFIX: &str = "CITEPROCPREVIEW";
+
+/// A citation as it's written in a chapter and as the configured style
+/// renders it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Preview {
+    pub chapter: String,
+    pub citation: String,
+    pub rendered: String,
+}
+
+/// Renders every distinct citation in the book in `root`, chapter by
+/// chapter, for `check --preview`. Empty if citations aren't transpiled.
+pub fn preview(root: &Path) -> Result<Vec<Preview>, Error> {
+    let mut config = load_config(root)?;
+    if config.bibliography.is_none() {
+        return Ok(Vec::new());
+    }
+    http::configure_network(&config)?;
+    style::fetch_remote(&mut config, http::is_offline())?;
+    let mut book = MDBook::load(root)?;
+    if config.wiki_links {
+        wiki_links::normalize_book(&mut book.book);
+    }
+    let backend = backend::select(&config, root, false)?;
+    previews(backend.as_ref(), &config, &book.book)
+}
+
+/// The previews of `book`'s citations, rendered by `backend` in one run:
+/// each citation in a paragraph of its own, as plain text, without a
+/// bibliography.
+fn previews(
+    backend: &dyn CitationBackend,
+    config: &Config,
+    book: &Book,
+) -> Result<Vec<Preview>, Error> {
+    let mut previews: Vec<Preview> = Vec::new();
+    for item in book.iter() {
+        let BookItem::Chapter(chapter) = item else {
+            continue;
+        };
+        let content = config.rename_keys(&chapter.content);
+        for cited in citation::parse(&content) {
+            let citation = content[cited.span].to_string();
+            let seen = previews
+                .iter()
+                .any(|p| p.chapter == chapter.name && p.citation == citation);
+            if !seen {
+                previews.push(Preview {
+                    chapter: chapter.name.clone(),
+                    citation,
+                    rendered: String::new(),
+                });
+            }
+        }
+    }
+    if previews.is_empty() {
+        return Ok(previews);
+    }
+
+    let mut config = config.clone();
+    config.to = Format::new("plain");
+    config
+        .metadata
+        .insert("suppress-bibliography".into(), "true".into());
+    let input: String = previews
+        .iter()
+        .enumerate()
+        .map(|(i, p)| format!("{PREVIEW_PREFIX}{i}X {}\n\n", p.citation))
+        .collect();
+    let output = backend.convert(&config, &input)?;
+    if let Some(failure) = output.failure {
+        return Err(CiteprocError::pandoc_failed(format!(
+            "{} failed rendering the citation previews ({failure}): {}",
+            backend.name(),
+            output.stderr.trim()
+        )));
+    }
+    let output = String::from_utf8_lossy(&output.stdout);
+    for (i, preview) in previews.iter_mut().enumerate() {
+        let marker = format!("{PREVIEW_PREFIX}{i}X");
+        let Some(start) = output.find(&marker) else {
+            continue;
+        };
+        let rest = &output[start + marker.len()..];
+        let end = rest.find("\n\n").unwrap_or(rest.len());
+        preview.rendered = rest[..end].split_whitespace().collect::<Vec<_>>().join(" ");
+    }
+    Ok(previews)
+}
+
+/// Extension settings which would lose citations or the notes they render
+/// into.
+pub fn extension_warnings(config: &Config, root: &Path) -> Vec<String> {
+    let mut warnings = Vec::new();
+    if let Some(bibliography) = &config.bibliography {
+        if config.extensions.get("footnotes") != Some(&PandocSetting::Preserve)
+            && is_note_style(&root.join(&bibliography.bibliography_style))
+        {
+            warnings.push(format!(
+                "{} is a note style, so citations become footnotes, but footnotes aren't preserved",
+                bibliography.bibliography_style
+            ));
+        }
+    }
+    warnings
+}
+
+/// Everything `config` asks for which a backend with `capabilities` can't
+/// deliver.
+pub fn capability_warnings(
+    config: &Config,
+    root: &Path,
+    backend: &str,
+    capabilities: &Capabilities,
+) -> Vec<String> {
+    let mut warnings = Vec::new();
+
+    if let Some(bibliography) = &config.bibliography {
+        if !capabilities.citeproc {
+            warnings.push(format!(
+                "citations are set to transpile but the {backend} backend can't resolve citations"
+            ));
+        }
+        if !capabilities.custom_styles {
+            warnings.push(format!(
+                "the {backend} backend can't use the custom style {}",
+                bibliography.bibliography_style
+            ));
+        }
+        if !capabilities.note_styles && is_note_style(&root.join(&bibliography.bibliography_style))
+        {
+            warnings.push(format!(
+                "{} is a note style, which the {backend} backend doesn't support",
+                bibliography.bibliography_style
+            ));
+        }
+        if !capabilities.reads_outside_root {
+            for path in [&bibliography.bibliography, &bibliography.bibliography_style] {
+                if escapes_root(Path::new(path)) {
+                    warnings.push(format!(
+                        "the {backend} backend can only read files inside the book, but {path} is outside of it"
+                    ));
+                }
+            }
+        }
+    }
+
+    if let (Some(locale), Some(locales)) = (&config.locale, &capabilities.locales) {
+        if !locales.contains(locale) {
+            warnings.push(format!(
+                "the {backend} backend doesn't support the locale {locale}"
+            ));
+        }
+    }
+
+    warnings
+}
+
+/// Whether the CSL style at `path` declares `class="note"`.
+fn is_note_style(path: &Path) -> bool {
+    let Ok(style) = fs::read_to_string(path) else {
+        return false;
+    };
+    style
+        .find("<style")
+        .and_then(|start| {
+            style[start..]
+                .find('>')
+                .map(|end| &style[start..start + end])
+        })
+        .is_some_and(|tag| tag.contains("class=\"note\"") || tag.contains("class='note'"))
+}
+
+fn escapes_root(path: &Path) -> bool {
+    path.is_absolute() || path.components().any(|c| c == Component::ParentDir)
+}
+
+#[cfg(test)]
+mod tests {
+    use mdbook::book::Chapter;
+
+    use super::*;
+    use crate::backend::Output;
+
+    /// Hands the input back with its citations upper-cased, as if rendered.
+    struct Shouting;
+
+    impl CitationBackend for Shouting {
+        fn name(&self) -> &str {
+            "shouting"
+        }
+
+        fn capabilities(&self) -> Capabilities {
+            Capabilities::default()
+        }
+
+        fn convert(&self, _config: &Config, input: &str) -> Result<Output, Error> {
+            Ok(Output {
+                stdout: input.to_uppercase().into_bytes(),
+                ..Output::default()
+            })
+        }
+    }
+
+    #[test]
+    fn previews_each_citation_once_per_chapter() {
+        let mut book = Book::new();
+        let chapter = |name: &str, content: &str| {
+            BookItem::Chapter(Chapter::new(
+                name,
+                content.into(),
+                format!("{name}.md"),
+                vec![],
+            ))
+        };
+        book.push_item(chapter(
+            "One",
+            "See [@smith2020, p. 3] and @adams2018.\n\n@adams2018 again.",
+        ));
+        book.push_item(chapter("Two", "`@not` [@smith2020, p. 3]"));
+        let toml = "citations = \"transpile\"\nbibliography = \"refs.bib\"\ncsl = \"style.csl\"";
+        let config =
+            Config::from_table(&toml::from_str(toml).unwrap(), Path::new("/book")).unwrap();
+        let previews = previews(&Shouting, &config, &book).unwrap();
+        let shown: Vec<(&str, &str, &str)> = previews
+            .iter()
+            .map(|p| (p.chapter.as_str(), p.citation.as_str(), p.rendered.as_str()))
+            .collect();
+        assert_eq!(
+            shown,
+            [
+                ("One", "[@smith2020, p. 3]", "[@SMITH2020, P. 3]"),
+                ("One", "@adams2018", "@ADAMS2018"),
+                ("Two", "[@smith2020, p. 3]", "[@SMITH2020, P. 3]"),
+            ]
+        );
+    }
+
+    /// `check` needn't be run from the book's root, as mdbook runs us: here
+    /// it's run from the crate's.
+    #[cfg(unix)]
+    #[test]
+    fn previews_a_book_elsewhere() {
+        use std::fs;
+        use std::os::unix::fs::PermissionsExt;
+
+        let root = std::env::temp_dir().join(format!("citeproc-preview-{}", std::process::id()));
+        fs::create_dir_all(root.join("src")).unwrap();
+        // A fake pandoc which fails, as pandoc does, if it can't find a
+        // file it's given.
+        let pandoc = root.join("pandoc");
+        fs::write(
+            &pandoc,
+            "#!/bin/sh\nfor arg; do case $arg in\n\
+             --version) echo pandoc 3.1; exit;;\n\
+             --bibliography=*|--csl=*) test -f \"${arg#*=}\" || exit 4;;\n\
+             esac; done\ncat\n",
+        )
+        .unwrap();
+        fs::set_permissions(&pandoc, fs::Permissions::from_mode(0o755)).unwrap();
+        fs::write(
+            root.join("book.toml"),
+            format!(
+                "[preprocessor.citeproc]\ncitations = \"transpile\"\n\
+                 bibliography = \"refs.bib\"\ncsl = \"style.csl\"\n\
+                 pandoc-command = [{:?}]\n",
+                pandoc.display().to_string()
+            ),
+        )
+        .unwrap();
+        fs::write(root.join("refs.bib"), "@book{a, title = {A}}\n").unwrap();
+        fs::write(root.join("style.csl"), "").unwrap();
+        fs::write(root.join("src/SUMMARY.md"), "- [One](one.md)\n").unwrap();
+        fs::write(root.join("src/one.md"), "See [@a].\n").unwrap();
+
+        let previews = preview(&root).unwrap();
+        assert_eq!(previews.len(), 1);
+        assert_eq!(previews[0].rendered, "[@a]");
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,361 @@
+//! A parser for pandoc's citation syntax, for everything we do with
+//! citations besides handing them to citeproc.
+//!
+//! It follows pandoc's rules closely enough for real chapters: bracketed
+//! citations (`[see @smith, p. 3; -@jones]`), in-text citations (`@smith`,
+//! optionally followed by a bracketed locator), and `@{key}` for keys with
+//! unusual characters. Citations in code are ignored.
+
+use std::ops::Range;
+
+use serde::{Deserialize, Serialize};
+
+/// A citation as it appears in a chapter.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Citation {
+    /// Where the citation is in the text it was parsed from, in bytes.
+    pub span: Range<usize>,
+    pub mode: Mode,
+    pub items: Vec<Item>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Mode {
+    /// `[@smith]`: rendered in parentheses (or as a note).
+    Bracketed,
+    /// `@smith`: the author is part of the sentence.
+    InText,
+}
+
+/// One cited work within a citation.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Item {
+    pub key: String,
+    /// Text before the key, e.g. `see`.
+    pub prefix: String,
+    /// Text after the key, e.g. `p. 3`, without the separating comma.
+    pub suffix: String,
+    /// `-@smith`: only the year is rendered.
+    pub suppress_author: bool,
+}
+
+/// Parses every citation in `text`.
+pub fn parse(text: &str) -> Vec<Citation> {
+    let mut citations = Vec::new();
+    let bytes = text.as_bytes();
+    let mut fence: Option<(u8, usize)> = None;
+    let mut line_start = true;
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if line_start {
+            let line_end = text[i..].find('\n').map_or(text.len(), |end| i + end + 1);
+            let trimmed = text[i..line_end].trim_start_matches(' ');
+            let c = trimmed.bytes().next();
+            let run = trimmed.bytes().take_while(|b| Some(*b) == c).count();
+            match (fence, c) {
+                (Some((open, len)), Some(c)) if c == open && run >= len => {
+                    if trimmed.trim_end().len() == run {
+                        fence = None;
+                    }
+                    i = line_end;
+                    continue;
+                }
+                (Some(_), _) => {
+                    i = line_end;
+                    continue;
+                }
+                (None, Some(c @ (b'`' | b'~'))) if run >= 3 => {
+                    fence = Some((c, run));
+                    i = line_end;
+                    continue;
+                }
+                _ => {}
+            }
+            line_start = false;
+        }
+
+        match bytes[i] {
+            b'\n' => {
+                line_start = true;
+                i += 1;
+            }
+            b'\\' => i += 2,
+            b'`' => {
+                let ticks = bytes[i..].iter().take_while(|b| **b == b'`').count();
+                let close = text[i + ticks..].find(&"`".repeat(ticks));
+                i = close.map_or(i + ticks, |end| i + ticks + end + ticks);
+            }
+            // A link destination.
+            b'(' if i > 0 && bytes[i - 1] == b']' => {
+                i = text[i..].find(')').map_or(i + 1, |end| i + end + 1);
+            }
+            b'[' => match bracketed(text, i) {
+                Some(citation) => {
+                    i = citation.span.end;
+                    citations.push(citation);
+                }
+                None => i += 1,
+            },
+            b'@' if i == 0 || !is_word(bytes[i - 1]) => match key(text, i) {
+                Some((key, end)) => {
+                    let (suffix, end) = in_text_suffix(text, end);
+                    citations.push(Citation {
+                        span: i..end,
+                        mode: Mode::InText,
+                        items: vec![Item {
+                            key,
+                            prefix: String::new(),
+                            suffix,
+                            suppress_author: false,
+                        }],
+                    });
+                    i = end;
+                }
+                None => i += 1,
+            },
+            _ => i += 1,
+        }
+    }
+    citations
+}
+
+/// Cites each of `keys`, for pandoc's `nocite` metadata.
+pub fn nocite(keys: &[String]) -> String {
+    keys.iter()
+        .map(|key| cite(key))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// `@key`, braced if the key wouldn't parse back on its own.
+fn cite(key: &str) -> String {
+    match self::key(&format!("@{key}"), 0) {
+        Some((parsed, _)) if parsed == key => format!("@{key}"),
+        _ => format!("@{{{key}}}"),
+    }
+}
+
+/// Replaces every cited key in `text` which `rename` maps to another one.
+pub fn rename_keys<'a>(text: &str, rename: impl Fn(&str) -> Option<&'a str>) -> String {
+    let bytes = text.as_bytes();
+    let mut out = String::with_capacity(text.len());
+    let mut last = 0;
+    for citation in parse(text) {
+        let mut i = citation.span.start;
+        while i < citation.span.end {
+            if bytes[i] == b'@' && (i == 0 || !is_word(bytes[i - 1])) {
+                if let Some((key, end)) = key(text, i) {
+                    if let Some(new) = rename(&key) {
+                        out.push_str(&text[last..i]);
+                        out.push_str(&cite(new));
+                        last = end;
+                    }
+                    i = end;
+                    continue;
+                }
+            }
+            i += 1;
+        }
+    }
+    out.push_str(&text[last..]);
+    out
+}
+
+fn is_word(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'_' || b > 0x7f
+}
+
+/// The citation key starting at the `@` at `at`, and where it ends.
+fn key(text: &str, at: usize) -> Option<(String, usize)> {
+    let rest = &text[at + 1..];
+    if let Some(braced) = rest.strip_prefix('{') {
+        let end = braced.find('}')?;
+        return Some((braced[..end].to_string(), at + 1 + end + 2));
+    }
+    let first = rest.chars().next()?;
+    if !(first.is_alphanumeric() || first == '_') {
+        return None;
+    }
+    // Internal punctuation is part of the key, trailing punctuation isn't.
+    let mut end = 0;
+    let mut chars = rest.char_indices().peekable();
+    while let Some((offset, c)) = chars.next() {
+        if c.is_alphanumeric() || c == '_' {
+            end = offset + c.len_utf8();
+        } else if ":.#$%&-+?<>~/".contains(c) {
+            match chars.peek() {
+                Some((_, next)) if next.is_alphanumeric() || *next == '_' => {}
+                _ => break,
+            }
+        } else {
+            break;
+        }
+    }
+    Some((rest[..end].to_string(), at + 1 + end))
+}
+
+/// A bracketed locator directly after an in-text citation, as in
+/// `@smith [p. 3]`.
+fn in_text_suffix(text: &str, end: usize) -> (String, usize) {
+    let rest = &text[end..];
+    let Some(bracket) = rest.strip_prefix(' ').unwrap_or(rest).strip_prefix('[') else {
+        return (String::new(), end);
+    };
+    let start = end + (rest.len() - bracket.len());
+    match bracket.find(']') {
+        Some(close) if !bracket[..close].contains(['@', '[']) => {
+            let after = &text[start + close + 1..];
+            // `@smith [text](url)` is a citation followed by a link.
+            if after.starts_with(['(', '[']) {
+                return (String::new(), end);
+            }
+            (bracket[..close].trim().to_string(), start + close + 1)
+        }
+        _ => (String::new(), end),
+    }
+}
+
+/// The bracketed citation starting at the `[` at `at`, if it is one.
+fn bracketed(text: &str, at: usize) -> Option<Citation> {
+    let inner_start = at + 1;
+    let mut depth = 0usize;
+    let mut close = None;
+    for (offset, c) in text[inner_start..].char_indices() {
+        match c {
+            '[' => depth += 1,
+            ']' if depth == 0 => {
+                close = Some(inner_start + offset);
+                break;
+            }
+            ']' => depth -= 1,
+            '\n' if text[inner_start + offset + 1..].starts_with('\n') => return None,
+            _ => {}
+        }
+    }
+    let close = close?;
+    // Links and reference links aren't citations.
+    if text[close + 1..].starts_with(['(', '[']) {
+        return None;
+    }
+
+    let mut items = Vec::new();
+    for part in text[inner_start..close].split(';') {
+        let bytes = part.as_bytes();
+        let at =
+            (0..bytes.len()).find(|&i| bytes[i] == b'@' && (i == 0 || !is_word(bytes[i - 1])))?;
+        let (key, end) = key(part, at)?;
+        let (prefix, suppress_author) = match part[..at].strip_suffix('-') {
+            Some(prefix) => (prefix, true),
+            None => (&part[..at], false),
+        };
+        let suffix = part[end..].trim();
+        items.push(Item {
+            key,
+            prefix: prefix.trim().to_string(),
+            suffix: suffix
+                .strip_prefix(',')
+                .unwrap_or(suffix)
+                .trim()
+                .to_string(),
+            suppress_author,
+        });
+    }
+    Some(Citation {
+        span: at..close + 1,
+        mode: Mode::Bracketed,
+        items,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(text: &str) -> Vec<Vec<String>> {
+        parse(text)
+            .into_iter()
+            .map(|c| c.items.into_iter().map(|item| item.key).collect())
+            .collect()
+    }
+
+    #[test]
+    fn parses_bracketed_citations() {
+        let citations = parse("As shown [see @smith2020, p. 3; -@jones.2021].");
+        assert_eq!(citations.len(), 1);
+        let citation = &citations[0];
+        assert_eq!(citation.mode, Mode::Bracketed);
+        assert_eq!(citation.span, 9..45);
+        assert_eq!(
+            citation.items,
+            [
+                Item {
+                    key: "smith2020".into(),
+                    prefix: "see".into(),
+                    suffix: "p. 3".into(),
+                    suppress_author: false,
+                },
+                Item {
+                    key: "jones.2021".into(),
+                    prefix: String::new(),
+                    suffix: String::new(),
+                    suppress_author: true,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_clusters_together() {
+        // Styles can only collapse `[1]–[3]` if the cluster reaches
+        // citeproc as one citation.
+        assert_eq!(
+            keys("[@a; @b; @c] and [@d]"),
+            [vec!["a", "b", "c"], vec!["d"]]
+        );
+    }
+
+    #[test]
+    fn cites_keys_for_nocite() {
+        let keys = [
+            "smith2020".to_string(),
+            "odd key".to_string(),
+            "a.".to_string(),
+        ];
+        assert_eq!(nocite(&keys), "@smith2020 @{odd key} @{a.}");
+        assert_eq!(
+            self::keys(&nocite(&keys)),
+            [["smith2020"], ["odd key"], ["a."]]
+        );
+    }
+
+    #[test]
+    fn renames_keys() {
+        let renamed = rename_keys(
+            "[see @old, p. 3; @keep] and @old. `@old` me@old.com",
+            |key| (key == "old").then_some("new:2020"),
+        );
+        assert_eq!(
+            renamed,
+            "[see @new:2020, p. 3; @keep] and @new:2020. `@old` me@old.com"
+        );
+    }
+
+    #[test]
+    fn parses_in_text_citations() {
+        let citations = parse("@smith [p. 3] says, and so does @{odd key}.");
+        assert_eq!(citations.len(), 2);
+        assert_eq!(citations[0].mode, Mode::InText);
+        assert_eq!(citations[0].items[0].suffix, "p. 3");
+        assert_eq!(citations[0].span, 0..13);
+        assert_eq!(citations[1].items[0].key, "odd key");
+    }
+
+    #[test]
+    fn ignores_things_which_are_not_citations() {
+        assert!(keys("me@example.com [a link](@x) `@code` [text]").is_empty());
+        assert!(keys("```\n@fenced\n```\n").is_empty());
+        assert_eq!(keys("Trailing @smith."), [["smith"]]);
+    }
+}
@@ -0,0 +1,151 @@
+//! COinS, for `coins`: each reference in the HTML renderer's bibliographies
+//! carries an empty `<span class="Z3988">` whose title is the entry as an
+//! OpenURL ContextObject, which Zotero's browser connector (and others
+//! like it) find on the page to import the references from.
+
+use serde_json::Value;
+
+use crate::archive::encode;
+use crate::bibliography::{self, Entry};
+
+const VERSION: &str = "ctx_ver=Z39.88-2004";
+
+/// The COinS `<span>` for `entry`.
+pub fn span(entry: &Entry) -> String {
+    let title = context_object(entry)
+        .iter()
+        .map(|(key, value)| format!("{key}={}", encode(value)))
+        .collect::<Vec<_>>()
+        .join("&amp;");
+    format!("<span class=\"Z3988\" title=\"{VERSION}&amp;{title}\"></span>")
+}
+
+/// The keys and values of `entry`'s ContextObject, in the journal, book or
+/// (for anything else) Dublin Core format.
+fn context_object(entry: &Entry) -> Vec<(&'static str, String)> {
+    let csl = entry.csl_json();
+    let get = |variable: &str| {
+        csl.get(variable)
+            .and_then(Value::as_str)
+            .map(str::to_string)
+    };
+    let mut kev = Vec::new();
+    let mut push = |key: &'static str, value: Option<String>| {
+        if let Some(value) = value.filter(|value| !value.is_empty()) {
+            kev.push((key, value));
+        }
+    };
+    let format = match entry.kind.as_str() {
+        "article" | "article-journal" | "article-magazine" | "article-newspaper"
+        | "paper-conference" => "journal",
+        "book" | "chapter" => "book",
+        _ => "dc",
+    };
+    push(
+        "rft_val_fmt",
+        Some(format!("info:ofi/fmt:kev:mtx:{format}")),
+    );
+    push("rft_id", get("DOI").map(|doi| format!("info:doi/{doi}")));
+    push("rft_id", get("URL"));
+    let names = entry
+        .fields
+        .get("author")
+        .map(|authors| bibliography::names(authors))
+        .unwrap_or_default();
+    match format {
+        "journal" => {
+            let genre = match entry.kind.as_str() {
+                "paper-conference" => "proceeding",
+                _ => "article",
+            };
+            push("rft.genre", Some(genre.into()));
+            push("rft.atitle", get("title"));
+            push("rft.jtitle", get("container-title"));
+            push("rft.volume", get("volume"));
+            push("rft.issue", get("issue"));
+            push("rft.pages", get("page"));
+            push("rft.issn", get("ISSN"));
+        }
+        "book" => {
+            match entry.kind.as_str() {
+                "chapter" => {
+                    push("rft.genre", Some("bookitem".into()));
+                    push("rft.atitle", get("title"));
+                    push("rft.btitle", get("container-title"));
+                    push("rft.pages", get("page"));
+                }
+                _ => {
+                    push("rft.genre", Some("book".into()));
+                    push("rft.btitle", get("title"));
+                }
+            }
+            push("rft.pub", get("publisher"));
+            push("rft.place", get("publisher-place"));
+            push("rft.edition", get("edition"));
+            push("rft.isbn", get("ISBN"));
+        }
+        _ => {
+            push("rft.type", Some(entry.kind.clone()));
+            push("rft.title", get("title"));
+            push("rft.publisher", get("publisher"));
+            for name in &names {
+                push("rft.creator", Some(full_name(name)));
+            }
+        }
+    }
+    push("rft.date", get("issued"));
+    if format != "dc" {
+        if let Some(first) = names.first().filter(|name| name.literal.is_none()) {
+            push("rft.aulast", Some(first.family.clone()));
+            push("rft.aufirst", Some(first.given.clone()));
+        }
+        for name in &names {
+            push("rft.au", Some(full_name(name)));
+        }
+    }
+    kev
+}
+
+fn full_name(name: &bibliography::Name) -> String {
+    if let Some(literal) = &name.literal {
+        return literal.clone();
+    }
+    [&name.given, &name.particle, &name.family, &name.suffix]
+        .iter()
+        .filter(|part| !part.is_empty())
+        .map(|part| part.as_str())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn describes_articles_as_context_objects() {
+        let entry = Entry {
+            key: "smith2020".into(),
+            kind: "article-journal".into(),
+            fields: [
+                ("author", "Smith, Jo and Doe, J."),
+                ("title", "On {Citing} & Such"),
+                ("journal", "Things"),
+                ("volume", "3"),
+                ("year", "2020"),
+                ("doi", "10.1/x"),
+            ]
+            .iter()
+            .map(|(field, value)| (field.to_string(), value.to_string()))
+            .collect(),
+        };
+        assert_eq!(
+            span(&entry),
+            "<span class=\"Z3988\" title=\"ctx_ver=Z39.88-2004&amp;\
+             rft_val_fmt=info%3Aofi%2Ffmt%3Akev%3Amtx%3Ajournal&amp;rft_id=info%3Adoi%2F10.1%2Fx&amp;\
+             rft.genre=article&amp;rft.atitle=On%20Citing%20%26%20Such&amp;rft.jtitle=Things&amp;\
+             rft.volume=3&amp;rft.date=2020&amp;rft.aulast=Smith&amp;rft.aufirst=Jo&amp;\
+             rft.au=Jo%20Smith&amp;rft.au=J.%20Doe\"></span>"
+        );
+    }
+}
@@ -0,0 +1,185 @@
+//! Pandoc's command line changes between versions, so the arguments for a
+//! chapter are chosen for the pandoc which will convert it:
+//!
+//! | feature               | before          | from   |
+//! |-----------------------|-----------------|--------|
+//! | `--citeproc`          | unsupported     | 2.11   |
+//! | `--markdown-headings` | `--atx-headers` | 2.11.2 |
+//! | `--sandbox`           | unsupported     | 2.15   |
+//! | the `typst` writer    | unsupported     | 3.1.2  |
+//!
+//! `--atx-headers` was removed in pandoc 3, so it's only used for the
+//! versions without its replacement. When the version isn't known the
+//! arguments are those of current pandoc.
+
+use std::ffi::OsString;
+use std::fmt;
+use std::path::Path;
+use std::process::Stdio;
+
+use mdbook::errors::Error;
+
+use crate::config::{Config, HeadingStyle};
+use crate::error::CiteprocError;
+use crate::subprocess;
+
+const CITEPROC: &[u32] = &[2, 11];
+const MARKDOWN_HEADINGS: &[u32] = &[2, 11, 2];
+const SANDBOX: &[u32] = &[2, 15];
+const TYPST: &[u32] = &[3, 1, 2];
+
+/// A pandoc version, e.g. `3.1.11.1`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PandocVersion(Vec<u32>);
+
+impl PandocVersion {
+    pub fn parse(version: &str) -> Option<Self> {
+        let parts = version
+            .trim()
+            .split('.')
+            .map(|part| part.parse().ok())
+            .collect::<Option<Vec<u32>>>()?;
+        Some(Self(parts))
+    }
+
+    /// The version in the first line of `pandoc --version`, such as
+    /// `pandoc 3.1.11.1` (or `pandoc.exe 2.19.2` on Windows).
+    pub fn from_version_output(output: &str) -> Option<Self> {
+        let line = output.lines().next()?;
+        let (program, version) = line.split_once(' ')?;
+        if Path::new(program).file_stem() != Some("pandoc".as_ref()) {
+            return None;
+        }
+        Self::parse(version.split_whitespace().next()?)
+    }
+
+    pub fn at_least(&self, version: &[u32]) -> bool {
+        self.0.as_slice() >= version
+    }
+}
+
+impl fmt::Display for PandocVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let parts: Vec<String> = self.0.iter().map(u32::to_string).collect();
+        f.write_str(&parts.join("."))
+    }
+}
+
+/// The version of the pandoc `program` runs, if it says.
+pub fn detect(program: &[OsString]) -> Option<PandocVersion> {
+    let output = subprocess::command(program)
+        .ok()?
+        .arg("--version")
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    PandocVersion::from_version_output(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// Checks pandoc `version` can do everything `config` asks of it.
+pub fn check(config: &Config, version: &PandocVersion) -> Result<(), Error> {
+    let too_old = |feature: &str, needed: &[u32]| {
+        let needed = PandocVersion(needed.to_vec());
+        Err(CiteprocError::pandoc_missing(format!(
+            "{feature} needs pandoc {needed} or later, but pandoc is {version}"
+        )))
+    };
+    if config.bibliography.is_some() && !version.at_least(CITEPROC) {
+        return too_old("rendering citations", CITEPROC);
+    }
+    if config.sandbox.enabled && !version.at_least(SANDBOX) {
+        return too_old("sandbox", SANDBOX);
+    }
+    if config.to.name == "typst" && !version.at_least(TYPST) {
+        return too_old("the typst writer", TYPST);
+    }
+    Ok(())
+}
+
+/// The arguments for heading `style`. Before `--markdown-headings`, ATX
+/// headings had a flag of their own and setext ones were the default.
+pub fn heading_args(style: HeadingStyle, version: Option<&PandocVersion>) -> Option<String> {
+    match version {
+        Some(version) if !version.at_least(MARKDOWN_HEADINGS) => {
+            (style == HeadingStyle::Atx).then(|| "--atx-headers".to_string())
+        }
+        _ => Some(format!("--markdown-headings={}", heading_style(style))),
+    }
+}
+
+/// `style`'s name in `--markdown-headings`.
+pub fn heading_style(style: HeadingStyle) -> &'static str {
+    match style {
+        HeadingStyle::Atx => "atx",
+        HeadingStyle::Setext => "setext",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::args::Format;
+
+    #[test]
+    fn parses_versions() {
+        let version = |output: &str| PandocVersion::from_version_output(output);
+        assert_eq!(
+            version("pandoc 3.1.11.1\nFeatures: +server +lua\n"),
+            PandocVersion::parse("3.1.11.1")
+        );
+        assert_eq!(
+            version("pandoc.exe 2.19.2\r\n").map(|v| v.to_string()),
+            Some("2.19.2".into())
+        );
+        assert_eq!(version("pandoc-citeproc 0.17"), None);
+        assert_eq!(version("not pandoc 3.0"), None);
+        assert_eq!(version(""), None);
+
+        let v2_11_0_4 = PandocVersion::parse("2.11.0.4").unwrap();
+        assert!(v2_11_0_4.at_least(CITEPROC));
+        assert!(!v2_11_0_4.at_least(MARKDOWN_HEADINGS));
+    }
+
+    #[test]
+    fn picks_flags_for_the_version() {
+        let old = PandocVersion::parse("2.11.1").unwrap();
+        let new = PandocVersion::parse("3.5").unwrap();
+        assert_eq!(
+            heading_args(HeadingStyle::Atx, Some(&old)).as_deref(),
+            Some("--atx-headers")
+        );
+        assert_eq!(heading_args(HeadingStyle::Setext, Some(&old)), None);
+        assert_eq!(
+            heading_args(HeadingStyle::Setext, Some(&new)).as_deref(),
+            Some("--markdown-headings=setext")
+        );
+        assert_eq!(
+            heading_args(HeadingStyle::Atx, None).as_deref(),
+            Some("--markdown-headings=atx")
+        );
+    }
+
+    #[test]
+    fn old_pandoc_is_refused_what_it_cannot_do() {
+        let table = toml::from_str(
+            r#"
+            citations = "transpile"
+            bibliography = "refs.bib"
+            csl = "style.csl"
+            "#,
+        )
+        .unwrap();
+        let mut config = Config::from_table(&table, Path::new(".")).unwrap();
+        let e = check(&config, &PandocVersion::parse("2.9.2.1").unwrap()).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "rendering citations needs pandoc 2.11 or later, but pandoc is 2.9.2.1"
+        );
+        assert!(check(&config, &PandocVersion::parse("2.11").unwrap()).is_ok());
+
+        config.to = Format::parse("typst-smart");
+        assert!(check(&config, &PandocVersion::parse("3.1.1").unwrap()).is_err());
+        assert!(check(&config, &PandocVersion::parse("3.1.2").unwrap()).is_ok());
+    }
+}
@@ -0,0 +1,2235 @@
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::env;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use mdbook::errors::Error;
+use toml::value::{Table, Value};
+
+use crate::args::Format;
+use crate::badge;
+use crate::bookdown;
+use crate::citation;
+use crate::deprecated;
+use crate::error::CiteprocError;
+use crate::extensions;
+use crate::fields::FieldMarkup;
+use crate::filter::Filter;
+use crate::http::RetryPolicy;
+use crate::library;
+use crate::pandoc_defaults::PandocDefaults;
+use crate::quarto;
+use crate::remote_bibliography::RemoteBibliography;
+use crate::restyle::WriterStyle;
+use crate::style::CITATION_OPTIONS;
+use crate::targets;
+use crate::urls::UrlPolicy;
+use crate::vendor;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PandocSetting {
+    #[default]
+    Preserve,
+    Transpile,
+}
+
+/// What to do when pandoc hands back output which is not valid UTF-8 (or
+/// which contains replacement characters that were not in the input).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EncodingPolicy {
+    /// Fail the build.
+    Error,
+    /// Warn and use the output with invalid sequences replaced by U+FFFD.
+    #[default]
+    Lossy,
+    /// Warn and keep the chapter's original, unprocessed content.
+    Original,
+}
+
+/// What to do when the bibliography is malformed or pandoc fails on a
+/// chapter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FailureMode {
+    /// Fail the build.
+    #[default]
+    Error,
+    /// Warn and keep the last good output for the chapter (from the cache),
+    /// or its unprocessed content if there is none.
+    KeepOriginal,
+}
+
+/// How much a bibliography entry missing a field matters. See
+/// [`lint`](crate::lint).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Off,
+    Warning,
+    /// Fails `check` and strict builds.
+    Error,
+}
+
+/// How raw HTML in chapters is handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RawHtml {
+    /// Let pandoc parse and re-emit it like any other markdown.
+    #[default]
+    Pandoc,
+    /// Hide it from pandoc so it comes back byte-identical.
+    Preserve,
+}
+
+/// What happens to a chapter's YAML front matter once it's been read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FrontMatterMode {
+    /// Put it back at the top of the processed chapter, for preprocessors
+    /// which run after us.
+    #[default]
+    Keep,
+    /// Remove it from the processed chapter.
+    Strip,
+}
+
+/// The front matter keys passed to pandoc as metadata unless
+/// `front-matter-metadata` says otherwise.
+const FRONT_MATTER_METADATA: &[&str] = &[
+    "csl",
+    "lang",
+    "link-citations",
+    "nocite",
+    "reference-section-title",
+    "suppress-bibliography",
+];
+
+/// How the entries of a chapter's bibliography are ordered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BibliographySort {
+    /// However the CSL style sorts them.
+    #[default]
+    Style,
+    /// By where each work is first cited.
+    Appearance,
+}
+
+/// How much of the book citations are rendered in view of.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Scope {
+    /// Each chapter on its own.
+    #[default]
+    Chapter,
+    /// The whole book, so e.g. year suffixes agree between chapters.
+    Book,
+}
+
+/// Where web entries without an access date get one from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AccessedDefault {
+    /// When the bibliography file was last modified.
+    Modified,
+    /// A fixed `YYYY-MM-DD` date.
+    Date(String),
+}
+
+/// How titles in the bibliography are cased before pandoc renders them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TitleCase {
+    /// As the bibliography has them.
+    #[default]
+    Preserve,
+    /// Only the first word, and any after a colon, capitalized, as APA
+    /// wants.
+    Sentence,
+    /// Every word but short ones like `of` capitalized.
+    Title,
+}
+
+/// A heading the bibliography is split under, from `bibliography-groups`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BibliographyGroup {
+    pub title: String,
+    /// The CSL item types in the group. `None` collects every entry no
+    /// other group claims.
+    pub types: Option<Vec<String>>,
+}
+
+impl BibliographyGroup {
+    /// The group entries which aren't of a listed type end up in when no
+    /// group collects them.
+    fn other() -> Self {
+        Self {
+            title: "Other".to_string(),
+            types: None,
+        }
+    }
+}
+
+/// The LaTeX package citations are written for in LaTeX passthrough mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LatexPackage {
+    /// `\autocite`, `\textcite`, ...
+    Biblatex,
+    /// `\citep`, `\citet`, ...
+    Natbib,
+}
+
+/// The pandoc writer for a renderer, from `[preprocessor.citeproc.renderers.<name>]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RendererConfig {
+    /// The pandoc writer, e.g. `typst`. `None` keeps the markdown writer.
+    pub writer: Option<String>,
+    /// Extensions to enable or disable, e.g. `["-raw_html"]`.
+    pub extensions: Vec<String>,
+}
+
+impl RendererConfig {
+    /// The writer for this renderer, in place of `to`.
+    pub fn writer_format(&self, to: &Format) -> Format {
+        let mut format = match &self.writer {
+            Some(writer) => Format::parse(writer),
+            None => to.clone(),
+        };
+        for extension in &self.extensions {
+            format.toggle_all(extension);
+        }
+        format
+    }
+}
+
+/// The heading syntax pandoc writes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeadingStyle {
+    /// `# Heading`
+    Atx,
+    /// `Heading` underlined with `===` or `---`.
+    Setext,
+}
+
+/// The order chapters are handed to pandoc in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Schedule {
+    #[default]
+    BookOrder,
+    /// The longest chapters first, so one slow chapter doesn't start last
+    /// and hold up the whole build.
+    LargestFirst,
+}
+
+/// Where the cache keeps processed chapters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CacheBackend {
+    /// A JSON file per chapter.
+    #[default]
+    Files,
+    /// One SQLite database, with the `sqlite` feature.
+    Sqlite,
+}
+
+/// Which [`CitationBackend`](crate::backend::CitationBackend) converts
+/// chapters.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum BackendKind {
+    /// A pandoc subprocess per chapter.
+    #[default]
+    Pandoc,
+    /// A `pandoc-server` listening at the given URL.
+    PandocServer(String),
+    /// A WASI build of pandoc at the given path, run in-process.
+    Wasm(PathBuf),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BibliographyConfig {
+    pub bibliography: String,
+    pub bibliography_style: String,
+}
+
+impl BibliographyConfig {
+    fn new(bibliography: String, bibliography_style: String) -> Self {
+        Self {
+            bibliography,
+            bibliography_style,
+        }
+    }
+}
+
+/// A "Further reading" list for each chapter, of the entries with both
+/// `keyword` and the chapter's own keyword among their `keywords`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FurtherReading {
+    pub keyword: String,
+    pub title: String,
+}
+
+/// The Atom feed of newly cited works, see [`feed`](crate::feed).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Feed {
+    /// Where it's written.
+    pub path: PathBuf,
+    pub title: String,
+    /// Where the book is published, which the feed's ids are made from.
+    pub url: Option<String>,
+}
+
+/// How pandoc is confined when chapters come from contributors who aren't
+/// trusted.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Sandbox {
+    /// Pass `--sandbox`, so pandoc reads no files but the ones it's given:
+    /// the bibliographies and the style. Needs pandoc 2.15.
+    pub enabled: bool,
+    /// Run pandoc with an empty environment, but for the `PATH` and
+    /// `pass_env`.
+    pub clear_env: bool,
+    pub pass_env: Vec<String>,
+    /// Run pandoc in this directory instead of the book's, so a relative
+    /// path in a chapter can't reach the book's files.
+    pub working_dir: Option<PathBuf>,
+}
+
+/// Limits on each pandoc process, so a runaway conversion can't take the
+/// machine down with it. Set with rlimits on Unix and a job object on
+/// Windows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Limits {
+    /// In bytes, of address space on Unix.
+    pub memory: Option<u64>,
+    pub cpu: Option<Duration>,
+}
+
+/// What changes for a translation, from `[lang.<language>]`.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct LanguageConfig {
+    pub bibliography_style: Option<String>,
+    pub reference_section_title: Option<String>,
+    pub locale: Option<String>,
+    /// Bibliographies used as well as the book's, e.g. with the
+    /// translations' own sources.
+    pub bibliographies: Vec<String>,
+}
+
+impl LanguageConfig {
+    fn from_table(language: &str, table: &Table) -> Result<Self, Error> {
+        if let Some(key) = table.keys().find(|key| {
+            !["csl", "reference-section-title", "locale", "bibliography"].contains(&key.as_str())
+        }) {
+            return Err(CiteprocError::config(format!(
+                "lang.{language}.{key} isn't something a translation can change; use \
+                 csl, reference-section-title, locale or bibliography"
+            )));
+        }
+        Ok(Self {
+            bibliography_style: get_str(table, "csl")?,
+            reference_section_title: get_str(table, "reference-section-title")?,
+            locale: get_str(table, "locale")?,
+            bibliographies: get_str_list(table, "bibliography")?.unwrap_or_default(),
+        })
+    }
+}
+
+pub type PandocConfig = HashMap<String, PandocSetting>;
+
+/// The markdown extensions which can be set to `"preserve"` or `"transpile"`.
+pub(crate) const EXTENSIONS: &[&str] = &[
+    "backtick_code_blocks",
+    "bracketed_spans",
+    "citations",
+    "definition_lists",
+    "emoji",
+    "fenced_code_attributes",
+    "fenced_code_blocks",
+    "fenced_divs",
+    "footnotes",
+    "grid_tables",
+    "hard_line_breaks",
+    "inline_notes",
+    "link_attributes",
+    "mark",
+    "markdown_in_html_blocks",
+    "multiline_tables",
+    "pipe_tables",
+    "simple_tables",
+    "table_captions",
+    "task_lists",
+];
+
+/// Extensions for blocks which can contain citations, and how they're
+/// handled by default whenever citations are transpiled. mdbook renders
+/// footnotes itself but not fenced divs, which are written as HTML `<div>`s
+/// instead (this includes the bibliography).
+const NESTING_EXTENSIONS: &[(&str, PandocSetting)] = &[
+    ("fenced_divs", PandocSetting::Transpile),
+    ("footnotes", PandocSetting::Preserve),
+];
+
+/// Table formats pandoc's writer prefers over pipe tables when enabled, so
+/// preserving any of them alongside `pipe_tables` would rewrite pipe tables.
+const TABLE_EXTENSIONS: &[&str] = &["grid_tables", "multiline_tables", "simple_tables"];
+
+/// The parsed `[preprocessor.citeproc]` table.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub from: Format,
+    pub to: Format,
+    /// How each markdown extension which was configured (or defaulted) is
+    /// handled.
+    pub extensions: PandocConfig,
+    pub bibliography: Option<BibliographyConfig>,
+    pub bibliography_sort: BibliographySort,
+    /// Only entries matching this are given to pandoc.
+    pub bibliography_filter: Option<Filter>,
+    /// Fields merged over bibliography entries, by key.
+    pub overrides: BTreeMap<String, BTreeMap<String, String>>,
+    /// Old citation keys chapters may still use, mapped to the current
+    /// ones.
+    pub aliases: BTreeMap<String, String>,
+    /// Cite entries for the same work by the first one's key, so the
+    /// bibliography lists the work once. See
+    /// [`duplicates`](crate::bibliography::duplicates).
+    pub deduplicate: bool,
+    /// Keys of entries whose names are each passed as a literal, like a
+    /// corporate author, however they're written.
+    pub literal_names: Vec<String>,
+    /// Write each author with an ORCID iD under one name, see
+    /// [`orcid`](crate::orcid).
+    pub orcid_names: bool,
+    /// The severity of entries of a CSL type missing a field, by type and
+    /// field, over the built-in requirements.
+    pub required_fields: BTreeMap<String, BTreeMap<String, Severity>>,
+    /// What `{{#source}}` lines start with, before the citation.
+    pub source_label: String,
+    /// The acknowledgment of each grant `{{#grant}}` can name, by its id.
+    pub grants: BTreeMap<String, String>,
+    /// The title of the section `{{#grant}}` acknowledgments go in.
+    pub acknowledgments_title: String,
+    /// Reading lists appended to chapters, if configured.
+    pub further_reading: Option<FurtherReading>,
+    /// Describe the works each chapter cites as schema.org JSON-LD, for
+    /// the HTML renderer. See [`structured_data`](crate::structured_data).
+    pub structured_data: bool,
+    /// Embed a COinS span in each reference, for the HTML renderer. See
+    /// [`coins`](crate::coins).
+    pub coins: bool,
+    /// The feed of works the book has started citing, if any.
+    pub feed: Option<Feed>,
+    /// Where the manifest of chapter hashes is written, if anywhere. See
+    /// [`manifest`](crate::manifest).
+    pub manifest: Option<PathBuf>,
+    /// The line put under each chapter's title counting the works it
+    /// cites, if any. See [`badge`](crate::badge).
+    pub citation_count: Option<String>,
+    /// The groups of renderers `only-` classes can name, by group.
+    pub targets: BTreeMap<String, Vec<String>>,
+    /// Entries defined in `book.toml` as CSL-JSON items, which take
+    /// precedence over the bibliography's own.
+    pub inline_entries: Vec<serde_json::Value>,
+    /// Further bibliography files for pandoc: any after the first in
+    /// `bibliography`, and the ones the preprocessor adds.
+    pub extra_bibliographies: Vec<PathBuf>,
+    /// A library in an online reference manager, which is one of the
+    /// bibliographies once fetched.
+    pub remote_bibliography: Option<RemoteBibliography>,
+    /// The math and code taken out of the derived bibliography. Filled in
+    /// by the preprocessor.
+    pub field_markup: FieldMarkup,
+    /// Groups the bibliography is split into by entry type, in order.
+    /// Empty when it isn't grouped.
+    pub bibliography_groups: Vec<BibliographyGroup>,
+    /// The level of the bibliography's heading, and one below it of its
+    /// groups'. `None` leaves pandoc's, a top-level heading.
+    pub reference_heading_level: Option<usize>,
+    /// Whether the bibliography gets a heading from
+    /// `reference-section-title` at all, wherever it's set. Off for books
+    /// whose chapters have their own heading above the `::: {#refs}`
+    /// marker.
+    pub reference_heading: bool,
+    /// Render the bibliography of a numeric style as ordered lists, with
+    /// the numbers its citations use.
+    pub numbered_bibliography: bool,
+    /// Render each entry's `annote` field beneath it.
+    pub annotations: bool,
+    /// Attributes set on the style's `<citation>` element, e.g. `collapse`.
+    pub citation_options: BTreeMap<String, String>,
+    /// How access dates are rendered, e.g. `"Accessed {day} {month}
+    /// {year}"`, replacing the style's own format.
+    pub accessed_format: Option<String>,
+    /// The access date for web entries which don't have one.
+    pub default_accessed: Option<AccessedDefault>,
+    /// How entries' titles are cased. See [`casing`](crate::casing).
+    pub title_case: TitleCase,
+    /// How links in the bibliography are rewritten.
+    pub url_policy: UrlPolicy,
+    /// Link each web entry to its closest Wayback Machine snapshot, with
+    /// `archive_link_text` as the link's text.
+    pub archive_links: bool,
+    pub archive_link_text: String,
+    /// The snapshots found for `archive_links`, by key. Filled in by the
+    /// preprocessor.
+    pub snapshots: BTreeMap<String, String>,
+    /// A Handlebars template bibliography entries are rendered with
+    /// instead of the style.
+    pub entry_template: Option<PathBuf>,
+    pub encoding: EncodingPolicy,
+    pub on_failure: FailureMode,
+    pub raw_html: RawHtml,
+    /// `None` leaves pandoc's default.
+    pub heading_style: Option<HeadingStyle>,
+    pub writer_style: WriterStyle,
+    pub front_matter: FrontMatterMode,
+    /// The renderer the book is being preprocessed for. Filled in by the
+    /// preprocessor; `html` otherwise.
+    pub renderer: String,
+    pub renderers: HashMap<String, RendererConfig>,
+    /// Write citations as LaTeX commands for these renderers instead of
+    /// resolving them.
+    pub latex_citations: Option<(LatexPackage, Vec<String>)>,
+    /// Process the bodies of mdbook-admonish blocks. `None` until resolved
+    /// against the book's configuration, see [`Config::resolve_admonish`].
+    pub admonish: Option<bool>,
+    /// Follow Quarto's conventions, see [`quarto`](crate::quarto). On by
+    /// default when the book's root has a `_quarto.yml`.
+    pub quarto: bool,
+    /// Leave bookdown's references alone, see [`bookdown`](crate::bookdown).
+    /// On by default when the book's root has a `_bookdown.yml`.
+    pub bookdown: bool,
+    /// Read `[[@key]]` wiki links as citations, see
+    /// [`wiki_links`](crate::wiki_links).
+    pub wiki_links: bool,
+    /// Render citations in chapter and part titles too.
+    pub process_titles: bool,
+    /// The front matter keys passed to pandoc as metadata.
+    pub front_matter_metadata: Vec<String>,
+    /// Extra metadata for pandoc, taken from a chapter's front matter.
+    pub metadata: BTreeMap<String, String>,
+    /// What disambiguation (e.g. `2020a`) takes into account.
+    pub disambiguation: Scope,
+    /// Where a work's first, full citation is: the first in each chapter
+    /// or only the first in the book. See [`positions`](crate::positions).
+    pub first_citation: Scope,
+    /// Keys every chapter implicitly cites, so pandoc disambiguates them in
+    /// view of the whole book. Filled in by the preprocessor.
+    pub nocite: Vec<String>,
+    /// `nocite` as a pandoc metadata file, for backends which take
+    /// arguments. Written by the preprocessor.
+    pub metadata_file: Option<PathBuf>,
+    pub strict: bool,
+    /// Make no network requests, relying on what's cached.
+    pub offline: bool,
+    /// Fail unless remote resources resolve as `citeproc.lock` says. See
+    /// [`lock`](crate::lock).
+    pub locked: bool,
+    /// Extra certificate authorities to trust, as a PEM file.
+    pub ca_bundle: Option<PathBuf>,
+    pub retry_policy: RetryPolicy,
+    /// How long answers from remote services are trusted. `None` keeps
+    /// them until `mdbook-citeproc refresh`.
+    pub remote_ttl: Option<Duration>,
+    /// Turn CRLF line endings in pandoc's output into LF, which pandoc
+    /// writes on Windows.
+    pub normalize_newlines: bool,
+    /// How many chapters are converted at once. Each one is a pandoc
+    /// process, which can take a lot of memory for long chapters.
+    pub max_jobs: usize,
+    pub schedule: Schedule,
+    /// Refuse chapters larger than this many bytes, rather than running
+    /// out of memory converting them.
+    pub max_chapter_size: Option<usize>,
+    /// Warn about changes pandoc made to chapters outside of citations.
+    pub audit: bool,
+    pub locale: Option<String>,
+    /// The language of the book being built, from `book.language`, which
+    /// mdbook-i18n-helpers sets for each translation.
+    pub language: Option<String>,
+    /// The overrides for each translation, by language.
+    pub languages: BTreeMap<String, LanguageConfig>,
+    /// The directory for everything we keep between builds.
+    pub cache_root: PathBuf,
+    /// Where processed chapters are cached, if caching is enabled.
+    pub cache_dir: Option<PathBuf>,
+    /// The book's `citeproc-vendor` directory, if it has one. See
+    /// [`vendor`](crate::vendor).
+    pub vendor_dir: Option<PathBuf>,
+    pub cache_backend: CacheBackend,
+    /// How large the cache root may grow, in bytes, before what was used
+    /// longest ago is removed. See [`gc`](crate::gc).
+    pub cache_max_size: Option<u64>,
+    /// How long what's in the cache root is kept without being used.
+    pub cache_max_age: Option<Duration>,
+    /// The program (plus any leading arguments) to run instead of `pandoc`,
+    /// e.g. `["quarto", "pandoc"]`.
+    pub pandoc_command: Vec<String>,
+    /// Download and use the pinned pandoc release instead of the one on the
+    /// `PATH`.
+    pub auto_install_pandoc: bool,
+    /// The expected SHA-256 of the downloaded pandoc archive.
+    pub pandoc_sha256: Option<String>,
+    pub sandbox: Sandbox,
+    pub pandoc_limits: Limits,
+    /// Chapters of at least this many bytes are given to pandoc in a
+    /// temporary file, and its output read back from another, instead of
+    /// through stdin and stdout.
+    pub tempfile_threshold: Option<u64>,
+    pub backend: BackendKind,
+    /// The `[preprocessor.citeproc.profile.<name>]` applied, if any.
+    pub profile: Option<String>,
+}
+
+/// The environment variable selecting a profile by name, which takes
+/// precedence over the profiles' `for-renderers`.
+pub const PROFILE_VAR: &str = "MDBOOK_CITEPROC_PROFILE";
+
+impl Config {
+    /// Reads `table` with the profile for `renderer` applied: the one
+    /// named by [`PROFILE_VAR`], or else the one whose `for-renderers`
+    /// lists `renderer`.
+    pub fn from_table_for(
+        table: &Table,
+        root: &Path,
+        renderer: Option<&str>,
+    ) -> Result<Self, Error> {
+        let requested = env::var(PROFILE_VAR).ok().filter(|name| !name.is_empty());
+        let table = migrated(table)?;
+        let (table, profile) = apply_profile(&table, renderer, requested.as_deref())?;
+        let mut config = Self::from_table(&table, root)?;
+        config.profile = profile;
+        Ok(config)
+    }
+
+    pub fn from_table(table: &Table, root: &Path) -> Result<Self, Error> {
+        let table = &migrated(table)?;
+        let mut settings: PandocConfig = HashMap::new();
+
+        let mut from = Format::new("markdown_strict");
+        let mut to = Format::new("markdown_strict");
+
+        for &setting in EXTENSIONS {
+            if let Some(option) = table.get(setting) {
+                from.enable(setting);
+                let action = match option.as_str() {
+                    None => PandocSetting::default(),
+                    Some("preserve") => PandocSetting::Preserve,
+                    Some("transpile") => PandocSetting::Transpile,
+                    Some(_) => {
+                        return Err(CiteprocError::config(format!(
+                            "{setting} must be either \"transpile\" or \"preserve\""
+                        )));
+                    }
+                };
+                to.set(setting, action == PandocSetting::Preserve);
+                settings.insert(setting.to_string(), action);
+            }
+        }
+
+        // Citations nested in footnotes or fenced divs (e.g. admonitions)
+        // are only found when pandoc parses those blocks.
+        if settings.get("citations") == Some(&PandocSetting::Transpile) {
+            for &(setting, action) in NESTING_EXTENSIONS {
+                if !settings.contains_key(setting) {
+                    from.enable(setting);
+                    to.set(setting, action == PandocSetting::Preserve);
+                    settings.insert(setting.to_string(), action);
+                }
+            }
+        }
+
+        if settings.get("pipe_tables") == Some(&PandocSetting::Preserve) {
+            for &other in TABLE_EXTENSIONS {
+                if settings.get(other) == Some(&PandocSetting::Preserve) {
+                    return Err(CiteprocError::config(format!(
+                        "pipe_tables and {other} can't both be preserved: \
+                         pandoc would rewrite pipe tables as {}",
+                        other.replace('_', " ")
+                    )));
+                }
+            }
+        }
+
+        let key = |name: &str| match settings.contains_key(name) {
+            true if table.contains_key(name) => Some(format!("`{name}`")),
+            true => Some("`citations = \"transpile\"`".to_string()),
+            false => None,
+        };
+        extensions::validate(&from, "pandoc's reader", key)?;
+        extensions::validate(&to, "pandoc's writer", key)?;
+
+        // Whatever book.toml doesn't say may be in a pandoc defaults file,
+        // or Quarto's project file.
+        let has_project = root.join(quarto::PROJECT).is_file();
+        let quarto = get_bool(table, "quarto")?.unwrap_or(has_project);
+        let pandoc_defaults = match get_str(table, "pandoc-defaults")? {
+            Some(path) => PandocDefaults::read(root, &path)?,
+            None if quarto && has_project => PandocDefaults::read(root, quarto::PROJECT)?,
+            None => PandocDefaults::default(),
+        };
+
+        let cache_root =
+            root.join(get_str(table, "cache-dir")?.unwrap_or_else(|| ".citeproc-cache".into()));
+        let vendor_dir = root.join(vendor::DIR);
+        // A reference manager's library is one more bibliography.
+        let library = match get_str(table, "library")? {
+            Some(path) => {
+                let format = get_str(table, "library-format")?;
+                let exported = library::export(root, &path, format.as_deref(), &cache_root)?;
+                Some(exported.display().to_string())
+            }
+            None => None,
+        };
+        let remote_bibliography = match table.get("remote-bibliography") {
+            None => None,
+            Some(Value::Table(remote)) => {
+                Some(RemoteBibliography::from_table(remote, &cache_root)?.vendored_in(&vendor_dir))
+            }
+            Some(_) => return Err(CiteprocError::config("remote-bibliography must be a table")),
+        };
+        let remote = remote_bibliography
+            .as_ref()
+            .map(|remote| remote.path.display().to_string());
+
+        // Any bibliographies after the first are given to pandoc as well.
+        let mut bibliographies = get_str_list(table, "bibliography")?
+            .unwrap_or(pandoc_defaults.bibliography)
+            .into_iter()
+            .chain(library)
+            .chain(remote);
+        let bib_style = get_str(table, "csl")?.or(pandoc_defaults.csl);
+        let bibliography = if let Some(PandocSetting::Transpile) = settings.get("citations") {
+            if let (Some(bib_style), Some(bib)) = (bib_style, bibliographies.next()) {
+                Some(BibliographyConfig::new(bib, bib_style))
+            } else {
+                return Err(CiteprocError::config(
+                    "citations set to transpile so csl and bibliography option must be provided!",
+                ));
+            }
+        } else {
+            None
+        };
+
+        let bibliography_sort = match get_str(table, "bibliography-sort")?.as_deref() {
+            None => BibliographySort::default(),
+            Some("style") => BibliographySort::Style,
+            Some("appearance") => BibliographySort::Appearance,
+            Some(_) => {
+                return Err(CiteprocError::config(
+                    "bibliography-sort must be either \"style\" or \"appearance\"",
+                ));
+            }
+        };
+
+        let bibliography_filter = match get_str(table, "bibliography-filter")? {
+            None => None,
+            Some(expression) => Some(Filter::parse(&expression).map_err(|e| {
+                CiteprocError::config(format!("bibliography-filter {expression:?}: {e}"))
+            })?),
+        };
+
+        let mut overrides = BTreeMap::new();
+        match table.get("overrides") {
+            None => {}
+            Some(Value::Table(entries)) => {
+                for (key, fields) in entries {
+                    let fields = fields.as_table().ok_or_else(|| {
+                        CiteprocError::config(format!("overrides.{key} must be a table"))
+                    })?;
+                    overrides.insert(
+                        key.clone(),
+                        field_values(fields, &format!("overrides.{key}"))?,
+                    );
+                }
+            }
+            Some(_) => return Err(CiteprocError::config("overrides must be a table")),
+        }
+
+        let mut aliases = BTreeMap::new();
+        match table.get("aliases") {
+            None => {}
+            Some(Value::Table(table)) => {
+                for (old, new) in table {
+                    let new = new.as_str().ok_or_else(|| {
+                        CiteprocError::config(format!("aliases.{old} must be a citation key"))
+                    })?;
+                    aliases.insert(old.clone(), new.to_string());
+                }
+            }
+            Some(_) => return Err(CiteprocError::config("aliases must be a table")),
+        }
+
+        let mut grants = BTreeMap::new();
+        match table.get("grants") {
+            None => {}
+            Some(Value::Table(table)) => {
+                for (id, grant) in table {
+                    let acknowledgment = match grant {
+                        Value::String(text) => text.clone(),
+                        Value::Table(grant) => match get_str(grant, "text")? {
+                            Some(text) => text,
+                            None => {
+                                let funder = get_str(grant, "funder")?.ok_or_else(|| {
+                                    CiteprocError::config(format!(
+                                        "grants.{id} needs a funder or its text"
+                                    ))
+                                })?;
+                                let number = get_str(grant, "number")?.unwrap_or(id.clone());
+                                format!("This work was supported by {funder} under grant {number}.")
+                            }
+                        },
+                        _ => {
+                            return Err(CiteprocError::config(format!(
+                                "grants.{id} must be an acknowledgment or a table"
+                            )))
+                        }
+                    };
+                    grants.insert(id.clone(), acknowledgment);
+                }
+            }
+            Some(_) => return Err(CiteprocError::config("grants must be a table")),
+        }
+
+        let mut required_fields = BTreeMap::new();
+        match table.get("required-fields") {
+            None => {}
+            Some(Value::Table(types)) => {
+                for (kind, fields) in types {
+                    let fields = fields.as_table().ok_or_else(|| {
+                        CiteprocError::config(format!("required-fields.{kind} must be a table"))
+                    })?;
+                    let mut severities = BTreeMap::new();
+                    for (field, severity) in fields {
+                        let severity = match severity.as_str() {
+                            Some("off") => Severity::Off,
+                            Some("warning") => Severity::Warning,
+                            Some("error") => Severity::Error,
+                            _ => {
+                                return Err(CiteprocError::config(format!(
+                                    "required-fields.{kind}.{field} must be \"off\", \"warning\" or \"error\""
+                                )))
+                            }
+                        };
+                        severities.insert(field.clone(), severity);
+                    }
+                    required_fields.insert(kind.clone(), severities);
+                }
+            }
+            Some(_) => return Err(CiteprocError::config("required-fields must be a table")),
+        }
+
+        let feed_title = get_str(table, "feed-title")?;
+        let feed_url = get_str(table, "feed-url")?;
+        let further_reading_title = get_str(table, "further-reading-title")?;
+        let further_reading = get_str(table, "further-reading")?.map(|keyword| FurtherReading {
+            keyword,
+            title: further_reading_title.unwrap_or_else(|| "Further reading".into()),
+        });
+
+        let citation_count = match table.get("citation-count") {
+            None | Some(Value::Boolean(false)) => None,
+            Some(Value::Boolean(true)) => Some(badge::DEFAULT_TEMPLATE.to_string()),
+            Some(Value::String(template)) => Some(template.clone()),
+            Some(_) => {
+                return Err(CiteprocError::config(
+                    "citation-count must be true, false or a line to show",
+                ))
+            }
+        };
+
+        let mut languages = BTreeMap::new();
+        match table.get("lang") {
+            None => {}
+            Some(Value::Table(table)) => {
+                for (language, overrides) in table {
+                    let overrides = overrides.as_table().ok_or_else(|| {
+                        CiteprocError::config(format!("lang.{language} must be a table"))
+                    })?;
+                    languages.insert(
+                        language.clone(),
+                        LanguageConfig::from_table(language, overrides)?,
+                    );
+                }
+            }
+            Some(_) => return Err(CiteprocError::config("lang must be a table")),
+        }
+
+        let mut targets = targets::default_groups();
+        match table.get("targets") {
+            None => {}
+            Some(Value::Table(table)) => {
+                for (group, renderers) in table {
+                    let renderers = match renderers {
+                        Value::String(renderer) => Some(vec![renderer.clone()]),
+                        Value::Array(renderers) => renderers
+                            .iter()
+                            .map(|renderer| renderer.as_str().map(str::to_string))
+                            .collect(),
+                        _ => None,
+                    };
+                    let renderers = renderers.ok_or_else(|| {
+                        CiteprocError::config(format!(
+                            "targets.{group} must be a renderer or a list of them"
+                        ))
+                    })?;
+                    targets.insert(group.clone(), renderers);
+                }
+            }
+            Some(_) => return Err(CiteprocError::config("targets must be a table")),
+        }
+
+        let mut inline_entries = Vec::new();
+        match table.get("entries") {
+            None => {}
+            Some(Value::Table(entries)) => {
+                for (key, fields) in entries {
+                    let fields = fields.as_table().ok_or_else(|| {
+                        CiteprocError::config(format!("entries.{key} must be a table"))
+                    })?;
+                    if !fields.contains_key("type") {
+                        return Err(CiteprocError::config(format!(
+                            "entries.{key} needs a CSL type, e.g. type = \"book\""
+                        )));
+                    }
+                    let mut item = serde_json::to_value(fields)?;
+                    item["id"] = key.clone().into();
+                    inline_entries.push(item);
+                }
+            }
+            Some(_) => return Err(CiteprocError::config("entries must be a table")),
+        }
+
+        let mut bibliography_groups = Vec::new();
+        match table.get("bibliography-groups") {
+            None => {}
+            Some(Value::Array(groups)) => {
+                for group in groups {
+                    let group = group.as_table().ok_or_else(|| {
+                        CiteprocError::config("bibliography-groups must be an array of tables")
+                    })?;
+                    let title = get_str(group, "title")?.ok_or_else(|| {
+                        CiteprocError::config("every bibliography group needs a title")
+                    })?;
+                    bibliography_groups.push(BibliographyGroup {
+                        title,
+                        types: get_str_list(group, "types")?,
+                    });
+                }
+                if bibliography_groups.iter().all(|g| g.types.is_some()) {
+                    bibliography_groups.push(BibliographyGroup::other());
+                }
+            }
+            Some(_) => {
+                return Err(CiteprocError::config(
+                    "bibliography-groups must be an array of tables",
+                ))
+            }
+        }
+
+        let reference_heading = get_bool(table, "reference-heading")?.unwrap_or(true);
+        let reference_heading_level = match table.get("reference-heading-level") {
+            None => None,
+            Some(Value::Integer(level)) if (1..=6).contains(level) => Some(*level as usize),
+            Some(_) => {
+                return Err(CiteprocError::config(
+                    "reference-heading-level must be a heading level, from 1 to 6",
+                ))
+            }
+        };
+
+        let mut citation_options = BTreeMap::new();
+        match table.get("citation-options") {
+            None => {}
+            Some(Value::Table(options)) => {
+                for (name, value) in options {
+                    let Some((_, allowed)) = CITATION_OPTIONS.iter().find(|(o, _)| o == name)
+                    else {
+                        return Err(CiteprocError::config(format!(
+                            "unknown citation option {name:?}"
+                        )));
+                    };
+                    let value = match value {
+                        Value::String(value) => value.clone(),
+                        Value::Boolean(value) => value.to_string(),
+                        _ => {
+                            return Err(CiteprocError::config(format!(
+                                "citation-options.{name} must be a string"
+                            )))
+                        }
+                    };
+                    if let Some(allowed) = allowed.filter(|a| !a.contains(&value.as_str())) {
+                        return Err(CiteprocError::config(format!(
+                            "citation-options.{name} must be one of {}",
+                            allowed.join(", ")
+                        )));
+                    }
+                    citation_options.insert(name.clone(), value);
+                }
+            }
+            Some(_) => return Err(CiteprocError::config("citation-options must be a table")),
+        }
+
+        let accessed_format = get_str(table, "accessed-format")?;
+        if let Some(format) = &accessed_format {
+            crate::style::accessed_date(format).map_err(CiteprocError::config)?;
+        }
+        let title_case = match get_str(table, "title-case")?.as_deref() {
+            None | Some("preserve") => TitleCase::Preserve,
+            Some("sentence") => TitleCase::Sentence,
+            Some("title") => TitleCase::Title,
+            Some(_) => {
+                return Err(CiteprocError::config(
+                    "title-case must be \"preserve\", \"sentence\" or \"title\"",
+                ))
+            }
+        };
+
+        let default_accessed = match get_str(table, "default-accessed")? {
+            None => None,
+            Some(value) if value == "mtime" => Some(AccessedDefault::Modified),
+            Some(date) if is_date(&date) => Some(AccessedDefault::Date(date)),
+            Some(_) => {
+                return Err(CiteprocError::config(
+                    "default-accessed must be \"mtime\" or a YYYY-MM-DD date",
+                ))
+            }
+        };
+
+        let url_policy = UrlPolicy {
+            max_length: match table.get("url-max-length") {
+                None => None,
+                Some(Value::Integer(length)) if *length > 0 => Some(*length as usize),
+                Some(_) => {
+                    return Err(CiteprocError::config(
+                        "url-max-length must be a positive integer",
+                    ))
+                }
+            },
+            prefer_doi: get_bool(table, "prefer-doi")?.unwrap_or(false),
+            strip_tracking: get_bool(table, "strip-tracking-parameters")?.unwrap_or(false),
+            suppress_urls: get_bool(table, "suppress-urls")?.unwrap_or(false),
+            suppress_dois: get_bool(table, "suppress-dois")?.unwrap_or(false),
+        };
+
+        let defaults = RetryPolicy::default();
+        let retry_policy = RetryPolicy {
+            retries: match table.get("retries") {
+                None => defaults.retries,
+                Some(Value::Integer(retries)) if (0..=10).contains(retries) => *retries as u32,
+                Some(_) => {
+                    return Err(CiteprocError::config(
+                        "retries must be an integer from 0 to 10",
+                    ))
+                }
+            },
+            backoff: match table.get("retry-backoff-ms") {
+                None => defaults.backoff,
+                Some(Value::Integer(ms)) if *ms >= 0 => Duration::from_millis(*ms as u64),
+                Some(_) => {
+                    return Err(CiteprocError::config(
+                        "retry-backoff-ms must be a non-negative integer",
+                    ))
+                }
+            },
+            requests_per_second: match table.get("requests-per-second") {
+                None => None,
+                Some(Value::Integer(rate)) if *rate > 0 => Some(*rate as f64),
+                Some(Value::Float(rate)) if *rate > 0.0 => Some(*rate),
+                Some(_) => {
+                    return Err(CiteprocError::config(
+                        "requests-per-second must be a positive number",
+                    ))
+                }
+            },
+        };
+
+        let remote_ttl = match table.get("remote-cache-days") {
+            None => None,
+            Some(Value::Integer(days)) if *days > 0 => {
+                Some(Duration::from_secs(*days as u64 * 24 * 60 * 60))
+            }
+            Some(_) => {
+                return Err(CiteprocError::config(
+                    "remote-cache-days must be a positive integer",
+                ))
+            }
+        };
+
+        let cache_max_size = get_size(table, "cache-max-size")?;
+        let cache_max_age = match table.get("cache-max-age-days") {
+            None => None,
+            Some(Value::Integer(days)) if *days > 0 => {
+                Some(Duration::from_secs(*days as u64 * 24 * 60 * 60))
+            }
+            Some(_) => {
+                return Err(CiteprocError::config(
+                    "cache-max-age-days must be a positive integer",
+                ))
+            }
+        };
+
+        let normalize_newlines = get_bool(table, "normalize-newlines")?.unwrap_or(true);
+
+        let max_jobs = match table.get("max-jobs") {
+            // One per core.
+            None => std::thread::available_parallelism().map_or(1, usize::from),
+            Some(Value::Integer(jobs)) if *jobs > 0 => *jobs as usize,
+            Some(_) => return Err(CiteprocError::config("max-jobs must be a positive integer")),
+        };
+
+        let max_chapter_size = match table.get("max-chapter-size") {
+            None => None,
+            Some(Value::Integer(bytes)) if *bytes > 0 => Some(*bytes as usize),
+            Some(Value::String(size)) if parse_size(size).is_some() => parse_size(size),
+            Some(_) => {
+                return Err(CiteprocError::config(
+                    "max-chapter-size must be a number of bytes or a size like \"50MB\"",
+                ))
+            }
+        };
+
+        let schedule = match get_str(table, "schedule")?.as_deref() {
+            None | Some("book-order") => Schedule::BookOrder,
+            Some("largest-first") => Schedule::LargestFirst,
+            Some(_) => {
+                return Err(CiteprocError::config(
+                    "schedule must be either \"book-order\" or \"largest-first\"",
+                ))
+            }
+        };
+
+        let first_citation = match get_str(table, "first-citation")?.as_deref() {
+            None => Scope::default(),
+            Some("chapter") => Scope::Chapter,
+            Some("book") => Scope::Book,
+            Some(_) => {
+                return Err(CiteprocError::config(
+                    "first-citation must be either \"chapter\" or \"book\"",
+                ));
+            }
+        };
+        let disambiguation = match get_str(table, "disambiguation")?.as_deref() {
+            None => Scope::default(),
+            Some("chapter") => Scope::Chapter,
+            Some("book") => Scope::Book,
+            Some(_) => {
+                return Err(CiteprocError::config(
+                    "disambiguation must be either \"chapter\" or \"book\"",
+                ));
+            }
+        };
+
+        let encoding = match get_str(table, "encoding")?.as_deref() {
+            None => EncodingPolicy::default(),
+            Some("error") => EncodingPolicy::Error,
+            Some("lossy") => EncodingPolicy::Lossy,
+            Some("original") => EncodingPolicy::Original,
+            Some(_) => {
+                return Err(CiteprocError::config(
+                    "encoding must be one of \"error\", \"lossy\" or \"original\"",
+                ));
+            }
+        };
+
+        let on_failure = match get_str(table, "on-failure")?.as_deref() {
+            None => FailureMode::default(),
+            Some("error") => FailureMode::Error,
+            Some("keep-original") => FailureMode::KeepOriginal,
+            Some(_) => {
+                return Err(CiteprocError::config(
+                    "on-failure must be either \"error\" or \"keep-original\"",
+                ));
+            }
+        };
+
+        let raw_html = match get_str(table, "raw-html")?.as_deref() {
+            None => RawHtml::default(),
+            Some("pandoc") => RawHtml::Pandoc,
+            Some("preserve") => RawHtml::Preserve,
+            Some(_) => {
+                return Err(CiteprocError::config(
+                    "raw-html must be either \"pandoc\" or \"preserve\"",
+                ));
+            }
+        };
+
+        let heading_style = match get_str(table, "heading-style")?.as_deref() {
+            None => None,
+            Some("atx") => Some(HeadingStyle::Atx),
+            Some("setext") => Some(HeadingStyle::Setext),
+            Some(_) => {
+                return Err(CiteprocError::config(
+                    "heading-style must be either \"atx\" or \"setext\"",
+                ));
+            }
+        };
+        let writer_style = WriterStyle {
+            list_marker: get_char(table, "list-marker", &['-', '*', '+'])?,
+            emphasis_marker: get_char(table, "emphasis-marker", &['*', '_'])?,
+        };
+
+        let front_matter = match get_str(table, "front-matter")?.as_deref() {
+            None => FrontMatterMode::default(),
+            Some("keep") => FrontMatterMode::Keep,
+            Some("strip") => FrontMatterMode::Strip,
+            Some(_) => {
+                return Err(CiteprocError::config(
+                    "front-matter must be either \"keep\" or \"strip\"",
+                ));
+            }
+        };
+        let front_matter_metadata =
+            get_str_list(table, "front-matter-metadata")?.unwrap_or_else(|| {
+                FRONT_MATTER_METADATA
+                    .iter()
+                    .map(|s| s.to_string())
+                    .collect()
+            });
+
+        let latex_package = match get_str(table, "latex-citations")?.as_deref() {
+            None => None,
+            Some("biblatex") => Some(LatexPackage::Biblatex),
+            Some("natbib") => Some(LatexPackage::Natbib),
+            Some(_) => {
+                return Err(CiteprocError::config(
+                    "latex-citations must be either \"biblatex\" or \"natbib\"",
+                ));
+            }
+        };
+        let latex_renderers =
+            get_str_list(table, "latex-renderers")?.unwrap_or_else(|| vec!["latex".to_string()]);
+
+        let mut renderers = HashMap::new();
+        match table.get("renderers") {
+            None => {}
+            Some(Value::Table(table)) => {
+                for (name, value) in table {
+                    let renderer = value.as_table().ok_or_else(|| {
+                        CiteprocError::config(format!("renderers.{name} must be a table"))
+                    })?;
+                    let extensions = get_str_list(renderer, "extensions")?.unwrap_or_default();
+                    if let Some(bad) = extensions.iter().find(|e| !e.starts_with(['+', '-'])) {
+                        return Err(CiteprocError::config(format!(
+                            "renderers.{name}.extensions must start with + or -, found {bad:?}"
+                        )));
+                    }
+                    renderers.insert(
+                        name.clone(),
+                        RendererConfig {
+                            writer: get_str(renderer, "writer")?,
+                            extensions,
+                        },
+                    );
+                }
+            }
+            Some(_) => return Err(CiteprocError::config("renderers must be a table")),
+        }
+        for (name, renderer) in &renderers {
+            let writer = renderer.writer_format(&to);
+            let side = format!("the {name} renderer's writer");
+            extensions::validate(&writer, &side, |extension| {
+                match renderer.extensions.iter().any(|e| &e[1..] == extension) {
+                    true => Some(format!("`renderers.{name}.extensions`")),
+                    false => key(extension),
+                }
+            })?;
+        }
+
+        let cache_dir = get_bool(table, "cache")?
+            .unwrap_or(false)
+            .then(|| cache_root.clone());
+
+        let cache_backend = match get_str(table, "cache-backend")?.as_deref() {
+            None | Some("files") => CacheBackend::Files,
+            Some("sqlite") => CacheBackend::Sqlite,
+            Some(_) => {
+                return Err(CiteprocError::config(
+                    "cache-backend must be either \"files\" or \"sqlite\"",
+                ))
+            }
+        };
+
+        let pandoc_command = match get_str_list(table, "pandoc-command")? {
+            None => vec!["pandoc".to_string()],
+            Some(command) if command.is_empty() => {
+                return Err(CiteprocError::config("pandoc-command must not be empty"));
+            }
+            Some(command) => command,
+        };
+        let auto_install_pandoc = get_bool(table, "auto-install-pandoc")?.unwrap_or(false);
+        if auto_install_pandoc && table.contains_key("pandoc-command") {
+            return Err(CiteprocError::config(
+                "auto-install-pandoc and pandoc-command can't be used together",
+            ));
+        }
+
+        let wasm_module = get_str(table, "wasm-module")?.map(PathBuf::from);
+        let default_backend = if wasm_module.is_some() {
+            "wasm"
+        } else {
+            "pandoc"
+        };
+        let backend = match get_str(table, "backend")?
+            .as_deref()
+            .unwrap_or(default_backend)
+        {
+            "pandoc" => BackendKind::Pandoc,
+            "pandoc-server" => BackendKind::PandocServer(
+                get_str(table, "pandoc-server-url")?
+                    .unwrap_or_else(|| "http://localhost:3030".into()),
+            ),
+            "wasm" => BackendKind::Wasm(wasm_module.ok_or_else(|| {
+                CiteprocError::config("the wasm backend requires wasm-module to be set")
+            })?),
+            _ => {
+                return Err(CiteprocError::config(
+                    "backend must be one of \"pandoc\", \"pandoc-server\" or \"wasm\"",
+                ))
+            }
+        };
+        if backend != BackendKind::Pandoc
+            && (auto_install_pandoc || table.contains_key("pandoc-command"))
+        {
+            return Err(CiteprocError::config(
+                "pandoc-command and auto-install-pandoc only apply to the pandoc backend",
+            ));
+        }
+        let sandbox = Sandbox {
+            enabled: get_bool(table, "sandbox")?.unwrap_or(false),
+            clear_env: get_bool(table, "pandoc-clear-env")?.unwrap_or(false),
+            pass_env: get_str_list(table, "pandoc-pass-env")?.unwrap_or_default(),
+            working_dir: get_str(table, "pandoc-working-dir")?.map(|dir| root.join(dir)),
+        };
+        if !sandbox.pass_env.is_empty() && !sandbox.clear_env {
+            return Err(CiteprocError::config(
+                "pandoc-pass-env only applies with pandoc-clear-env",
+            ));
+        }
+        if backend != BackendKind::Pandoc && (sandbox.clear_env || sandbox.working_dir.is_some()) {
+            // pandoc-server and the wasm module have neither.
+            return Err(CiteprocError::config(
+                "pandoc-clear-env and pandoc-working-dir only apply to the pandoc backend",
+            ));
+        }
+        let pandoc_limits = Limits {
+            memory: get_size(table, "pandoc-max-memory")?,
+            cpu: match table.get("pandoc-max-cpu-seconds") {
+                None => None,
+                Some(Value::Integer(seconds)) if *seconds > 0 => {
+                    Some(Duration::from_secs(*seconds as u64))
+                }
+                Some(_) => {
+                    return Err(CiteprocError::config(
+                        "pandoc-max-cpu-seconds must be a positive integer",
+                    ))
+                }
+            },
+        };
+        if backend != BackendKind::Pandoc && pandoc_limits != Limits::default() {
+            return Err(CiteprocError::config(
+                "pandoc-max-memory and pandoc-max-cpu-seconds only apply to the pandoc backend",
+            ));
+        }
+        let tempfile_threshold = get_size(table, "tempfile-threshold")?;
+        if backend != BackendKind::Pandoc && tempfile_threshold.is_some() {
+            return Err(CiteprocError::config(
+                "tempfile-threshold only applies to the pandoc backend",
+            ));
+        }
+
+        Ok(Self {
+            from,
+            to,
+            extensions: settings,
+            bibliography,
+            bibliography_sort,
+            bibliography_filter,
+            overrides,
+            aliases,
+            deduplicate: get_bool(table, "deduplicate")?.unwrap_or(true),
+            literal_names: get_str_list(table, "literal-names")?.unwrap_or_default(),
+            orcid_names: get_bool(table, "orcid-names")?.unwrap_or(false),
+            required_fields,
+            source_label: get_str(table, "source-label")?.unwrap_or_else(|| "Source".into()),
+            grants,
+            acknowledgments_title: get_str(table, "acknowledgments-title")?
+                .unwrap_or_else(|| "Acknowledgments".into()),
+            further_reading,
+            structured_data: get_bool(table, "structured-data")?.unwrap_or(false),
+            coins: get_bool(table, "coins")?.unwrap_or(false),
+            feed: get_str(table, "feed")?.map(|path| Feed {
+                path: root.join(path),
+                title: feed_title.unwrap_or_else(|| "References added".into()),
+                url: feed_url,
+            }),
+            manifest: get_str(table, "manifest")?.map(|path| root.join(path)),
+            citation_count,
+            targets,
+            inline_entries,
+            extra_bibliographies: bibliographies.map(|path| root.join(path)).collect(),
+            remote_bibliography,
+            field_markup: FieldMarkup::default(),
+            bibliography_groups,
+            reference_heading_level,
+            reference_heading,
+            numbered_bibliography: get_bool(table, "numbered-bibliography")?.unwrap_or(false),
+            annotations: get_bool(table, "annotations")?.unwrap_or(false),
+            citation_options,
+            accessed_format,
+            default_accessed,
+            title_case,
+            url_policy,
+            archive_links: get_bool(table, "archive-links")?.unwrap_or(false),
+            archive_link_text: get_str(table, "archive-link-text")?
+                .unwrap_or_else(|| "Archived".to_string()),
+            snapshots: BTreeMap::new(),
+            entry_template: get_str(table, "entry-template")?.map(|path| root.join(path)),
+            encoding,
+            on_failure,
+            raw_html,
+            heading_style,
+            writer_style,
+            front_matter,
+            renderer: "html".to_string(),
+            renderers,
+            latex_citations: latex_package.map(|package| (package, latex_renderers)),
+            admonish: get_bool(table, "admonish")?,
+            quarto,
+            bookdown: get_bool(table, "bookdown")?
+                .unwrap_or_else(|| root.join(bookdown::PROJECT).is_file()),
+            wiki_links: get_bool(table, "wiki-links")?.unwrap_or(false),
+            process_titles: get_bool(table, "process-titles")?.unwrap_or(false),
+            front_matter_metadata,
+            metadata: pandoc_defaults
+                .metadata
+                .into_iter()
+                .filter(|(key, _)| reference_heading || key != "reference-section-title")
+                .collect(),
+            disambiguation,
+            first_citation,
+            nocite: Vec::new(),
+            metadata_file: None,
+            strict: get_bool(table, "strict")?.unwrap_or(false),
+            offline: get_bool(table, "offline")?.unwrap_or(false),
+            locked: get_bool(table, "locked")?.unwrap_or(false),
+            ca_bundle: get_str(table, "ca-bundle")?.map(|path| root.join(path)),
+            retry_policy,
+            remote_ttl,
+            normalize_newlines,
+            max_jobs,
+            schedule,
+            max_chapter_size,
+            audit: get_bool(table, "audit")?.unwrap_or(false),
+            locale: get_str(table, "locale")?.or(pandoc_defaults.lang),
+            language: None,
+            languages,
+            cache_root,
+            cache_dir,
+            cache_backend,
+            vendor_dir: Some(vendor_dir).filter(|dir| dir.is_dir()),
+            cache_max_size,
+            cache_max_age,
+            pandoc_command,
+            auto_install_pandoc,
+            pandoc_sha256: get_str(table, "pandoc-sha256")?,
+            sandbox,
+            pandoc_limits,
+            tempfile_threshold,
+            backend,
+            profile: None,
+        })
+    }
+}
+
+/// The renames [`migrated`] has warned about, so each is only warned about
+/// once however many times the config is loaded, e.g. by `check` or the
+/// daemon.
+static WARNED: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+
+/// `table` with any renamed options under their new names, warning about
+/// each. See [`deprecated`].
+fn migrated(table: &Table) -> Result<Table, Error> {
+    let mut table = table.clone();
+    let mut warned = WARNED.lock().expect("migration warnings poisoned");
+    for message in deprecated::migrate(&mut table)? {
+        if !warned.insert(message.clone()) {
+            continue;
+        }
+        eprintln!(
+            "Warning: {message}; the old name still works for now, \
+             and `mdbook-citeproc lint-config --fix` renames it"
+        );
+    }
+    Ok(table)
+}
+
+/// `table` without its profiles, with the one selected by name in
+/// `requested` or else by `renderer` merged over it, and that profile's
+/// name.
+fn apply_profile<'a>(
+    table: &'a Table,
+    renderer: Option<&str>,
+    requested: Option<&str>,
+) -> Result<(Cow<'a, Table>, Option<String>), Error> {
+    let no_profiles = Table::new();
+    let profiles = match table.get("profile") {
+        None if requested.is_none() => return Ok((Cow::Borrowed(table), None)),
+        None => &no_profiles,
+        Some(Value::Table(profiles)) => profiles,
+        Some(_) => return Err(CiteprocError::config("profile must be a table of profiles")),
+    };
+    let mut selected = None;
+    for (name, profile) in profiles {
+        let profile = profile
+            .as_table()
+            .ok_or_else(|| CiteprocError::config(format!("profile.{name} must be a table")))?;
+        if profile.contains_key("profile") {
+            return Err(CiteprocError::config(format!(
+                "profile.{name} can't contain profiles of its own"
+            )));
+        }
+        let renderers = get_str_list(profile, "for-renderers")?.unwrap_or_default();
+        let chosen = match requested {
+            Some(requested) => requested == name,
+            None => renderer.is_some_and(|renderer| renderers.iter().any(|r| r == renderer)),
+        };
+        if !chosen {
+            continue;
+        }
+        if let Some((other, _)) = selected {
+            return Err(CiteprocError::config(format!(
+                "profiles {other} and {name} are both for the {} renderer",
+                renderer.unwrap_or_default()
+            )));
+        }
+        selected = Some((name.clone(), profile));
+    }
+    if let (Some(requested), None) = (requested, &selected) {
+        return Err(CiteprocError::config(format!(
+            "{PROFILE_VAR} selects the profile {requested:?}, \
+             but there's no [preprocessor.citeproc.profile.{requested}]"
+        )));
+    }
+
+    let mut merged = table.clone();
+    merged.remove("profile");
+    let Some((name, profile)) = selected else {
+        return Ok((Cow::Owned(merged), None));
+    };
+    let mut profile = profile.clone();
+    profile.remove("for-renderers");
+    merge(&mut merged, &profile);
+    Ok((Cow::Owned(merged), Some(name)))
+}
+
+/// Merges `overlay` into `base`, table by table.
+fn merge(base: &mut Table, overlay: &Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(key), value) {
+            (Some(Value::Table(base)), Value::Table(overlay)) => merge(base, overlay),
+            _ => {
+                base.insert(key.clone(), value.clone());
+            }
+        }
+    }
+}
+
+impl Config {
+    /// Turns admonish compatibility on when it wasn't configured either way
+    /// but the book uses mdbook-admonish.
+    pub fn resolve_admonish(&mut self, book: &mdbook::Config) {
+        if self.admonish.is_none() {
+            self.admonish = Some(book.get_preprocessor("admonish").is_some());
+        }
+    }
+
+    /// Targets `renderer`, switching to its writer if one is configured.
+    pub fn set_renderer(&mut self, renderer: &str) {
+        self.renderer = renderer.to_string();
+        if let Some(mapping) = self.renderers.get(renderer) {
+            self.to = mapping.writer_format(&self.to);
+        }
+    }
+
+    /// Sets the book's language, which is also the locale unless one is
+    /// configured, and applies its `[lang.<language>]`. A translation of
+    /// the bibliography next to it, e.g. `refs.fr.bib` for `refs.bib`, is
+    /// used instead if there is one, and the untranslated file returned.
+    pub fn set_language(&mut self, language: &str, root: &Path) -> Option<String> {
+        self.language = Some(language.to_string());
+        let overrides = self.languages.get(language).cloned().unwrap_or_default();
+        if let Some(locale) = overrides.locale {
+            self.locale = Some(locale);
+        } else if self.locale.is_none() {
+            self.locale = Some(language.to_string());
+        }
+        if let Some(title) = overrides
+            .reference_section_title
+            .filter(|_| self.reference_heading)
+        {
+            self.metadata
+                .insert("reference-section-title".into(), title);
+        }
+        if let Some(bibliography) = &mut self.bibliography {
+            if let Some(style) = overrides.bibliography_style {
+                bibliography.bibliography_style = style;
+            }
+            self.extra_bibliographies
+                .extend(overrides.bibliographies.iter().map(|path| root.join(path)));
+        }
+        let bibliography = self.bibliography.as_mut()?;
+        let path = Path::new(&bibliography.bibliography);
+        let (stem, extension) = (path.file_stem()?, path.extension()?);
+        let mut name = stem.to_os_string();
+        name.push(format!(".{language}."));
+        name.push(extension);
+        let translated = path.with_file_name(name);
+        if !root.join(&translated).is_file() {
+            return None;
+        }
+        let translated = translated.to_string_lossy().into_owned();
+        Some(std::mem::replace(
+            &mut bibliography.bibliography,
+            translated,
+        ))
+    }
+
+    /// What `only-` classes keep content for: the renderer and the groups
+    /// it's in.
+    pub fn targets(&self) -> Vec<&str> {
+        let groups = self
+            .targets
+            .iter()
+            .filter(|(_, renderers)| renderers.contains(&self.renderer))
+            .map(|(group, _)| group.as_str());
+        std::iter::once(self.renderer.as_str())
+            .chain(groups)
+            .collect()
+    }
+
+    /// Whether chapters come out differently for the current renderer than
+    /// for the others.
+    pub fn renderer_specific(&self) -> bool {
+        self.renderer == "epub"
+            || self.renderers.contains_key(&self.renderer)
+            || self.latex_package().is_some()
+    }
+
+    /// Whether pandoc is given a copy of the bibliography rather than the
+    /// file itself, because entries are filtered, changed or replaced.
+    pub fn derives_bibliography(&self) -> bool {
+        self.bibliography.is_some()
+            && (self.bibliography_filter.is_some()
+                || !self.overrides.is_empty()
+                || !self.literal_names.is_empty()
+                || self.orcid_names
+                || !self.inline_entries.is_empty()
+                || self.default_accessed.is_some()
+                || self.title_case != TitleCase::Preserve)
+    }
+
+    /// Where `archive-links` lookups are kept, and for how long: the
+    /// vendor directory's are kept for good.
+    pub fn archive_cache(&self) -> (&Path, Option<Duration>) {
+        match &self.vendor_dir {
+            Some(dir) => (dir, None),
+            None => (&self.cache_root, self.remote_ttl),
+        }
+    }
+
+    /// Whether the bibliography's entries have to be read, beyond pandoc
+    /// rendering them.
+    pub fn needs_library(&self) -> bool {
+        self.bibliography.is_some()
+            && (!self.bibliography_groups.is_empty()
+                || self.annotations
+                || self.entry_template.is_some()
+                || self.archive_links
+                || self.further_reading.is_some()
+                || self.structured_data
+                || self.coins
+                || self.feed.is_some()
+                || self.strict)
+    }
+
+    /// `text` with any aliased citation keys replaced by the current ones.
+    pub fn rename_keys<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        if self.aliases.is_empty() {
+            return Cow::Borrowed(text);
+        }
+        Cow::Owned(citation::rename_keys(text, |key| {
+            self.aliases.get(key).map(String::as_str)
+        }))
+    }
+
+    /// The package to write LaTeX citations for, if the current renderer
+    /// uses LaTeX passthrough.
+    pub fn latex_package(&self) -> Option<LatexPackage> {
+        self.latex_citations
+            .as_ref()
+            .filter(|(_, renderers)| renderers.contains(&self.renderer))
+            .map(|(package, _)| *package)
+    }
+
+    /// This configuration as it applies to a chapter with the given front
+    /// matter keys: `csl` and `lang` override the book's style and locale,
+    /// and the rest of `front-matter-metadata` is passed on as is.
+    pub fn for_front_matter(&self, keys: &BTreeMap<String, String>) -> Config {
+        let mut config = self.clone();
+        for key in &self.front_matter_metadata {
+            let Some(value) = keys.get(key) else {
+                continue;
+            };
+            match key.as_str() {
+                "csl" => {
+                    if let Some(bibliography) = &mut config.bibliography {
+                        bibliography.bibliography_style = value.clone();
+                    }
+                }
+                "lang" => config.locale = Some(value.clone()),
+                "reference-section-title" if !self.reference_heading => {}
+                _ => {
+                    config.metadata.insert(key.clone(), value.clone());
+                }
+            }
+        }
+        config
+    }
+}
+
+/// Reads an optional string option, rejecting values of any other type.
+pub fn get_str(table: &Table, key: &str) -> Result<Option<String>, Error> {
+    match table.get(key) {
+        None => Ok(None),
+        Some(value) => value
+            .as_str()
+            .map(|s| Some(s.to_string()))
+            .ok_or_else(|| CiteprocError::config(format!("{key} must be a string"))),
+    }
+}
+
+/// Reads an optional boolean option, rejecting values of any other type.
+pub fn get_bool(table: &Table, key: &str) -> Result<Option<bool>, Error> {
+    match table.get(key) {
+        None => Ok(None),
+        Some(value) => value
+            .as_bool()
+            .map(Some)
+            .ok_or_else(|| CiteprocError::config(format!("{key} must be a boolean"))),
+    }
+}
+
+/// Reads an optional size, as a number of bytes or a size string (see
+/// [`parse_size`]).
+fn get_size(table: &Table, key: &str) -> Result<Option<u64>, Error> {
+    match table.get(key) {
+        None => Ok(None),
+        Some(Value::Integer(bytes)) if *bytes > 0 => Ok(Some(*bytes as u64)),
+        Some(Value::String(size)) if parse_size(size).is_some() => {
+            Ok(parse_size(size).map(|bytes| bytes as u64))
+        }
+        Some(_) => Err(CiteprocError::config(format!(
+            "{key} must be a number of bytes or a size like \"500MB\""
+        ))),
+    }
+}
+
+/// Reads an optional single character option which must be one of `allowed`.
+fn get_char(table: &Table, key: &str, allowed: &[char]) -> Result<Option<char>, Error> {
+    let Some(value) = get_str(table, key)? else {
+        return Ok(None);
+    };
+    let mut chars = value.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) if allowed.contains(&c) => Ok(Some(c)),
+        _ => {
+            let allowed: Vec<String> = allowed.iter().map(|c| format!("\"{c}\"")).collect();
+            Err(CiteprocError::config(format!(
+                "{key} must be one of {}",
+                allowed.join(", ")
+            )))
+        }
+    }
+}
+
+/// The fields of a bibliography entry given as a TOML table, with names
+/// lowercased and scalar values as strings.
+fn field_values(table: &Table, name: &str) -> Result<BTreeMap<String, String>, Error> {
+    let mut fields = BTreeMap::new();
+    for (field, value) in table {
+        let value = match value {
+            Value::String(value) => value.clone(),
+            Value::Integer(value) => value.to_string(),
+            Value::Float(value) => value.to_string(),
+            Value::Boolean(value) => value.to_string(),
+            _ => {
+                return Err(CiteprocError::config(format!(
+                    "{name}.{field} must be a string or number"
+                )))
+            }
+        };
+        fields.insert(field.to_lowercase(), value);
+    }
+    Ok(fields)
+}
+
+/// Whether `value` is a `YYYY-MM-DD` date.
+fn is_date(value: &str) -> bool {
+    let parts: Vec<&str> = value.split('-').collect();
+    matches!(parts.as_slice(), [year, month, day]
+        if year.len() == 4 && month.len() == 2 && day.len() == 2
+            && parts.iter().all(|part| part.bytes().all(|b| b.is_ascii_digit())))
+}
+
+/// A size like `50MB` or `512 KiB` in bytes. Units are powers of 1024,
+/// whether written `MB` or `MiB`.
+pub fn parse_size(value: &str) -> Option<usize> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let number: usize = value[..split].parse().ok().filter(|n| *n > 0)?;
+    let unit = match value[split..].trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" | "kib" => 1 << 10,
+        "m" | "mb" | "mib" => 1 << 20,
+        "g" | "gb" | "gib" => 1 << 30,
+        _ => return None,
+    };
+    number.checked_mul(unit)
+}
+
+/// Reads an optional option which is either a string or an array of strings.
+pub fn get_str_list(table: &Table, key: &str) -> Result<Option<Vec<String>>, Error> {
+    let invalid =
+        || CiteprocError::config(format!("{key} must be a string or an array of strings"));
+    match table.get(key) {
+        None => Ok(None),
+        Some(Value::String(s)) => Ok(Some(vec![s.clone()])),
+        Some(Value::Array(values)) => values
+            .iter()
+            .map(|value| value.as_str().map(str::to_string).ok_or_else(invalid))
+            .collect::<Result<_, _>>()
+            .map(Some),
+        Some(_) => Err(invalid()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(toml: &str) -> Result<Config, Error> {
+        Config::from_table(&toml::from_str(toml).unwrap(), Path::new("."))
+    }
+
+    #[test]
+    fn preserved_pipe_tables_are_read_and_written() {
+        let config = config(
+            r#"
+            pipe_tables = "preserve"
+            table_captions = "preserve"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.from.to_string(),
+            "markdown_strict+pipe_tables+table_captions"
+        );
+        assert_eq!(
+            config.to.to_string(),
+            "markdown_strict+pipe_tables+table_captions"
+        );
+    }
+
+    #[test]
+    fn transpiled_citations_read_footnotes_and_fenced_divs() {
+        let config = config(
+            r#"
+            citations = "transpile"
+            bibliography = "refs.bib"
+            csl = "style.csl"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.from.to_string(),
+            "markdown_strict+citations+fenced_divs+footnotes"
+        );
+        assert_eq!(
+            config.to.to_string(),
+            "markdown_strict-citations-fenced_divs+footnotes"
+        );
+    }
+
+    #[test]
+    fn explicit_nesting_extensions_are_respected() {
+        let config = config(
+            r#"
+            citations = "transpile"
+            footnotes = "transpile"
+            bibliography = "refs.bib"
+            csl = "style.csl"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.extensions.get("footnotes"),
+            Some(&PandocSetting::Transpile)
+        );
+        assert!(
+            config.to.to_string().ends_with("-footnotes-fenced_divs"),
+            "{}",
+            config.to
+        );
+    }
+
+    #[test]
+    fn renderers_map_to_writers() {
+        let mut config = config(
+            r#"
+            citations = "preserve"
+            [renderers.typst]
+            writer = "typst"
+            extensions = ["-smart"]
+            [renderers.markdown]
+            extensions = ["-raw_html"]
+            "#,
+        )
+        .unwrap();
+        config.set_renderer("typst");
+        assert_eq!(config.to.to_string(), "typst-smart");
+        assert!(config.renderer_specific());
+
+        let mut config = config.clone();
+        config.to = Format::parse("markdown_strict+citations");
+        config.set_renderer("markdown");
+        assert_eq!(config.to.to_string(), "markdown_strict+citations-raw_html");
+    }
+
+    #[test]
+    fn extension_dependencies_name_the_keys_responsible() {
+        let e = config("fenced_code_attributes = \"preserve\"").unwrap_err();
+        assert!(
+            e.to_string().starts_with(
+                "fenced_code_attributes (set by `fenced_code_attributes`) needs fenced_code_blocks"
+            ),
+            "{e}"
+        );
+        let e = config(
+            r#"
+            raw-html = "preserve"
+            markdown_in_html_blocks = "preserve"
+            [renderers.markdown]
+            extensions = ["-raw_html"]
+            "#,
+        )
+        .unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "markdown_in_html_blocks (set by `markdown_in_html_blocks`) needs raw_html in \
+             the markdown renderer's writer, but `renderers.markdown.extensions` turns raw_html off"
+        );
+        config("fenced_code_blocks = \"preserve\"\nfenced_code_attributes = \"transpile\"")
+            .unwrap();
+    }
+
+    #[test]
+    fn translations_use_their_language_and_bibliography() {
+        let root = std::env::temp_dir().join(format!("citeproc-language-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("refs")).unwrap();
+        std::fs::write(root.join("refs/main.fr.bib"), "").unwrap();
+        let toml = "citations = \"transpile\"\nbibliography = \"refs/main.bib\"\n\
+                    csl = \"style.csl\"";
+
+        let mut french = config(toml).unwrap();
+        let original = french.set_language("fr", &root);
+        assert_eq!(original.as_deref(), Some("refs/main.bib"));
+        assert_eq!(
+            french.bibliography.unwrap().bibliography,
+            "refs/main.fr.bib"
+        );
+        assert_eq!(french.locale.as_deref(), Some("fr"));
+
+        let mut german = config(&format!("{toml}\nlocale = \"de-CH\"")).unwrap();
+        assert_eq!(german.set_language("de", &root), None);
+        assert_eq!(german.bibliography.unwrap().bibliography, "refs/main.bib");
+        assert_eq!(german.locale.as_deref(), Some("de-CH"));
+
+        let mut french = config(&format!(
+            "{toml}\n[lang.fr-CA]\nlocale = \"fr-CA\"\ncsl = \"iso690.csl\"\n\
+             reference-section-title = \"Bibliographie\"\nbibliography = \"refs/quebec.bib\""
+        ))
+        .unwrap();
+        french.set_language("fr-CA", &root);
+        assert_eq!(french.locale.as_deref(), Some("fr-CA"));
+        assert_eq!(french.metadata["reference-section-title"], "Bibliographie");
+        assert_eq!(french.extra_bibliographies, [root.join("refs/quebec.bib")]);
+        let bibliography = french.bibliography.unwrap();
+        assert_eq!(bibliography.bibliography, "refs/main.bib");
+        assert_eq!(bibliography.bibliography_style, "iso690.csl");
+
+        let e = config(&format!("{toml}\nlang.fr.title = \"Bibliographie\"")).unwrap_err();
+        assert!(e.to_string().starts_with("lang.fr.title isn't"), "{e}");
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn targets_are_the_renderer_and_its_groups() {
+        assert!(config("targets.print = 1").is_err());
+        let mut targets =
+            config("targets.print = \"pandoc\"\ntargets.slides = [\"html\", \"revealjs\"]")
+                .unwrap();
+        targets.set_renderer("html");
+        assert_eq!(targets.targets(), ["html", "slides", "web"]);
+        targets.set_renderer("pandoc");
+        assert_eq!(targets.targets(), ["pandoc", "print"]);
+    }
+
+    #[test]
+    fn profiles_are_chosen_by_name_or_renderer() {
+        let table: Table = toml::from_str(
+            r#"
+            citations = "transpile"
+            bibliography = "refs.bib"
+            csl = "web.csl"
+            [renderers.typst]
+            writer = "typst"
+            [profile.print]
+            for-renderers = ["latex", "typst"]
+            csl = "print.csl"
+            renderers.typst.extensions = ["-smart"]
+            [profile.draft]
+            citations = "preserve"
+            "#,
+        )
+        .unwrap();
+        let load = |renderer: Option<&str>, requested: Option<&str>| {
+            let (table, name) = apply_profile(&table, renderer, requested)?;
+            let mut config = Config::from_table(&table, Path::new("."))?;
+            config.profile = name;
+            Ok::<_, Error>(config)
+        };
+
+        let config = load(Some("html"), None).unwrap();
+        assert_eq!(config.profile, None);
+        assert_eq!(config.bibliography.unwrap().bibliography_style, "web.csl");
+
+        let config = load(Some("typst"), None).unwrap();
+        assert_eq!(config.profile.as_deref(), Some("print"));
+        assert_eq!(config.bibliography.unwrap().bibliography_style, "print.csl");
+        let typst = &config.renderers["typst"];
+        assert_eq!(typst.writer.as_deref(), Some("typst"));
+        assert_eq!(typst.extensions, ["-smart"]);
+
+        let config = load(Some("typst"), Some("draft")).unwrap();
+        assert_eq!(config.profile.as_deref(), Some("draft"));
+        assert!(config.bibliography.is_none());
+
+        let e = load(None, Some("final")).unwrap_err();
+        assert!(
+            e.to_string()
+                .contains("no [preprocessor.citeproc.profile.final]"),
+            "{e}"
+        );
+    }
+
+    #[test]
+    fn reference_heading_levels_are_heading_levels() {
+        assert_eq!(
+            config("reference-heading-level = 2")
+                .unwrap()
+                .reference_heading_level,
+            Some(2)
+        );
+        for level in ["0", "7", "\"2\""] {
+            let e = config(&format!("reference-heading-level = {level}")).unwrap_err();
+            assert!(e.to_string().contains("from 1 to 6"), "{e}");
+        }
+    }
+
+    #[test]
+    fn reference_headings_can_be_left_out() {
+        let toml =
+            "reference-heading = false\n[lang.fr]\nreference-section-title = \"Bibliographie\"";
+        let mut without = config(toml).unwrap();
+        without.set_language("fr", Path::new("."));
+        let front_matter = BTreeMap::from([(
+            "reference-section-title".to_string(),
+            "Works cited".to_string(),
+        )]);
+        let without = without.for_front_matter(&front_matter);
+        assert!(!without.metadata.contains_key("reference-section-title"));
+        let with = config("").unwrap().for_front_matter(&front_matter);
+        assert_eq!(with.metadata["reference-section-title"], "Works cited");
+
+        let root = std::env::temp_dir().join(format!("citeproc-headings-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(
+            root.join("defaults.yaml"),
+            "metadata:\n  reference-section-title: References\n  link-citations: true\n",
+        )
+        .unwrap();
+        let from_defaults = |reference_heading: bool| {
+            let toml = format!(
+                "pandoc-defaults = \"defaults.yaml\"\nreference-heading = {reference_heading}"
+            );
+            Config::from_table(&toml::from_str(&toml).unwrap(), &root)
+                .unwrap()
+                .metadata
+        };
+        assert!(!from_defaults(false).contains_key("reference-section-title"));
+        assert_eq!(from_defaults(false)["link-citations"], "true");
+        assert_eq!(from_defaults(true)["reference-section-title"], "References");
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn pipe_tables_conflict_with_other_preserved_table_formats() {
+        for other in TABLE_EXTENSIONS {
+            let e = config(&format!(
+                "pipe_tables = \"preserve\"\n{other} = \"preserve\""
+            ))
+            .unwrap_err();
+            assert!(e.to_string().contains(other), "{e}");
+        }
+        config("pipe_tables = \"preserve\"\nsimple_tables = \"transpile\"").unwrap();
+    }
+
+    #[test]
+    fn bibliography_groups_end_with_the_rest() {
+        let grouped = config(
+            r#"
+            citations = "preserve"
+            bibliography-groups = [
+                { title = "Books", types = ["book", "chapter"] },
+                { title = "Web resources", types = "webpage" },
+            ]
+            "#,
+        )
+        .unwrap();
+        let titles: Vec<&str> = grouped
+            .bibliography_groups
+            .iter()
+            .map(|group| group.title.as_str())
+            .collect();
+        assert_eq!(titles, ["Books", "Web resources", "Other"]);
+        assert_eq!(
+            grouped.bibliography_groups[1].types,
+            Some(vec!["webpage".into()])
+        );
+
+        let catch_all_first = config(
+            r#"
+            citations = "preserve"
+            bibliography-groups = [{ title = "Misc" }, { title = "Books", types = ["book"] }]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(catch_all_first.bibliography_groups.len(), 2);
+    }
+
+    #[test]
+    fn citation_options_are_validated() {
+        let options = config(
+            r#"
+            citations = "preserve"
+            citation-options = { collapse = "citation-number", disambiguate-add-year-suffix = true }
+            "#,
+        )
+        .unwrap()
+        .citation_options;
+        assert_eq!(options["collapse"], "citation-number");
+        assert_eq!(options["disambiguate-add-year-suffix"], "true");
+
+        let e = config("citation-options = { collapse = \"yes\" }").unwrap_err();
+        assert!(e.to_string().contains("citation-number"), "{e}");
+        assert!(config("citation-options = { et-al-min = \"3\" }").is_err());
+    }
+
+    #[test]
+    fn overrides_are_read_per_key() {
+        let overrides = config(
+            r#"
+            citations = "preserve"
+            [overrides.smith2020]
+            Title = "A translated title"
+            year = 2021
+            "#,
+        )
+        .unwrap()
+        .overrides;
+        assert_eq!(overrides["smith2020"]["title"], "A translated title");
+        assert_eq!(overrides["smith2020"]["year"], "2021");
+        assert!(config("overrides.smith = { author = [\"a\"] }").is_err());
+        assert!(config("required-fields.book = { publisher-place = \"fatal\" }").is_err());
+    }
+
+    #[test]
+    fn access_date_options_are_validated() {
+        let options = config(
+            r#"
+            citations = "preserve"
+            default-accessed = "2024-03-03"
+            accessed-format = "Accessed {day} {month} {year}"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            options.default_accessed,
+            Some(AccessedDefault::Date("2024-03-03".into()))
+        );
+        assert!(config("default-accessed = \"3 March 2024\"").is_err());
+        assert!(config("accessed-format = \"{weekday}\"").is_err());
+        assert!(config("title-case = \"upper\"").is_err());
+    }
+
+    #[test]
+    fn build_limits_are_validated() {
+        let options = config(
+            r#"
+            citations = "preserve"
+            max-jobs = 2
+            schedule = "largest-first"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(options.max_jobs, 2);
+        assert_eq!(options.schedule, Schedule::LargestFirst);
+        assert!(config("max-jobs = 0").is_err());
+        assert_eq!(parse_size("50MB"), Some(50 << 20));
+        assert_eq!(parse_size("512 KiB"), Some(512 << 10));
+        assert_eq!(parse_size("1.5GB"), None);
+        assert!(config("max-chapter-size = \"lots\"").is_err());
+        assert!(config("schedule = \"random\"").is_err());
+    }
+
+    #[test]
+    fn inline_entries_become_csl_json() {
+        let entries = config(
+            r#"
+            citations = "preserve"
+            [entries.rfc2119]
+            type = "report"
+            title = "Key words for use in RFCs"
+            author = [{ family = "Bradner", given = "Scott" }]
+            issued = { date-parts = [[1997, 3]] }
+            "#,
+        )
+        .unwrap()
+        .inline_entries;
+        assert_eq!(entries[0]["id"], "rfc2119");
+        assert_eq!(entries[0]["author"][0]["family"], "Bradner");
+        assert_eq!(entries[0]["issued"]["date-parts"][0][1], 3);
+        assert!(config("entries.x = { title = \"No type\" }").is_err());
+    }
+
+    #[test]
+    fn aliases_rename_keys() {
+        let config = config(
+            r#"
+            citations = "preserve"
+            aliases = { Smith2020 = "smith:2020:citations" }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.rename_keys("[@Smith2020; @smith2020]"),
+            "[@smith:2020:citations; @smith2020]"
+        );
+    }
+}
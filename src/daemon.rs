@@ -0,0 +1,185 @@
+//! A long-running process which `mdbook serve` rebuilds can delegate to.
+//!
+//! The client sends a [`Request`], holding the raw preprocessor input (the
+//! `[context, book]` JSON mdbook writes to our stdin) and its own flags,
+//! over a unix socket and shuts down its write half; the daemon answers
+//! with a [`Response`] and closes the connection. The daemon's [`Pandoc`]
+//! keeps processed chapters, the parsed bibliography and the backend in
+//! memory, so between rebuilds only edited chapters go back through pandoc
+//! and the bibliography is only parsed again when it changes.
+
+use std::io::{Read, Write};
+use std::net::Shutdown;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::{fs, io};
+
+use mdbook::errors::Error;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{kind_of, CiteprocError, ErrorKind};
+use crate::Pandoc;
+
+/// A build, with the flags the client was run with.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Request {
+    pub input: String,
+    pub offline: bool,
+    pub locked: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Response {
+    /// The processed book, already serialized.
+    Book(String),
+    Error {
+        kind: String,
+        message: String,
+    },
+}
+
+/// Listens on `socket` forever, processing one build at a time.
+pub fn serve(socket: &Path, pre: &Pandoc) -> Result<(), Error> {
+    // A stale socket from a previous daemon would make bind fail.
+    match fs::remove_file(socket) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+    let listener = UnixListener::bind(socket)?;
+    eprintln!("citeproc daemon listening on {}", socket.display());
+
+    for stream in listener.incoming() {
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Warning: failed to accept daemon connection: {e}");
+                continue;
+            }
+        };
+        let mut request = Vec::new();
+        if let Err(e) = stream.read_to_end(&mut request) {
+            eprintln!("Warning: failed to read daemon request: {e}");
+            continue;
+        }
+        let request: Request = match serde_json::from_slice(&request) {
+            Ok(request) => request,
+            Err(e) => {
+                eprintln!("Warning: invalid daemon request: {e}");
+                continue;
+            }
+        };
+        // The daemon's own flags would otherwise apply to every build.
+        crate::set_offline(request.offline);
+        crate::set_locked(request.locked);
+        let response = match crate::process_input(pre, request.input.as_bytes()) {
+            Ok(output) => Response::Book(output),
+            Err(e) => {
+                eprintln!("{e:#}");
+                // With its context, as the CLI would print it.
+                Response::Error {
+                    kind: kind_of(&e).as_str().to_string(),
+                    message: format!("{e:#}"),
+                }
+            }
+        };
+        if let Err(e) = serde_json::to_writer(&mut stream, &response) {
+            eprintln!("Warning: failed to answer daemon request: {e}");
+        }
+    }
+    Ok(())
+}
+
+/// Hands `request` to the daemon listening on `socket`.
+///
+/// Returns `Ok(None)` when no daemon is reachable, so the caller can fall
+/// back to processing the book itself.
+pub fn delegate(socket: &Path, request: &Request) -> Result<Option<String>, Error> {
+    let mut stream = match UnixStream::connect(socket) {
+        Ok(stream) => stream,
+        Err(_) => return Ok(None),
+    };
+    stream.write_all(&serde_json::to_vec(request)?)?;
+    stream.shutdown(Shutdown::Write)?;
+    let response: Response = serde_json::from_reader(stream)?;
+    match response {
+        Response::Book(output) => Ok(Some(output)),
+        Response::Error { kind, message } => {
+            let kind = ErrorKind::parse(&kind).unwrap_or(ErrorKind::Other);
+            Err(CiteprocError::new(kind, message).into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+
+    /// The daemon is started wherever `mdbook serve` happens to be, which
+    /// here is the crate rather than the book.
+    #[test]
+    fn finds_the_bibliography_from_outside_the_book() {
+        let root = std::env::temp_dir().join(format!("citeproc-daemon-{}", std::process::id()));
+        fs::create_dir_all(root.join("src")).unwrap();
+        fs::write(root.join("refs.bib"), "@book{a, title = {A}}\n").unwrap();
+        fs::write(root.join("style.csl"), "").unwrap();
+        // A fake pandoc which fails, as pandoc does, if it can't find a
+        // file it's given.
+        let pandoc = root.join("pandoc");
+        fs::write(
+            &pandoc,
+            "#!/bin/sh\nfor arg; do case $arg in\n\
+             --version) echo pandoc 3.1; exit;;\n\
+             --bibliography=*|--csl=*) test -f \"${arg#*=}\" || exit 4;;\n\
+             esac; done\ncat\n",
+        )
+        .unwrap();
+        fs::set_permissions(&pandoc, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let config = serde_json::json!({
+            "citations": "transpile",
+            "bibliography": "refs.bib",
+            "csl": "style.csl",
+            "cache": false,
+            "pandoc-command": [pandoc],
+        });
+        let context = serde_json::json!({
+            "root": root,
+            "config": {
+                "book": {"authors": [], "language": "en", "multilingual": false, "src": "src"},
+                "preprocessor": {"citeproc": config},
+            },
+            "renderer": "html",
+            "mdbook_version": mdbook::MDBOOK_VERSION,
+        });
+        let book = serde_json::json!({"sections": [{"Chapter": {
+            "name": "One",
+            "content": "See [@a].",
+            "number": [1],
+            "sub_items": [],
+            "path": "one.md",
+            "source_path": "one.md",
+            "parent_names": [],
+        }}], "__non_exhaustive": null});
+        let request = Request {
+            input: serde_json::json!([context, book]).to_string(),
+            offline: false,
+            locked: false,
+        };
+
+        let socket = root.join("daemon.sock");
+        let listening = socket.clone();
+        thread::spawn(move || serve(&listening, &Pandoc::new().quiet(true)));
+        let output = loop {
+            match delegate(&socket, &request).unwrap() {
+                Some(output) => break output,
+                None => thread::sleep(Duration::from_millis(10)),
+            }
+        };
+        assert!(output.contains("See [@a]."), "{output}");
+        fs::remove_dir_all(root).unwrap();
+    }
+}
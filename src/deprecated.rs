@@ -0,0 +1,115 @@
+//! Options which have been renamed. The old names keep working, with a
+//! warning giving the new one, so a book keeps building across releases
+//! until its `book.toml` catches up.
+//!
+//! Anywhere an option can be set counts: `[preprocessor.citeproc]` itself,
+//! its profiles and its `[lang.<language>]` tables. There are two ways to
+//! catch up:
+//!
+//! - `mdbook-citeproc lint-config --fix`, which renames them in `book.toml`
+//!   in place, keeping its comments and layout; or
+//! - [`migrate`], for tools which write the table themselves, which renames
+//!   them in a parsed table and says what it did.
+//!
+//! Renaming an option is a matter of adding it to [`RENAMED`] and reading
+//! the new name in [`Config`](crate::config::Config).
+
+use mdbook::errors::Error;
+use toml::value::Table;
+
+use crate::error::CiteprocError;
+
+/// An option which has a new name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Renamed {
+    pub old: &'static str,
+    pub new: &'static str,
+}
+
+/// Every renamed option, oldest first.
+pub const RENAMED: &[Renamed] = &[Renamed {
+    old: "bibliography-style",
+    new: "csl",
+}];
+
+/// The tables of tables options can be set in as well as the top level.
+pub const NESTED: &[&str] = &["profile", "lang"];
+
+/// Renames the old options in `table`, a `[preprocessor.citeproc]` table,
+/// returning a line for each saying what it's called now. It's an error for
+/// an option to be set under both names.
+pub fn migrate(table: &mut Table) -> Result<Vec<String>, Error> {
+    let mut migrated = Vec::new();
+    migrate_table(table, "", &mut migrated)?;
+    for &nested in NESTED {
+        let Some(tables) = table.get_mut(nested).and_then(|t| t.as_table_mut()) else {
+            continue;
+        };
+        for (name, inner) in tables.iter_mut() {
+            if let Some(inner) = inner.as_table_mut() {
+                migrate_table(inner, &format!("{nested}.{name}."), &mut migrated)?;
+            }
+        }
+    }
+    Ok(migrated)
+}
+
+fn migrate_table(table: &mut Table, prefix: &str, migrated: &mut Vec<String>) -> Result<(), Error> {
+    for renamed in RENAMED {
+        let Some(value) = table.remove(renamed.old) else {
+            continue;
+        };
+        if table.contains_key(renamed.new) {
+            return Err(CiteprocError::config(format!(
+                "{prefix}{} and {prefix}{} are both set, but {} is the old name of {}; remove it",
+                renamed.old, renamed.new, renamed.old, renamed.new
+            )));
+        }
+        table.insert(renamed.new.to_string(), value);
+        migrated.push(renamed.message(prefix));
+    }
+    Ok(())
+}
+
+impl Renamed {
+    /// What to tell someone still using the old name, where options are
+    /// named with `prefix`.
+    pub fn message(&self, prefix: &str) -> String {
+        format!(
+            "{prefix}{} has been renamed to {prefix}{}",
+            self.old, self.new
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renames_old_options_everywhere() {
+        let mut table: Table = toml::from_str(
+            "bibliography-style = \"a.csl\"\n\
+             [profile.print]\nbibliography-style = \"b.csl\"\n\
+             [lang.fr]\ncsl = \"c.csl\"\n",
+        )
+        .unwrap();
+        assert_eq!(
+            migrate(&mut table).unwrap(),
+            [
+                "bibliography-style has been renamed to csl",
+                "profile.print.bibliography-style has been renamed to profile.print.csl",
+            ]
+        );
+        let expected: Table = toml::from_str(
+            "csl = \"a.csl\"\n[profile.print]\ncsl = \"b.csl\"\n[lang.fr]\ncsl = \"c.csl\"\n",
+        )
+        .unwrap();
+        assert_eq!(table, expected);
+        assert_eq!(migrate(&mut table).unwrap(), Vec::<String>::new());
+
+        let mut both: Table =
+            toml::from_str("bibliography-style = \"a.csl\"\ncsl = \"b.csl\"").unwrap();
+        assert!(migrate(&mut both).is_err());
+    }
+}
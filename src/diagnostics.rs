@@ -0,0 +1,306 @@
+//! Problems with a book's citations at the place in its sources they're
+//! written, for editors and CI rather than for reading through a build log:
+//! keys which aren't in the bibliography, and keys more than one
+//! bibliography file defines, where which entry pandoc uses is down to the
+//! order they're given in.
+//!
+//! `check --message-format=short` prints them as `file:line:col: message`,
+//! which editors' error parsers and GitHub's problem matchers pick up, and
+//! `--message-format=sarif` as a SARIF log for code scanning.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use mdbook::book::Book;
+use mdbook::errors::Error;
+use mdbook::{BookItem, MDBook};
+use serde_json::{json, Value};
+
+use crate::bibliography::{self, Entry};
+use crate::check;
+use crate::citation;
+use crate::config::Config;
+
+/// A problem at a place in one of the book's sources.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub file: PathBuf,
+    /// Counted from 1.
+    pub line: usize,
+    /// In characters, counted from 1.
+    pub column: usize,
+    pub rule: Rule,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rule {
+    UnknownKey,
+    AmbiguousKey,
+}
+
+impl Rule {
+    pub fn id(self) -> &'static str {
+        match self {
+            Rule::UnknownKey => "unknown-key",
+            Rule::AmbiguousKey => "ambiguous-key",
+        }
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            Rule::UnknownKey => "A cited key has no entry in the bibliography.",
+            Rule::AmbiguousKey => "A cited key has entries in more than one bibliography file.",
+        }
+    }
+}
+
+impl Diagnostic {
+    /// `file:line:col: warning: message`.
+    pub fn short(&self) -> String {
+        format!(
+            "{}:{}:{}: warning: {}",
+            self.file.display(),
+            self.line,
+            self.column,
+            self.message
+        )
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}: {}",
+            self.file.display(),
+            self.line,
+            self.column,
+            self.message
+        )
+    }
+}
+
+/// The diagnostics for the book in `root`, in book order. Chapters are
+/// read as they are on disk, so `{{#include}}`d files aren't seen.
+pub fn collect(root: &Path) -> Result<Vec<Diagnostic>, Error> {
+    let config = check::load_config(root)?;
+    let Some(bibliography) = &config.bibliography else {
+        return Ok(Vec::new());
+    };
+    let book = MDBook::load(root)?;
+    let mut files = vec![root.join(&bibliography.bibliography)];
+    files.extend(config.extra_bibliographies.iter().cloned());
+    let mut sources = Vec::new();
+    for file in files {
+        // Entries can't be read from other formats, so any key might be in
+        // them.
+        let readable = matches!(
+            file.extension().and_then(|e| e.to_str()),
+            Some("bib" | "bibtex" | "json")
+        );
+        if !readable {
+            return Ok(Vec::new());
+        }
+        let entries = bibliography::load(&file)?;
+        sources.push((file, entries));
+    }
+    let src = root.join(&book.config.book.src);
+    let src = src.strip_prefix(".").unwrap_or(&src);
+    Ok(diagnose(&config, src, &book.book, &sources))
+}
+
+/// The diagnostics for `book`, whose sources are in `src`, against the
+/// entries of each bibliography file.
+fn diagnose(
+    config: &Config,
+    src: &Path,
+    book: &Book,
+    sources: &[(PathBuf, Vec<Entry>)],
+) -> Vec<Diagnostic> {
+    let mut defined: BTreeMap<&str, Vec<&Path>> = BTreeMap::new();
+    for (file, entries) in sources {
+        for entry in entries {
+            let files = defined.entry(&entry.key).or_default();
+            if !files.contains(&file.as_path()) {
+                files.push(file);
+            }
+        }
+    }
+
+    let mut diagnostics = Vec::new();
+    for item in book.iter() {
+        let BookItem::Chapter(chapter) = item else {
+            continue;
+        };
+        let Some(path) = &chapter.source_path else {
+            continue;
+        };
+        let content = &chapter.content;
+        for cited in citation::parse(content) {
+            let (line, column) = position(content, cited.span.start);
+            for item in &cited.items {
+                let key = config.aliases.get(&item.key).unwrap_or(&item.key);
+                let (rule, message) = match defined.get(key.as_str()).map(Vec::as_slice) {
+                    Some([_]) => continue,
+                    Some(files) => (
+                        Rule::AmbiguousKey,
+                        format!(
+                            "`{key}` is in more than one bibliography: {}",
+                            files
+                                .iter()
+                                .map(|file| file.display().to_string())
+                                .collect::<Vec<_>>()
+                                .join(", ")
+                        ),
+                    ),
+                    None => {
+                        let similar = defined.keys().find(|k| k.eq_ignore_ascii_case(key));
+                        let message = match similar {
+                            Some(similar) => format!(
+                                "`{key}` isn't in the bibliography; did you mean `{similar}`?"
+                            ),
+                            None => format!("`{key}` isn't in the bibliography"),
+                        };
+                        (Rule::UnknownKey, message)
+                    }
+                };
+                diagnostics.push(Diagnostic {
+                    file: src.join(path),
+                    line,
+                    column,
+                    rule,
+                    message,
+                });
+            }
+        }
+    }
+    diagnostics
+}
+
+/// The line and column of byte `offset` in `text`.
+fn position(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text[..offset];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    (
+        before.matches('\n').count() + 1,
+        before[line_start..].chars().count() + 1,
+    )
+}
+
+/// `diagnostics` as a SARIF 2.1.0 log.
+pub fn sarif(diagnostics: &[Diagnostic]) -> Value {
+    let rules: Vec<Value> = [Rule::UnknownKey, Rule::AmbiguousKey]
+        .iter()
+        .map(|rule| {
+            json!({
+                "id": rule.id(),
+                "shortDescription": {"text": rule.description()},
+            })
+        })
+        .collect();
+    let results: Vec<Value> = diagnostics
+        .iter()
+        .map(|diagnostic| {
+            json!({
+                "ruleId": diagnostic.rule.id(),
+                "level": "warning",
+                "message": {"text": diagnostic.message},
+                "locations": [{
+                    "physicalLocation": {
+                        "artifactLocation": {
+                            "uri": diagnostic.file.to_string_lossy().replace('\\', "/"),
+                        },
+                        "region": {
+                            "startLine": diagnostic.line,
+                            "startColumn": diagnostic.column,
+                        },
+                    },
+                }],
+            })
+        })
+        .collect();
+    json!({
+        "$schema": "https://json.schemastore.org/sarif-2.1.0.json",
+        "version": "2.1.0",
+        "runs": [{
+            "tool": {"driver": {
+                "name": env!("CARGO_PKG_NAME"),
+                "version": env!("CARGO_PKG_VERSION"),
+                "rules": rules,
+            }},
+            "results": results,
+        }],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use mdbook::book::Chapter;
+
+    use super::*;
+
+    fn entry(key: &str) -> Entry {
+        Entry {
+            key: key.into(),
+            kind: "book".into(),
+            fields: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn points_at_the_citation() {
+        let mut book = Book::new();
+        book.push_item(BookItem::Chapter(Chapter::new(
+            "One",
+            "# One\n\nAs é [@Smith2020; @adams2018] and\n@old and @nobody.\n".into(),
+            "one.md",
+            vec![],
+        )));
+        let toml = "bibliography = \"refs.bib\"\ncsl = \"style.csl\"\n\
+                    aliases = { old = \"adams2018\" }";
+        let config = Config::from_table(&toml::from_str(toml).unwrap(), Path::new(".")).unwrap();
+        let sources = [
+            (
+                PathBuf::from("refs.bib"),
+                vec![entry("smith2020"), entry("adams2018")],
+            ),
+            (PathBuf::from("more.bib"), vec![entry("adams2018")]),
+        ];
+        let short: Vec<String> = diagnose(&config, Path::new("src"), &book, &sources)
+            .iter()
+            .map(Diagnostic::short)
+            .collect();
+        assert_eq!(
+            short,
+            [
+                "src/one.md:3:6: warning: `Smith2020` isn't in the bibliography; \
+                 did you mean `smith2020`?",
+                "src/one.md:3:6: warning: `adams2018` is in more than one bibliography: \
+                 refs.bib, more.bib",
+                "src/one.md:4:1: warning: `adams2018` is in more than one bibliography: \
+                 refs.bib, more.bib",
+                "src/one.md:4:10: warning: `nobody` isn't in the bibliography",
+            ]
+        );
+    }
+
+    #[test]
+    fn writes_sarif() {
+        let diagnostic = Diagnostic {
+            file: PathBuf::from("src/one.md"),
+            line: 3,
+            column: 6,
+            rule: Rule::UnknownKey,
+            message: "`x` isn't in the bibliography".into(),
+        };
+        let log = sarif(&[diagnostic]);
+        assert_eq!(log["version"], "2.1.0");
+        let result = &log["runs"][0]["results"][0];
+        assert_eq!(result["ruleId"], "unknown-key");
+        let location = &result["locations"][0]["physicalLocation"];
+        assert_eq!(location["artifactLocation"]["uri"], "src/one.md");
+        assert_eq!(location["region"]["startLine"], 3);
+    }
+}
@@ -0,0 +1,313 @@
+//! Directives written the way mdbook's own are, each on a line of its own:
+//!
+//! - `{{#epigraph @smith2020 "Quote text."}}` opens a chapter with a quote
+//!   attributed to a cited work, as a blockquote with the short citation
+//!   after it. A locator can follow the key: `@smith2020, p. 4`.
+//! - `{{#source [@smith2020, fig. 3]}}` goes beneath a figure or table,
+//!   giving its source: "Source: Smith (2020), fig. 3". The label is
+//!   `source-label`'s.
+//! - `{{#grant NSF-123456}}` acknowledges the funding `[grants]` describes,
+//!   anywhere in a chapter: the acknowledgments of all of a chapter's
+//!   grants are put at the end of its section titled `acknowledgments-title`,
+//!   which is added at the end of the chapter if it has none.
+//!
+//! Directives become markdown pandoc renders the citations in, so they're
+//! in the chapter's bibliography like any other, between placeholder
+//! paragraphs which [`finish`] swaps for the HTML they're to be styled by
+//! (`<div class="epigraph">`, `<div class="figure-source">` and
+//! `<div class="acknowledgments">`), or takes out for other renderers.
+
+use std::borrow::Cow;
+
+use crate::config::Config;
+
+/// Each directive's name and the class of the `<div>` it's put in.
+const DIRECTIVES: &[(&str, &str)] = &[
+    ("epigraph", "epigraph"),
+    ("source", "figure-source"),
+    ("grant", "acknowledgments"),
+];
+
+/// The placeholders before and after what directive `name` expands to.
+fn placeholders(name: &str) -> (String, String) {
+    let name = name.to_uppercase();
+    (
+        format!("CITEPROC{name}OPENX"),
+        format!("CITEPROC{name}CLOSEX"),
+    )
+}
+
+/// Whether `content` might use a directive.
+pub fn has_directives(content: &str) -> bool {
+    DIRECTIVES
+        .iter()
+        .any(|(name, _)| content.contains(&format!("{{{{#{name}")))
+}
+
+/// `content` with its directives expanded, or an error about the first
+/// which can't be read. Directives inside code blocks are left alone.
+pub fn expand<'a>(content: &'a str, config: &Config) -> Result<Cow<'a, str>, String> {
+    if !has_directives(content) {
+        return Ok(Cow::Borrowed(content));
+    }
+    let mut out = String::with_capacity(content.len());
+    let mut fence: Option<String> = None;
+    let mut acknowledgments: Vec<&str> = Vec::new();
+    // Where the acknowledgments section is in `out`, and then where it
+    // ends, by its heading's level.
+    let mut section: Option<(usize, Option<usize>)> = None;
+    for line in content.split_inclusive('\n') {
+        let trimmed = line.trim();
+        if let Some(marker) = &fence {
+            if closes_fence(trimmed, marker) {
+                fence = None;
+            }
+            out.push_str(line);
+            continue;
+        }
+        if let Some(marker) = fence_marker(trimmed) {
+            fence = Some(marker);
+            out.push_str(line);
+            continue;
+        }
+        if let Some((level, title)) = heading(trimmed) {
+            match section {
+                None if title == config.acknowledgments_title => section = Some((level, None)),
+                Some((open, None)) if level <= open => section = Some((open, Some(out.len()))),
+                _ => {}
+            }
+        }
+        let directive = DIRECTIVES.iter().find_map(|(name, _)| {
+            let arguments = trimmed
+                .strip_prefix(&format!("{{{{#{name}"))?
+                .strip_suffix("}}")?;
+            // `{{#sources}}` isn't `{{#source}}`.
+            (arguments.is_empty() || arguments.starts_with(' ')).then_some((*name, arguments))
+        });
+        let markdown = match directive {
+            Some(("epigraph", arguments)) => epigraph(arguments)?,
+            Some(("grant", arguments)) => {
+                for grant in grants(arguments, config)? {
+                    if !acknowledgments.contains(&grant) {
+                        acknowledgments.push(grant);
+                    }
+                }
+                continue;
+            }
+            Some((_, arguments)) => source(arguments, &config.source_label)?,
+            None => {
+                out.push_str(line);
+                continue;
+            }
+        };
+        let (open, close) = placeholders(directive.map_or("", |(name, _)| name));
+        out.push_str(&format!("\n{open}\n\n{markdown}\n\n{close}\n\n"));
+    }
+    if !acknowledgments.is_empty() {
+        let (open, close) = placeholders("grant");
+        let paragraphs = acknowledgments.join("\n\n");
+        let block = format!("\n{open}\n\n{paragraphs}\n\n{close}\n\n");
+        match section {
+            Some((_, Some(end))) => out.insert_str(end, &block),
+            Some((_, None)) => out.push_str(&block),
+            None => {
+                let title = &config.acknowledgments_title;
+                out.push_str(&format!("\n\n## {title}\n{block}"));
+            }
+        }
+    }
+    Ok(Cow::Owned(out))
+}
+
+/// The level and title of the ATX heading `line` is, if it's one.
+fn heading(line: &str) -> Option<(usize, &str)> {
+    let level = line.chars().take_while(|c| *c == '#').count();
+    let title = line[level..].strip_prefix(' ')?;
+    let title = title.split(" {").next().unwrap_or_default();
+    (1..=6)
+        .contains(&level)
+        .then(|| (level, title.trim_end_matches('#').trim()))
+}
+
+/// The acknowledgment of each grant `{{#grant <id> ...}}` names.
+fn grants<'a>(arguments: &str, config: &'a Config) -> Result<Vec<&'a str>, String> {
+    let ids: Vec<&str> = arguments.split_whitespace().collect();
+    if ids.is_empty() {
+        return Err(
+            "{{#grant}} should name a grant in [grants], e.g. {{#grant NSF-123456}}".into(),
+        );
+    }
+    ids.iter()
+        .map(|id| {
+            config
+                .grants
+                .get(*id)
+                .map(String::as_str)
+                .ok_or_else(|| format!("{{{{#grant {id}}}}} names a grant which isn't in [grants]"))
+        })
+        .collect()
+}
+
+/// A citation as it's given to a directive, `@key` or `[@key, p. 4]`, in
+/// brackets.
+fn bracketed(citation: &str) -> Option<String> {
+    let citation = citation.trim();
+    match citation.starts_with('[') {
+        true => citation.ends_with(']').then(|| citation.to_string()),
+        false => citation.starts_with('@').then(|| format!("[{citation}]")),
+    }
+}
+
+/// The markdown for `{{#epigraph <citation> "<quote>"}}`.
+fn epigraph(arguments: &str) -> Result<String, String> {
+    let malformed = || {
+        format!(
+            "{{{{#epigraph{arguments}}}}} should be a citation and then a quote, \
+             e.g. {{{{#epigraph @smith2020 \"Quote text.\"}}}}"
+        )
+    };
+    let (citation, quote) = arguments.split_once('"').ok_or_else(malformed)?;
+    let quote = quote
+        .strip_suffix('"')
+        .ok_or_else(malformed)?
+        .replace("\\\"", "\"");
+    let citation = bracketed(citation).ok_or_else(malformed)?;
+    let quote = quote.trim();
+    if quote.is_empty() {
+        return Err(malformed());
+    }
+    Ok(format!("> {quote}\n>\n> — {citation}"))
+}
+
+/// The markdown for `{{#source <citation>}}`.
+fn source(arguments: &str, label: &str) -> Result<String, String> {
+    let citation = bracketed(arguments).ok_or_else(|| {
+        format!(
+            "{{{{#source{arguments}}}}} should be a citation, \
+             e.g. {{{{#source [@smith2020, fig. 3]}}}}"
+        )
+    })?;
+    Ok(format!("{label}: {citation}"))
+}
+
+/// Replaces the placeholders around expanded directives in pandoc's
+/// `output`: with HTML to style them by for the `html` renderer, otherwise
+/// with nothing.
+pub fn finish(output: &str, renderer: &str) -> String {
+    let mut out = output.to_string();
+    for (name, class) in DIRECTIVES {
+        let (open, close) = placeholders(name);
+        let (open_html, close_html) = match renderer {
+            "html" => (
+                format!("<div class=\"{class}\">\n\n"),
+                "</div>\n\n".to_string(),
+            ),
+            _ => (String::new(), String::new()),
+        };
+        for (placeholder, html) in [(open, open_html), (close, close_html)] {
+            while let Some(start) = out.find(&placeholder) {
+                let after = &out[start + placeholder.len()..];
+                let end = start
+                    + placeholder.len()
+                    + (after.len() - after.trim_start_matches('\n').len());
+                let html = if end == out.len() {
+                    html.trim_end()
+                } else {
+                    &html
+                };
+                out.replace_range(start..end, html);
+            }
+        }
+    }
+    out
+}
+
+fn fence_marker(line: &str) -> Option<String> {
+    let c = line.chars().next().filter(|c| *c == '`' || *c == '~')?;
+    let len = line.chars().take_while(|x| *x == c).count();
+    (len >= 3).then(|| c.to_string().repeat(len))
+}
+
+fn closes_fence(line: &str, marker: &str) -> bool {
+    line.len() >= marker.len() && line.chars().all(|c| marker.starts_with(c))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    fn config(toml: &str) -> Config {
+        Config::from_table(&toml::from_str(toml).unwrap(), Path::new(".")).unwrap()
+    }
+
+    #[test]
+    fn expands_epigraphs() {
+        let chapter = "# One\n\n{{#epigraph @smith2020, p. 4 \"To cite is to \\\"owe\\\".\"}}\n\n\
+                       ```\n{{#epigraph @adams2018 \"Not this.\"}}\n```\n";
+        let expanded = expand(chapter, &config("")).unwrap();
+        assert_eq!(
+            expanded,
+            "# One\n\n\nCITEPROCEPIGRAPHOPENX\n\n> To cite is to \"owe\".\n>\n\
+             > — [@smith2020, p. 4]\n\nCITEPROCEPIGRAPHCLOSEX\n\n\n\
+             ```\n{{#epigraph @adams2018 \"Not this.\"}}\n```\n"
+        );
+        let output = "# One\n\nCITEPROCEPIGRAPHOPENX\n\n> Quote.\n>\n> — (Smith 2020)\n\n\
+                      CITEPROCEPIGRAPHCLOSEX\n\nText.\n";
+        assert_eq!(
+            finish(output, "html"),
+            "# One\n\n<div class=\"epigraph\">\n\n> Quote.\n>\n> — (Smith 2020)\n\n</div>\n\nText.\n"
+        );
+        assert_eq!(
+            finish(output, "latex"),
+            "# One\n\n> Quote.\n>\n> — (Smith 2020)\n\nText.\n"
+        );
+
+        let e = expand("{{#epigraph \"No citation.\"}}\n", &config("")).unwrap_err();
+        assert!(e.contains("should be a citation and then a quote"), "{e}");
+    }
+
+    #[test]
+    fn expands_figure_sources() {
+        let chapter = "![A chart](chart.png)\n{{#source [@smith2020, fig. 3]}}\n\n{{#sources}}\n";
+        let expanded = expand(chapter, &config("source-label = \"Quelle\"")).unwrap();
+        assert_eq!(
+            expanded,
+            "![A chart](chart.png)\n\nCITEPROCSOURCEOPENX\n\nQuelle: [@smith2020, fig. 3]\n\n\
+             CITEPROCSOURCECLOSEX\n\n\n{{#sources}}\n"
+        );
+        assert_eq!(
+            finish(&expanded, "html"),
+            "![A chart](chart.png)\n\n<div class=\"figure-source\">\n\n\
+             Quelle: [@smith2020, fig. 3]\n\n</div>\n\n{{#sources}}\n"
+        );
+        assert!(expand("{{#source smith2020}}", &config("")).is_err());
+    }
+
+    #[test]
+    fn gathers_grants_into_acknowledgments() {
+        let config = config(
+            "[grants]\n\
+             NSF-123456 = { funder = \"the National Science Foundation\" }\n\
+             erc = \"This project has received funding from the ERC.\"\n",
+        );
+        let chapter = "# One\n\n{{#grant NSF-123456}}\nText.\n{{#grant erc NSF-123456}}\n";
+        assert_eq!(
+            expand(chapter, &config).unwrap(),
+            "# One\n\nText.\n\n\n## Acknowledgments\n\nCITEPROCGRANTOPENX\n\n\
+             This work was supported by the National Science Foundation under grant NSF-123456.\n\n\
+             This project has received funding from the ERC.\n\nCITEPROCGRANTCLOSEX\n\n"
+        );
+
+        let chapter = "# One\n\n## Acknowledgments\n\nThanks.\n\n## Notes\n\n{{#grant erc}}\n";
+        assert_eq!(
+            expand(chapter, &config).unwrap(),
+            "# One\n\n## Acknowledgments\n\nThanks.\n\n\nCITEPROCGRANTOPENX\n\n\
+             This project has received funding from the ERC.\n\nCITEPROCGRANTCLOSEX\n\n\
+             ## Notes\n\n"
+        );
+        let e = expand("{{#grant nih}}\n", &config).unwrap_err();
+        assert!(e.contains("isn't in [grants]"), "{e}");
+    }
+}
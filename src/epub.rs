@@ -0,0 +1,98 @@
+//! Output adjustments for the epub renderer.
+//!
+//! EPUB readers are strict XHTML consumers without scripting, so the
+//! bibliography pandoc writes is rewritten to validate: list roles become
+//! the DPUB-ARIA bibliography roles, pandoc's non-standard spacing
+//! attributes become `data-` attributes, and links are kept plain.
+
+/// Makes a processed chapter safe for EPUB.
+pub fn epub_safe(content: &str) -> String {
+    let mut out = String::with_capacity(content.len());
+    let mut rest = content;
+    while let Some(start) = rest.find('<') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if rest
+            .get(..7)
+            .is_some_and(|s| s.eq_ignore_ascii_case("<script"))
+        {
+            let lower = rest.to_ascii_lowercase();
+            rest = match lower.find("</script>") {
+                Some(end) => &rest[end + "</script>".len()..],
+                None => "",
+            };
+            continue;
+        }
+        let is_tag = rest[1..].starts_with(|c: char| c.is_ascii_alphabetic() || c == '/');
+        let Some(len) = rest.find('>').map(|end| end + 1).filter(|_| is_tag) else {
+            out.push('<');
+            rest = &rest[1..];
+            continue;
+        };
+        out.push_str(&rewrite_tag(&rest[..len]));
+        rest = &rest[len..];
+    }
+    out.push_str(rest);
+    out
+}
+
+fn rewrite_tag(tag: &str) -> String {
+    let mut tag = tag.to_string();
+    if tag.starts_with("<div") {
+        if tag.contains("id=\"refs\"") {
+            tag = tag.replace("role=\"list\"", "role=\"doc-bibliography\"");
+        } else if tag.contains("csl-entry") {
+            tag = tag.replace("role=\"listitem\"", "role=\"doc-biblioentry\"");
+        }
+        for attribute in ["entry-spacing", "line-spacing"] {
+            tag = tag.replace(&format!(" {attribute}="), &format!(" data-{attribute}="));
+        }
+    }
+    if tag.starts_with("<a ") {
+        for attribute in ["target", "rel"] {
+            tag = remove_attribute(&tag, attribute);
+        }
+    }
+    tag
+}
+
+/// Removes a quoted `attribute` from `tag`.
+fn remove_attribute(tag: &str, attribute: &str) -> String {
+    let needle = format!(" {attribute}=\"");
+    let Some(start) = tag.find(&needle) else {
+        return tag.to_string();
+    };
+    let value = start + needle.len();
+    match tag[value..].find('"') {
+        Some(end) => format!("{}{}", &tag[..start], &tag[value + end + 1..]),
+        None => tag.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rewrites_the_bibliography() {
+        let bibliography = "<div id=\"refs\" class=\"references csl-bib-body\" entry-spacing=\"0\" role=\"list\">\n\
+                            <div id=\"ref-smith\" class=\"csl-entry\" role=\"listitem\">\n\
+                            Smith. <a href=\"https://example.com\" target=\"_blank\">link</a>\n\
+                            </div>\n</div>\n";
+        assert_eq!(
+            epub_safe(bibliography),
+            "<div id=\"refs\" class=\"references csl-bib-body\" data-entry-spacing=\"0\" role=\"doc-bibliography\">\n\
+             <div id=\"ref-smith\" class=\"csl-entry\" role=\"doc-biblioentry\">\n\
+             Smith. <a href=\"https://example.com\">link</a>\n\
+             </div>\n</div>\n"
+        );
+    }
+
+    #[test]
+    fn strips_scripts() {
+        assert_eq!(
+            epub_safe("a <script>let x = 1 < 2;</script>b < c [Smith](#ref-smith)"),
+            "a b < c [Smith](#ref-smith)"
+        );
+    }
+}
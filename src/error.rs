@@ -0,0 +1,147 @@
+//! Errors with a [`CiteprocError`]'s [`ErrorKind`] somewhere in their
+//! chain, so `main` can tell what went wrong however much context they were
+//! wrapped in, and exit with that kind's code:
+//!
+//! | kind             | exit code |
+//! |------------------|-----------|
+//! | `other`          | 1         |
+//! | `config`         | 2         |
+//! | `pandoc-missing` | 3         |
+//! | `pandoc-failed`  | 4         |
+//! | `citation`       | 5         |
+//! | `bibliography`   | 6         |
+
+use std::fmt;
+
+use mdbook::errors::Error;
+use serde_json::json;
+
+/// The broad category of a failure, which determines the process exit code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// Anything not covered by a more specific kind.
+    Other,
+    /// The `[preprocessor.citeproc]` table is missing or invalid.
+    Config,
+    /// The pandoc executable could not be found.
+    PandocMissing,
+    /// Pandoc ran but failed, or its output could not be used.
+    PandocFailed,
+    /// Pandoc reported citation problems and `strict` is enabled.
+    Citation,
+    /// A bibliography file is malformed.
+    Bibliography,
+}
+
+impl ErrorKind {
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Self::Other => 1,
+            Self::Config => 2,
+            Self::PandocMissing => 3,
+            Self::PandocFailed => 4,
+            Self::Citation => 5,
+            Self::Bibliography => 6,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Other => "other",
+            Self::Config => "config",
+            Self::PandocMissing => "pandoc-missing",
+            Self::PandocFailed => "pandoc-failed",
+            Self::Citation => "citation",
+            Self::Bibliography => "bibliography",
+        }
+    }
+
+    /// The inverse of [`ErrorKind::as_str`].
+    pub fn parse(kind: &str) -> Option<Self> {
+        [
+            Self::Other,
+            Self::Config,
+            Self::PandocMissing,
+            Self::PandocFailed,
+            Self::Citation,
+            Self::Bibliography,
+        ]
+        .into_iter()
+        .find(|k| k.as_str() == kind)
+    }
+}
+
+/// An error carrying an [`ErrorKind`], so it survives being wrapped in an
+/// [`Error`] and can be recovered in `main`.
+#[derive(Debug)]
+pub struct CiteprocError {
+    kind: ErrorKind,
+    message: String,
+}
+
+impl CiteprocError {
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+        }
+    }
+
+    pub fn config(message: impl Into<String>) -> Error {
+        Self::new(ErrorKind::Config, message).into()
+    }
+
+    pub fn pandoc_missing(message: impl Into<String>) -> Error {
+        Self::new(ErrorKind::PandocMissing, message).into()
+    }
+
+    pub fn pandoc_failed(message: impl Into<String>) -> Error {
+        Self::new(ErrorKind::PandocFailed, message).into()
+    }
+
+    pub fn citation(message: impl Into<String>) -> Error {
+        Self::new(ErrorKind::Citation, message).into()
+    }
+}
+
+impl fmt::Display for CiteprocError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for CiteprocError {}
+
+/// How failures are reported on stderr.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ErrorFormat {
+    #[default]
+    Human,
+    Json,
+}
+
+/// Returns the kind of `error`, looking through any context it was wrapped in.
+pub fn kind_of(error: &Error) -> ErrorKind {
+    error
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<CiteprocError>())
+        .map_or(ErrorKind::Other, |e| e.kind)
+}
+
+/// Prints `error` in the requested format and returns the exit code to use.
+pub fn report(error: &Error, format: ErrorFormat) -> i32 {
+    let kind = kind_of(error);
+    match format {
+        ErrorFormat::Human => eprintln!("{}", error),
+        ErrorFormat::Json => eprintln!(
+            "{}",
+            json!({
+                "kind": kind.as_str(),
+                "exit_code": kind.exit_code(),
+                "message": error.to_string(),
+                "causes": error.chain().skip(1).map(|c| c.to_string()).collect::<Vec<_>>(),
+            })
+        ),
+    }
+    kind.exit_code()
+}
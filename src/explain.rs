@@ -0,0 +1,238 @@
+//! `mdbook-citeproc explain`: what a build would run, so conversions can
+//! be reproduced and debugged outside mdbook.
+//!
+//! The book goes through the preprocessor as it would in a build, but with
+//! a backend which records what pandoc would be asked and hands every
+//! chapter back as it is. The files pandoc would be pointed at, like
+//! derived bibliographies, are written just as they would be.
+
+use std::ffi::OsString;
+use std::fmt::{self, Write as _};
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use mdbook::errors::Error;
+
+use crate::backend::{self, Capabilities, CitationBackend, Output};
+use crate::check;
+use crate::compat::{self, PandocVersion};
+use crate::config::{BackendKind, Config};
+use crate::install;
+use crate::preprocessor::Pandoc;
+use crate::subprocess;
+
+/// Records the arguments of every conversion, in the order they ran.
+struct Recorder {
+    version: Option<PandocVersion>,
+    runs: Arc<Mutex<Vec<Vec<String>>>>,
+}
+
+impl CitationBackend for Recorder {
+    fn name(&self) -> &str {
+        "explain"
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            pandoc_version: self.version.clone(),
+            ..Capabilities::default()
+        }
+    }
+
+    fn convert(&self, config: &Config, input: &str) -> Result<Output, Error> {
+        if let Some(version) = &self.version {
+            compat::check(config, version)?;
+        }
+        let args = backend::pandoc_args_for(config, self.version.as_ref());
+        self.runs.lock().expect("runs poisoned").push(args);
+        Ok(Output {
+            stdout: input.as_bytes().to_vec(),
+            ..Output::default()
+        })
+    }
+}
+
+/// Describes the configuration and pandoc invocations building the book
+/// in `root` for `renderer` would use.
+pub fn explain(root: &Path, renderer: &str) -> Result<String, Error> {
+    let mut config = check::load_config_for(root, renderer)?;
+    config.set_renderer(renderer);
+    let (program, version) = match &config.backend {
+        BackendKind::Pandoc if config.auto_install_pandoc => (
+            vec![install::installed_path(&config.cache_root.join("pandoc")).into()],
+            PandocVersion::parse(install::PANDOC_VERSION),
+        ),
+        BackendKind::Pandoc => {
+            let program: Vec<OsString> = config.pandoc_command.iter().map(OsString::from).collect();
+            let version = compat::detect(&program);
+            (program, version)
+        }
+        _ => (vec!["pandoc".into()], None),
+    };
+
+    let runs = Arc::new(Mutex::new(Vec::new()));
+    let recorder = Recorder {
+        version: version.clone(),
+        runs: runs.clone(),
+    };
+    let preprocessor = Pandoc::new().quiet(true).backend(Box::new(recorder));
+    let input = crate::uncached_input(root, renderer)?;
+    crate::process_input_to(&preprocessor, &input, io::sink())?;
+    let runs = runs.lock().expect("runs poisoned").clone();
+
+    let mut out = describe(&config, renderer, &program, version.as_ref());
+    // Chapters with the same arguments are shown once.
+    let mut groups: Vec<(Vec<String>, usize)> = Vec::new();
+    for args in runs {
+        match groups.iter_mut().find(|(group, _)| *group == args) {
+            Some((_, count)) => *count += 1,
+            None => groups.push((args, 1)),
+        }
+    }
+    match groups.is_empty() {
+        true => out += "\nNothing would be converted.\n",
+        false => out += "\nEach chapter or title is piped through:\n",
+    }
+    for (args, count) in groups {
+        let plural = if count == 1 { "" } else { "s" };
+        let _ = write!(
+            out,
+            "\n{count} conversion{plural}:\n  {}\n",
+            subprocess::display(&program, &args)
+        );
+    }
+    Ok(out)
+}
+
+fn describe(
+    config: &Config,
+    renderer: &str,
+    program: &[OsString],
+    version: Option<&PandocVersion>,
+) -> String {
+    let mut lines: Vec<(&str, String)> = vec![
+        ("renderer", renderer.to_string()),
+        (
+            "profile",
+            config.profile.clone().unwrap_or_else(|| "none".into()),
+        ),
+        ("reader", config.from.to_string()),
+        ("writer", config.to.to_string()),
+    ];
+    lines.push((
+        "bibliography",
+        match &config.bibliography {
+            Some(bibliography) => format!(
+                "{}, styled by {}",
+                bibliography.bibliography, bibliography.bibliography_style
+            ),
+            None => "none, citations are left as they are".into(),
+        },
+    ));
+    lines.push((
+        "backend",
+        match &config.backend {
+            BackendKind::Pandoc => match version {
+                Some(version) => {
+                    format!("{} (pandoc {version})", subprocess::display(program, &[]))
+                }
+                None => format!("{} (version unknown)", subprocess::display(program, &[])),
+            },
+            BackendKind::PandocServer(url) => {
+                format!("pandoc-server at {url}, sent the equivalent of the arguments below")
+            }
+            BackendKind::Wasm(module) => {
+                format!("{} in-process, given the arguments below", module.display())
+            }
+        },
+    ));
+    lines.push(("disambiguation", setting(config.disambiguation)));
+    lines.push(("raw-html", setting(config.raw_html)));
+    lines.push((
+        "admonish",
+        match config.admonish {
+            Some(true) => "blocks are processed".into(),
+            _ => "no".into(),
+        },
+    ));
+    lines.push((
+        "cache",
+        match &config.cache_dir {
+            Some(dir) => format!("{} ({})", dir.display(), setting(config.cache_backend)),
+            None => "off".into(),
+        },
+    ));
+    lines.push((
+        "jobs",
+        format!(
+            "{} at a time, {}",
+            config.max_jobs,
+            setting(config.schedule)
+        ),
+    ));
+
+    let width = lines.iter().map(|(name, _)| name.len()).max().unwrap_or(0);
+    let mut out = String::from("Configuration:\n");
+    for (name, value) in lines {
+        let _ = writeln!(out, "  {name:width$}  {value}");
+    }
+    out
+}
+
+/// An option's value as it's written in `book.toml`, e.g. `book-order`
+/// for `Schedule::BookOrder`.
+fn setting(value: impl fmt::Debug) -> String {
+    let mut out = String::new();
+    for (i, c) in format!("{value:?}").chars().enumerate() {
+        if c.is_uppercase() && i > 0 {
+            out.push('-');
+        }
+        out.extend(c.to_lowercase());
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn explains_a_transpiling_book() {
+        let dir = std::env::temp_dir().join(format!("citeproc-explain-{}", std::process::id()));
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::write(
+            dir.join("book.toml"),
+            "[preprocessor.citeproc]\ncitations = \"transpile\"\nbibliography = \"refs.bib\"\n\
+             csl = \"style.csl\"\npandoc-command = [\"no-such-pandoc\"]\n",
+        )
+        .unwrap();
+        fs::write(dir.join("refs.bib"), "@book{a, title = {A}}\n").unwrap();
+        fs::write(dir.join("style.csl"), "<style/>").unwrap();
+        fs::write(
+            dir.join("src/SUMMARY.md"),
+            "[One](one.md)\n[Two](two.md)\n[Three](three.md)\n",
+        )
+        .unwrap();
+        fs::write(dir.join("src/one.md"), "See @a.\n").unwrap();
+        fs::write(dir.join("src/two.md"), "See @a.\n").unwrap();
+        fs::write(dir.join("src/three.md"), "---\nlang: fr\n---\nVoir @a.\n").unwrap();
+
+        let explained = explain(&dir, "html").unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(
+            explained.contains("  bibliography    refs.bib, styled by style.csl\n"),
+            "{explained}"
+        );
+        assert!(explained.contains("no-such-pandoc (version unknown)"));
+        assert!(explained.contains(
+            "\n2 conversions:\n  no-such-pandoc --from=markdown_strict+citations+fenced_divs+footnotes"
+        ));
+        assert!(explained.contains(
+            "\n1 conversion:\n  no-such-pandoc --from=markdown_strict+citations+fenced_divs+footnotes"
+        ));
+        assert!(explained.contains("--metadata=lang:fr"), "{explained}");
+        assert!(explained.contains(" --citeproc\n"));
+    }
+}
@@ -0,0 +1,144 @@
+//! Checks the extension sets pandoc is given before it's run, since it
+//! quietly ignores extensions which depend on others that are off.
+
+use std::collections::BTreeSet;
+
+use mdbook::errors::Error;
+
+use crate::args::Format;
+use crate::error::CiteprocError;
+
+/// Extensions which do nothing without at least one of some others.
+const REQUIRES: &[(&str, &[&str])] = &[
+    (
+        "fenced_code_attributes",
+        &["fenced_code_blocks", "backtick_code_blocks"],
+    ),
+    (
+        "table_captions",
+        &[
+            "pipe_tables",
+            "simple_tables",
+            "multiline_tables",
+            "grid_tables",
+        ],
+    ),
+    ("markdown_in_html_blocks", &["raw_html"]),
+];
+
+/// The extensions `format` has unless they're turned off, or `None` if we
+/// don't know them and so can't check its extensions.
+fn defaults(format: &str) -> Option<&'static [&'static str]> {
+    match format {
+        "markdown_strict" => Some(&[
+            "raw_html",
+            "shortcut_reference_links",
+            "spaced_reference_links",
+        ]),
+        _ => None,
+    }
+}
+
+/// The extensions `format` is left with, starting from its defaults, or
+/// `None` if we don't know them.
+fn enabled(format: &Format) -> Option<BTreeSet<&str>> {
+    let mut enabled: BTreeSet<&str> = defaults(&format.name)?.iter().copied().collect();
+    for extension in &format.extensions {
+        match extension.enabled {
+            true => enabled.insert(&extension.name),
+            false => enabled.remove(extension.name.as_str()),
+        };
+    }
+    Some(enabled)
+}
+
+/// Checks every extension `format` enables has what it depends on. `side`
+/// describes who reads `format` (e.g. "pandoc's reader"), and `key` names
+/// the configuration which turned an extension on or off, if any did.
+pub fn validate(
+    format: &Format,
+    side: &str,
+    key: impl Fn(&str) -> Option<String>,
+) -> Result<(), Error> {
+    let Some(enabled) = enabled(format) else {
+        return Ok(());
+    };
+    for &(extension, needs) in REQUIRES {
+        if !enabled.contains(extension) || needs.iter().any(|need| enabled.contains(need)) {
+            continue;
+        }
+        let set_by = key(extension).map_or(String::new(), |key| format!(" (set by {key})"));
+        let alternatives = match needs {
+            [need] => need.to_string(),
+            [init @ .., last] => format!("{} or {last}", init.join(", ")),
+            [] => unreachable!("extensions depend on something"),
+        };
+        let turned_off = format
+            .extensions
+            .iter()
+            .filter(|e| !e.enabled && needs.contains(&e.name.as_str()))
+            .find_map(|e| key(&e.name).map(|key| format!("{key} turns {} off", e.name)));
+        let reason = turned_off.unwrap_or_else(|| match needs.len() {
+            1 => "it isn't enabled".into(),
+            _ => "none of them are enabled".into(),
+        });
+        return Err(CiteprocError::config(format!(
+            "{extension}{set_by} needs {alternatives} in {side}, but {reason}"
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn starts_from_the_format_defaults() {
+        let format = Format::parse("markdown_strict+footnotes-raw_html+raw_html-smart");
+        let on = enabled(&format).unwrap();
+        assert!(on.contains("footnotes") && on.contains("raw_html"));
+        assert!(on.contains("shortcut_reference_links"));
+        assert!(!on.contains("smart"));
+        assert!(enabled(&Format::parse("typst-smart")).is_none());
+    }
+
+    #[test]
+    fn reports_missing_dependencies() {
+        let key = |name: &str| (name != "raw_html").then(|| format!("`{name}`"));
+        let e = validate(
+            &Format::parse("markdown_strict+fenced_code_attributes"),
+            "pandoc's reader",
+            key,
+        )
+        .unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "fenced_code_attributes (set by `fenced_code_attributes`) needs \
+             fenced_code_blocks or backtick_code_blocks in pandoc's reader, but none of them are enabled"
+        );
+        validate(
+            &Format::parse("markdown_strict+fenced_code_blocks+fenced_code_attributes"),
+            "pandoc's reader",
+            key,
+        )
+        .unwrap();
+        validate(
+            &Format::parse("typst+fenced_code_attributes"),
+            "pandoc's writer",
+            key,
+        )
+        .unwrap();
+
+        let e = validate(
+            &Format::parse("markdown_strict+markdown_in_html_blocks-raw_html"),
+            "pandoc's writer",
+            |name| Some(format!("`{name}`")),
+        )
+        .unwrap_err();
+        assert!(
+            e.to_string().ends_with("but `raw_html` turns raw_html off"),
+            "{e}"
+        );
+    }
+}
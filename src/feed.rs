@@ -0,0 +1,233 @@
+//! An Atom feed of the works a book has started citing, for `feed`, so
+//! readers of a book that's published as it's written can follow its
+//! reading list.
+//!
+//! Which works were cited at the last build is kept in the cache root:
+//! each build adds the ones cited since to the top of the feed, keeping the
+//! last [`MAX_ITEMS`]. The first build only records what's cited already,
+//! so a book doesn't announce its whole bibliography. The feed is written
+//! where `feed` says: under `src` it's copied into the book, and it's only
+//! rewritten when it changes, so `mdbook serve` doesn't rebuild for it.
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use mdbook::errors::Error;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::bibliography::{self, Entry, Library};
+use crate::cache;
+use crate::config::Feed;
+use crate::preprocessor::civil_date;
+
+/// How many works the feed lists.
+pub const MAX_ITEMS: usize = 50;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct History {
+    /// Every key cited at some build.
+    seen: BTreeSet<String>,
+    /// The feed's items, newest first.
+    items: Vec<Item>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Item {
+    key: String,
+    title: String,
+    authors: Vec<String>,
+    issued: Option<String>,
+    link: Option<String>,
+    /// When it was first cited, in seconds since the Unix epoch.
+    added: u64,
+}
+
+/// Adds the works among `keys` which weren't cited before to the feed, and
+/// writes it if it's changed.
+pub fn update(
+    feed: &Feed,
+    cache_root: &Path,
+    library: &Library,
+    keys: &[String],
+) -> Result<(), Error> {
+    let state = cache_root.join("feed.json");
+    let first = !state.exists();
+    let mut history: History = fs::read(&state)
+        .ok()
+        .and_then(|contents| serde_json::from_slice(&contents).ok())
+        .unwrap_or_default();
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    let mut added = Vec::new();
+    for key in keys {
+        let Some(entry) = library.get(key) else {
+            continue;
+        };
+        if history.seen.insert(key.clone()) && !first {
+            added.push(item(entry, now));
+        }
+    }
+    if first || !added.is_empty() {
+        history.items.splice(0..0, added);
+        history.items.truncate(MAX_ITEMS);
+        fs::create_dir_all(cache_root)?;
+        cache::write_atomic(&state, serde_json::to_vec_pretty(&history)?)?;
+    }
+
+    let atom = render(feed, &history.items);
+    if fs::read_to_string(&feed.path).ok().as_deref() != Some(atom.as_str()) {
+        if let Some(dir) = feed.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(&feed.path, atom)?;
+    }
+    Ok(())
+}
+
+fn item(entry: &Entry, added: u64) -> Item {
+    let csl = entry.csl_json();
+    let get = |variable: &str| {
+        csl.get(variable)
+            .and_then(Value::as_str)
+            .map(str::to_string)
+    };
+    let authors = entry
+        .fields
+        .get("author")
+        .map(|authors| bibliography::names(authors))
+        .unwrap_or_default()
+        .iter()
+        .map(|name| match &name.literal {
+            Some(literal) => literal.clone(),
+            None => [&name.given, &name.particle, &name.family, &name.suffix]
+                .iter()
+                .filter(|part| !part.is_empty())
+                .map(|part| part.as_str())
+                .collect::<Vec<_>>()
+                .join(" "),
+        })
+        .collect();
+    Item {
+        key: entry.key.clone(),
+        title: get("title").unwrap_or_else(|| entry.key.clone()),
+        authors,
+        issued: get("issued"),
+        link: get("DOI")
+            .map(|doi| format!("https://doi.org/{doi}"))
+            .or_else(|| get("URL")),
+        added,
+    }
+}
+
+/// The Atom document listing `items`.
+fn render(feed: &Feed, items: &[Item]) -> String {
+    let id = |fragment: &str| match &feed.url {
+        Some(url) => format!("{}{fragment}", url.trim_end_matches('/')),
+        None => format!("urn:mdbook-citeproc:feed{fragment}"),
+    };
+    let updated = items.first().map_or(0, |item| item.added);
+    let mut atom = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    atom += "<feed xmlns=\"http://www.w3.org/2005/Atom\">\n";
+    atom += &format!("  <title>{}</title>\n", escape(&feed.title));
+    atom += &format!("  <id>{}</id>\n", escape(&id("")));
+    if let Some(url) = &feed.url {
+        atom += &format!("  <link href=\"{}\"/>\n", escape(url));
+    }
+    atom += &format!("  <updated>{}</updated>\n", timestamp(updated));
+    atom += &format!("  <author><name>{}</name></author>\n", escape(&feed.title));
+    for item in items {
+        atom += "  <entry>\n";
+        atom += &format!("    <title>{}</title>\n", escape(&item.title));
+        atom += &format!(
+            "    <id>{}</id>\n",
+            escape(&id(&format!("#ref-{}", item.key)))
+        );
+        atom += &format!("    <updated>{}</updated>\n", timestamp(item.added));
+        for author in &item.authors {
+            atom += &format!("    <author><name>{}</name></author>\n", escape(author));
+        }
+        if let Some(link) = &item.link {
+            atom += &format!("    <link href=\"{}\"/>\n", escape(link));
+        }
+        let mut summary = item.authors.join(", ");
+        if let Some(issued) = &item.issued {
+            summary += &format!(" ({issued})");
+        }
+        if !summary.trim().is_empty() {
+            atom += &format!("    <summary>{}</summary>\n", escape(summary.trim()));
+        }
+        atom += "  </entry>\n";
+    }
+    atom += "</feed>\n";
+    atom
+}
+
+/// `secs` since the Unix epoch as an RFC 3339 date and time in UTC.
+fn timestamp(secs: u64) -> String {
+    let date = civil_date(UNIX_EPOCH + Duration::from_secs(secs));
+    let time = secs % 86_400;
+    format!(
+        "{date}T{:02}:{:02}:{:02}Z",
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    )
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lists_works_cited_since_the_last_build() {
+        let dir = std::env::temp_dir().join(format!("citeproc-feed-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let entry = |key: &str, title: &str| Entry {
+            key: key.into(),
+            kind: "book".into(),
+            fields: [("title", title), ("author", "Smith, Jo"), ("year", "2020")]
+                .iter()
+                .map(|(field, value)| (field.to_string(), value.to_string()))
+                .collect(),
+        };
+        let library = Library::new([entry("a", "First"), entry("b", "Fish & {Chips}")]);
+        let feed = Feed {
+            path: dir.join("src/references.xml"),
+            title: "Reading list".into(),
+            url: Some("https://book.example/".into()),
+        };
+        let cache = dir.join("cache");
+
+        update(&feed, &cache, &library, &["a".into()]).unwrap();
+        let atom = fs::read_to_string(&feed.path).unwrap();
+        assert!(atom.contains("<id>https://book.example</id>"), "{atom}");
+        assert!(!atom.contains("<entry>"), "{atom}");
+
+        update(&feed, &cache, &library, &["a".into(), "b".into()]).unwrap();
+        let atom = fs::read_to_string(&feed.path).unwrap();
+        assert_eq!(atom.matches("<entry>").count(), 1, "{atom}");
+        assert!(atom.contains("<title>Fish &amp; Chips</title>"), "{atom}");
+        assert!(
+            atom.contains("<id>https://book.example#ref-b</id>"),
+            "{atom}"
+        );
+        assert!(
+            atom.contains("<summary>Jo Smith (2020)</summary>"),
+            "{atom}"
+        );
+        fs::remove_dir_all(dir).unwrap();
+
+        assert_eq!(timestamp(86_400 + 3_723), "1970-01-02T01:02:03Z");
+    }
+}
@@ -0,0 +1,183 @@
+//! Math and code in bibliography fields, like a title with `$O(n \log n)$`
+//! or `` `Vec<T>` `` in it. Pandoc reads BibTeX's math as LaTeX and writes
+//! it back escaped, and has no idea backticks are code at all.
+//!
+//! So when the bibliography is derived, each span is swapped for a
+//! placeholder word, protected from the style's case changes, and swapped
+//! back into pandoc's output in the renderer's own syntax: MathJax's
+//! `\\( … \\)` for mdbook's HTML, `$ … $` for everything else, and a code
+//! span for code.
+
+use std::fs;
+use std::path::Path;
+
+const PREFIX: &str = "CITEPROCFIELD";
+const SUFFIX: &str = "X";
+
+/// Fields which aren't text, so a `$` in them is just a `$`.
+const VERBATIM: &[&str] = &["doi", "eprint", "file", "url", "urldate"];
+
+/// Whether the bibliography at `path` might have math or code in it, so
+/// has to be derived for [`FieldMarkup`] to protect it.
+pub fn might_have_markup(path: &Path) -> bool {
+    let readable = matches!(
+        path.extension().and_then(|e| e.to_str()),
+        Some("bib" | "bibtex" | "json")
+    );
+    readable && fs::read_to_string(path).is_ok_and(|text| text.contains(['$', '`']))
+}
+
+/// The math and code spans taken out of a bibliography's fields, in
+/// order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FieldMarkup {
+    spans: Vec<Markup>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Markup {
+    Math(String),
+    Code(String),
+}
+
+impl FieldMarkup {
+    pub fn is_empty(&self) -> bool {
+        self.spans.is_empty()
+    }
+
+    /// `value`, the (lowercased) `field` of an entry, with its math and
+    /// code taken out, or `None` if it has none. Placeholders are braced
+    /// for BibTeX, and otherwise marked `nocase` as CSL-JSON's markup has
+    /// it.
+    pub fn protect(&mut self, field: &str, value: &str, bibtex: bool) -> Option<String> {
+        if VERBATIM.contains(&field) || !value.contains(['$', '`']) {
+            return None;
+        }
+        let mut out = String::with_capacity(value.len());
+        let mut rest = value;
+        let mut found = false;
+        while let Some((start, end, markup)) = next_span(rest) {
+            let placeholder = format!("{PREFIX}{}{SUFFIX}", self.spans.len());
+            out.push_str(&rest[..start]);
+            match bibtex {
+                true => out.push_str(&format!("{{{placeholder}}}")),
+                false => out.push_str(&format!("<span class=\"nocase\">{placeholder}</span>")),
+            }
+            self.spans.push(markup);
+            found = true;
+            rest = &rest[end..];
+        }
+        out.push_str(rest);
+        found.then_some(out)
+    }
+
+    /// `output` with the math and code put back, as `renderer` writes it.
+    pub fn restore(&self, output: &str, renderer: &str) -> String {
+        let mut restored = output.to_string();
+        for (i, markup) in self.spans.iter().enumerate() {
+            let placeholder = format!("{PREFIX}{i}{SUFFIX}");
+            if !restored.contains(&placeholder) {
+                continue;
+            }
+            let text = match markup {
+                Markup::Math(math) if renderer == "html" => format!("\\\\({math}\\\\)"),
+                Markup::Math(math) => format!("${math}$"),
+                Markup::Code(code) => {
+                    let longest = code.split(|c| c != '`').map(str::len).max().unwrap_or(0);
+                    let fence = "`".repeat(longest + 1);
+                    let pad = if code.starts_with('`') || code.ends_with('`') {
+                        " "
+                    } else {
+                        ""
+                    };
+                    format!("{fence}{pad}{code}{pad}{fence}")
+                }
+            };
+            restored = restored.replace(&placeholder, &text);
+        }
+        restored
+    }
+}
+
+/// The first math or code span in `text`: its start, its end and what's in
+/// it.
+fn next_span(text: &str) -> Option<(usize, usize, Markup)> {
+    let bytes = text.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' => i += 1,
+            b'$' => {
+                let inner = &text[i + 1..];
+                if let Some(close) = unescaped(inner, '$').filter(|close| *close > 0) {
+                    let end = i + 1 + close + 1;
+                    return Some((i, end, Markup::Math(inner[..close].to_string())));
+                }
+            }
+            b'`' => {
+                let ticks = bytes[i..].iter().take_while(|b| **b == b'`').count();
+                let fence = "`".repeat(ticks);
+                let inner = &text[i + ticks..];
+                if let Some(close) = inner.find(&fence) {
+                    let code = inner[..close].trim();
+                    if !code.is_empty() {
+                        let end = i + ticks + close + ticks;
+                        return Some((i, end, Markup::Code(code.to_string())));
+                    }
+                }
+                i += ticks - 1;
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    None
+}
+
+/// Where the first `c` in `text` not escaped with a backslash is.
+fn unescaped(text: &str, c: char) -> Option<usize> {
+    let mut escaped = false;
+    for (i, x) in text.char_indices() {
+        if x == c && !escaped {
+            return Some(i);
+        }
+        escaped = x == '\\' && !escaped;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_math_and_code_for_the_renderer() {
+        let mut markup = FieldMarkup::default();
+        let title = markup
+            .protect(
+                "title",
+                "Sorting in $O(n \\log n)$ with `sort_by` for \\$5",
+                true,
+            )
+            .unwrap();
+        assert_eq!(
+            title,
+            "Sorting in {CITEPROCFIELD0X} with {CITEPROCFIELD1X} for \\$5"
+        );
+        assert_eq!(markup.protect("url", "https://a.example/$x$", true), None);
+        assert_eq!(
+            markup.protect("title", "`a`", false).as_deref(),
+            Some("<span class=\"nocase\">CITEPROCFIELD2X</span>")
+        );
+
+        let output = "Smith. *Sorting in CITEPROCFIELD0X with CITEPROCFIELD1X.*";
+        assert_eq!(
+            markup.restore(output, "html"),
+            "Smith. *Sorting in \\\\(O(n \\log n)\\\\) with `sort_by`.*"
+        );
+        assert_eq!(
+            markup.restore(output, "latex"),
+            "Smith. *Sorting in $O(n \\log n)$ with `sort_by`.*"
+        );
+    }
+}
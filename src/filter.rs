@@ -0,0 +1,200 @@
+//! `bibliography-filter` expressions, which select the entries of a large
+//! shared bibliography a book uses.
+//!
+//! An expression combines `field:value` terms with `AND`, `OR`, `NOT` and
+//! parentheses, e.g. `keyword:mdbook OR (type:book AND NOT keyword:draft)`.
+//! `keyword:` matches one of the entry's keywords, `type:` its CSL type,
+//! `key:` its key, and any other field matches if it contains the value.
+//! Values can be quoted to include spaces. Matching ignores case.
+
+use crate::bibliography::Entry;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Filter {
+    Term { field: String, value: String },
+    Not(Box<Filter>),
+    And(Box<Filter>, Box<Filter>),
+    Or(Box<Filter>, Box<Filter>),
+}
+
+impl Filter {
+    pub fn parse(expression: &str) -> Result<Self, String> {
+        let tokens = tokenize(expression)?;
+        let mut parser = Parser { tokens, pos: 0 };
+        let filter = parser.or()?;
+        match parser.tokens.get(parser.pos) {
+            None => Ok(filter),
+            Some(token) => Err(format!("unexpected {token:?}")),
+        }
+    }
+
+    pub fn matches(&self, entry: &Entry) -> bool {
+        match self {
+            Self::Term { field, value } => match field.as_str() {
+                "key" => entry.key.eq_ignore_ascii_case(value),
+                "type" => entry.kind.eq_ignore_ascii_case(value),
+                "keyword" | "keywords" => entry
+                    .keywords()
+                    .any(|keyword| keyword.eq_ignore_ascii_case(value)),
+                field => entry
+                    .fields
+                    .get(field)
+                    .is_some_and(|text| text.to_lowercase().contains(&value.to_lowercase())),
+            },
+            Self::Not(filter) => !filter.matches(entry),
+            Self::And(a, b) => a.matches(entry) && b.matches(entry),
+            Self::Or(a, b) => a.matches(entry) || b.matches(entry),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Open,
+    Close,
+    Word(String),
+}
+
+fn tokenize(expression: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = expression.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' => {
+                chars.next();
+                tokens.push(Token::Open);
+            }
+            ')' => {
+                chars.next();
+                tokens.push(Token::Close);
+            }
+            _ => {
+                let mut word = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || c == '(' || c == ')' {
+                        break;
+                    }
+                    chars.next();
+                    if c == '"' {
+                        loop {
+                            match chars.next() {
+                                Some('"') => break,
+                                Some(c) => word.push(c),
+                                None => return Err("unterminated quote".into()),
+                            }
+                        }
+                    } else {
+                        word.push(c);
+                    }
+                }
+                tokens.push(Token::Word(word));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn keyword(&mut self, keyword: &str) -> bool {
+        let is_keyword = matches!(self.tokens.get(self.pos), Some(Token::Word(w)) if w == keyword);
+        if is_keyword {
+            self.pos += 1;
+        }
+        is_keyword
+    }
+
+    fn or(&mut self) -> Result<Filter, String> {
+        let mut filter = self.and()?;
+        while self.keyword("OR") {
+            filter = Filter::Or(Box::new(filter), Box::new(self.and()?));
+        }
+        Ok(filter)
+    }
+
+    fn and(&mut self) -> Result<Filter, String> {
+        let mut filter = self.not()?;
+        while self.keyword("AND") {
+            filter = Filter::And(Box::new(filter), Box::new(self.not()?));
+        }
+        Ok(filter)
+    }
+
+    fn not(&mut self) -> Result<Filter, String> {
+        if self.keyword("NOT") {
+            return Ok(Filter::Not(Box::new(self.not()?)));
+        }
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        match token {
+            Some(Token::Open) => {
+                let filter = self.or()?;
+                match self.tokens.get(self.pos) {
+                    Some(Token::Close) => {
+                        self.pos += 1;
+                        Ok(filter)
+                    }
+                    _ => Err("missing `)`".into()),
+                }
+            }
+            Some(Token::Word(word)) => match word.split_once(':') {
+                Some((field, value)) if !field.is_empty() => Ok(Filter::Term {
+                    field: field.to_lowercase(),
+                    value: value.to_string(),
+                }),
+                _ => Err(format!("expected `field:value`, found {word:?}")),
+            },
+            Some(Token::Close) => Err("unexpected `)`".into()),
+            None => Err("unexpected end of expression".into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    fn entry(kind: &str, keywords: &str) -> Entry {
+        Entry {
+            key: "smith".into(),
+            kind: kind.into(),
+            fields: BTreeMap::from([
+                ("keywords".to_string(), keywords.to_string()),
+                ("title".to_string(), "Teaching with mdBook".to_string()),
+            ]),
+        }
+    }
+
+    #[test]
+    fn matches_expressions() {
+        let filter = Filter::parse("keyword:mdbook OR keyword:teaching").unwrap();
+        assert!(filter.matches(&entry("book", "rust, mdBook")));
+        assert!(!filter.matches(&entry("book", "mdbooks")));
+
+        let filter =
+            Filter::parse("type:book AND NOT (keyword:draft OR title:\"with pandoc\")").unwrap();
+        assert!(filter.matches(&entry("book", "final")));
+        assert!(!filter.matches(&entry("book", "final; draft")));
+        assert!(!filter.matches(&entry("article-journal", "")));
+        assert!(Filter::parse("title:\"with mdbook\"")
+            .unwrap()
+            .matches(&entry("book", "")));
+    }
+
+    #[test]
+    fn reports_syntax_errors() {
+        assert!(Filter::parse("keyword:a OR").is_err());
+        assert!(Filter::parse("(type:book").is_err());
+        assert!(Filter::parse("mdbook").is_err());
+        assert!(Filter::parse("type:book type:article").is_err());
+    }
+}
@@ -0,0 +1,203 @@
+//! YAML front matter at the top of a chapter, as used by other
+//! preprocessors, and the little YAML pandoc's defaults files need.
+//!
+//! Pandoc never sees the front matter itself; the keys we care about are
+//! passed on as metadata instead. Only top-level keys with scalar or list
+//! values are understood, which covers everything citeproc reads.
+
+use std::collections::BTreeMap;
+
+/// A chapter split into its front matter and the rest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Split<'a> {
+    /// The front matter including its `---` delimiters and trailing newline.
+    pub front_matter: &'a str,
+    pub body: &'a str,
+}
+
+/// Splits off the front matter at the start of `content`, if it has any.
+pub fn split(content: &str) -> Option<Split<'_>> {
+    let first = content.split_inclusive('\n').next()?;
+    if first.trim_end() != "---" {
+        return None;
+    }
+    let mut offset = first.len();
+    for line in content[offset..].split_inclusive('\n') {
+        offset += line.len();
+        if matches!(line.trim_end(), "---" | "...") {
+            return Some(Split {
+                front_matter: &content[..offset],
+                body: &content[offset..],
+            });
+        }
+    }
+    None
+}
+
+/// Parses the top-level keys of `front_matter`. Lists are joined with
+/// `, `, which is what pandoc expects for e.g. `nocite`. Values which
+/// aren't scalars or lists of scalars are skipped.
+pub fn parse(front_matter: &str) -> BTreeMap<String, String> {
+    let lines: Vec<&str> = front_matter
+        .lines()
+        .skip(1)
+        .take_while(|line| !matches!(line.trim_end(), "---" | "..."))
+        .collect();
+    parse_lines(&lines)
+}
+
+/// Parses the top-level keys of a YAML document, as [`parse`] does front
+/// matter.
+pub fn parse_yaml(yaml: &str) -> BTreeMap<String, String> {
+    let lines: Vec<&str> = yaml
+        .lines()
+        .filter(|line| !matches!(line.trim_end(), "---" | "..."))
+        .collect();
+    parse_lines(&lines)
+}
+
+/// The mapping under the top-level `key` of a YAML document, unindented,
+/// for [`parse_yaml`].
+pub fn nested(yaml: &str, key: &str) -> Option<String> {
+    let mut lines = yaml.lines();
+    lines.find(|line| {
+        line.strip_prefix(key)
+            .and_then(|rest| rest.strip_prefix(':'))
+            .is_some_and(|rest| rest.trim().is_empty() || rest.trim().starts_with('#'))
+    })?;
+    let nested: Vec<&str> = lines
+        .take_while(|line| line.trim().is_empty() || line.starts_with([' ', '\t']))
+        .collect();
+    let indent = nested
+        .iter()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.len() - line.trim_start().len())
+        .min()?;
+    let unindented: Vec<&str> = nested
+        .iter()
+        .map(|line| line.get(indent..).unwrap_or_default())
+        .collect();
+    Some(unindented.join("\n"))
+}
+
+fn parse_lines(lines: &[&str]) -> BTreeMap<String, String> {
+    let mut keys = BTreeMap::new();
+    let mut i = 0;
+    while i < lines.len() {
+        let line = lines[i];
+        i += 1;
+        let Some((key, value)) = line.split_once(':') else {
+            continue;
+        };
+        if line.starts_with([' ', '\t', '#', '-']) || key.contains(' ') {
+            continue;
+        }
+        let nested: Vec<&str> = lines[i..]
+            .iter()
+            .take_while(|line| line.is_empty() || line.starts_with([' ', '\t', '-']))
+            .copied()
+            .collect();
+        i += nested.len();
+
+        let value = value.trim();
+        let parsed = match value.chars().next() {
+            Some('|' | '>') => Some(block_scalar(value, &nested)),
+            Some('[') => value
+                .strip_prefix('[')
+                .and_then(|v| v.strip_suffix(']'))
+                .map(|items| {
+                    items
+                        .split(',')
+                        .map(|item| scalar(item.trim()))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                }),
+            Some('{') => None,
+            Some(_) => Some(scalar(value)),
+            None => {
+                let items: Option<Vec<String>> = nested
+                    .iter()
+                    .filter(|line| !line.trim().is_empty())
+                    .map(|line| {
+                        line.trim_start()
+                            .strip_prefix("- ")
+                            .map(|v| scalar(v.trim()))
+                    })
+                    .collect();
+                items
+                    .filter(|items| !items.is_empty())
+                    .map(|items| items.join(", "))
+            }
+        };
+        if let Some(parsed) = parsed {
+            keys.insert(key.to_string(), parsed);
+        }
+    }
+    keys
+}
+
+/// A `|` (literal) or `>` (folded) block scalar.
+fn block_scalar(indicator: &str, lines: &[&str]) -> String {
+    let indent = lines
+        .iter()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.len() - line.trim_start().len())
+        .min()
+        .unwrap_or(0);
+    let lines: Vec<&str> = lines
+        .iter()
+        .map(|line| line.get(indent..).unwrap_or_default())
+        .collect();
+    let separator = if indicator.starts_with('>') {
+        " "
+    } else {
+        "\n"
+    };
+    lines.join(separator).trim_end().to_string()
+}
+
+/// A plain, single-quoted or double-quoted scalar.
+fn scalar(value: &str) -> String {
+    let value = match value.find(" #") {
+        Some(comment) if !value.starts_with(['"', '\'']) => value[..comment].trim_end(),
+        _ => value,
+    };
+    if let Some(inner) = value.strip_prefix('\'').and_then(|v| v.strip_suffix('\'')) {
+        inner.replace("''", "'")
+    } else if let Some(inner) = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+        inner.replace("\\\"", "\"").replace("\\\\", "\\")
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_front_matter() {
+        let content = "---\ntitle: Intro\n---\n# Intro\n";
+        let split = split(content).unwrap();
+        assert_eq!(split.front_matter, "---\ntitle: Intro\n---\n");
+        assert_eq!(split.body, "# Intro\n");
+        assert_eq!(super::split("# Intro\n---\n"), None);
+        assert_eq!(super::split("---\nunterminated\n"), None);
+    }
+
+    #[test]
+    fn parses_scalars_and_lists() {
+        let keys = parse(
+            "---\nlang: 'fr-FR'\ncsl: styles/apa.csl # comment\n\
+             nocite:\n  - \"@smith\"\n  - '@jones'\n\
+             tags: [a, b]\nabstract: |\n  Line one\n  Line two\n\
+             author:\n  name: Me\n---\n",
+        );
+        assert_eq!(keys["lang"], "fr-FR");
+        assert_eq!(keys["csl"], "styles/apa.csl");
+        assert_eq!(keys["nocite"], "@smith, @jones");
+        assert_eq!(keys["tags"], "a, b");
+        assert_eq!(keys["abstract"], "Line one\nLine two");
+        assert!(!keys.contains_key("author"));
+    }
+}
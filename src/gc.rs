@@ -0,0 +1,222 @@
+//! Keeping the cache root from growing without bound across branches and
+//! experiments: with `cache-max-size` or `cache-max-age-days` set, what
+//! builds haven't used for longest is removed after each build, and
+//! `mdbook-citeproc cache stats|clean` shows what's there and prunes it.
+//!
+//! Reading a cached chapter (or reusing a generated file) marks it used,
+//! so the files removed are the ones no recent build needed. Anything
+//! removed is made again when it's next needed.
+
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use mdbook::errors::Error;
+
+use crate::preprocessor::size;
+
+/// Files the cache needs to make sense of the rest, or which can't be
+/// made again: the recorded dependencies, the feed's record of what was
+/// cited and the SQLite cache (which is pruned as a whole or not at all).
+const KEPT: &[&str] = &["metadata.json", "feed.json", "cache.sqlite3"];
+
+/// The directory `auto-install-pandoc` installs into, which is too costly
+/// to download again to be pruned with the rest.
+const KEPT_DIR: &str = "pandoc";
+
+/// What's in the cache root.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub files: usize,
+    pub bytes: u64,
+    /// The files and bytes in each directory directly in the cache root,
+    /// and `.` for the files in it.
+    pub by_dir: Vec<(String, usize, u64)>,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (dir, files, bytes) in &self.by_dir {
+            writeln!(
+                f,
+                "{dir:<16} {files:>6} files {:>12}",
+                size(*bytes as usize)
+            )?;
+        }
+        write!(
+            f,
+            "{:<16} {:>6} files {:>12}",
+            "total",
+            self.files,
+            size(self.bytes as usize)
+        )
+    }
+}
+
+/// What [`clean`] removed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Cleaned {
+    pub files: usize,
+    pub bytes: u64,
+}
+
+struct File {
+    path: PathBuf,
+    bytes: u64,
+    used: SystemTime,
+}
+
+/// Counts what's in the cache root `dir`.
+pub fn stats(dir: &Path) -> Stats {
+    let mut stats = Stats::default();
+    for file in files(dir) {
+        let top = match file.path.strip_prefix(dir).ok().and_then(|p| p.parent()) {
+            Some(parent) if parent != Path::new("") => parent
+                .components()
+                .next()
+                .map_or(".".into(), |c| c.as_os_str().to_string_lossy().into_owned()),
+            _ => ".".to_string(),
+        };
+        match stats.by_dir.iter_mut().find(|(name, _, _)| *name == top) {
+            Some((_, files, bytes)) => {
+                *files += 1;
+                *bytes += file.bytes;
+            }
+            None => stats.by_dir.push((top, 1, file.bytes)),
+        }
+        stats.files += 1;
+        stats.bytes += file.bytes;
+    }
+    stats.by_dir.sort();
+    stats
+}
+
+/// Removes the files in the cache root `dir` last used longer than
+/// `max_age` ago, then the least recently used until the rest fit in
+/// `max_size` bytes. With `all`, removes everything.
+pub fn clean(
+    dir: &Path,
+    max_size: Option<u64>,
+    max_age: Option<Duration>,
+    all: bool,
+) -> Result<Cleaned, Error> {
+    let mut cleaned = Cleaned::default();
+    if all {
+        let stats = stats(dir);
+        match fs::remove_dir_all(dir) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        cleaned.files = stats.files;
+        cleaned.bytes = stats.bytes;
+        return Ok(cleaned);
+    }
+
+    let mut files: Vec<File> = files(dir)
+        .into_iter()
+        .filter(|file| {
+            let name = file.path.file_name().unwrap_or_default().to_string_lossy();
+            !KEPT.iter().any(|kept| name.starts_with(kept))
+                && !file.path.starts_with(dir.join(KEPT_DIR))
+        })
+        .collect();
+    // Least recently used first.
+    files.sort_by_key(|file| file.used);
+    let mut total: u64 = files.iter().map(|file| file.bytes).sum();
+    let now = SystemTime::now();
+    for file in files {
+        let expired = max_age
+            .is_some_and(|max_age| now.duration_since(file.used).unwrap_or_default() > max_age);
+        let oversized = max_size.is_some_and(|max_size| total > max_size);
+        if !expired && !oversized {
+            continue;
+        }
+        fs::remove_file(&file.path)?;
+        total -= file.bytes;
+        cleaned.files += 1;
+        cleaned.bytes += file.bytes;
+    }
+    Ok(cleaned)
+}
+
+/// Every file under `dir`.
+fn files(dir: &Path) -> Vec<File> {
+    let mut files = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        for entry in fs::read_dir(&dir).into_iter().flatten().flatten() {
+            let Ok(metadata) = entry.metadata() else {
+                continue;
+            };
+            if metadata.is_dir() {
+                pending.push(entry.path());
+            } else {
+                files.push(File {
+                    path: entry.path(),
+                    bytes: metadata.len(),
+                    used: metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+                });
+            }
+        }
+    }
+    files
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn removes_the_least_recently_used_first() {
+        let dir = std::env::temp_dir().join(format!("citeproc-gc-{}", std::process::id()));
+        fs::create_dir_all(dir.join("chapters")).unwrap();
+        let write = |name: &str, age: u64| {
+            let path = dir.join(name);
+            fs::write(&path, "0123456789").unwrap();
+            let used = SystemTime::now() - Duration::from_secs(age * 86_400);
+            fs::File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(used)
+                .unwrap();
+        };
+        write("chapters/old.json", 30);
+        write("chapters/older.json", 60);
+        write("chapters/new.json", 0);
+        write("metadata.json", 90);
+        write("links.json", 1);
+        fs::create_dir_all(dir.join("pandoc")).unwrap();
+        write("pandoc/pandoc", 90);
+
+        let stats = stats(&dir);
+        assert_eq!((stats.files, stats.bytes), (6, 60));
+        assert_eq!(
+            stats.by_dir,
+            [
+                (".".to_string(), 2, 20),
+                ("chapters".to_string(), 3, 30),
+                ("pandoc".to_string(), 1, 10)
+            ]
+        );
+
+        let cleaned = clean(&dir, None, Some(Duration::from_secs(45 * 86_400)), false).unwrap();
+        assert_eq!(
+            cleaned,
+            Cleaned {
+                files: 1,
+                bytes: 10
+            }
+        );
+        assert!(dir.join("metadata.json").exists());
+        assert!(dir.join("pandoc/pandoc").exists());
+        let cleaned = clean(&dir, Some(25), None, false).unwrap();
+        assert_eq!(cleaned.files, 1);
+        assert!(!dir.join("chapters/old.json").exists());
+        assert!(dir.join("chapters/new.json").exists());
+
+        clean(&dir, None, None, true).unwrap();
+        assert!(!dir.exists());
+    }
+}
@@ -0,0 +1,185 @@
+//! `mdbook-citeproc graph`: which chapters cite which references, as a
+//! bipartite graph for GraphViz or other tools.
+
+use std::fmt::Write as _;
+use std::path::Path;
+
+use mdbook::errors::Error;
+use mdbook::MDBook;
+use serde_json::{json, Value};
+
+use crate::bibliography::{self, Entry, Library};
+use crate::check;
+use crate::model::BookCitations;
+use crate::quarto;
+use crate::wiki_links;
+
+/// Reads the book in `root` and collects its citations, resolved against
+/// its bibliography.
+///
+/// Chapters are read as they are on disk: citations in `{{#include}}`d
+/// files aren't seen.
+pub fn load(root: &Path) -> Result<BookCitations, Error> {
+    let config = check::load_config(root)?;
+    let mut book = MDBook::load(root)?;
+    if config.wiki_links {
+        wiki_links::normalize_book(&mut book.book);
+    }
+    let mut citations = BookCitations::collect(&book.book);
+    if config.quarto {
+        quarto::skip_crossrefs(&mut citations);
+    }
+    if let Some(bibliography) = &config.bibliography {
+        let entries = bibliography::load(&root.join(&bibliography.bibliography))?;
+        citations.resolve(&Library::new(entries));
+    }
+    Ok(citations)
+}
+
+/// Each chapter and key with how often the chapter cites it, in order of
+/// first citation.
+fn edges(citations: &BookCitations) -> Vec<(usize, String, usize)> {
+    let mut edges: Vec<(usize, String, usize)> = Vec::new();
+    for (chapter, chapter_citations) in citations.chapters.iter().enumerate() {
+        for key in chapter_citations.keys() {
+            match edges
+                .iter_mut()
+                .find(|(c, k, _)| *c == chapter && *k == key)
+            {
+                Some((_, _, count)) => *count += 1,
+                None => edges.push((chapter, key, 1)),
+            }
+        }
+    }
+    edges
+}
+
+/// The graph in GraphViz's DOT language. References without a
+/// bibliography entry are drawn dashed.
+pub fn dot(citations: &BookCitations) -> String {
+    let mut out = String::from("graph citations {\n  rankdir=LR;\n");
+    for (i, chapter) in citations.chapters.iter().enumerate() {
+        let _ = writeln!(
+            out,
+            "  chapter{i} [label=\"{}\", shape=box];",
+            escape(&chapter.name)
+        );
+    }
+    for key in citations.keys() {
+        let (label, style) = match citations.entries.get(&key) {
+            Some(entry) => match title(entry) {
+                Some(title) => (format!("{}\\n{}", escape(&key), escape(&title)), "solid"),
+                None => (escape(&key), "solid"),
+            },
+            None => (escape(&key), "dashed"),
+        };
+        let _ = writeln!(
+            out,
+            "  \"ref:{}\" [label=\"{label}\", shape=ellipse, style={style}];",
+            escape(&key)
+        );
+    }
+    for (chapter, key, count) in edges(citations) {
+        let _ = writeln!(
+            out,
+            "  chapter{chapter} -- \"ref:{}\" [weight={count}, label=\"{count}\"];",
+            escape(&key)
+        );
+    }
+    out += "}\n";
+    out
+}
+
+/// The graph as JSON: `chapters` and `references` are the nodes, `edges`
+/// connect them by chapter index and key.
+pub fn json(citations: &BookCitations) -> Value {
+    let edges = edges(citations);
+    let chapters: Vec<Value> = citations
+        .chapters
+        .iter()
+        .enumerate()
+        .map(|(i, chapter)| {
+            json!({
+                "index": i,
+                "name": chapter.name,
+                "path": chapter.path,
+                "citations": edges.iter().filter(|e| e.0 == i).map(|e| e.2).sum::<usize>(),
+            })
+        })
+        .collect();
+    let references: Vec<Value> = citations
+        .keys()
+        .into_iter()
+        .map(|key| {
+            let entry = citations.entries.get(&key);
+            json!({
+                "key": key,
+                "resolved": entry.is_some(),
+                "kind": entry.map(|e| &e.kind),
+                "title": entry.and_then(title),
+                "chapters": edges.iter().filter(|e| e.1 == key).count(),
+            })
+        })
+        .collect();
+    let edges: Vec<Value> = edges
+        .into_iter()
+        .map(|(chapter, key, count)| json!({"chapter": chapter, "key": key, "count": count}))
+        .collect();
+    json!({"chapters": chapters, "references": references, "edges": edges})
+}
+
+fn title(entry: &Entry) -> Option<String> {
+    entry.csl_json()["title"].as_str().map(str::to_string)
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use mdbook::book::{Book, Chapter};
+
+    use super::*;
+
+    fn citations() -> BookCitations {
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "Intro",
+            "[@smith] and @smith".into(),
+            "intro.md",
+            Vec::new(),
+        ));
+        book.push_item(Chapter::new("Empty", String::new(), "empty.md", Vec::new()));
+        book.push_item(Chapter::new(
+            "The \"end\"",
+            "[@jones]".into(),
+            "end.md",
+            Vec::new(),
+        ));
+        BookCitations::collect(&book)
+    }
+
+    #[test]
+    fn writes_dot() {
+        let dot = dot(&citations());
+        assert!(
+            dot.contains("chapter2 [label=\"The \\\"end\\\"\", shape=box];"),
+            "{dot}"
+        );
+        assert!(dot.contains("\"ref:smith\" [label=\"smith\", shape=ellipse, style=dashed];"));
+        assert!(dot.contains("chapter0 -- \"ref:smith\" [weight=2, label=\"2\"];"));
+    }
+
+    #[test]
+    fn writes_json() {
+        let json = json(&citations());
+        assert_eq!(json["chapters"][0]["citations"], 2);
+        assert_eq!(json["chapters"][1]["citations"], 0);
+        assert_eq!(json["references"][1]["key"], "jones");
+        assert_eq!(
+            json["edges"][1],
+            json!({"chapter": 2, "key": "jones", "count": 1})
+        );
+    }
+}
@@ -0,0 +1,395 @@
+//! The single place network requests are made from, so every feature which
+//! touches the network behaves the same way.
+//!
+//! Requests go through the proxy named by the usual environment variables
+//! and trust the `ca-bundle` as well as the built-in roots. Transient
+//! failures are retried with exponential backoff, and requests to each host
+//! can be rate limited. In offline mode every request fails, except to the
+//! local machine (e.g. a `pandoc-server` on localhost), and callers fall
+//! back on what they've cached.
+
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use mdbook::errors::Error;
+use serde_json::Value;
+
+use crate::config::Config;
+use crate::error::CiteprocError;
+
+/// The longest a server's `Retry-After` is waited for.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+/// How failed requests are retried and how often each host is asked.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    /// Further attempts after a transient failure: a connection error or a
+    /// 429, 500, 502, 503 or 504 response.
+    pub retries: u32,
+    /// The wait before the first retry, doubled for each one after, unless
+    /// the server says how long to wait.
+    pub backoff: Duration,
+    /// At most this many requests per second to any one host.
+    pub requests_per_second: Option<f64>,
+}
+
+impl RetryPolicy {
+    pub const DEFAULT: Self = Self {
+        retries: 2,
+        backoff: Duration::from_millis(500),
+        requests_per_second: None,
+    };
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// Offline for `--offline`, whatever the book says.
+static FORCED_OFFLINE: AtomicBool = AtomicBool::new(false);
+/// Offline for the book being built's `offline` option.
+static OFFLINE: AtomicBool = AtomicBool::new(false);
+static TLS: Mutex<Option<Arc<rustls::ClientConfig>>> = Mutex::new(None);
+static POLICY: Mutex<RetryPolicy> = Mutex::new(RetryPolicy::DEFAULT);
+/// When the latest request to each host is (or was) sent.
+static LAST_REQUEST: Mutex<BTreeMap<String, Instant>> = Mutex::new(BTreeMap::new());
+
+/// Applies the network options in `config`, replacing those of any book
+/// built before it in this process.
+pub fn configure_network(config: &Config) -> Result<(), Error> {
+    OFFLINE.store(config.offline, Ordering::Relaxed);
+    let tls = match &config.ca_bundle {
+        Some(ca_bundle) => Some(tls_with(ca_bundle)?),
+        None => None,
+    };
+    *TLS.lock().expect("TLS config poisoned") = tls;
+    *POLICY.lock().expect("retry policy poisoned") = config.retry_policy;
+    Ok(())
+}
+
+/// Turns offline mode on for every book, for `--offline`.
+pub fn set_offline(offline: bool) {
+    FORCED_OFFLINE.store(offline, Ordering::Relaxed);
+}
+
+pub fn is_offline() -> bool {
+    FORCED_OFFLINE.load(Ordering::Relaxed) || OFFLINE.load(Ordering::Relaxed)
+}
+
+/// A TLS config trusting the PEM certificates in `path` as well as the
+/// built-in roots, for the `ca-bundle` option.
+fn tls_with(path: &Path) -> Result<Arc<rustls::ClientConfig>, Error> {
+    use rustls::pki_types::pem::PemObject;
+    use rustls::pki_types::CertificateDer;
+
+    let invalid = |e: &dyn std::fmt::Display| {
+        CiteprocError::config(format!("ca-bundle {}: {e}", path.display()))
+    };
+    let mut roots = rustls::RootCertStore::empty();
+    roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    let mut added = 0;
+    for cert in CertificateDer::pem_file_iter(path).map_err(|e| invalid(&e))? {
+        roots
+            .add(cert.map_err(|e| invalid(&e))?)
+            .map_err(|e| invalid(&e))?;
+        added += 1;
+    }
+    if added == 0 {
+        return Err(invalid(&"contains no certificates"));
+    }
+    let tls = rustls::ClientConfig::builder_with_provider(
+        rustls::crypto::ring::default_provider().into(),
+    )
+    .with_safe_default_protocol_versions()
+    .map_err(|e| invalid(&e))?
+    .with_root_certificates(roots)
+    .with_no_client_auth();
+    Ok(Arc::new(tls))
+}
+
+/// The agent to request `url` with: through a proxy if the environment
+/// asks for one, and trusting any `ca-bundle`.
+fn agent(url: &str) -> Result<ureq::Agent, Error> {
+    if !reachable(url, is_offline()) {
+        return Err(Error::msg(format!(
+            "{url} isn't available offline; build once without offline mode to cache it"
+        )));
+    }
+    let mut agent = ureq::AgentBuilder::new().try_proxy_from_env(false);
+    if let Some(proxy) = proxy_for(url, |name| std::env::var(name).ok()) {
+        let proxy = ureq::Proxy::new(&proxy)
+            .map_err(|e| Error::msg(format!("invalid proxy {proxy}: {e}")))?;
+        agent = agent.proxy(proxy);
+    }
+    if let Some(tls) = TLS.lock().expect("TLS config poisoned").clone() {
+        agent = agent.tls_config(tls);
+    }
+    Ok(agent.build())
+}
+
+/// Whether `url` can be requested, `offline` or not: offline, only the
+/// local machine can.
+fn reachable(url: &str, offline: bool) -> bool {
+    !offline || matches!(host(url), "localhost" | "127.0.0.1" | "::1")
+}
+
+/// The proxy for `url` according to the `HTTPS_PROXY`, `HTTP_PROXY`,
+/// `ALL_PROXY` and `NO_PROXY` variables (or their lowercase versions).
+fn proxy_for(url: &str, var: impl Fn(&str) -> Option<String>) -> Option<String> {
+    let var = |name: &str| {
+        var(name)
+            .or_else(|| var(&name.to_lowercase()))
+            .filter(|value| !value.is_empty())
+    };
+    let host = host(url);
+    if let Some(no_proxy) = var("NO_PROXY") {
+        let bypassed = no_proxy.split(',').map(str::trim).any(|pattern| {
+            let pattern = pattern.split(':').next().unwrap_or_default();
+            let domain = pattern.trim_start_matches("*.").trim_start_matches('.');
+            pattern == "*"
+                || (!domain.is_empty() && (host == domain || host.ends_with(&format!(".{domain}"))))
+        });
+        if bypassed {
+            return None;
+        }
+    }
+    match url.split_once("://").map(|(scheme, _)| scheme) {
+        Some("https") => var("HTTPS_PROXY"),
+        Some("http") => var("HTTP_PROXY"),
+        _ => None,
+    }
+    .or_else(|| var("ALL_PROXY"))
+}
+
+/// Sends the request `build` makes with `agent` (with `body`, if any),
+/// retrying transient failures according to the retry policy.
+fn send(
+    agent: &ureq::Agent,
+    url: &str,
+    body: Option<&str>,
+    build: impl Fn(&ureq::Agent) -> ureq::Request,
+) -> Result<ureq::Response, Box<ureq::Error>> {
+    let policy = *POLICY.lock().expect("retry policy poisoned");
+    let mut attempt = 0;
+    loop {
+        throttle(url, policy.requests_per_second);
+        let request = build(agent);
+        let result = match body {
+            Some(body) => request.send_string(body),
+            None => request.call(),
+        };
+        let retry = match &result {
+            Err(ureq::Error::Status(429 | 500 | 502 | 503 | 504, response)) => {
+                Some(retry_after(response))
+            }
+            Err(ureq::Error::Transport(_)) => Some(None),
+            _ => None,
+        };
+        match retry {
+            Some(wait) if attempt < policy.retries => {
+                let backoff = policy.backoff.saturating_mul(2u32.saturating_pow(attempt));
+                thread::sleep(wait.unwrap_or(backoff));
+                attempt += 1;
+            }
+            _ => return result.map_err(Box::new),
+        }
+    }
+}
+
+/// How long a `Retry-After` header (in seconds) asks to wait.
+fn retry_after(response: &ureq::Response) -> Option<Duration> {
+    let seconds = response.header("Retry-After")?.trim().parse().ok()?;
+    Some(Duration::from_secs(seconds).min(MAX_RETRY_AFTER))
+}
+
+/// Waits until a request to `url`'s host is within `requests_per_second`.
+fn throttle(url: &str, requests_per_second: Option<f64>) {
+    let Some(rate) = requests_per_second else {
+        return;
+    };
+    let interval = Duration::from_secs_f64(1.0 / rate);
+    let wait = {
+        let mut last = LAST_REQUEST.lock().expect("rate limits poisoned");
+        let now = Instant::now();
+        let slot = last
+            .get(host(url))
+            .map_or(now, |last| (*last + interval).max(now));
+        last.insert(host(url).to_string(), slot);
+        slot - now
+    };
+    thread::sleep(wait);
+}
+
+/// The host `url` points at, without a port or IPv6 brackets.
+fn host(url: &str) -> &str {
+    let authority = url
+        .split_once("://")
+        .map_or(url, |(_, rest)| rest)
+        .split(['/', '?', '#'])
+        .next()
+        .unwrap_or_default();
+    let host = authority
+        .rsplit_once('@')
+        .map_or(authority, |(_, host)| host);
+    match host.strip_prefix('[') {
+        Some(ipv6) => ipv6.split(']').next().unwrap_or_default(),
+        None => host.split(':').next().unwrap_or_default(),
+    }
+}
+
+/// Fetches `url` and returns the response body.
+pub fn get(url: &str) -> Result<Vec<u8>, Error> {
+    let response = send(&agent(url)?, url, None, |agent| agent.get(url))
+        .map_err(|e| Error::msg(format!("failed to fetch {e}")))?;
+    let mut body = Vec::new();
+    response
+        .into_reader()
+        .read_to_end(&mut body)
+        .map_err(|e| Error::msg(format!("failed to read {url}: {e}")))?;
+    Ok(body)
+}
+
+/// One page of a paginated API's answer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Page {
+    pub body: Vec<u8>,
+    /// The next page, from the `Link` header.
+    pub next: Option<String>,
+}
+
+/// Fetches `url` as `accept`, authorized with the bearer `token`.
+pub fn get_page(url: &str, token: &str, accept: &str) -> Result<Page, Error> {
+    let authorization = format!("Bearer {token}");
+    let response = send(&agent(url)?, url, None, |agent| {
+        agent
+            .get(url)
+            .set("Accept", accept)
+            .set("Authorization", &authorization)
+    })
+    .map_err(|e| Error::msg(format!("failed to fetch {e}")))?;
+    let next = response.header("Link").and_then(next_link);
+    let mut body = Vec::new();
+    response
+        .into_reader()
+        .read_to_end(&mut body)
+        .map_err(|e| Error::msg(format!("failed to read {url}: {e}")))?;
+    Ok(Page { body, next })
+}
+
+/// The `rel="next"` URL in a `Link` header.
+fn next_link(header: &str) -> Option<String> {
+    header.split(',').find_map(|link| {
+        let (url, params) = link.split_once(';')?;
+        params
+            .split(';')
+            .any(|param| matches!(param.trim(), "rel=\"next\"" | "rel=next"))
+            .then(|| {
+                url.trim()
+                    .trim_start_matches('<')
+                    .trim_end_matches('>')
+                    .to_string()
+            })
+    })
+}
+
+/// Posts `body` as JSON to `url` and returns the decoded JSON response.
+pub fn post_json(url: &str, body: &Value) -> Result<Value, Error> {
+    let body = body.to_string();
+    let response = send(&agent(url)?, url, Some(&body), |agent| {
+        agent
+            .post(url)
+            .set("Accept", "application/json")
+            .set("Content-Type", "application/json")
+    })
+    .map_err(|e| Error::msg(format!("failed to post {e}")))?;
+    serde_json::from_reader(response.into_reader())
+        .map_err(|e| Error::msg(format!("invalid JSON response from {url}: {e}")))
+}
+
+/// Requests `url` with `method` (e.g. `HEAD`), following redirects, and
+/// returns the final status code, whether or not it's a success.
+pub fn status(method: &str, url: &str) -> Result<u16, Error> {
+    let response = send(&agent(url)?, url, None, |agent| {
+        agent.request(method, url).timeout(Duration::from_secs(30))
+    });
+    match response.map_err(|e| *e) {
+        Ok(response) => Ok(response.status()),
+        Err(ureq::Error::Status(status, _)) => Ok(status),
+        Err(e) => Err(Error::msg(format!("failed to reach {e}"))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn offline_mode_only_allows_the_local_machine() {
+        let allowed = |url| reachable(url, true);
+        assert!(allowed("http://localhost:3030/"));
+        assert!(allowed("http://[::1]:3030"));
+        assert!(!allowed("https://doi.org/10.1/x"));
+        assert!(!allowed("https://localhost.example.com/"));
+        assert!(reachable("https://doi.org/10.1/x", false));
+    }
+
+    #[test]
+    fn rate_limits_each_host() {
+        let start = Instant::now();
+        for _ in 0..3 {
+            throttle("https://limited.example.com/x", Some(20.0));
+        }
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        let other = Instant::now();
+        throttle("https://other.example.com/", Some(20.0));
+        assert!(other.elapsed() < Duration::from_millis(50));
+    }
+
+    #[test]
+    fn follows_next_links() {
+        let header = "<https://api.example.com/d?page=1>; rel=\"first\", \
+                      <https://api.example.com/d?page=3>; rel=\"next\"";
+        assert_eq!(
+            next_link(header).as_deref(),
+            Some("https://api.example.com/d?page=3")
+        );
+        assert_eq!(next_link("<https://api.example.com/d>; rel=\"last\""), None);
+    }
+
+    #[test]
+    fn picks_proxies_from_the_environment() {
+        let env = |vars: &'static [(&'static str, &'static str)]| {
+            move |name: &str| {
+                vars.iter()
+                    .find(|(var, _)| *var == name)
+                    .map(|(_, value)| value.to_string())
+            }
+        };
+        let proxied = env(&[
+            ("https_proxy", "http://proxy:3128"),
+            ("NO_PROXY", "internal.example.com, .corp:443"),
+        ]);
+        assert_eq!(
+            proxy_for("https://doi.org/10.1/x", proxied).as_deref(),
+            Some("http://proxy:3128")
+        );
+        assert_eq!(proxy_for("http://doi.org/10.1/x", proxied), None);
+        assert_eq!(proxy_for("https://internal.example.com/", proxied), None);
+        assert_eq!(proxy_for("https://git.corp/x", proxied), None);
+        assert_eq!(
+            proxy_for(
+                "https://user@[::1]:8080/",
+                env(&[("ALL_PROXY", "socks5://s:1080")])
+            )
+            .as_deref(),
+            Some("socks5://s:1080")
+        );
+    }
+}
@@ -0,0 +1,233 @@
+//! Opt-in download of a pinned pandoc release, for CI machines which don't
+//! have pandoc installed.
+//!
+//! The archive must match `pandoc-sha256`, and what it held is recorded
+//! beside the binary: the installed pandoc is checked against that before
+//! it's first run each time, and installed again if `pandoc-sha256` has
+//! changed since.
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::{self, Cursor, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use mdbook::errors::Error;
+
+use crate::cache::sha256_hex;
+use crate::error::CiteprocError;
+use crate::http;
+
+/// The pandoc release downloaded by `auto-install-pandoc`.
+pub const PANDOC_VERSION: &str = "3.5";
+
+/// The release asset for the host platform, if pandoc publishes one.
+fn asset_name() -> Option<String> {
+    let asset = match (std::env::consts::OS, std::env::consts::ARCH) {
+        ("linux", "x86_64") => "linux-amd64.tar.gz",
+        ("linux", "aarch64") => "linux-arm64.tar.gz",
+        ("macos", "x86_64") => "x86_64-macOS.zip",
+        ("macos", "aarch64") => "arm64-macOS.zip",
+        ("windows", "x86_64") => "windows-x86_64.zip",
+        _ => return None,
+    };
+    Some(format!("pandoc-{PANDOC_VERSION}-{asset}"))
+}
+
+fn binary_name() -> &'static str {
+    if cfg!(windows) {
+        "pandoc.exe"
+    } else {
+        "pandoc"
+    }
+}
+
+/// Where [`ensure_pandoc`] installs the pinned pandoc in `dir`.
+pub fn installed_path(dir: &Path) -> PathBuf {
+    dir.join(PANDOC_VERSION).join(binary_name())
+}
+
+/// The binaries checked against their record already, so each is only
+/// hashed once per process.
+static VERIFIED: Mutex<BTreeSet<PathBuf>> = Mutex::new(BTreeSet::new());
+
+/// What was installed in `install_dir`: the archive's hash and the
+/// binary's.
+fn record_path(install_dir: &Path) -> PathBuf {
+    install_dir.join("pandoc.sha256")
+}
+
+fn read_record(install_dir: &Path) -> Option<(String, String)> {
+    let record = fs::read_to_string(record_path(install_dir)).ok()?;
+    let mut archive = None;
+    let mut binary = None;
+    for line in record.lines() {
+        match line.split_once(' ') {
+            Some(("archive", hash)) => archive = Some(hash.to_string()),
+            Some(("binary", hash)) => binary = Some(hash.to_string()),
+            _ => {}
+        }
+    }
+    Some((archive?, binary?))
+}
+
+/// Checks the installed `binary` against what was recorded when it was
+/// installed from an archive with hash `sha256`. Returns whether it's to
+/// be installed again, for an install from another archive (or from
+/// before installs were recorded).
+fn verify_installed(
+    install_dir: &Path,
+    binary: &Path,
+    sha256: Option<&str>,
+) -> Result<bool, Error> {
+    let Some((archive, recorded)) = read_record(install_dir) else {
+        return Ok(true);
+    };
+    if !sha256.is_some_and(|sha256| sha256.eq_ignore_ascii_case(&archive)) {
+        return Ok(true);
+    }
+    let mut verified = VERIFIED.lock().expect("verified binaries poisoned");
+    if verified.contains(binary) {
+        return Ok(false);
+    }
+    let actual = sha256_hex(fs::read(binary)?);
+    if actual != recorded {
+        return Err(CiteprocError::pandoc_missing(format!(
+            "auto-install-pandoc: {} has changed since it was installed (SHA-256 {actual}, \
+             installed as {recorded}); remove {} to install it again",
+            binary.display(),
+            install_dir.display()
+        )));
+    }
+    verified.insert(binary.to_path_buf());
+    Ok(false)
+}
+
+/// Returns the path of the pinned pandoc in `dir`, downloading it first if
+/// it isn't there yet.
+///
+/// The archive must match `sha256`. There is deliberately no built-in
+/// default: on a mismatch (or if no hash is configured) the error reports
+/// the hash of what was downloaded, so pinning it is an explicit decision.
+pub fn ensure_pandoc(dir: &Path, sha256: Option<&str>) -> Result<PathBuf, Error> {
+    let binary = installed_path(dir);
+    let install_dir = dir.join(PANDOC_VERSION);
+    if binary.is_file() {
+        if !verify_installed(&install_dir, &binary, sha256)? {
+            return Ok(binary);
+        }
+        fs::remove_dir_all(&install_dir)?;
+    }
+
+    let asset = asset_name().ok_or_else(|| {
+        CiteprocError::pandoc_missing(format!(
+            "auto-install-pandoc: pandoc {PANDOC_VERSION} has no release for {}-{}",
+            std::env::consts::OS,
+            std::env::consts::ARCH
+        ))
+    })?;
+    let url = format!("https://github.com/jgm/pandoc/releases/download/{PANDOC_VERSION}/{asset}");
+    eprintln!("Downloading pandoc {PANDOC_VERSION} from {url}");
+    let archive = http::get(&url).map_err(|e| CiteprocError::pandoc_missing(e.to_string()))?;
+
+    let actual = sha256_hex(&archive);
+    match sha256 {
+        Some(expected) if expected.eq_ignore_ascii_case(&actual) => {}
+        Some(expected) => {
+            return Err(CiteprocError::pandoc_missing(format!(
+            "auto-install-pandoc: checksum mismatch for {asset}: expected {expected}, got {actual}"
+        )))
+        }
+        None => {
+            return Err(CiteprocError::config(format!(
+                "auto-install-pandoc requires pandoc-sha256 to be set; \
+                 the downloaded {asset} has SHA-256 {actual}"
+            )))
+        }
+    }
+
+    let contents = if asset.ends_with(".zip") {
+        extract_from_zip(&archive)?
+    } else {
+        extract_from_tar_gz(&archive)?
+    }
+    .ok_or_else(|| {
+        CiteprocError::pandoc_missing(format!("{asset} does not contain a pandoc binary"))
+    })?;
+
+    // Write under a temporary name first so an interrupted download never
+    // leaves a truncated binary behind.
+    fs::create_dir_all(&install_dir)?;
+    let partial = install_dir.join(format!("{}.partial", binary_name()));
+    fs::write(
+        record_path(&install_dir),
+        format!("archive {actual}\nbinary {}\n", sha256_hex(&contents)),
+    )?;
+    fs::write(&partial, contents)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&partial, fs::Permissions::from_mode(0o755))?;
+    }
+    fs::rename(&partial, &binary)?;
+    Ok(binary)
+}
+
+fn is_binary(path: &Path) -> bool {
+    path.file_name().and_then(|n| n.to_str()) == Some(binary_name())
+}
+
+fn extract_from_tar_gz(archive: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+    let mut tar = tar::Archive::new(flate2::read::GzDecoder::new(archive));
+    for entry in tar.entries()? {
+        let mut entry = entry?;
+        if is_binary(&entry.path()?) {
+            let mut contents = Vec::new();
+            entry.read_to_end(&mut contents)?;
+            return Ok(Some(contents));
+        }
+    }
+    Ok(None)
+}
+
+fn extract_from_zip(archive: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+    let mut zip = zip::ZipArchive::new(Cursor::new(archive))?;
+    for i in 0..zip.len() {
+        let mut file = zip.by_index(i)?;
+        if file.enclosed_name().is_some_and(|path| is_binary(&path)) {
+            let mut contents = Vec::new();
+            io::copy(&mut file, &mut contents)?;
+            return Ok(Some(contents));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn installed_pandoc_is_checked_against_its_record() {
+        let dir = std::env::temp_dir().join(format!("citeproc-install-{}", std::process::id()));
+        let install_dir = dir.join(PANDOC_VERSION);
+        fs::create_dir_all(&install_dir).unwrap();
+        let binary = installed_path(&dir);
+        fs::write(&binary, "pandoc").unwrap();
+        assert!(verify_installed(&install_dir, &binary, Some("abc")).unwrap());
+
+        let record = format!("archive abc\nbinary {}\n", sha256_hex("pandoc"));
+        fs::write(record_path(&install_dir), record).unwrap();
+        assert!(!verify_installed(&install_dir, &binary, Some("ABC")).unwrap());
+        assert!(verify_installed(&install_dir, &binary, Some("def")).unwrap());
+
+        VERIFIED.lock().unwrap().clear();
+        fs::write(&binary, "tampered").unwrap();
+        let e = verify_installed(&install_dir, &binary, Some("abc")).unwrap_err();
+        assert!(
+            e.to_string().contains("has changed since it was installed"),
+            "{e}"
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,99 @@
+//! LaTeX passthrough: rewrites pandoc citations as biblatex or natbib
+//! commands instead of resolving them, for PDF toolchains which build the
+//! bibliography themselves.
+
+use crate::citation::{self, Citation, Item, Mode};
+use crate::config::LatexPackage;
+
+/// Replaces every citation in `content` with the equivalent command.
+pub fn convert(content: &str, package: LatexPackage) -> String {
+    let mut out = String::with_capacity(content.len());
+    let mut last = 0;
+    for citation in citation::parse(content) {
+        out.push_str(&content[last..citation.span.start]);
+        out.push_str(&command(&citation, package));
+        last = citation.span.end;
+    }
+    out.push_str(&content[last..]);
+    out
+}
+
+fn command(citation: &Citation, package: LatexPackage) -> String {
+    let suppress_author = citation.items.iter().all(|item| item.suppress_author);
+    match package {
+        LatexPackage::Biblatex => {
+            let name = match (citation.mode, suppress_author) {
+                (Mode::InText, _) => "textcite",
+                (Mode::Bracketed, true) => "autocite*",
+                (Mode::Bracketed, false) => "autocite",
+            };
+            match citation.items.as_slice() {
+                [item] => format!("\\{name}{}{{{}}}", notes(item), item.key),
+                items => {
+                    // `\autocites` takes a pre- and postnote for every key.
+                    let name = name.replacen("cite", "cites", 1);
+                    let mut command = format!("\\{name}");
+                    for item in items {
+                        command += &format!("{}{{{}}}", notes(item), item.key);
+                    }
+                    command
+                }
+            }
+        }
+        LatexPackage::Natbib => {
+            let name = match (citation.mode, suppress_author) {
+                (Mode::InText, _) => "citet",
+                (Mode::Bracketed, true) => "citeyearpar",
+                (Mode::Bracketed, false) => "citep",
+            };
+            // natbib only has one pre- and postnote per command, so use
+            // the first item's prefix and the last item's suffix.
+            let keys: Vec<&str> = citation.items.iter().map(|i| i.key.as_str()).collect();
+            let prefix = citation.items.first().map_or("", |i| i.prefix.as_str());
+            let suffix = citation.items.last().map_or("", |i| i.suffix.as_str());
+            let notes = notes(&Item {
+                key: String::new(),
+                prefix: prefix.to_string(),
+                suffix: suffix.to_string(),
+                suppress_author,
+            });
+            format!("\\{name}{notes}{{{}}}", keys.join(","))
+        }
+    }
+}
+
+/// The optional `[prenote][postnote]` arguments for `item`.
+fn notes(item: &Item) -> String {
+    match (item.prefix.as_str(), item.suffix.as_str()) {
+        ("", "") => String::new(),
+        ("", suffix) => format!("[{suffix}]"),
+        (prefix, suffix) => format!("[{prefix}][{suffix}]"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn biblatex_commands() {
+        assert_eq!(
+            convert("@smith says [see @jones, p. 3].", LatexPackage::Biblatex),
+            "\\textcite{smith} says \\autocite[see][p. 3]{jones}."
+        );
+        assert_eq!(
+            convert("[@a; -@b, ch. 2]", LatexPackage::Biblatex),
+            "\\autocites{a}[ch. 2]{b}"
+        );
+        assert_eq!(convert("[-@a]", LatexPackage::Biblatex), "\\autocite*{a}");
+    }
+
+    #[test]
+    fn natbib_commands() {
+        assert_eq!(
+            convert("@smith [p. 1] and [see @a; @b, p. 3]", LatexPackage::Natbib),
+            "\\citet[p. 1]{smith} and \\citep[see][p. 3]{a,b}"
+        );
+        assert_eq!(convert("[-@a]", LatexPackage::Natbib), "\\citeyearpar{a}");
+    }
+}
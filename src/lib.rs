@@ -0,0 +1,161 @@
+//! An mdbook preprocessor which runs chapters through pandoc and citeproc.
+
+use std::io::Write;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use mdbook::errors::Error;
+use mdbook::preprocess::{CmdPreprocessor, Preprocessor};
+use mdbook::MDBook;
+use semver::{Version, VersionReq};
+
+use crate::profile::Profile;
+
+mod admonish;
+mod archive;
+pub mod args;
+mod audit;
+pub mod backend;
+mod badge;
+pub mod bibliography;
+mod bookdown;
+mod cache;
+mod casing;
+pub mod check;
+pub mod citation;
+mod coins;
+pub mod compat;
+pub mod config;
+#[cfg(unix)]
+pub mod daemon;
+pub mod deprecated;
+pub mod diagnostics;
+mod directives;
+mod epub;
+pub mod error;
+pub mod explain;
+mod extensions;
+mod feed;
+mod fields;
+mod filter;
+mod front_matter;
+pub mod gc;
+pub mod graph;
+mod http;
+mod install;
+mod latex;
+mod library;
+pub mod links;
+pub mod lint;
+pub mod lint_config;
+mod lock;
+mod manifest;
+pub mod migrate;
+pub mod model;
+mod orcid;
+mod ordering;
+mod pandoc_defaults;
+mod positions;
+mod preprocessor;
+pub mod profile;
+mod progress;
+mod quarto;
+mod raw_html;
+pub mod refresh;
+mod refs;
+mod remote_bibliography;
+mod restyle;
+mod source_map;
+mod structured_data;
+mod style;
+mod subprocess;
+mod tables;
+mod targets;
+mod template;
+#[cfg(feature = "test-utils")]
+pub mod testing;
+mod urls;
+pub mod vendor;
+#[cfg(feature = "wasm")]
+mod wasm;
+mod wiki_links;
+
+pub use crate::http::{configure_network, set_offline};
+pub use crate::lock::set_locked;
+pub use crate::preprocessor::Pandoc;
+
+/// Runs `pre` over the raw preprocessor `input` mdbook handed us and returns
+/// the serialized result.
+pub fn process_input(pre: &dyn Preprocessor, input: &[u8]) -> Result<String, Error> {
+    let mut output = Vec::new();
+    process(pre, input, &mut output, &mut Duration::default())?;
+    Ok(String::from_utf8(output).expect("serde_json writes UTF-8"))
+}
+
+/// Like [`process_input`], but writes the result to `output` as it's
+/// serialized, so a big book isn't held in memory a second time as JSON.
+pub fn process_input_to(
+    pre: &dyn Preprocessor,
+    input: &[u8],
+    output: impl Write,
+) -> Result<(), Error> {
+    process(pre, input, output, &mut Duration::default())
+}
+
+/// Like [`process_input_to`], but also returns where the time went. `pre`
+/// must have been built with [`Pandoc::profile`].
+pub fn profile_input(pre: &Pandoc, input: &[u8], output: impl Write) -> Result<Profile, Error> {
+    let mut serialization = Duration::ZERO;
+    process(pre, input, output, &mut serialization)?;
+    let mut profile = pre.take_profile().unwrap_or_default();
+    profile.serialization = serialization;
+    Ok(profile)
+}
+
+/// What mdbook would send us for building the book in `root` for
+/// `renderer`, with the chapter cache off so every chapter is converted.
+fn uncached_input(root: &Path, renderer: &str) -> Result<Vec<u8>, Error> {
+    let mut book = MDBook::load(root)?;
+    book.config
+        .set("preprocessor.citeproc.cache", toml::Value::Boolean(false))?;
+    Ok(serde_json::to_vec(&serde_json::json!([
+        {
+            "root": root,
+            "config": book.config,
+            "renderer": renderer,
+            "mdbook_version": mdbook::MDBOOK_VERSION,
+        },
+        book.book,
+    ]))?)
+}
+
+fn process(
+    pre: &dyn Preprocessor,
+    input: &[u8],
+    mut output: impl Write,
+    serialization: &mut Duration,
+) -> Result<(), Error> {
+    let start = Instant::now();
+    let (ctx, book) = CmdPreprocessor::parse_input(input)?;
+    *serialization += start.elapsed();
+
+    let book_version = Version::parse(&ctx.mdbook_version)?;
+    let version_req = VersionReq::parse(mdbook::MDBOOK_VERSION)?;
+
+    if !version_req.matches(&book_version) {
+        eprintln!(
+            "Warning: The {} plugin was built against version {} of mdbook, \
+             but we're being called from version {}",
+            pre.name(),
+            mdbook::MDBOOK_VERSION,
+            ctx.mdbook_version
+        );
+    }
+
+    let processed_book = pre.run(&ctx, book)?;
+    let start = Instant::now();
+    serde_json::to_writer(&mut output, &processed_book)?;
+    output.flush()?;
+    *serialization += start.elapsed();
+    Ok(())
+}
@@ -0,0 +1,206 @@
+//! A reference manager's library read as a bibliography, with `library`
+//! set to its root directory:
+//!
+//! - papis keeps each document in a directory of its own, described by an
+//!   `info.yaml`;
+//! - pubs keeps a BibTeX file per entry in its `bib` directory;
+//! - JabRef keeps BibTeX files, read as they are.
+//!
+//! Which one it is is told from the directory's layout unless
+//! `library-format` says. The library is exported to one BibTeX file in the
+//! cache, which is used like any other bibliography: as the book's if it
+//! has no `bibliography`, or alongside it.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use mdbook::errors::Error;
+
+use crate::cache;
+use crate::error::{CiteprocError, ErrorKind};
+use crate::front_matter;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LibraryFormat {
+    Papis,
+    Pubs,
+    JabRef,
+}
+
+/// papis' own keys in `info.yaml`, which aren't BibTeX fields.
+const PAPIS_INTERNAL: &[&str] = &[
+    "author_list",
+    "files",
+    "notes",
+    "papis_id",
+    "ref",
+    "time-added",
+    "type",
+];
+
+impl LibraryFormat {
+    fn parse(format: &str) -> Result<Self, Error> {
+        match format {
+            "papis" => Ok(Self::Papis),
+            "pubs" => Ok(Self::Pubs),
+            "jabref" => Ok(Self::JabRef),
+            _ => Err(CiteprocError::config(
+                "library-format must be \"papis\", \"pubs\" or \"jabref\"",
+            )),
+        }
+    }
+
+    /// The format of the library in `dir`, from its layout.
+    fn detect(dir: &Path) -> Option<Self> {
+        if dir.join("bib").is_dir() && dir.join("meta").is_dir() {
+            return Some(Self::Pubs);
+        }
+        if papis_documents(dir).next().is_some() {
+            return Some(Self::Papis);
+        }
+        (!files_with_extension(dir, "bib").is_empty()).then_some(Self::JabRef)
+    }
+}
+
+/// Exports the library at `path` (relative to `root`) to a BibTeX file
+/// under `cache_root`, returning where it is.
+pub fn export(
+    root: &Path,
+    path: &str,
+    format: Option<&str>,
+    cache_root: &Path,
+) -> Result<PathBuf, Error> {
+    let dir = root.join(path);
+    if !dir.is_dir() {
+        return Err(CiteprocError::config(format!(
+            "library {path} isn't a directory"
+        )));
+    }
+    let format = match format {
+        Some(format) => LibraryFormat::parse(format)?,
+        None => LibraryFormat::detect(&dir).ok_or_else(|| {
+            CiteprocError::config(format!(
+                "library {path} doesn't look like a papis, pubs or JabRef library; \
+                 set library-format if it is one"
+            ))
+        })?,
+    };
+    let bibtex = match format {
+        LibraryFormat::Papis => papis_documents(&dir)
+            .map(|document| papis_entry(&document))
+            .collect::<Result<Vec<_>, _>>()?
+            .concat(),
+        LibraryFormat::Pubs => concatenate(&files_with_extension(&dir.join("bib"), "bib"))?,
+        LibraryFormat::JabRef => concatenate(&files_with_extension(&dir, "bib"))?,
+    };
+    Ok(cache::store(&cache_root.join("library"), "bib", &bibtex)?)
+}
+
+/// The directories of the papis library in `dir` with an `info.yaml`.
+fn papis_documents(dir: &Path) -> impl Iterator<Item = PathBuf> {
+    let mut documents: Vec<PathBuf> = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.join("info.yaml").is_file())
+        .collect();
+    documents.sort();
+    documents.into_iter()
+}
+
+/// The papis document in `dir` as a BibTeX entry, keyed by its `ref` or
+/// else the directory's name.
+fn papis_entry(dir: &Path) -> Result<String, Error> {
+    let info = dir.join("info.yaml");
+    let yaml = fs::read_to_string(&info).map_err(|e| {
+        CiteprocError::new(
+            ErrorKind::Bibliography,
+            format!("{} cannot be read: {e}", info.display()),
+        )
+    })?;
+    let fields = front_matter::parse_yaml(&yaml);
+    let name = dir.file_name().unwrap_or_default().to_string_lossy();
+    let key = fields.get("ref").map_or(name.as_ref(), String::as_str);
+    let kind = fields.get("type").map_or("misc", String::as_str);
+    let mut entry = format!("@{kind}{{{key},\n");
+    for (field, value) in &fields {
+        if PAPIS_INTERNAL.contains(&field.as_str()) {
+            continue;
+        }
+        let field = match field.as_str() {
+            "tags" => "keywords",
+            field => field,
+        };
+        entry.push_str(&format!("  {field} = {{{value}}},\n"));
+    }
+    entry.push_str("}\n\n");
+    Ok(entry)
+}
+
+/// The files directly in `dir` with `extension`, sorted.
+fn files_with_extension(dir: &Path, extension: &str) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file() && path.extension().is_some_and(|e| e == extension))
+        .collect();
+    files.sort();
+    files
+}
+
+fn concatenate(files: &[PathBuf]) -> Result<String, Error> {
+    let mut out = String::new();
+    for file in files {
+        let contents = fs::read_to_string(file).map_err(|e| {
+            CiteprocError::new(
+                ErrorKind::Bibliography,
+                format!("{} cannot be read: {e}", file.display()),
+            )
+        })?;
+        out.push_str(contents.trim_end());
+        out.push_str("\n\n");
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bibliography;
+
+    #[test]
+    fn exports_papis_and_pubs_libraries() {
+        let root = std::env::temp_dir().join(format!("citeproc-library-{}", std::process::id()));
+        let papis = root.join("papis");
+        fs::create_dir_all(papis.join("smith-2020")).unwrap();
+        fs::create_dir_all(papis.join("untitled")).unwrap();
+        fs::write(
+            papis.join("smith-2020/info.yaml"),
+            "ref: smith2020\ntype: article\nauthor: Smith, Jo\ntitle: 'A Title'\n\
+             year: 2020\ntags: [rust, books]\nfiles:\n- paper.pdf\n",
+        )
+        .unwrap();
+        fs::write(papis.join("untitled/info.yaml"), "title: Notes\n").unwrap();
+        let pubs = root.join("pubs");
+        fs::create_dir_all(pubs.join("bib")).unwrap();
+        fs::create_dir_all(pubs.join("meta")).unwrap();
+        fs::write(pubs.join("bib/doe.bib"), "@book{doe, title = {B}}\n").unwrap();
+
+        let cache = root.join("cache");
+        let exported = export(&root, "papis", None, &cache).unwrap();
+        let entries = bibliography::load(&exported).unwrap();
+        let keys: Vec<&str> = entries.iter().map(|entry| entry.key.as_str()).collect();
+        assert_eq!(keys, ["smith2020", "untitled"]);
+        assert_eq!(entries[0].kind, "article-journal");
+        assert_eq!(entries[0].fields["keywords"], "rust, books");
+        assert!(!entries[0].fields.contains_key("files"));
+
+        let exported = export(&root, "pubs", None, &cache).unwrap();
+        assert_eq!(bibliography::load(&exported).unwrap()[0].key, "doe");
+        assert!(export(&root, "pubs", Some("zotero"), &cache).is_err());
+        fs::remove_dir_all(root).unwrap();
+    }
+}
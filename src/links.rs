@@ -0,0 +1,151 @@
+//! `mdbook-citeproc check-links`: whether the URLs and DOIs of the cited
+//! entries still resolve.
+//!
+//! Links which worked are remembered in `links.json` in the cache root for
+//! a day, so repeated CI runs don't hammer the same hosts. Offline, they're
+//! remembered for good and everything else is unreachable.
+
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use crate::cache::Remote;
+use crate::http;
+use crate::model::BookCitations;
+
+/// How long a link which worked isn't checked again.
+const MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// A link of a cited entry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Link {
+    pub key: String,
+    pub url: String,
+    pub status: Status,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Status {
+    Ok(u16),
+    /// The server answered with an error status.
+    Dead(u16),
+    /// The server couldn't be reached at all.
+    Unreachable(String),
+}
+
+impl Status {
+    pub fn is_ok(&self) -> bool {
+        matches!(self, Self::Ok(_))
+    }
+}
+
+/// The `url` (and `doi`, as a `doi.org` link) of each resolved entry, by
+/// key.
+pub fn links(citations: &BookCitations) -> Vec<(String, String)> {
+    let mut links = Vec::new();
+    for (key, entry) in &citations.entries {
+        if let Some(url) = entry.fields.get("url") {
+            links.push((key.clone(), url.clone()));
+        }
+        if let Some(doi) = entry.fields.get("doi") {
+            let url = match doi.starts_with("http") {
+                true => doi.clone(),
+                false => format!("https://doi.org/{doi}"),
+            };
+            links.push((key.clone(), url));
+        }
+    }
+    links
+}
+
+/// Checks `links` with up to `jobs` requests at a time.
+pub fn check(links: Vec<(String, String)>, jobs: usize, cache_root: &Path) -> Vec<Link> {
+    let ttl = Some(MAX_AGE).filter(|_| !http::is_offline());
+    let mut known = Remote::<u16>::open(cache_root.join("links.json"), ttl);
+
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(Vec::with_capacity(links.len()));
+    thread::scope(|scope| {
+        for _ in 0..jobs.clamp(1, links.len().max(1)) {
+            scope.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let Some((key, url)) = links.get(i) else {
+                    break;
+                };
+                let status = match known.get(url) {
+                    Some(status) => Status::Ok(*status),
+                    None => status(url),
+                };
+                let link = Link {
+                    key: key.clone(),
+                    url: url.clone(),
+                    status,
+                };
+                results.lock().expect("results poisoned").push((i, link));
+            });
+        }
+    });
+    let mut results = results.into_inner().expect("results poisoned");
+    results.sort_by_key(|(i, _)| *i);
+    let results: Vec<Link> = results.into_iter().map(|(_, link)| link).collect();
+
+    for link in &results {
+        match link.status {
+            Status::Ok(status) if known.get(&link.url).is_none() => {
+                known.insert(link.url.clone(), status);
+            }
+            Status::Ok(_) => {}
+            _ => known.remove(&link.url),
+        }
+    }
+    if let Err(e) = known.save() {
+        eprintln!("Warning: the link cache can't be written: {e}");
+    }
+    results
+}
+
+/// Checks `url` with a `HEAD` request, or a `GET` for servers which don't
+/// support those.
+fn status(url: &str) -> Status {
+    let status = match http::status("HEAD", url) {
+        Ok(405 | 501) => http::status("GET", url),
+        status => status,
+    };
+    match status {
+        Ok(status) if status < 400 => Status::Ok(status),
+        Ok(status) => Status::Dead(status),
+        Err(e) => Status::Unreachable(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    use crate::bibliography::Entry;
+
+    #[test]
+    fn checks_known_links_from_the_cache() {
+        let dir = std::env::temp_dir().join(format!("citeproc-links-{}", std::process::id()));
+        let mut known = Remote::open(dir.join("links.json"), None);
+        known.insert("https://doi.org/10.1/x".into(), 200);
+        known.save().unwrap();
+        let mut citations = BookCitations::default();
+        citations.entries.insert(
+            "smith".into(),
+            Entry {
+                key: "smith".into(),
+                kind: "article-journal".into(),
+                fields: BTreeMap::from([("doi".to_string(), "10.1/x".to_string())]),
+            },
+        );
+
+        let links = links(&citations);
+        assert_eq!(links, [("smith".into(), "https://doi.org/10.1/x".into())]);
+        assert_eq!(check(links, 4, &dir)[0].status, Status::Ok(200));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
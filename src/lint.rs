@@ -0,0 +1,257 @@
+//! Bibliography entries missing fields their type needs, like an article
+//! without its journal or a web page without its URL, which otherwise only
+//! show up as a thin reference in the rendered book.
+//!
+//! What an entry needs depends on the style as well: one which never prints
+//! `publisher-place` has no use for it, and another won't do without it. So
+//! the built-in requirements only apply to fields the style uses, and
+//! `required-fields` sets the severity of any field per type, e.g.
+//! `book = { publisher-place = "error" }`.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use mdbook::errors::Error;
+
+use crate::bibliography::{Entry, Library};
+use crate::config::{Config, Severity};
+use crate::error::{CiteprocError, ErrorKind};
+
+/// The CSL variables every entry needs.
+const ALWAYS: &[&str] = &["title"];
+
+/// The CSL variables entries of each CSL type need, beyond [`ALWAYS`].
+const REQUIRED: &[(&str, &[&str])] = &[
+    ("article-journal", &["container-title", "issued"]),
+    ("book", &["publisher", "issued"]),
+    ("chapter", &["container-title", "publisher"]),
+    ("paper-conference", &["container-title"]),
+    ("report", &["publisher"]),
+    ("thesis", &["publisher"]),
+    ("webpage", &["URL"]),
+];
+
+/// An entry without a field it needs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+    pub key: String,
+    /// The entry's CSL type.
+    pub kind: String,
+    /// The CSL variable it's missing.
+    pub field: String,
+    pub severity: Severity,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "`{}` ({}) has no {}", self.key, self.kind, self.field)?;
+        match bibtex_fields(&self.field) {
+            Some(fields) => write!(f, " ({fields} in BibTeX)"),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The problems with the entries of `library` the book cites, as `keys`,
+/// for the style in `style` (everything counts as used if it's `None`).
+pub fn lint(
+    config: &Config,
+    style: Option<&str>,
+    library: &Library,
+    keys: &[String],
+) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    let mut seen = Vec::new();
+    for key in keys {
+        let key = config.aliases.get(key).unwrap_or(key);
+        let Some(entry) = library.get(key) else {
+            continue;
+        };
+        if seen.contains(&key) {
+            continue;
+        }
+        seen.push(key);
+        problems.extend(entry_problems(config, style, entry));
+    }
+    problems
+}
+
+fn entry_problems(config: &Config, style: Option<&str>, entry: &Entry) -> Vec<Problem> {
+    let configured = config.required_fields.get(&entry.kind);
+    let mut fields: BTreeMap<&str, Severity> = BTreeMap::new();
+    let built_in = REQUIRED
+        .iter()
+        .find(|(kind, _)| *kind == entry.kind)
+        .map_or(&[][..], |(_, fields)| fields);
+    for field in ALWAYS.iter().chain(built_in) {
+        if style.is_none_or(|style| uses(style, field)) {
+            fields.insert(field, Severity::Warning);
+        }
+    }
+    for (field, severity) in configured.into_iter().flatten() {
+        fields.insert(field, *severity);
+    }
+
+    let json = entry.csl_json();
+    fields
+        .into_iter()
+        .filter(|(_, severity)| *severity != Severity::Off)
+        .filter(|(field, _)| {
+            json.as_object().is_none_or(|object| {
+                !object.iter().any(|(name, value)| {
+                    name.eq_ignore_ascii_case(field)
+                        && value.as_str().is_none_or(|value| !value.trim().is_empty())
+                })
+            })
+        })
+        .map(|(field, severity)| Problem {
+            key: entry.key.clone(),
+            kind: entry.kind.clone(),
+            field: field.to_string(),
+            severity,
+        })
+        .collect()
+}
+
+/// Whether the CSL `style` renders `variable` anywhere.
+fn uses(style: &str, variable: &str) -> bool {
+    style.split("variable=").skip(1).any(|rest| {
+        let Some(quote) = rest.chars().next().filter(|c| *c == '"' || *c == '\'') else {
+            return false;
+        };
+        let value = &rest[1..];
+        let value = &value[..value.find(quote).unwrap_or(value.len())];
+        value.split_whitespace().any(|v| v == variable)
+    })
+}
+
+/// The BibTeX fields which give an entry the CSL `variable`, where they're
+/// named differently.
+fn bibtex_fields(variable: &str) -> Option<&'static str> {
+    match variable {
+        "container-title" => Some("journal or booktitle"),
+        "publisher-place" => Some("address or location"),
+        "issued" => Some("year or date"),
+        "URL" => Some("url"),
+        _ => None,
+    }
+}
+
+/// Lints the entries the book cites, as `keys`, against the style
+/// configured for the book in `root`: returns the warnings, or an error
+/// listing every problem with an `error` severity.
+pub fn check(
+    config: &Config,
+    root: &Path,
+    library: &Library,
+    keys: &[String],
+) -> Result<Vec<String>, Error> {
+    let Some(bibliography) = &config.bibliography else {
+        return Ok(Vec::new());
+    };
+    let style = fs::read_to_string(root.join(&bibliography.bibliography_style)).ok();
+    let keys: Vec<String> = if config.nocite.iter().any(|key| key == "*") {
+        library
+            .entries()
+            .iter()
+            .map(|entry| entry.key.clone())
+            .collect()
+    } else {
+        keys.iter().chain(&config.nocite).cloned().collect()
+    };
+    let problems = lint(config, style.as_deref(), library, &keys);
+    let errors: Vec<String> = problems
+        .iter()
+        .filter(|problem| problem.severity == Severity::Error)
+        .map(Problem::to_string)
+        .collect();
+    if !errors.is_empty() {
+        return Err(CiteprocError::new(
+            ErrorKind::Bibliography,
+            format!("entries missing required fields:\n{}", errors.join("\n")),
+        )
+        .into());
+    }
+    Ok(problems
+        .iter()
+        .filter(|problem| problem.severity == Severity::Warning)
+        .map(Problem::to_string)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(toml: &str) -> Config {
+        let table = format!("bibliography = \"refs.bib\"\ncsl = \"style.csl\"\n{toml}");
+        Config::from_table(&toml::from_str(&table).unwrap(), Path::new(".")).unwrap()
+    }
+
+    fn entry(key: &str, kind: &str, fields: &[(&str, &str)]) -> Entry {
+        Entry {
+            key: key.into(),
+            kind: kind.into(),
+            fields: fields
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn reports_missing_fields_the_style_uses() {
+        let library = Library::new([
+            entry(
+                "smith",
+                "article-journal",
+                &[("title", "A"), ("year", "2020")],
+            ),
+            entry(
+                "site",
+                "webpage",
+                &[("title", "B"), ("url", "https://a.example")],
+            ),
+            entry("adams", "book", &[("title", "C")]),
+            entry("lost", "webpage", &[("title", "D")]),
+        ]);
+        let keys: Vec<String> = ["smith", "old", "adams", "lost", "smith"]
+            .map(String::from)
+            .to_vec();
+        let style = "<text variable=\"title\"/><text variable=\"container-title URL\"/>\
+                     <text variable='publisher'/>";
+        let problems: Vec<String> = lint(
+            &config("aliases = { old = \"site\" }"),
+            Some(style),
+            &library,
+            &keys,
+        )
+        .iter()
+        .map(Problem::to_string)
+        .collect();
+        assert_eq!(
+            problems,
+            [
+                "`smith` (article-journal) has no container-title (journal or booktitle in BibTeX)",
+                "`adams` (book) has no publisher",
+                "`lost` (webpage) has no URL (url in BibTeX)",
+            ]
+        );
+
+        let configured = config(
+            "[required-fields]\nbook = { publisher-place = \"error\", publisher = \"off\" }",
+        );
+        let problems = lint(&configured, Some(style), &library, &keys[2..3]);
+        assert_eq!(
+            problems,
+            [Problem {
+                key: "adams".into(),
+                kind: "book".into(),
+                field: "publisher-place".into(),
+                severity: Severity::Error,
+            }]
+        );
+    }
+}
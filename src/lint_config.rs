@@ -0,0 +1,257 @@
+//! `mdbook-citeproc lint-config`: finds settings in `[preprocessor.citeproc]`
+//! which don't do what they look like they do, and with `--fix` rewrites
+//! them in place, keeping the rest of `book.toml` as it's written.
+//!
+//! Options are spelled with dashes, so `url_max_length` is never read; only
+//! pandoc's extensions, like `pipe_tables`, have underscores. Options which
+//! have been [renamed](crate::deprecated) still work, but are given their
+//! new names. And `bibliography` is a path or a list of them, not a glob,
+//! so a pattern there is spelled out as the files it matches.
+
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use globset::Glob;
+use mdbook::errors::Error;
+use similar::TextDiff;
+use toml_edit::{Array, DocumentMut, Item, Table, Value};
+
+use crate::config::EXTENSIONS;
+use crate::deprecated::{NESTED, RENAMED};
+use crate::error::CiteprocError;
+
+/// What linting a `book.toml` found.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Linted {
+    /// A line describing each problem, and its fix if it has one.
+    pub findings: Vec<String>,
+    pub original: String,
+    /// The file with every fixable problem fixed.
+    pub fixed: String,
+}
+
+impl Linted {
+    /// The fixes as a unified diff of `book.toml`, empty if there are none.
+    pub fn diff(&self) -> String {
+        TextDiff::from_lines(&self.original, &self.fixed)
+            .unified_diff()
+            .header("book.toml", "book.toml")
+            .to_string()
+    }
+}
+
+/// Lints the `book.toml` of the book in `root`.
+pub fn lint(root: &Path) -> Result<Linted, Error> {
+    let path = root.join("book.toml");
+    let original = fs::read_to_string(&path)
+        .map_err(|e| CiteprocError::config(format!("failed to read book.toml: {e}")))?;
+    lint_str(&original, root)
+}
+
+fn lint_str(original: &str, root: &Path) -> Result<Linted, Error> {
+    let mut document: DocumentMut = original
+        .parse()
+        .map_err(|e| CiteprocError::config(format!("book.toml: {e}")))?;
+    let mut findings = Vec::new();
+    let table = document
+        .get_mut("preprocessor")
+        .and_then(|preprocessor| preprocessor.get_mut("citeproc"))
+        .and_then(Item::as_table_mut)
+        .ok_or_else(|| CiteprocError::config("No config table for citeproc preprocessor"))?;
+
+    lint_table(table, "", root, &mut findings);
+    for &nested in NESTED {
+        let Some(tables) = table.get_mut(nested).and_then(Item::as_table_mut) else {
+            continue;
+        };
+        for (name, inner) in tables.iter_mut() {
+            if let Some(inner) = inner.as_table_mut() {
+                lint_table(inner, &format!("{nested}.{name}."), root, &mut findings);
+            }
+        }
+    }
+    Ok(Linted {
+        findings,
+        original: original.to_string(),
+        fixed: document.to_string(),
+    })
+}
+
+/// Lints one table of options, whose keys are named with `prefix`.
+fn lint_table(table: &mut Table, prefix: &str, root: &Path, findings: &mut Vec<String>) {
+    let keys: Vec<String> = table.iter().map(|(key, _)| key.to_string()).collect();
+    for key in &keys {
+        if !key.contains('_') || EXTENSIONS.contains(&key.as_str()) {
+            continue;
+        }
+        let dashed = key.replace('_', "-");
+        if table.contains_key(&dashed) {
+            findings.push(format!(
+                "{prefix}{key} is never read, and {prefix}{dashed} is already set; remove one"
+            ));
+            continue;
+        }
+        findings.push(format!(
+            "{prefix}{key} is never read: renamed to {prefix}{dashed}"
+        ));
+        rename(table, key, &dashed);
+    }
+    for renamed in RENAMED {
+        if !table.contains_key(renamed.old) {
+            continue;
+        }
+        if table.contains_key(renamed.new) {
+            findings.push(format!(
+                "{}, and {prefix}{} is already set; remove one",
+                renamed.message(prefix),
+                renamed.new
+            ));
+            continue;
+        }
+        findings.push(renamed.message(prefix));
+        rename(table, renamed.old, renamed.new);
+    }
+
+    let Some(pattern) = table
+        .get("bibliography")
+        .and_then(Item::as_str)
+        .filter(|path| path.contains(['*', '?', '[']))
+        .map(str::to_string)
+    else {
+        return;
+    };
+    let files = match glob(root, &pattern) {
+        Ok(files) => files,
+        Err(e) => {
+            findings.push(format!(
+                "{prefix}bibliography {pattern:?} isn't a valid glob: {e}"
+            ));
+            return;
+        }
+    };
+    if files.is_empty() {
+        findings.push(format!(
+            "{prefix}bibliography is {pattern:?}, a glob, which matches no files"
+        ));
+        return;
+    }
+    findings.push(format!(
+        "{prefix}bibliography is {pattern:?}, a glob: replaced by the {} files it matches",
+        files.len()
+    ));
+    let mut array: Array = files.iter().map(String::as_str).collect();
+    if let Some(Item::Value(value)) = table.get_mut("bibliography") {
+        // In place, so the comments around it stay.
+        *array.decor_mut() = value.decor().clone();
+        *value = Value::Array(array);
+    }
+}
+
+/// Renames `old` to `new` in `table`, where `old` was.
+fn rename(table: &mut Table, old: &str, new: &str) {
+    let order: Vec<String> = table
+        .iter()
+        .map(|(key, _)| match key == old {
+            true => new.to_string(),
+            false => key.to_string(),
+        })
+        .collect();
+    let Some((key, item)) = table.remove_entry(old) else {
+        return;
+    };
+    let decor = key.leaf_decor().clone();
+    table.insert(new, item);
+    if let Some(mut key) = table.key_mut(new) {
+        *key.leaf_decor_mut() = decor;
+    }
+    let position = |key: &str| order.iter().position(|k| k == key);
+    table.sort_values_by(|a, _, b, _| position(a.get()).cmp(&position(b.get())));
+}
+
+/// The files under `root` matching `pattern`, relative to it and sorted.
+fn glob(root: &Path, pattern: &str) -> Result<Vec<String>, globset::Error> {
+    let matcher = Glob::new(pattern)?.compile_matcher();
+    // Only the directory before the first wildcard has to be walked.
+    let base: PathBuf = Path::new(pattern)
+        .components()
+        .take_while(|c| {
+            !c.as_os_str()
+                .to_string_lossy()
+                .contains(['*', '?', '[', '{'])
+        })
+        .filter(|c| matches!(c, Component::Normal(_) | Component::ParentDir))
+        .collect();
+    let mut files = Vec::new();
+    walk(&root.join(&base), &mut files);
+    let mut matched: Vec<String> = files
+        .into_iter()
+        .filter_map(|file| {
+            let relative = file.strip_prefix(root).ok()?;
+            let relative = relative.to_string_lossy().replace('\\', "/");
+            matcher.is_match(&relative).then_some(relative)
+        })
+        .collect();
+    matched.sort();
+    Ok(matched)
+}
+
+fn walk(dir: &Path, files: &mut Vec<PathBuf>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            walk(&path, files);
+        } else {
+            files.push(path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fixes_dashes_and_globs_in_place() {
+        let root =
+            std::env::temp_dir().join(format!("citeproc-lint-config-{}", std::process::id()));
+        fs::create_dir_all(root.join("refs/more")).unwrap();
+        for file in ["refs/a.bib", "refs/more/b.bib", "refs/notes.txt"] {
+            fs::write(root.join(file), "").unwrap();
+        }
+        let original = "[book]\ntitle = \"T\"\n\n[preprocessor.citeproc]\n\
+                        citations = \"transpile\"\n\
+                        # The sources.\nbibliography = \"refs/**/*.bib\"\n\
+                        bibliography_style = \"style.csl\" # APA\n\
+                        pipe_tables = \"preserve\"\n\
+                        [preprocessor.citeproc.profile.print]\nurl_max_length = 30\n\
+                        [preprocessor.citeproc.lang.fr]\nbibliography-style = \"fr.csl\"\n";
+        let linted = lint_str(original, &root).unwrap();
+        assert_eq!(
+            linted.findings,
+            [
+                "bibliography_style is never read: renamed to bibliography-style",
+                "bibliography-style has been renamed to csl",
+                "bibliography is \"refs/**/*.bib\", a glob: replaced by the 2 files it matches",
+                "profile.print.url_max_length is never read: renamed to profile.print.url-max-length",
+                "lang.fr.bibliography-style has been renamed to lang.fr.csl",
+            ]
+        );
+        assert_eq!(
+            linted.fixed,
+            "[book]\ntitle = \"T\"\n\n[preprocessor.citeproc]\n\
+             citations = \"transpile\"\n\
+             # The sources.\nbibliography = [\"refs/a.bib\", \"refs/more/b.bib\"]\n\
+             csl = \"style.csl\" # APA\n\
+             pipe_tables = \"preserve\"\n\
+             [preprocessor.citeproc.profile.print]\nurl-max-length = 30\n\
+             [preprocessor.citeproc.lang.fr]\ncsl = \"fr.csl\"\n"
+        );
+        assert!(linted
+            .diff()
+            .contains("-bibliography_style = \"style.csl\" # APA\n"));
+        fs::remove_dir_all(root).unwrap();
+    }
+}
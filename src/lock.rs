@@ -0,0 +1,202 @@
+//! `citeproc.lock`: what the book's remote resources resolved to, written
+//! beside `book.toml` by each build, so a later build can be told to use
+//! exactly the same ones with `locked = true` or `--locked`.
+//!
+//! It records the pinned pandoc `auto-install-pandoc` downloads and its
+//! checksum, the hash of the library `[remote-bibliography]` fetched, and
+//! the snapshot `archive-links` found for each entry. A locked build fails
+//! on any difference rather than rewriting the file. Books with none of
+//! these don't get a lockfile.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use mdbook::errors::Error;
+use serde::{Deserialize, Serialize};
+
+use crate::cache::{self, sha256_hex};
+use crate::config::Config;
+use crate::error::CiteprocError;
+use crate::install::PANDOC_VERSION;
+
+/// The lockfile's name, in the book's root.
+pub const LOCKFILE: &str = "citeproc.lock";
+
+const HEADER: &str = "# Written by mdbook-citeproc: the remote resources this book was built\n\
+                      # with. Commit it, and build with --locked to use exactly these.\n\n";
+
+static LOCKED: AtomicBool = AtomicBool::new(false);
+
+/// Makes builds fail unless they match the lockfile, for `--locked`.
+pub fn set_locked(locked: bool) {
+    LOCKED.store(locked, Ordering::Relaxed);
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Lock {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pandoc: Option<Pandoc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    remote_bibliography: Option<Resource>,
+    /// Each archived link, by entry key.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    snapshots: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Pandoc {
+    version: String,
+    sha256: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Resource {
+    url: String,
+    sha256: String,
+}
+
+impl Lock {
+    /// What `config`'s remote resources resolved to in this build.
+    pub fn current(config: &Config) -> Self {
+        Self {
+            pandoc: config.auto_install_pandoc.then(|| Pandoc {
+                version: PANDOC_VERSION.to_string(),
+                sha256: config.pandoc_sha256.as_ref().map(|sha| sha.to_lowercase()),
+            }),
+            remote_bibliography: config.remote_bibliography.as_ref().and_then(|remote| {
+                Some(Resource {
+                    url: remote.url.clone(),
+                    sha256: sha256_hex(fs::read(&remote.path).ok()?),
+                })
+            }),
+            snapshots: config.snapshots.clone(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+
+    /// How `self` differs from what was `locked`.
+    fn differences(&self, locked: &Lock) -> Vec<String> {
+        let mut differences = Vec::new();
+        if self.pandoc != locked.pandoc {
+            differences.push(format!(
+                "pandoc is {}, locked to {}",
+                describe(&self.pandoc),
+                describe(&locked.pandoc)
+            ));
+        }
+        if self.remote_bibliography != locked.remote_bibliography {
+            differences.push(format!(
+                "the remote bibliography is {}, locked to {}",
+                describe(&self.remote_bibliography),
+                describe(&locked.remote_bibliography)
+            ));
+        }
+        let keys: BTreeSet<&String> = self
+            .snapshots
+            .keys()
+            .chain(locked.snapshots.keys())
+            .collect();
+        for key in keys {
+            let (current, was) = (self.snapshots.get(key), locked.snapshots.get(key));
+            if current != was {
+                differences.push(format!(
+                    "the snapshot of {key} is {}, locked to {}",
+                    describe(&current),
+                    describe(&was)
+                ));
+            }
+        }
+        differences
+    }
+}
+
+fn describe(value: &Option<impl Serialize>) -> String {
+    match value {
+        None => "none".to_string(),
+        Some(value) => serde_json::to_string(value).unwrap_or_default(),
+    }
+}
+
+/// Writes `current` to the book's lockfile in `root`, or with `locked`
+/// (or `--locked`) checks that it's what the lockfile says.
+pub fn update(root: &Path, current: &Lock, locked: bool) -> Result<(), Error> {
+    let path = root.join(LOCKFILE);
+    let existing = fs::read_to_string(&path).ok();
+    if locked || LOCKED.load(Ordering::Relaxed) {
+        let existing = existing.ok_or_else(|| {
+            CiteprocError::config(format!(
+                "this build is locked, but there's no {LOCKFILE}; build once without --locked to write it"
+            ))
+        })?;
+        let lock: Lock = toml::from_str(&existing)
+            .map_err(|e| CiteprocError::config(format!("{LOCKFILE}: {e}")))?;
+        let differences = current.differences(&lock);
+        if !differences.is_empty() {
+            return Err(CiteprocError::config(format!(
+                "this build doesn't match {LOCKFILE}: {}",
+                differences.join("; ")
+            )));
+        }
+        return Ok(());
+    }
+    if current.is_empty() && existing.is_none() {
+        return Ok(());
+    }
+    let contents = format!("{HEADER}{}", toml::to_string(current)?);
+    if existing.as_deref() != Some(contents.as_str()) {
+        cache::write_atomic(&path, contents)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn locked_builds_must_match() {
+        let root = std::env::temp_dir().join(format!("citeproc-lock-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        update(&root, &Lock::default(), false).unwrap();
+        assert!(!root.join(LOCKFILE).exists());
+
+        let mut lock = Lock {
+            pandoc: Some(Pandoc {
+                version: "3.5".into(),
+                sha256: Some("abc".into()),
+            }),
+            ..Lock::default()
+        };
+        lock.snapshots
+            .insert("smith".into(), "https://web.archive.org/1".into());
+        update(&root, &lock, false).unwrap();
+        let written = fs::read_to_string(root.join(LOCKFILE)).unwrap();
+        assert!(
+            written.contains("[snapshots]\nsmith = \"https://web.archive.org/1\""),
+            "{written}"
+        );
+        update(&root, &lock, true).unwrap();
+
+        let mut changed = lock.clone();
+        changed
+            .snapshots
+            .insert("smith".into(), "https://web.archive.org/2".into());
+        changed.pandoc = None;
+        let e = update(&root, &changed, true).unwrap_err().to_string();
+        assert!(
+            e.contains("pandoc is none, locked to {\"version\":\"3.5\""),
+            "{e}"
+        );
+        assert!(
+            e.contains("the snapshot of smith is \"https://web.archive.org/2\""),
+            "{e}"
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -1,244 +1,568 @@
-use std::collections::HashMap;
-use std::io;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
 use std::process;
+use std::time::Duration;
 
-use clap::{Arg, ArgMatches, Command};
-use mdbook::book::Book;
+use clap::{Arg, ArgAction, ArgMatches, Command};
 use mdbook::errors::Error;
-use mdbook::preprocess::{CmdPreprocessor, Preprocessor, PreprocessorContext};
-use semver::{Version, VersionReq};
-
-use crate::pandoc_lib::Pandoc;
+use mdbook::preprocess::Preprocessor;
+#[cfg(unix)]
+use mdbook_citeproc::daemon;
+use mdbook_citeproc::error::{self, CiteprocError, ErrorFormat};
+use mdbook_citeproc::{
+    check, config, diagnostics, explain, gc, graph, links, lint_config, migrate, process_input_to,
+    profile, profile_input, refresh, vendor, Pandoc,
+};
 
 pub fn make_app() -> Command {
     Command::new("citeproc-preprocessor")
         .about("A mdbook preprocessor which runs your code through pandoc and citeproc")
+        .arg(
+            Arg::new("error-format")
+                .long("error-format")
+                .value_parser(["human", "json"])
+                .default_value("human")
+                .global(true)
+                .help("How failures are reported on stderr"),
+        )
+        .arg(
+            Arg::new("quiet")
+                .long("quiet")
+                .short('q')
+                .action(ArgAction::SetTrue)
+                .global(true)
+                .help("Don't report per-chapter progress on stderr"),
+        )
+        .arg(
+            Arg::new("offline")
+                .long("offline")
+                .action(ArgAction::SetTrue)
+                .global(true)
+                .help("Make no network requests, relying on what's cached"),
+        )
+        .arg(
+            Arg::new("locked")
+                .long("locked")
+                .action(ArgAction::SetTrue)
+                .global(true)
+                .help("Fail unless remote resources resolve as citeproc.lock says"),
+        )
+        .arg(
+            Arg::new("profile")
+                .long("profile")
+                .action(ArgAction::SetTrue)
+                .help("Report the time spent on each chapter on stderr"),
+        )
+        .arg(
+            Arg::new("daemon-socket")
+                .long("daemon-socket")
+                .value_name("SOCKET")
+                .help("Delegate preprocessing to a daemon listening on SOCKET, if one is running"),
+        )
         .subcommand(
             Command::new("supports")
                 .arg(Arg::new("renderer").required(true))
                 .about("Check whether a renderer is supported by this preprocessor"),
         )
+        .subcommand(
+            Command::new("check")
+                .arg(Arg::new("dir").default_value(".").help("The book's root directory"))
+                .arg(
+                    Arg::new("preview")
+                        .long("preview")
+                        .action(ArgAction::SetTrue)
+                        .help("Print each citation as the configured style renders it"),
+                )
+                .arg(
+                    Arg::new("message-format")
+                        .long("message-format")
+                        .value_parser(["human", "short", "sarif"])
+                        .default_value("human")
+                        .help("Report problems with citations for people, as file:line:col lines, or as SARIF"),
+                )
+                .about("Check the book's citeproc configuration and bibliography"),
+        )
+        .subcommand(
+            Command::new("graph")
+                .arg(Arg::new("dir").default_value(".").help("The book's root directory"))
+                .arg(
+                    Arg::new("format")
+                        .long("format")
+                        .value_parser(["dot", "json"])
+                        .default_value("dot")
+                        .help("Write GraphViz DOT or JSON"),
+                )
+                .about("Print which chapters cite which references as a graph"),
+        )
+        .subcommand(
+            Command::new("check-links")
+                .arg(Arg::new("dir").default_value(".").help("The book's root directory"))
+                .arg(
+                    Arg::new("jobs")
+                        .long("jobs")
+                        .short('j')
+                        .value_parser(clap::value_parser!(usize))
+                        .default_value("8")
+                        .help("How many links to check at once"),
+                )
+                .about("Check that the URLs and DOIs of cited entries resolve"),
+        )
+        .subcommand(
+            Command::new("bench")
+                .arg(Arg::new("dir").default_value(".").help("The book's root directory"))
+                .arg(
+                    Arg::new("iterations")
+                        .long("iterations")
+                        .short('n')
+                        .value_parser(clap::value_parser!(usize))
+                        .default_value("5")
+                        .help("How many times to build the book"),
+                )
+                .arg(
+                    Arg::new("renderer")
+                        .long("renderer")
+                        .default_value("html")
+                        .help("The renderer to build for"),
+                )
+                .about("Time building the book without a cache, per chapter and step"),
+        )
+        .subcommand(
+            Command::new("explain")
+                .arg(Arg::new("dir").default_value(".").help("The book's root directory"))
+                .arg(
+                    Arg::new("renderer")
+                        .long("renderer")
+                        .default_value("html")
+                        .help("The renderer to build for"),
+                )
+                .about("Show the resolved configuration and the pandoc commands a build would run"),
+        )
+        .subcommand(
+            Command::new("refresh")
+                .arg(Arg::new("dir").default_value(".").help("The book's root directory"))
+                .about("Fetch everything cached from remote services again"),
+        )
+        .subcommand(
+            Command::new("vendor")
+                .arg(Arg::new("dir").default_value(".").help("The book's root directory"))
+                .about("Copy everything fetched from remote services into citeproc-vendor/"),
+        )
+        .subcommand(
+            Command::new("cache")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("stats")
+                        .arg(Arg::new("dir").default_value(".").help("The book's root directory"))
+                        .about("Show how many files the cache holds and how large they are"),
+                )
+                .subcommand(
+                    Command::new("clean")
+                        .arg(Arg::new("dir").default_value(".").help("The book's root directory"))
+                        .arg(
+                            Arg::new("all")
+                                .long("all")
+                                .action(ArgAction::SetTrue)
+                                .help("Remove everything cached"),
+                        )
+                        .arg(
+                            Arg::new("max-size")
+                                .long("max-size")
+                                .value_name("SIZE")
+                                .help("Prune to SIZE, e.g. 500MB [default: cache-max-size]"),
+                        )
+                        .arg(
+                            Arg::new("max-age-days")
+                                .long("max-age-days")
+                                .value_name("DAYS")
+                                .value_parser(clap::value_parser!(u64))
+                                .help("Remove what's gone unused for DAYS [default: cache-max-age-days]"),
+                        )
+                        .about("Remove what builds haven't used for longest, or everything with --all"),
+                )
+                .about("Inspect and prune the cache"),
+        )
+        .subcommand(
+            Command::new("migrate-keys")
+                .arg(Arg::new("dir").default_value(".").help("The book's root directory"))
+                .arg(
+                    Arg::new("map")
+                        .long("map")
+                        .value_name("FILE")
+                        .help("A TOML table of old keys to new ones [default: the book's aliases]"),
+                )
+                .arg(
+                    Arg::new("dry-run")
+                        .long("dry-run")
+                        .action(ArgAction::SetTrue)
+                        .help("Only report what would change"),
+                )
+                .arg(
+                    Arg::new("backup")
+                        .long("backup")
+                        .action(ArgAction::SetTrue)
+                        .help("Copy each changed file to <file>.bak first"),
+                )
+                .about("Rewrite citation keys in the book's sources"),
+        )
+        .subcommand(
+            Command::new("lint-config")
+                .arg(Arg::new("dir").default_value(".").help("The book's root directory"))
+                .arg(
+                    Arg::new("fix")
+                        .long("fix")
+                        .action(ArgAction::SetTrue)
+                        .help("Rewrite book.toml with the fixes"),
+                )
+                .about("Find settings in book.toml which are never read, and fix them with --fix"),
+        )
+        .subcommand(
+            Command::new("daemon")
+                .arg(Arg::new("socket").required(true))
+                .about("Process books sent over a unix socket, keeping results in memory between builds"),
+        )
 }
 
 fn main() {
     let matches = make_app().get_matches();
 
-    let preprocessor = Pandoc::new();
+    let error_format = match matches
+        .get_one::<String>("error-format")
+        .map(String::as_str)
+    {
+        Some("json") => ErrorFormat::Json,
+        _ => ErrorFormat::Human,
+    };
+
+    mdbook_citeproc::set_offline(matches.get_flag("offline"));
+    mdbook_citeproc::set_locked(matches.get_flag("locked"));
+    let profiling = matches.get_flag("profile");
+    let mut preprocessor = Pandoc::new().quiet(matches.get_flag("quiet"));
+    if profiling {
+        preprocessor = preprocessor.profile();
+    }
 
-    if let Some(sub_args) = matches.subcommand_matches("supports") {
+    let result = if let Some(sub_args) = matches.subcommand_matches("supports") {
         handle_supports(&preprocessor, sub_args);
-    } else if let Err(e) = handle_preprocessing(&preprocessor) {
-        eprintln!("{}", e);
-        process::exit(1);
+    } else if let Some(sub_args) = matches.subcommand_matches("check") {
+        handle_check(sub_args)
+    } else if let Some(sub_args) = matches.subcommand_matches("graph") {
+        handle_graph(sub_args)
+    } else if let Some(sub_args) = matches.subcommand_matches("check-links") {
+        handle_check_links(sub_args)
+    } else if let Some(sub_args) = matches.subcommand_matches("bench") {
+        handle_bench(sub_args)
+    } else if let Some(sub_args) = matches.subcommand_matches("explain") {
+        handle_explain(sub_args)
+    } else if let Some(sub_args) = matches.subcommand_matches("refresh") {
+        handle_refresh(sub_args)
+    } else if let Some(sub_args) = matches.subcommand_matches("vendor") {
+        handle_vendor(sub_args)
+    } else if let Some(sub_args) = matches.subcommand_matches("cache") {
+        handle_cache(sub_args)
+    } else if let Some(sub_args) = matches.subcommand_matches("migrate-keys") {
+        handle_migrate_keys(sub_args)
+    } else if let Some(sub_args) = matches.subcommand_matches("lint-config") {
+        handle_lint_config(sub_args)
+    } else if let Some(sub_args) = matches.subcommand_matches("daemon") {
+        handle_daemon(sub_args)
+    } else {
+        handle_preprocessing(&preprocessor, &matches, profiling)
+    };
+
+    if let Err(e) = result {
+        process::exit(error::report(&e, error_format));
     }
 }
 
-fn handle_preprocessing(pre: &dyn Preprocessor) -> Result<(), Error> {
-    let (ctx, book) = CmdPreprocessor::parse_input(io::stdin())?;
-
-    let book_version = Version::parse(&ctx.mdbook_version)?;
-    let version_req = VersionReq::parse(mdbook::MDBOOK_VERSION)?;
+fn handle_preprocessing(pre: &Pandoc, matches: &ArgMatches, profiling: bool) -> Result<(), Error> {
+    let mut input = Vec::new();
+    io::stdin().read_to_end(&mut input)?;
 
-    if !version_req.matches(&book_version) {
+    if let Some(socket) = matches.get_one::<String>("daemon-socket").map(Path::new) {
+        if let Some(output) = delegate_to_daemon(socket, &input, matches)? {
+            io::stdout().write_all(output.as_bytes())?;
+            return Ok(());
+        }
         eprintln!(
-            "Warning: The {} plugin was built against version {} of mdbook, \
-             but we're being called from version {}",
-            pre.name(),
-            mdbook::MDBOOK_VERSION,
-            ctx.mdbook_version
+            "Warning: no citeproc daemon is listening on {}, processing the book directly",
+            socket.display()
         );
     }
 
-    let processed_book = pre.run(&ctx, book)?;
-    serde_json::to_writer(io::stdout(), &processed_book)?;
-
+    // Written as it's serialized rather than collected first, which would
+    // hold the whole book twice.
+    let output = io::BufWriter::new(io::stdout().lock());
+    if profiling {
+        let profile = profile_input(pre, &input, output)?;
+        eprint!("{}", profile.report());
+    } else {
+        process_input_to(pre, &input, output)?;
+    }
     Ok(())
 }
 
-fn handle_supports(pre: &dyn Preprocessor, sub_args: &ArgMatches) -> ! {
-    let renderer = sub_args
-        .get_one::<String>("renderer")
-        .expect("Required argument");
-    let supported = pre.supports_renderer(renderer);
-
-    // Signals whether the renderer is supported by exiting with 1 or 0.
-    if supported {
-        process::exit(0);
-    } else {
-        process::exit(1);
-    }
+#[cfg(unix)]
+fn delegate_to_daemon(
+    socket: &Path,
+    input: &[u8],
+    matches: &ArgMatches,
+) -> Result<Option<String>, Error> {
+    let request = daemon::Request {
+        input: String::from_utf8(input.to_vec())
+            .map_err(|e| Error::msg(format!("preprocessor input isn't UTF-8: {e}")))?,
+        offline: matches.get_flag("offline"),
+        locked: matches.get_flag("locked"),
+    };
+    daemon::delegate(socket, &request)
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum PandocSetting {
-    Preserve,
-    Transpile,
+#[cfg(not(unix))]
+fn delegate_to_daemon(
+    _socket: &Path,
+    _input: &[u8],
+    _matches: &ArgMatches,
+) -> Result<Option<String>, Error> {
+    Ok(None)
 }
 
-impl Default for PandocSetting {
-    fn default() -> Self {
-        Self::Preserve
+fn handle_check(sub_args: &ArgMatches) -> Result<(), Error> {
+    let dir = sub_args.get_one::<String>("dir").expect("Has a default");
+    let warnings = check::check(Path::new(dir))?;
+    for warning in &warnings {
+        eprintln!("Warning: {warning}");
+    }
+    let diagnostics = diagnostics::collect(Path::new(dir))?;
+    match sub_args
+        .get_one::<String>("message-format")
+        .map(String::as_str)
+    {
+        Some("short") => {
+            for diagnostic in &diagnostics {
+                println!("{}", diagnostic.short());
+            }
+        }
+        Some("sarif") => println!("{:#}", diagnostics::sarif(&diagnostics)),
+        _ => {
+            for diagnostic in &diagnostics {
+                eprintln!("Warning: {diagnostic}");
+            }
+        }
+    }
+    if sub_args.get_flag("preview") {
+        for preview in check::preview(Path::new(dir))? {
+            println!(
+                "{}: {} → {}",
+                preview.chapter, preview.citation, preview.rendered
+            );
+        }
     }
+    // SARIF is the whole of stdout.
+    if sub_args
+        .get_one::<String>("message-format")
+        .map(String::as_str)
+        != Some("sarif")
+    {
+        println!(
+            "citeproc configuration OK ({} warnings)",
+            warnings.len() + diagnostics.len()
+        );
+    }
+    Ok(())
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-struct BibliographyConfig {
-    bibliography: String,
-    bibliography_style: String,
+fn handle_graph(sub_args: &ArgMatches) -> Result<(), Error> {
+    let dir = sub_args.get_one::<String>("dir").expect("Has a default");
+    let citations = graph::load(Path::new(dir))?;
+    match sub_args.get_one::<String>("format").map(String::as_str) {
+        Some("json") => println!("{:#}", graph::json(&citations)),
+        _ => print!("{}", graph::dot(&citations)),
+    }
+    Ok(())
 }
 
-impl BibliographyConfig {
-    fn new(bibliography: String, bibliography_style: String) -> Self {
-        Self {
-            bibliography,
-            bibliography_style,
+fn handle_check_links(sub_args: &ArgMatches) -> Result<(), Error> {
+    let dir = Path::new(sub_args.get_one::<String>("dir").expect("Has a default"));
+    let config = check::load_config(dir)?;
+    mdbook_citeproc::configure_network(&config)?;
+    let citations = graph::load(dir)?;
+    let jobs = *sub_args.get_one::<usize>("jobs").expect("Has a default");
+    let checked = links::check(links::links(&citations), jobs, &config.cache_root);
+    let mut dead = 0;
+    for link in checked.iter().filter(|link| !link.status.is_ok()) {
+        dead += 1;
+        match &link.status {
+            links::Status::Dead(status) => println!("{}: {} ({status})", link.key, link.url),
+            links::Status::Unreachable(e) => println!("{}: {}", link.key, e),
+            links::Status::Ok(_) => {}
         }
     }
+    println!("{} links checked, {dead} dead", checked.len());
+    if dead > 0 {
+        return Err(CiteprocError::citation(format!(
+            "{dead} cited links are dead"
+        )));
+    }
+    Ok(())
 }
 
-type PandocConfig = HashMap<String, PandocSetting>;
+fn handle_bench(sub_args: &ArgMatches) -> Result<(), Error> {
+    let dir = sub_args.get_one::<String>("dir").expect("Has a default");
+    let renderer = sub_args
+        .get_one::<String>("renderer")
+        .expect("Has a default");
+    let iterations = *sub_args
+        .get_one::<usize>("iterations")
+        .expect("Has a default");
+    let profiles = profile::bench(Path::new(dir), renderer, iterations.max(1))?;
+    print!("{}", profile::summary(&profiles));
+    Ok(())
+}
 
-/// The actual implementation of the `Pandoc` preprocessor.
-/// This would usually go in your main `lib.rs` file.
-mod pandoc_lib {
-    use std::io::Write;
+fn handle_explain(sub_args: &ArgMatches) -> Result<(), Error> {
+    let dir = sub_args.get_one::<String>("dir").expect("Has a default");
+    let renderer = sub_args
+        .get_one::<String>("renderer")
+        .expect("Has a default");
+    print!("{}", explain::explain(Path::new(dir), renderer)?);
+    Ok(())
+}
 
-    use mdbook::BookItem;
+fn handle_refresh(sub_args: &ArgMatches) -> Result<(), Error> {
+    let dir = sub_args.get_one::<String>("dir").expect("Has a default");
+    let snapshots = refresh::refresh(Path::new(dir))?;
+    println!("Refreshed the remote caches ({snapshots} archived links)");
+    Ok(())
+}
 
-    use super::*;
+fn handle_vendor(sub_args: &ArgMatches) -> Result<(), Error> {
+    let dir = Path::new(sub_args.get_one::<String>("dir").expect("Has a default"));
+    let vendored = vendor::vendor(dir)?;
+    if let Some(path) = &vendored.remote_bibliography {
+        println!("Vendored the remote bibliography as {}", path.display());
+    }
+    println!(
+        "Vendored {} styles and {} archived links into {}",
+        vendored.styles,
+        vendored.snapshots,
+        dir.join(vendor::DIR).display()
+    );
+    Ok(())
+}
+
+fn handle_cache(sub_args: &ArgMatches) -> Result<(), Error> {
+    let (command, sub_args) = sub_args.subcommand().expect("A subcommand is required");
+    let dir = Path::new(sub_args.get_one::<String>("dir").expect("Has a default"));
+    let config = check::load_config(dir)?;
+    if command == "stats" {
+        println!("{}", gc::stats(&config.cache_root));
+        return Ok(());
+    }
+    let max_size = match sub_args.get_one::<String>("max-size") {
+        Some(size) => Some(config::parse_size(size).ok_or_else(|| {
+            CiteprocError::config(format!("--max-size {size:?} isn't a size like \"500MB\""))
+        })? as u64),
+        None => config.cache_max_size,
+    };
+    let max_age = match sub_args.get_one::<u64>("max-age-days") {
+        Some(days) => Some(Duration::from_secs(days * 24 * 60 * 60)),
+        None => config.cache_max_age,
+    };
+    let all = sub_args.get_flag("all");
+    if !all && max_size.is_none() && max_age.is_none() {
+        return Err(CiteprocError::config(
+            "nothing to prune to: set cache-max-size or cache-max-age-days, \
+             or pass --max-size, --max-age-days or --all",
+        ));
+    }
+    let cleaned = gc::clean(&config.cache_root, max_size, max_age, all)?;
+    println!(
+        "Removed {} files ({} bytes) from {}",
+        cleaned.files,
+        cleaned.bytes,
+        config.cache_root.display()
+    );
+    Ok(())
+}
 
-    pub struct Pandoc;
+fn handle_migrate_keys(sub_args: &ArgMatches) -> Result<(), Error> {
+    let dir = Path::new(sub_args.get_one::<String>("dir").expect("Has a default"));
+    let map = match sub_args.get_one::<String>("map") {
+        Some(map) => migrate::load_map(Path::new(map))?,
+        None => check::load_config(dir)?.aliases,
+    };
+    let book = mdbook::Config::from_disk(dir.join("book.toml"))?;
+    let dry_run = sub_args.get_flag("dry-run");
+    let migrated = migrate::migrate(
+        &dir.join(&book.book.src),
+        &map,
+        dry_run,
+        sub_args.get_flag("backup"),
+    )?;
+    for file in &migrated {
+        println!("{}: {} keys", file.path.display(), file.renamed);
+    }
+    let verb = if dry_run {
+        "would be renamed"
+    } else {
+        "renamed"
+    };
+    println!(
+        "{} keys in {} files {verb}",
+        migrated.iter().map(|file| file.renamed).sum::<usize>(),
+        migrated.len()
+    );
+    Ok(())
+}
 
-    impl Pandoc {
-        pub fn new() -> Self {
-            Self
+fn handle_lint_config(sub_args: &ArgMatches) -> Result<(), Error> {
+    let dir = Path::new(sub_args.get_one::<String>("dir").expect("Has a default"));
+    let linted = lint_config::lint(dir)?;
+    for finding in &linted.findings {
+        println!("{finding}");
+    }
+    if linted.fixed == linted.original {
+        if linted.findings.is_empty() {
+            println!("book.toml is fine");
         }
+        return Ok(());
+    }
+    print!("{}", linted.diff());
+    if sub_args.get_flag("fix") {
+        fs::write(dir.join("book.toml"), &linted.fixed)?;
+        println!("book.toml fixed");
+    } else {
+        println!("Run with --fix to make these changes");
     }
+    Ok(())
+}
 
-    impl Preprocessor for Pandoc {
-        fn name(&self) -> &str {
-            "citeproc"
-        }
+#[cfg(unix)]
+fn handle_daemon(sub_args: &ArgMatches) -> Result<(), Error> {
+    let socket = sub_args
+        .get_one::<String>("socket")
+        .expect("Required argument");
+    let preprocessor = Pandoc::new().quiet(true).in_memory_cache();
+    daemon::serve(Path::new(socket), &preprocessor)
+}
 
-        fn run(&self, ctx: &PreprocessorContext, mut book: Book) -> Result<Book, Error> {
-            let res: Option<Result<BookItem, Error>> = None;
-            let mut config: PandocConfig = HashMap::new();
-
-            let mut from = "--from=markdown_strict".to_string();
-            let mut to = "--to=markdown_strict".to_string();
-
-            let bibliography_config;
-
-            if let Some(table) = ctx.config.get_preprocessor(self.name()) {
-                let mut parse_setting = |setting: &str, config: &mut PandocConfig| {
-                    if let Some(option) = table.get(setting) {
-                        from += &format!("+{setting}").to_string();
-                        let action = match option.as_str() {
-                            None => PandocSetting::default(),
-                            Some("preserve") => PandocSetting::Preserve,
-                            Some("transpile") => PandocSetting::Transpile,
-                            Some(_) => {
-                                panic!("{setting} must be either \"transpile\" or \"preserve\"");
-                            }
-                        };
-                        match action {
-                            PandocSetting::Preserve => {
-                                to += &format!("+{setting}");
-                            }
-                            PandocSetting::Transpile => {
-                                to += &format!("-{setting}");
-                            }
-                        };
-                        if let Some(value) = config.get_mut(setting) {
-                            *value = action;
-                        } else {
-                            config.insert(setting.to_string(), action);
-                        }
-                    };
-                };
-
-                parse_setting("backtick_code_blocks", &mut config);
-                parse_setting("bracketed_spans", &mut config);
-                parse_setting("citations", &mut config);
-                parse_setting("definition_lists", &mut config);
-                parse_setting("emoji", &mut config);
-                parse_setting("fenced_code_attributes", &mut config);
-                parse_setting("fenced_code_blocks", &mut config);
-                parse_setting("fenced_divs", &mut config);
-                parse_setting("footnotes", &mut config);
-                parse_setting("hard_line_breaks", &mut config);
-                parse_setting("inline_notes", &mut config);
-                parse_setting("link_attributes", &mut config);
-                parse_setting("mark", &mut config);
-                parse_setting("markdown_in_html_blocks", &mut config);
-                parse_setting("task_lists", &mut config);
-
-                let config = config;
-
-                bibliography_config = if let Some(PandocSetting::Transpile) =
-                    config.get("citations")
-                {
-                    if let (Some(bib_style), Some(bib)) = (
-                        table.get("bibliography-style").and_then(|x| x.as_str()),
-                        table.get("bibliography").and_then(|x| x.as_str()),
-                    ) {
-                        Some(BibliographyConfig::new(
-                            bib.to_string(),
-                            bib_style.to_string(),
-                        ))
-                    } else {
-                        panic!("citations set to transpile so bibliography-style and bibliography option must be provided!")
-                    }
-                } else {
-                    None
-                };
-            } else {
-                panic!("No config table for {} preprocessor", self.name());
-            }
+#[cfg(not(unix))]
+fn handle_daemon(_sub_args: &ArgMatches) -> Result<(), Error> {
+    Err(error::CiteprocError::config(
+        "daemon mode is only available on unix platforms",
+    ))
+}
 
-            book.for_each_mut(|item| {
-                if let Some(Err(_)) = res {
-                    return;
-                }
-                if let BookItem::Chapter(ref mut chapter) = *item {
-                    let mut process = process::Command::new("pandoc");
-                    let command = process.arg(from.clone()).arg(to.clone());
-                    let command = if let Some(bibliography_config) = &bibliography_config {
-                        command
-                            .arg(format!("--csl={}", bibliography_config.bibliography_style))
-                            .arg(format!(
-                                "--bibliography={}",
-                                bibliography_config.bibliography
-                            ))
-                            .arg("--metadata=link-citations")
-                            .arg("--metadata=link-bibliography")
-                            .arg("--citeproc")
-                    } else {
-                        command
-                    };
-                    let mut process = command
-                        .stdin(process::Stdio::piped())
-                        .stdout(process::Stdio::piped())
-                        .spawn()
-                        .expect("failed to spawn process");
-                    process
-                        .stdin
-                        .take()
-                        .expect("failed to open pandoc stdin")
-                        .write_all(chapter.content.as_bytes())
-                        .expect("failed to write to pandoc stdin");
-                    let output = process
-                        .wait_with_output()
-                        .expect("failed to wait on pandoc");
-                    chapter.content = String::from_utf8_lossy(output.stdout.as_slice()).to_string();
-                }
-            });
-
-            Ok(book)
-        }
+fn handle_supports(pre: &dyn Preprocessor, sub_args: &ArgMatches) -> ! {
+    let renderer = sub_args
+        .get_one::<String>("renderer")
+        .expect("Required argument");
+    let supported = pre.supports_renderer(renderer);
 
-        fn supports_renderer(&self, renderer: &str) -> bool {
-            renderer != "not-supported"
-        }
+    // Signals whether the renderer is supported by exiting with 1 or 0.
+    if supported {
+        process::exit(0);
+    } else {
+        process::exit(1);
     }
 }
@@ -0,0 +1,86 @@
+//! A manifest of what each chapter was processed into, for `manifest`:
+//! the SHA-256 of every chapter's content as the renderer was given it, by
+//! its source path and renderer. Deployment tooling can compare it with
+//! the last deploy's and upload only the chapters which changed, rather
+//! than every page a renumbered citation might have touched.
+//!
+//! The html renderer clears its output directory before it writes the
+//! book, so the manifest is best written into `src`, from where mdbook
+//! copies it into the build directory along with the book's other files.
+//! It's only rewritten when a hash changes, so `mdbook serve` doesn't keep
+//! rebuilding for it.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use mdbook::book::Book;
+use mdbook::errors::Error;
+use mdbook::BookItem;
+
+use crate::cache::sha256_hex;
+
+/// Each renderer's chapter hashes, by the chapter's source path.
+type Manifest = BTreeMap<String, BTreeMap<String, String>>;
+
+/// Records the hashes of `book`'s chapters for `renderer` in the manifest
+/// at `path`, keeping other renderers'.
+pub fn write(path: &Path, renderer: &str, book: &Book) -> Result<(), Error> {
+    let previous = fs::read_to_string(path).ok();
+    let mut manifest: Manifest = previous
+        .as_deref()
+        .and_then(|json| serde_json::from_str(json).ok())
+        .unwrap_or_default();
+    manifest.insert(renderer.to_string(), hashes(book));
+    let json = serde_json::to_string_pretty(&manifest)? + "\n";
+    if previous.as_deref() != Some(json.as_str()) {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, json)?;
+    }
+    Ok(())
+}
+
+fn hashes(book: &Book) -> BTreeMap<String, String> {
+    book.iter()
+        .filter_map(|item| match item {
+            BookItem::Chapter(chapter) => chapter.source_path.as_ref().map(|source| {
+                let source = source.to_string_lossy().replace('\\', "/");
+                (source, sha256_hex(&chapter.content))
+            }),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use mdbook::book::Chapter;
+
+    use super::*;
+
+    #[test]
+    fn hashes_each_chapter_by_renderer() {
+        let path =
+            std::env::temp_dir().join(format!("citeproc-manifest-{}.json", std::process::id()));
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "One",
+            "One (Smith 2020)".into(),
+            "one.md",
+            vec![],
+        ));
+        book.push_item(Chapter::new_draft("Draft", vec![]));
+        write(&path, "html", &book).unwrap();
+        write(&path, "latex", &Book::new()).unwrap();
+
+        let manifest: Manifest = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(
+            manifest["html"],
+            BTreeMap::from([("one.md".to_string(), sha256_hex("One (Smith 2020)"))])
+        );
+        assert!(manifest["latex"].is_empty());
+        fs::remove_file(path).unwrap();
+    }
+}
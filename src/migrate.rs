@@ -0,0 +1,122 @@
+//! `mdbook-citeproc migrate-keys`: rewrites citation keys in a book's
+//! sources, to finish a change of key scheme that `aliases` only papers
+//! over.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use mdbook::errors::Error;
+
+use crate::citation;
+use crate::error::CiteprocError;
+
+/// A source file whose citations were (or would be) rewritten.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Migrated {
+    pub path: PathBuf,
+    /// How many citation keys were renamed.
+    pub renamed: usize,
+}
+
+/// Reads a key map: a TOML table of old keys to new ones.
+pub fn load_map(path: &Path) -> Result<BTreeMap<String, String>, Error> {
+    let contents = fs::read_to_string(path)?;
+    let table: toml::value::Table = toml::from_str(&contents)
+        .map_err(|e| CiteprocError::config(format!("{}: {e}", path.display())))?;
+    table
+        .into_iter()
+        .map(|(old, new)| match new {
+            toml::Value::String(new) => Ok((old, new)),
+            _ => Err(CiteprocError::config(format!(
+                "{}: {old} must map to a citation key",
+                path.display()
+            ))),
+        })
+        .collect()
+}
+
+/// Renames the keys in `map` in every markdown file under `src`. Nothing is
+/// written with `dry_run`; with `backup` each changed file is first copied
+/// to `<file>.bak`.
+pub fn migrate(
+    src: &Path,
+    map: &BTreeMap<String, String>,
+    dry_run: bool,
+    backup: bool,
+) -> Result<Vec<Migrated>, Error> {
+    let mut files = Vec::new();
+    markdown_files(src, &mut files)?;
+    files.sort();
+
+    let mut migrated = Vec::new();
+    for path in files {
+        let content = fs::read_to_string(&path)?;
+        let renamed = citation::parse(&content)
+            .iter()
+            .flat_map(|citation| &citation.items)
+            .filter(|item| map.contains_key(&item.key))
+            .count();
+        if renamed == 0 {
+            continue;
+        }
+        if !dry_run {
+            if backup {
+                let mut backup = path.clone().into_os_string();
+                backup.push(".bak");
+                fs::copy(&path, backup)?;
+            }
+            let content = citation::rename_keys(&content, |key| map.get(key).map(String::as_str));
+            fs::write(&path, content)?;
+        }
+        migrated.push(Migrated { path, renamed });
+    }
+    Ok(migrated)
+}
+
+fn markdown_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<(), Error> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            markdown_files(&path, files)?;
+        } else if path.extension().is_some_and(|e| e == "md") {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rewrites_sources_with_backups() {
+        let dir = std::env::temp_dir().join(format!("citeproc-migrate-{}", std::process::id()));
+        fs::create_dir_all(dir.join("part")).unwrap();
+        fs::write(dir.join("one.md"), "[@old; @keep] and @old").unwrap();
+        fs::write(dir.join("part/two.md"), "Nothing to see").unwrap();
+        let map = BTreeMap::from([("old".to_string(), "new".to_string())]);
+
+        let dry = migrate(&dir, &map, true, true).unwrap();
+        assert_eq!(
+            dry,
+            [Migrated {
+                path: dir.join("one.md"),
+                renamed: 2
+            }]
+        );
+        assert!(!dir.join("one.md.bak").exists());
+
+        migrate(&dir, &map, false, true).unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.join("one.md")).unwrap(),
+            "[@new; @keep] and @new"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("one.md.bak")).unwrap(),
+            "[@old; @keep] and @old"
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,189 @@
+//! What the first pass over a book collects: every chapter's citations and
+//! front matter, before anything is rendered.
+//!
+//! Rendering a chapter can depend on the rest of the book (book-wide
+//! disambiguation, for one), so the preprocessor collects this for the
+//! whole book first and renders chapters second.
+//!
+//! The model serializes, for tools which want to work with a book's
+//! citations without rendering it.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use mdbook::book::Book;
+use mdbook::BookItem;
+use serde::{Deserialize, Serialize};
+
+use crate::bibliography::{Entry, Library};
+use crate::citation::{self, Citation};
+use crate::front_matter;
+
+/// The citations in a book, by chapter in book order.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BookCitations {
+    pub chapters: Vec<ChapterCitations>,
+    /// The bibliography entries of the cited keys, once
+    /// [resolved](BookCitations::resolve). Keys without an entry are missing.
+    pub entries: BTreeMap<String, Entry>,
+}
+
+/// The citations in one chapter.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChapterCitations {
+    pub name: String,
+    /// The chapter's source, relative to the book's `src` directory.
+    pub path: Option<PathBuf>,
+    /// The citations in the chapter's text, with spans into its content
+    /// (front matter included).
+    pub citations: Vec<Citation>,
+    /// The chapter's front matter keys.
+    pub front_matter: BTreeMap<String, String>,
+}
+
+impl BookCitations {
+    /// Collects the citations of every chapter in `book`.
+    pub fn collect(book: &Book) -> Self {
+        let chapters = book
+            .iter()
+            .filter_map(|item| match item {
+                BookItem::Chapter(chapter) => Some(chapter),
+                _ => None,
+            })
+            .map(|chapter| {
+                let content = &chapter.content;
+                let (offset, body, front_matter) = match front_matter::split(content) {
+                    Some(split) => (
+                        split.front_matter.len(),
+                        split.body,
+                        front_matter::parse(split.front_matter),
+                    ),
+                    None => (0, content.as_str(), BTreeMap::new()),
+                };
+                let citations = citation::parse(body)
+                    .into_iter()
+                    .map(|mut citation| {
+                        citation.span = citation.span.start + offset..citation.span.end + offset;
+                        citation
+                    })
+                    .collect();
+                ChapterCitations {
+                    name: chapter.name.clone(),
+                    path: chapter.source_path.clone(),
+                    citations,
+                    front_matter,
+                }
+            })
+            .collect();
+        Self {
+            chapters,
+            entries: BTreeMap::new(),
+        }
+    }
+
+    /// Looks up every cited key in `library`.
+    pub fn resolve(&mut self, library: &Library) {
+        self.entries = self
+            .keys()
+            .into_iter()
+            .filter_map(|key| Some((key.clone(), library.get(&key)?.clone())))
+            .collect();
+    }
+
+    /// The cited keys which [`resolve`](BookCitations::resolve) found no
+    /// entry for.
+    pub fn unresolved(&self) -> Vec<String> {
+        let mut keys = self.keys();
+        keys.retain(|key| !self.entries.contains_key(key));
+        keys
+    }
+
+    /// Every key cited anywhere in the book, in order of first citation.
+    pub fn keys(&self) -> Vec<String> {
+        let mut keys = Vec::new();
+        for key in self.chapters.iter().flat_map(ChapterCitations::keys) {
+            if !keys.contains(&key) {
+                keys.push(key);
+            }
+        }
+        keys
+    }
+}
+
+impl ChapterCitations {
+    /// The keys the chapter cites, in its text or its front matter's
+    /// `nocite`, in order and with repeats.
+    pub fn keys(&self) -> Vec<String> {
+        let nocite = self
+            .front_matter
+            .get("nocite")
+            .map(|nocite| citation::parse(nocite))
+            .unwrap_or_default();
+        self.citations
+            .iter()
+            .chain(&nocite)
+            .flat_map(|citation| &citation.items)
+            .map(|item| item.key.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mdbook::book::Chapter;
+
+    use super::*;
+
+    #[test]
+    fn collects_every_chapter() {
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "One",
+            "---\nnocite: \"@extra\"\n---\nSee [@smith; @jones].\n".into(),
+            "one.md",
+            Vec::new(),
+        ));
+        book.push_item(Chapter::new(
+            "Two",
+            "@jones again".into(),
+            "two.md",
+            Vec::new(),
+        ));
+
+        let citations = BookCitations::collect(&book);
+        let one = &citations.chapters[0];
+        assert_eq!(one.path, Some(PathBuf::from("one.md")));
+        assert_eq!(one.citations[0].span, 29..45);
+        assert_eq!(citations.keys(), ["smith", "jones", "extra"]);
+    }
+
+    #[test]
+    fn resolves_and_serializes() {
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "One",
+            "[@smith; -@missing]".into(),
+            "one.md",
+            Vec::new(),
+        ));
+        let mut citations = BookCitations::collect(&book);
+        citations.resolve(&Library::new([Entry {
+            key: "smith".into(),
+            kind: "book".into(),
+            fields: BTreeMap::new(),
+        }]));
+        assert_eq!(citations.unresolved(), ["missing"]);
+
+        let json = serde_json::to_value(&citations).unwrap();
+        assert_eq!(json["chapters"][0]["citations"][0]["mode"], "bracketed");
+        assert_eq!(
+            json["chapters"][0]["citations"][0]["items"][1]["suppress_author"],
+            true
+        );
+        assert_eq!(json["entries"]["smith"]["kind"], "book");
+        assert_eq!(
+            serde_json::from_value::<BookCitations>(json).unwrap(),
+            citations
+        );
+    }
+}
@@ -0,0 +1,216 @@
+//! Authors told apart by their ORCID iDs, for `orcid-names`: every name
+//! an iD is written under becomes the same one, the most common (and then
+//! the fullest) of them, and names which differ are warned about.
+//!
+//! iDs are read from the `orcid` field, in the order of the authors (an
+//! author without one is left empty, as in `0000-0002-1825-0097, ,
+//! 0000-0001-5109-3700`), or from Web of Science's `orcid-numbers`, which
+//! pairs each iD with a name: `Smith, J/0000-0002-1825-0097; …`.
+
+use std::collections::BTreeMap;
+
+use crate::bibliography::{self, Entry, Name};
+
+/// How each iD's author is to be written.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Names {
+    canonical: BTreeMap<String, String>,
+    /// A line for each iD written under more than one name.
+    pub conflicts: Vec<String>,
+}
+
+/// Collects the names each iD is written under in `entries`.
+pub fn names(entries: &[Entry]) -> Names {
+    // Every way each iD's name is written, with the keys it's in.
+    let mut variants: BTreeMap<String, Vec<(String, &str)>> = BTreeMap::new();
+    for entry in entries {
+        let Some(authors) = entry.fields.get("author") else {
+            continue;
+        };
+        let authors = bibliography::split_names(authors);
+        for (author, id) in authors.iter().zip(orcids(entry, &authors)) {
+            if let Some(id) = id {
+                variants
+                    .entry(id)
+                    .or_default()
+                    .push((author.to_string(), &entry.key));
+            }
+        }
+    }
+
+    let mut names = Names::default();
+    for (id, written) in variants {
+        let mut forms: Vec<(Name, &str, Vec<&str>)> = Vec::new();
+        for (author, key) in &written {
+            let parsed = bibliography::names(author).pop().unwrap_or_default();
+            match forms.iter_mut().find(|(name, _, _)| *name == parsed) {
+                Some((_, _, keys)) => keys.push(key),
+                None => forms.push((parsed, author, vec![key])),
+            }
+        }
+        let Some((_, canonical, _)) = forms.iter().max_by_key(|(name, author, keys)| {
+            (keys.len(), name.given.len(), std::cmp::Reverse(*author))
+        }) else {
+            continue;
+        };
+        if forms.len() > 1 {
+            let spellings: Vec<String> = forms
+                .iter()
+                .map(|(_, author, keys)| format!("`{author}` in {}", keys.join(", ")))
+                .collect();
+            names.conflicts.push(format!(
+                "ORCID {id} is written {}; they're all written `{canonical}`",
+                spellings.join(" and ")
+            ));
+        }
+        names.canonical.insert(id, canonical.to_string());
+    }
+    names
+}
+
+impl Names {
+    pub fn is_empty(&self) -> bool {
+        self.canonical.is_empty()
+    }
+
+    /// Writes each author of `entry` with an iD as the iD's name.
+    pub fn apply(&self, entry: &mut Entry) {
+        let Some(authors) = entry.fields.get("author") else {
+            return;
+        };
+        let split = bibliography::split_names(authors);
+        let ids = orcids(entry, &split);
+        let renamed: Vec<&str> = split
+            .iter()
+            .enumerate()
+            .map(|(i, author)| {
+                ids.get(i)
+                    .and_then(Option::as_ref)
+                    .and_then(|id| self.canonical.get(id))
+                    .map_or(*author, String::as_str)
+            })
+            .collect();
+        if renamed != split {
+            let renamed = renamed.join(" and ");
+            entry.fields.insert("author".into(), renamed);
+        }
+    }
+}
+
+/// The iD of each of `authors` of `entry`, in order.
+fn orcids(entry: &Entry, authors: &[&str]) -> Vec<Option<String>> {
+    if let Some(field) = entry.fields.get("orcid") {
+        return field
+            .replace(" and ", ";")
+            .split([',', ';'])
+            .map(normalized)
+            .collect();
+    }
+    let mut ids = vec![None; authors.len()];
+    let Some(numbers) = entry.fields.get("orcid-numbers") else {
+        return ids;
+    };
+    let families: Vec<String> = authors
+        .iter()
+        .map(|author| {
+            let name = bibliography::names(author).pop().unwrap_or_default();
+            name.family.to_lowercase()
+        })
+        .collect();
+    for pair in numbers.split(';') {
+        let Some((name, id)) = pair.rsplit_once('/') else {
+            continue;
+        };
+        let family = bibliography::names(name.trim())
+            .pop()
+            .unwrap_or_default()
+            .family
+            .to_lowercase();
+        if let Some(i) = families.iter().position(|f| !f.is_empty() && *f == family) {
+            ids[i] = normalized(id);
+        }
+    }
+    ids
+}
+
+/// `id` as a bare iD, like `0000-0002-1825-0097`, if it is one (with or
+/// without `https://orcid.org/` before it).
+fn normalized(id: &str) -> Option<String> {
+    let id = id.trim().trim_matches(['{', '}']);
+    let id = id
+        .trim_start_matches("https://")
+        .trim_start_matches("http://")
+        .trim_start_matches("orcid.org/")
+        .to_uppercase();
+    let groups: Vec<&str> = id.split('-').collect();
+    let valid = groups.len() == 4
+        && groups.iter().enumerate().all(|(i, group)| {
+            group.len() == 4
+                && group
+                    .chars()
+                    .enumerate()
+                    .all(|(j, c)| c.is_ascii_digit() || (i == 3 && j == 3 && c == 'X'))
+        });
+    valid.then_some(id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(key: &str, fields: &[(&str, &str)]) -> Entry {
+        Entry {
+            key: key.into(),
+            kind: "article-journal".into(),
+            fields: fields
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn writes_each_orcid_one_way() {
+        let mut entries = vec![
+            entry(
+                "a",
+                &[
+                    ("author", "Smith, J. and Doe, Jane"),
+                    ("orcid", "0000-0002-1825-0097, "),
+                ],
+            ),
+            entry(
+                "b",
+                &[
+                    ("author", "Smith, John"),
+                    ("orcid", "https://orcid.org/0000-0002-1825-0097"),
+                ],
+            ),
+            entry(
+                "c",
+                &[
+                    ("author", "Roe, R. and John Smith"),
+                    ("orcid-numbers", "Smith, John/0000-0002-1825-0097"),
+                ],
+            ),
+        ];
+        let names = names(&entries);
+        assert_eq!(
+            names.conflicts,
+            [
+                "ORCID 0000-0002-1825-0097 is written `Smith, J.` in a and `Smith, John` in b, c; \
+              they're all written `Smith, John`"
+            ]
+        );
+        for entry in &mut entries {
+            names.apply(entry);
+        }
+        assert_eq!(entries[0].fields["author"], "Smith, John and Doe, Jane");
+        assert_eq!(entries[2].fields["author"], "Roe, R. and Smith, John");
+        assert_eq!(
+            normalized("0000-0002-1825-009x").as_deref(),
+            Some("0000-0002-1825-009X")
+        );
+        assert_eq!(normalized("12345"), None);
+    }
+}
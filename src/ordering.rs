@@ -0,0 +1,189 @@
+//! Warnings about where we run relative to other preprocessors which are
+//! known to interact badly with us.
+//!
+//! mdbook's preprocessor protocol has no way for a preprocessor to declare
+//! its own ordering, so all we can do is tell the user which `before` or
+//! `after` keys to add.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use toml::Value;
+
+use crate::config::Config;
+
+/// mdbook's built-in preprocessors, which run unless
+/// `build.use-default-preprocessors` is off.
+const DEFAULT_PREPROCESSORS: &[&str] = &["links", "index"];
+
+/// Where we have to run relative to another preprocessor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Relation {
+    Before,
+    After,
+}
+
+/// Preprocessors we must run before or after, and why.
+const RULES: &[(&str, Relation, &str)] = &[
+    (
+        "links",
+        Relation::After,
+        "citations in {{#include}}d files wouldn't be rendered",
+    ),
+    (
+        "katex",
+        Relation::Before,
+        "pandoc would rewrite the HTML katex renders math into",
+    ),
+    (
+        "admonish",
+        Relation::After,
+        "citations inside admonish blocks wouldn't be rendered",
+    ),
+];
+
+/// The order mdbook will run the preprocessors configured in `book` in.
+///
+/// This mirrors mdbook's own resolution: a topological sort of the
+/// `before` and `after` keys, with ties broken by name.
+pub fn preprocessor_order(book: &mdbook::Config) -> Vec<String> {
+    let mut names = BTreeSet::new();
+    if book.build.use_default_preprocessors {
+        names.extend(DEFAULT_PREPROCESSORS.iter().map(|name| name.to_string()));
+    }
+    let table = book.get("preprocessor").and_then(Value::as_table);
+    if let Some(table) = table {
+        names.extend(table.keys().cloned());
+    }
+
+    // For every preprocessor, the ones which have to run before it.
+    let mut predecessors: BTreeMap<String, BTreeSet<String>> = names
+        .iter()
+        .map(|name| (name.clone(), BTreeSet::new()))
+        .collect();
+    let list = |value: Option<&Value>| -> Vec<String> {
+        value
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+            .filter_map(Value::as_str)
+            .filter(|name| names.contains(*name))
+            .map(str::to_string)
+            .collect()
+    };
+    for (name, config) in table.into_iter().flatten() {
+        for later in list(config.get("before")) {
+            predecessors.entry(later).or_default().insert(name.clone());
+        }
+        for earlier in list(config.get("after")) {
+            predecessors
+                .entry(name.clone())
+                .or_default()
+                .insert(earlier);
+        }
+    }
+
+    let mut order = Vec::new();
+    loop {
+        let ready: Vec<String> = predecessors
+            .iter()
+            .filter(|(_, before)| before.is_empty())
+            .map(|(name, _)| name.clone())
+            .collect();
+        if ready.is_empty() {
+            // Either done, or a cycle, which mdbook reports itself.
+            return order;
+        }
+        for name in &ready {
+            predecessors.remove(name);
+        }
+        for before in predecessors.values_mut() {
+            for name in &ready {
+                before.remove(name);
+            }
+        }
+        order.extend(ready);
+    }
+}
+
+/// Warnings for every known-bad ordering between us and the other
+/// preprocessors in `book`.
+pub fn warnings(book: &mdbook::Config, config: &Config) -> Vec<String> {
+    let order = preprocessor_order(book);
+    let position = |name: &str| order.iter().position(|n| n == name);
+    let Some(citeproc) = position("citeproc") else {
+        return Vec::new();
+    };
+
+    let mut warnings = Vec::new();
+    for &(other, relation, reason) in RULES {
+        // Compatibility mode handles admonish blocks before admonish does.
+        if other == "admonish" && config.admonish == Some(true) {
+            continue;
+        }
+        let Some(position) = position(other) else {
+            continue;
+        };
+        let (wrong, key, actual) = match relation {
+            Relation::Before => (position < citeproc, "before", "after"),
+            Relation::After => (position > citeproc, "after", "before"),
+        };
+        if wrong {
+            warnings.push(format!(
+                "citeproc runs {actual} {other}, so {reason}; \
+                 add `{key} = [\"{other}\"]` to [preprocessor.citeproc]"
+            ));
+        }
+    }
+    warnings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn book(toml: &str) -> mdbook::Config {
+        toml.parse().unwrap()
+    }
+
+    #[test]
+    fn ties_are_broken_by_name() {
+        let book = book("[preprocessor.citeproc]\n[preprocessor.katex]\n");
+        assert_eq!(
+            preprocessor_order(&book),
+            ["citeproc", "index", "katex", "links"]
+        );
+    }
+
+    #[test]
+    fn before_and_after_are_respected() {
+        let book = book(
+            "[preprocessor.citeproc]\nafter = [\"links\", \"missing\"]\n\
+             [preprocessor.katex]\nbefore = [\"citeproc\"]\n",
+        );
+        let order = preprocessor_order(&book);
+        let position = |name| order.iter().position(|n| n == name).unwrap();
+        assert!(position("links") < position("citeproc"));
+        assert!(position("katex") < position("citeproc"));
+    }
+
+    #[test]
+    fn warns_about_bad_orderings() {
+        let book = book(
+            "[preprocessor.citeproc]\nadmonish = false\n\
+             [preprocessor.admonish]\nafter = [\"citeproc\"]\n",
+        );
+        let mut config = Config::from_table(
+            book.get_preprocessor("citeproc").unwrap(),
+            std::path::Path::new("."),
+        )
+        .unwrap();
+        config.resolve_admonish(&book);
+        let warnings = warnings(&book, &config);
+        assert_eq!(warnings.len(), 2, "{warnings:?}");
+        assert!(warnings[0].contains("after = [\"links\"]"));
+        assert!(warnings[1].contains("after = [\"admonish\"]"));
+
+        config.admonish = Some(true);
+        assert_eq!(super::warnings(&book, &config).len(), 1);
+    }
+}
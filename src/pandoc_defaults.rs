@@ -0,0 +1,97 @@
+//! A pandoc defaults file, as `pandoc-defaults` names, for books whose
+//! sources are built with pandoc or Quarto as well. Its `bibliography`,
+//! `csl` and `metadata` are used wherever `book.toml` doesn't set them, so
+//! they're only written down once.
+//!
+//! Paths in it are relative to the book's root, as they would be to pandoc
+//! run from there, except that `${.}` is the directory the file is in, as
+//! it is for pandoc.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use mdbook::errors::Error;
+
+use crate::error::CiteprocError;
+use crate::front_matter;
+
+/// What's taken from a defaults file.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PandocDefaults {
+    pub bibliography: Vec<String>,
+    pub csl: Option<String>,
+    /// `metadata.lang`, which is the locale.
+    pub lang: Option<String>,
+    /// The rest of `metadata`.
+    pub metadata: BTreeMap<String, String>,
+}
+
+impl PandocDefaults {
+    /// Reads the defaults file at `path`, relative to `root`.
+    pub fn read(root: &Path, path: &str) -> Result<Self, Error> {
+        let yaml = fs::read_to_string(root.join(path)).map_err(|e| {
+            CiteprocError::config(format!("failed to read pandoc-defaults {path}: {e}"))
+        })?;
+        let dir = Path::new(path)
+            .parent()
+            .map(|dir| dir.to_string_lossy().replace('\\', "/"))
+            .unwrap_or_default();
+        Ok(Self::parse(&yaml, &dir))
+    }
+
+    fn parse(yaml: &str, dir: &str) -> Self {
+        let resolve = |path: &str| match dir {
+            "" => path.replace("${.}/", "").replace("${.}", "."),
+            dir => path.replace("${.}", dir),
+        };
+        let keys = front_matter::parse_yaml(yaml);
+        let mut metadata = front_matter::nested(yaml, "metadata")
+            .map(|metadata| front_matter::parse_yaml(&metadata))
+            .unwrap_or_default();
+        // Pandoc takes these from the metadata too.
+        let bibliography = keys
+            .get("bibliography")
+            .cloned()
+            .or(metadata.remove("bibliography"));
+        let csl = keys.get("csl").cloned().or(metadata.remove("csl"));
+        metadata.remove("bibliography");
+        metadata.remove("csl");
+        Self {
+            bibliography: bibliography
+                .iter()
+                .flat_map(|paths| paths.split(", "))
+                .map(resolve)
+                .collect(),
+            csl: csl.as_deref().map(resolve),
+            lang: metadata.remove("lang"),
+            metadata,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn takes_citation_settings_from_defaults() {
+        let yaml = "---\nfrom: markdown\nbibliography:\n  - ${.}/refs.bib\n  - shared.bib\n\
+                    metadata:\n  csl: ${.}/apa.csl\n  lang: en-GB\n  link-citations: true\n";
+        assert_eq!(
+            PandocDefaults::parse(yaml, "pandoc"),
+            PandocDefaults {
+                bibliography: vec!["pandoc/refs.bib".into(), "shared.bib".into()],
+                csl: Some("pandoc/apa.csl".into()),
+                lang: Some("en-GB".into()),
+                metadata: [("link-citations".to_string(), "true".to_string())].into(),
+            }
+        );
+        assert_eq!(
+            PandocDefaults::parse("csl: ${.}/apa.csl\n", "")
+                .csl
+                .as_deref(),
+            Some("apa.csl")
+        );
+    }
+}
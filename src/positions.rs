@@ -0,0 +1,87 @@
+//! `first-citation = "book"`: a work's first citation in the book is its
+//! full one, and later chapters cite it in the short form, rather than
+//! every chapter starting afresh.
+//!
+//! Pandoc only sees one chapter at a time, so the works earlier chapters
+//! cite are cited in a paragraph of their own at the top of the chapter,
+//! which makes every citation of them in the chapter a subsequent one.
+//! The paragraph, and the notes a note style gives it, are taken out of
+//! pandoc's output again.
+
+/// Starts the priming paragraph, so it can be found in the output. A
+/// single word, which pandoc passes through untouched.
+const MARKER: &str = "CITEPROCPRIMEDX";
+
+/// The paragraph citing `keys`, to go before a chapter's text.
+pub fn primer(keys: &[String]) -> String {
+    let items: Vec<String> = keys.iter().map(|key| format!("@{key}")).collect();
+    format!("{MARKER} [{}]\n\n", items.join("; "))
+}
+
+/// `output` without the priming paragraph or its notes.
+pub fn strip(output: &str) -> String {
+    let Some(start) = output.find(MARKER) else {
+        return output.to_string();
+    };
+    let end = output[start..]
+        .find("\n\n")
+        .map_or(output.len(), |i| start + i + 2);
+    let paragraph = &output[start..end];
+    let labels: Vec<&str> = paragraph
+        .match_indices("[^")
+        .filter_map(|(i, _)| {
+            let label = &paragraph[i + 2..];
+            label.find(']').map(|close| &label[..close])
+        })
+        .collect();
+    let rest = format!("{}{}", &output[..start], &output[end..]);
+    if labels.is_empty() {
+        return rest;
+    }
+
+    // A note runs from its `[^label]:` to the next line which isn't
+    // indented or blank.
+    let mut out = String::with_capacity(rest.len());
+    let mut in_note = false;
+    for line in rest.split_inclusive('\n') {
+        let starts_note = labels
+            .iter()
+            .any(|label| line.starts_with(&format!("[^{label}]:")));
+        if starts_note {
+            in_note = true;
+            continue;
+        }
+        if in_note && (line.trim().is_empty() || line.starts_with([' ', '\t'])) {
+            continue;
+        }
+        in_note = false;
+        out.push_str(line);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn takes_the_primer_out_again() {
+        let keys = ["smith2020".to_string(), "adams2018".to_string()];
+        assert_eq!(
+            primer(&keys),
+            "CITEPROCPRIMEDX [@smith2020; @adams2018]\n\n"
+        );
+        assert_eq!(
+            strip("CITEPROCPRIMEDX (Smith 2020; Adams 2018)\n\n# One\n\nText.\n"),
+            "# One\n\nText.\n"
+        );
+        assert_eq!(
+            strip(
+                "CITEPROCPRIMEDX[^1]\n\n# One\n\nText.[^2]\n\n\
+                 [^1]: Smith, *Citations*, 2020.\n\n    More.\n\n[^2]: Smith, 3.\n"
+            ),
+            "# One\n\nText.[^2]\n\n[^2]: Smith, 3.\n"
+        );
+        assert_eq!(strip("No primer.\n"), "No primer.\n");
+    }
+}
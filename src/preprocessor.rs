@@ -0,0 +1,1540 @@
+//! The actual implementation of the `Pandoc` preprocessor.
+
+use std::cmp::Reverse;
+use std::collections::BTreeSet;
+use std::fs;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use mdbook::book::{Book, Chapter};
+use mdbook::errors::Error;
+use mdbook::preprocess::{Preprocessor, PreprocessorContext};
+use mdbook::BookItem;
+
+use crate::admonish;
+use crate::archive;
+use crate::args::Format;
+use crate::audit;
+use crate::backend::{self, CitationBackend};
+use crate::badge;
+use crate::bibliography::{self, Library};
+use crate::bookdown;
+use crate::cache::{self, file_fingerprint, sha256_hex, Cache, Dependencies, MemoryCache};
+use crate::casing;
+use crate::citation;
+use crate::coins;
+use crate::config::{
+    AccessedDefault, BibliographySort, Config, EncodingPolicy, FailureMode, FrontMatterMode,
+    PandocSetting, RawHtml, Schedule, Scope,
+};
+use crate::directives;
+use crate::epub;
+use crate::error::CiteprocError;
+use crate::feed;
+use crate::fields::{self, FieldMarkup};
+use crate::front_matter;
+use crate::gc;
+use crate::http;
+use crate::latex;
+use crate::lint;
+use crate::lock::{self, Lock};
+use crate::manifest;
+use crate::model::{BookCitations, ChapterCitations};
+use crate::orcid;
+use crate::ordering;
+use crate::positions;
+use crate::profile::{ChapterProfile, Profile, Timings};
+use crate::progress::Progress;
+use crate::quarto;
+use crate::raw_html;
+use crate::refs::Bibliography;
+use crate::restyle;
+use crate::source_map::SourceMap;
+use crate::structured_data;
+use crate::style;
+use crate::subprocess;
+use crate::tables;
+use crate::targets;
+use crate::template::EntryTemplate;
+use crate::urls;
+use crate::wiki_links;
+
+pub struct Pandoc {
+    quiet: bool,
+    memory: Option<Mutex<MemoryCache>>,
+    /// Kept alongside `memory`, for the daemon.
+    hot: Option<Mutex<Hot>>,
+    backend: Option<Box<dyn CitationBackend>>,
+    profile: Option<Mutex<Profile>>,
+}
+
+/// What a long-lived process keeps between builds besides chapters, each
+/// with the fingerprint of what it was made from.
+#[derive(Default)]
+struct Hot {
+    /// The parsed bibliographies, by their files' fingerprints.
+    library: Option<(String, Arc<Library>)>,
+    /// The selected backend, by its configuration: a pandoc whose version
+    /// has been asked for, or a compiled wasm module.
+    backend: Option<(String, Arc<dyn CitationBackend + Send>)>,
+}
+
+impl Pandoc {
+    pub fn new() -> Self {
+        Self {
+            quiet: false,
+            memory: None,
+            hot: None,
+            backend: None,
+            profile: None,
+        }
+    }
+
+    /// Uses `backend` for every chapter, ignoring the `backend` option.
+    pub fn backend(mut self, backend: Box<dyn CitationBackend>) -> Self {
+        self.backend = Some(backend);
+        self
+    }
+
+    /// Suppresses progress reporting on stderr.
+    pub fn quiet(mut self, quiet: bool) -> Self {
+        self.quiet = quiet;
+        self
+    }
+
+    /// Keeps processed chapters, the parsed bibliography and the backend in
+    /// memory across calls to `run`, for long-lived processes like the
+    /// daemon.
+    pub fn in_memory_cache(mut self) -> Self {
+        self.memory = Some(Mutex::new(MemoryCache::default()));
+        self.hot = Some(Mutex::new(Hot::default()));
+        self
+    }
+
+    /// The bibliographies of `config`, parsed again only if one of their
+    /// files has changed since the last call.
+    fn library(&self, config: &Config, root: &Path) -> Result<Arc<Library>, Error> {
+        let Some(bibliography) = &config.bibliography else {
+            return Ok(Arc::default());
+        };
+        let paths: Vec<PathBuf> = std::iter::once(root.join(&bibliography.bibliography))
+            .chain(config.extra_bibliographies.iter().cloned())
+            .collect();
+        let load = || -> Result<Library, Error> {
+            let mut entries = Vec::new();
+            for path in &paths {
+                entries.extend(bibliography::load(path)?);
+            }
+            Ok(Library::new(entries))
+        };
+        let Some(hot) = &self.hot else {
+            return Ok(Arc::new(load()?));
+        };
+        let fingerprint: Vec<String> = paths
+            .iter()
+            .map(|path| format!("{}={}", path.display(), file_fingerprint(path)))
+            .collect();
+        let fingerprint = fingerprint.join("\n");
+        let mut hot = hot.lock().expect("hot state poisoned");
+        if let Some((known, library)) = &hot.library {
+            if *known == fingerprint {
+                return Ok(library.clone());
+            }
+        }
+        let library = Arc::new(load()?);
+        hot.library = Some((fingerprint, library.clone()));
+        Ok(library)
+    }
+
+    /// The backend `config` selects, kept from the last call if it's
+    /// configured the same way.
+    fn select_backend(
+        &self,
+        config: &Config,
+        root: &Path,
+        download: bool,
+    ) -> Result<Arc<dyn CitationBackend + Send>, Error> {
+        let Some(hot) = self.hot.as_ref().filter(|_| download) else {
+            return Ok(backend::select(config, root, download)?.into());
+        };
+        let fingerprint = format!(
+            "{:?} {:?} {} {:?} {}",
+            config.backend,
+            config.pandoc_command,
+            config.auto_install_pandoc,
+            config.pandoc_sha256,
+            root.display()
+        );
+        let mut hot = hot.lock().expect("hot state poisoned");
+        if let Some((known, backend)) = &hot.backend {
+            if *known == fingerprint {
+                return Ok(backend.clone());
+            }
+        }
+        let backend: Arc<dyn CitationBackend + Send> =
+            backend::select(config, root, download)?.into();
+        hot.backend = Some((fingerprint, backend.clone()));
+        Ok(backend)
+    }
+
+    /// Records where the time goes in each call to `run`, for
+    /// [`Pandoc::take_profile`].
+    pub fn profile(mut self) -> Self {
+        self.profile = Some(Mutex::new(Profile::default()));
+        self
+    }
+
+    /// The profile of the last call to `run`, if profiling.
+    pub fn take_profile(&self) -> Option<Profile> {
+        let profile = self.profile.as_ref()?;
+        Some(std::mem::take(
+            &mut *profile.lock().expect("profile poisoned"),
+        ))
+    }
+}
+
+impl Default for Pandoc {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Preprocessor for Pandoc {
+    fn name(&self) -> &str {
+        "citeproc"
+    }
+
+    fn run(&self, ctx: &PreprocessorContext, mut book: Book) -> Result<Book, Error> {
+        let start = Instant::now();
+        let mut res: Option<Error> = None;
+
+        let mut config = match ctx.config.get_preprocessor(self.name()) {
+            Some(table) => Config::from_table_for(table, &ctx.root, Some(&ctx.renderer))?,
+            None => {
+                return Err(CiteprocError::config(format!(
+                    "No config table for {} preprocessor",
+                    self.name()
+                )))
+            }
+        };
+
+        config.resolve_admonish(&ctx.config);
+        config.set_renderer(&ctx.renderer);
+        if let Some(language) = &ctx.config.book.language {
+            if let Some(original) = config.set_language(language, &ctx.root) {
+                for warning in translation_warnings(&ctx.root, &original, &config) {
+                    eprintln!("Warning: {warning}");
+                }
+            }
+        }
+        http::configure_network(&config)?;
+        if let Some(remote) = &config.remote_bibliography {
+            remote.fetch(config.remote_ttl, false)?;
+        }
+        style::fetch_remote(&mut config, http::is_offline())?;
+        for warning in ordering::warnings(&ctx.config, &config) {
+            eprintln!("Warning: {warning}");
+        }
+
+        // A malformed bibliography makes every chapter fail, so check it
+        // once up front where we can still give a precise error.
+        let bibliography_error = config.bibliography.as_ref().and_then(|bibliography| {
+            bibliography::validate(&ctx.root.join(&bibliography.bibliography)).err()
+        });
+        let serve_stale = match bibliography_error {
+            Some(e) if config.on_failure == FailureMode::Error => return Err(e.into()),
+            Some(e) => {
+                eprintln!("Warning: {e}; serving the last good build");
+                true
+            }
+            None => false,
+        };
+
+        let marked_up = config.bibliography.as_ref().is_some_and(|bibliography| {
+            fields::might_have_markup(&ctx.root.join(&bibliography.bibliography))
+        });
+        if (config.derives_bibliography() || marked_up) && !serve_stale {
+            derive_bibliography(&mut config, &ctx.root)?;
+        }
+
+        if config.wiki_links {
+            wiki_links::normalize_book(&mut book);
+        }
+
+        // Pass 1: everything rendering a chapter needs to know about the
+        // rest of the book.
+        let mut citations = BookCitations::collect(&book);
+        if config.quarto {
+            quarto::skip_crossrefs(&mut citations);
+        }
+        if config.deduplicate && config.bibliography.is_some() && !serve_stale {
+            deduplicate(&mut config, &ctx.root, &citations.keys())?;
+        }
+        if config.disambiguation == Scope::Book && config.bibliography.is_some() && !serve_stale {
+            for key in citations.keys() {
+                let key = config.aliases.get(&key).cloned().unwrap_or(key);
+                if !config.nocite.contains(&key) {
+                    config.nocite.push(key);
+                }
+            }
+            if !config.nocite.is_empty() {
+                let metadata = format!(
+                    "nocite: {}\n",
+                    serde_json::to_string(&citation::nocite(&config.nocite))?
+                );
+                config.metadata_file = Some(cache::store(
+                    &config.cache_root.join("metadata"),
+                    "yaml",
+                    &metadata,
+                )?);
+            }
+        }
+
+        let primed = match config.first_citation {
+            Scope::Book if config.bibliography.is_some() => cited_before(&config, &citations),
+            _ => Vec::new(),
+        };
+        let primed = |ordinal: usize| primed.get(ordinal - 1).map_or(&[][..], Vec::as_slice);
+
+        let library = match config.needs_library() && !serve_stale {
+            true => self.library(&config, &ctx.root)?,
+            false => Arc::default(),
+        };
+        if config.strict && !serve_stale {
+            for warning in lint::check(&config, &ctx.root, &library, &citations.keys())? {
+                eprintln!("Warning: {warning}");
+            }
+        }
+        if config.archive_links && !serve_stale {
+            let keys: Vec<String> = citations
+                .keys()
+                .into_iter()
+                .map(|key| config.aliases.get(&key).cloned().unwrap_or(key))
+                .collect();
+            let (archive_cache, ttl) = config.archive_cache();
+            config.snapshots = archive::snapshots(
+                &library,
+                keys.iter().map(String::as_str),
+                archive_cache,
+                ttl,
+                false,
+            )?;
+        }
+
+        if !serve_stale {
+            lock::update(&ctx.root, &Lock::current(&config), config.locked)?;
+        }
+        if let Some(feed) = config.feed.as_ref().filter(|_| !serve_stale) {
+            let keys: Vec<String> = citations
+                .keys()
+                .into_iter()
+                .map(|key| config.aliases.get(&key).cloned().unwrap_or(key))
+                .collect();
+            feed::update(feed, &config.cache_root, &library, &keys)?;
+        }
+
+        let cache = match &config.cache_dir {
+            // Don't let the broken inputs invalidate the last good build.
+            Some(dir) if serve_stale => Some(Cache::open_stale(dir.clone(), config.cache_backend)?),
+            Some(dir) => Some(Cache::open(
+                dir.clone(),
+                &dependencies(ctx, &config)?,
+                config.cache_backend,
+            )?),
+            None => None,
+        };
+        let mut memory = match &self.memory {
+            Some(memory) => {
+                let mut memory = memory.lock().expect("memory cache poisoned");
+                if !serve_stale {
+                    memory.validate(&dependencies(ctx, &config)?);
+                }
+                Some(memory)
+            }
+            None => None,
+        };
+        let last_good = |memory: &Option<MutexGuard<MemoryCache>>, key: &str| {
+            memory
+                .as_ref()
+                .and_then(|memory| memory.get_stale(key))
+                .or_else(|| cache.as_ref().and_then(|cache| cache.get_stale(key)))
+        };
+
+        let selected;
+        let backend: &dyn CitationBackend = match &self.backend {
+            Some(backend) => backend.as_ref(),
+            None => {
+                selected = self.select_backend(&config, &ctx.root, !serve_stale)?;
+                selected.as_ref()
+            }
+        };
+
+        let template = match &config.entry_template {
+            Some(path) if !serve_stale => Some(EntryTemplate::load(path)?),
+            _ => None,
+        };
+        let build = Build {
+            root: &ctx.root,
+            config: &config,
+            backend,
+            library: &library,
+            template: template.as_ref(),
+        };
+
+        let chapters = book
+            .iter()
+            .filter(|item| matches!(item, BookItem::Chapter(_)))
+            .count();
+        let mut progress = Progress::new(chapters, self.quiet);
+
+        // Pass 2: render titles and take whatever is cached, collecting the
+        // chapters which need pandoc.
+        let mut pending = Vec::new();
+        let mut profiled = Vec::new();
+        let mut ordinal = 0;
+        book.for_each_mut(|item| {
+            if res.is_some() {
+                return;
+            }
+            if config.process_titles && !serve_stale {
+                let title = match item {
+                    BookItem::Chapter(chapter) => Some(&mut chapter.name),
+                    BookItem::PartTitle(title) => Some(title),
+                    BookItem::Separator => None,
+                };
+                if let Some(title) = title.filter(|title| audit::contains_citation(title)) {
+                    match convert_title(build, title) {
+                        Ok(converted) => *title = converted,
+                        Err(e) if config.on_failure == FailureMode::KeepOriginal => {
+                            eprintln!("Warning: {e}; keeping the title \"{title}\" as is");
+                        }
+                        Err(e) => {
+                            res = Some(e);
+                            return;
+                        }
+                    }
+                }
+            }
+            if let BookItem::Chapter(ref mut chapter) = *item {
+                ordinal += 1;
+                let key = chapter_key(&config, chapter, primed(ordinal));
+                if serve_stale {
+                    progress.finish_one();
+                    if let Some(output) = last_good(&memory, &key) {
+                        chapter.content = output;
+                    }
+                    return;
+                }
+
+                if let Some(max) = config.max_chapter_size {
+                    if chapter.content.len() > max {
+                        let e = CiteprocError::config(format!(
+                            "chapter \"{}\" is {}, more than max-chapter-size ({}); split it \
+                             up or raise the limit",
+                            chapter.name,
+                            size(chapter.content.len()),
+                            size(max)
+                        ));
+                        match config.on_failure {
+                            FailureMode::KeepOriginal => {
+                                progress.finish_one();
+                                eprintln!("Warning: {e}; leaving it as is");
+                            }
+                            _ => res = Some(e),
+                        }
+                        return;
+                    }
+                }
+
+                let cached = memory
+                    .as_ref()
+                    .and_then(|memory| memory.get(&key, &chapter.content))
+                    .or_else(|| {
+                        cache
+                            .as_ref()
+                            .and_then(|cache| cache.get(&key, &chapter.content))
+                    });
+                match cached {
+                    Some(cached) => {
+                        progress.finish_one();
+                        chapter.content = cached;
+                        profiled.push((ordinal, chapter_profile(chapter, None)));
+                    }
+                    // Moved rather than copied, so a big chapter is only
+                    // held once while pandoc runs.
+                    None => pending.push(Pending {
+                        ordinal,
+                        name: chapter.name.clone(),
+                        references: citations
+                            .chapters
+                            .get(ordinal - 1)
+                            .map_or(0, |cited| references(&config, cited)),
+                        source: chapter.source_path.clone(),
+                        primed: primed(ordinal).to_vec(),
+                        content: mem::take(&mut chapter.content),
+                    }),
+                }
+            }
+        });
+        if let Some(e) = res {
+            return Err(e);
+        }
+
+        // Pass 3: run pandoc on several chapters at a time.
+        let results = convert_chapters(build, &pending, &mut progress);
+        let mut converted = pending.into_iter().zip(results).peekable();
+
+        // Pass 4: take the results in book order, so warnings and errors
+        // come out the same however the chapters were scheduled.
+        let mut ordinal = 0;
+        book.for_each_mut(|item| {
+            if res.is_some() {
+                return;
+            }
+            if let BookItem::Chapter(ref mut chapter) = *item {
+                ordinal += 1;
+                if converted
+                    .peek()
+                    .is_none_or(|(pending, _)| pending.ordinal != ordinal)
+                {
+                    return;
+                }
+                let Some((pending, result)) = converted.next() else {
+                    return;
+                };
+                chapter.content = pending.content;
+                let Some((result, timings)) = result else {
+                    // Not started after another chapter failed.
+                    return;
+                };
+                profiled.push((ordinal, chapter_profile(chapter, Some(timings))));
+                let key = chapter_key(&config, chapter, primed(ordinal));
+                let content = match result {
+                    Ok((content, stderr)) => {
+                        eprint!("{stderr}");
+                        if config.audit {
+                            report_modifications(&chapter.name, &chapter.content, &content);
+                        }
+                        content
+                    }
+                    Err(e) if config.on_failure == FailureMode::KeepOriginal => {
+                        eprintln!(
+                            "Warning: {e}; keeping the last good output for chapter \"{}\"",
+                            chapter.name
+                        );
+                        if let Some(output) = last_good(&memory, &key) {
+                            chapter.content = output;
+                        }
+                        return;
+                    }
+                    Err(e) => {
+                        res = Some(e);
+                        return;
+                    }
+                };
+                if let Some(memory) = &mut memory {
+                    memory.put(&key, &chapter.content, &content);
+                }
+                if let Some(cache) = &cache {
+                    if let Err(e) = cache.put(&key, &chapter.content, &content) {
+                        res = Some(e);
+                        return;
+                    }
+                }
+                chapter.content = content;
+            }
+        });
+
+        if let Some(e) = res {
+            return Err(e);
+        }
+
+        if let Some(path) = &config.manifest {
+            manifest::write(path, &config.renderer, &book)?;
+        }
+
+        if config.cache_max_size.is_some() || config.cache_max_age.is_some() {
+            let cleaned = gc::clean(
+                &config.cache_root,
+                config.cache_max_size,
+                config.cache_max_age,
+                false,
+            );
+            if let Err(e) = cleaned {
+                eprintln!("Warning: pruning the cache failed: {e}");
+            }
+        }
+
+        if let Some(profile) = &self.profile {
+            profiled.sort_by_key(|(ordinal, _)| *ordinal);
+            *profile.lock().expect("profile poisoned") = Profile {
+                chapters: profiled.into_iter().map(|(_, chapter)| chapter).collect(),
+                run: start.elapsed(),
+                serialization: Duration::ZERO,
+            };
+        }
+        Ok(book)
+    }
+
+    fn supports_renderer(&self, renderer: &str) -> bool {
+        renderer != "not-supported"
+    }
+}
+
+/// Everything chapters are converted with.
+#[derive(Clone, Copy)]
+struct Build<'a> {
+    root: &'a Path,
+    config: &'a Config,
+    backend: &'a dyn CitationBackend,
+    library: &'a Library,
+    template: Option<&'a EntryTemplate>,
+}
+
+/// A chapter waiting for pandoc, with its content taken out of the book.
+struct Pending {
+    /// Which chapter of the book it is, counting from 1.
+    ordinal: usize,
+    name: String,
+    /// How many works the chapter cites.
+    references: usize,
+    /// The chapter's source, relative to the book's `src` directory.
+    source: Option<PathBuf>,
+    /// The works cited before the chapter, with `first-citation = "book"`.
+    primed: Vec<String>,
+    content: String,
+}
+
+/// The result of converting a chapter, and what it took.
+type Converted = (Result<(String, String), Error>, Timings);
+
+/// Converts `chapters` with up to `max-jobs` at a time, starting them in
+/// the configured order. The results are in the order of `chapters`; once
+/// a chapter fails (and isn't to be kept as is) no more are started.
+fn convert_chapters(
+    build: Build,
+    chapters: &[Pending],
+    progress: &mut Progress,
+) -> Vec<Option<Converted>> {
+    let mut order: Vec<usize> = (0..chapters.len()).collect();
+    if build.config.schedule == Schedule::LargestFirst {
+        order.sort_by_key(|&i| Reverse(chapters[i].content.len()));
+    }
+    let fail_fast = build.config.on_failure != FailureMode::KeepOriginal;
+    let next = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    let progress = Mutex::new(progress);
+    let results = Mutex::new((0..chapters.len()).map(|_| None).collect::<Vec<_>>());
+    thread::scope(|scope| {
+        for _ in 0..build.config.max_jobs.clamp(1, chapters.len().max(1)) {
+            scope.spawn(|| loop {
+                if failed.load(Ordering::Relaxed) {
+                    break;
+                }
+                let Some(&i) = order.get(next.fetch_add(1, Ordering::Relaxed)) else {
+                    break;
+                };
+                let chapter = &chapters[i];
+                progress
+                    .lock()
+                    .expect("progress poisoned")
+                    .start(&chapter.name);
+                let mut timings = Timings::default();
+                let result = convert_chapter(build, chapter, &mut timings);
+                progress.lock().expect("progress poisoned").finish_one();
+                if fail_fast && result.is_err() {
+                    failed.store(true, Ordering::Relaxed);
+                }
+                results.lock().expect("results poisoned")[i] = Some((result, timings));
+            });
+        }
+    });
+    results.into_inner().expect("results poisoned")
+}
+
+/// Shows `bytes` in the largest unit it has one of.
+pub(crate) fn size(bytes: usize) -> String {
+    match bytes {
+        0..1024 => format!("{bytes} bytes"),
+        1024..1048576 => format!("{:.1} KB", bytes as f64 / 1024.0),
+        _ => format!("{:.1} MB", bytes as f64 / 1048576.0),
+    }
+}
+
+fn chapter_profile(chapter: &Chapter, timings: Option<Timings>) -> ChapterProfile {
+    ChapterProfile {
+        name: chapter.name.clone(),
+        cached: timings.is_none(),
+        timings: timings.unwrap_or_default(),
+    }
+}
+
+/// Runs a chapter through pandoc and returns the converted content along
+/// with anything pandoc printed on stderr, adding what each step took to
+/// `timings`.
+fn convert_chapter(
+    build: Build,
+    chapter: &Pending,
+    timings: &mut Timings,
+) -> Result<(String, String), Error> {
+    let mut start = Instant::now();
+    let mut lap = |phase: &mut Duration| {
+        *phase += start.elapsed();
+        start = Instant::now();
+    };
+    let config = build.config;
+    let content = config.rename_keys(&chapter.content);
+    let content = targets::select(&content, &config.targets());
+    let content = directives::expand(&content, config)
+        .map_err(|e| CiteprocError::citation(format!("chapter \"{}\": {e}", chapter.name)))?;
+    if let Some(package) = config.latex_package() {
+        let content = directives::finish(&latex::convert(&content, package), &config.renderer);
+        let content = with_count(config, chapter, content);
+        lap(&mut timings.splice);
+        return Ok((content, String::new()));
+    }
+
+    let (front_matter, body, chapter_config);
+    let config = match front_matter::split(&content) {
+        Some(split) => {
+            front_matter = split.front_matter;
+            body = split.body;
+            chapter_config = config.for_front_matter(&front_matter::parse(front_matter));
+            &chapter_config
+        }
+        None => {
+            front_matter = "";
+            body = &content;
+            config
+        }
+    };
+    let styled = citation_style(build.root, config)?;
+    let config = styled.as_ref().unwrap_or(config);
+    let build = Build { config, ..build };
+    let source_body = body;
+
+    let crossrefs = config.quarto.then(|| quarto::protect(body));
+    let body = crossrefs.as_ref().map_or(body, |c| &c.content);
+    let bookdown = config.bookdown.then(|| bookdown::protect(body));
+    let body = bookdown.as_ref().map_or(body, |b| &b.content);
+    let admonish = (config.admonish == Some(true)).then(|| admonish::protect(body));
+    let body = admonish.as_ref().map_or(body, |a| &a.content);
+    let protected = (config.raw_html == RawHtml::Preserve).then(|| raw_html::protect(body));
+    let body = protected.as_ref().map_or(body, |p| &p.content);
+    let tables = (config.extensions.get("pipe_tables") != Some(&PandocSetting::Transpile))
+        .then(|| tables::protect(body));
+    let input = tables.as_ref().map_or(body, |t| &t.content);
+    let primed_input;
+    let backend_input = match chapter.primed.as_slice() {
+        [] => input,
+        keys => {
+            primed_input = format!("{}{input}", positions::primer(keys));
+            &primed_input
+        }
+    };
+    lap(&mut timings.parse);
+    let (output, mut stderr) = run_backend(build.backend, config, &chapter.name, backend_input)?;
+    // Pandoc's positions are in what it was given.
+    if stderr.contains("line ") {
+        let map = SourceMap::new(&chapter.content)
+            .step(&chapter.content, &content)
+            .skip(front_matter.lines().count())
+            .step(source_body, backend_input);
+        stderr = map.relocate(&stderr);
+    }
+    let output = match config.normalize_newlines {
+        true => subprocess::normalize_newlines(output),
+        false => output,
+    };
+    lap(&mut timings.pandoc);
+    let content = match decode_output(&chapter.name, backend_input, output, config.encoding)? {
+        Some(content) => {
+            let content = match chapter.primed.is_empty() {
+                true => content,
+                false => positions::strip(&content),
+            };
+            let content = arrange_bibliography(build, input, content)?;
+            let content = restore_field_markup(config, content);
+            let content = if config.writer_style.is_default() {
+                content
+            } else {
+                restyle::restyle(&content, &config.writer_style)
+            };
+            let content = match &tables {
+                Some(tables) => tables.restore(&chapter.name, &content)?,
+                None => content,
+            };
+            let content = match &protected {
+                Some(protected) => protected.restore(&chapter.name, &content)?,
+                None => content,
+            };
+            let content = match &admonish {
+                Some(admonish) => admonish.restore(&chapter.name, &content)?,
+                None => content,
+            };
+            let content = match &bookdown {
+                Some(bookdown) => bookdown.restore(&chapter.name, &content)?,
+                None => content,
+            };
+            let content = match &crossrefs {
+                Some(crossrefs) => crossrefs.restore(&chapter.name, &content)?,
+                None => content,
+            };
+            let content = match directives::has_directives(&chapter.content) {
+                true => directives::finish(&content, &config.renderer),
+                false => content,
+            };
+            let content = match further_reading(build, chapter, front_matter, input)? {
+                Some((list, list_stderr)) => {
+                    stderr += &list_stderr;
+                    format!("{}\n\n{list}\n", content.trim_end())
+                }
+                None => content,
+            };
+            let content = match json_ld(build, input) {
+                Some(script) => format!("{}\n\n{script}\n", content.trim_end()),
+                None => content,
+            };
+            let content = match config.renderer.as_str() {
+                "epub" => epub::epub_safe(&content),
+                _ => content,
+            };
+            let content = with_count(config, chapter, content);
+            match config.front_matter {
+                FrontMatterMode::Keep => format!("{front_matter}{content}"),
+                FrontMatterMode::Strip => content,
+            }
+        }
+        None => chapter.content.clone(),
+    };
+    lap(&mut timings.splice);
+    Ok((content, stderr))
+}
+
+/// The JSON-LD `<script>` describing the works the chapter with `input`
+/// cites, with `structured-data` on for the HTML renderer.
+fn json_ld(build: Build, input: &str) -> Option<String> {
+    if !build.config.structured_data || build.config.renderer != "html" {
+        return None;
+    }
+    let mut entries = Vec::new();
+    for item in citation::parse(input).iter().flat_map(|c| &c.items) {
+        if let Some(entry) = build.library.get(&item.key) {
+            if !entries.contains(&entry) {
+                entries.push(entry);
+            }
+        }
+    }
+    structured_data::script(&entries)
+}
+
+/// The "Further reading" section for `chapter`, rendered in the chapter's
+/// style, with what pandoc printed on stderr. It lists the entries tagged
+/// with both the configured keyword and the chapter's own, which is its
+/// `further-reading` front matter or else its file name without the
+/// extension. Works the chapter cites are in its bibliography already, so
+/// they're left out.
+fn further_reading(
+    build: Build,
+    chapter: &Pending,
+    front_matter: &str,
+    input: &str,
+) -> Result<Option<(String, String)>, Error> {
+    let config = build.config;
+    let Some(further) = &config.further_reading else {
+        return Ok(None);
+    };
+    let own = front_matter::parse(front_matter)
+        .remove("further-reading")
+        .or_else(|| {
+            let stem = chapter.source.as_ref()?.file_stem()?;
+            Some(stem.to_string_lossy().into_owned())
+        });
+    let Some(own) = own else {
+        return Ok(None);
+    };
+    let cited: BTreeSet<String> = citation::parse(input)
+        .into_iter()
+        .flat_map(|citation| citation.items)
+        .map(|item| item.key)
+        .collect();
+    let tagged = |entry: &&bibliography::Entry| {
+        let has = |keyword: &str| entry.keywords().any(|k| k.eq_ignore_ascii_case(keyword));
+        has(&further.keyword) && has(&own)
+    };
+    let keys: Vec<String> = build
+        .library
+        .entries()
+        .into_iter()
+        .filter(tagged)
+        .filter(|entry| !cited.contains(&entry.key))
+        .map(|entry| format!("@{}", entry.key))
+        .collect();
+    if keys.is_empty() {
+        return Ok(None);
+    }
+
+    // Only the listed works, whatever the book-wide `nocite` says.
+    let mut list_config = config.clone();
+    list_config.metadata_file = None;
+    list_config.bibliography_groups.clear();
+    let list_build = Build {
+        config: &list_config,
+        ..build
+    };
+    let list_input = format!("[{}]\n", keys.join("; "));
+    let (output, stderr) = run_backend(build.backend, &list_config, &chapter.name, &list_input)?;
+    let Some(output) = decode_output(&chapter.name, &list_input, output, config.encoding)? else {
+        return Ok(None);
+    };
+    let output = arrange_bibliography(list_build, &list_input, output)?;
+    let output = restore_field_markup(config, output);
+    let Some(mut list) = Bibliography::parse(&output) else {
+        return Ok(None);
+    };
+    // The chapter's own bibliography is the one with `id="refs"`.
+    let open = list
+        .open
+        .replacen("id=\"refs\"", "id=\"further-reading\"", 1);
+    list.open = &open;
+    list.before = "";
+    list.after = "";
+    Ok(Some((
+        format!(
+            "{} {}\n\n{}",
+            "#".repeat(config.reference_heading_level.unwrap_or(2)),
+            further.title,
+            list.render()
+        ),
+        stderr,
+    )))
+}
+
+/// How many works `chapter` cites, counting each once under its current
+/// key.
+fn references(config: &Config, chapter: &ChapterCitations) -> usize {
+    let keys = chapter.keys();
+    let works: BTreeSet<&str> = keys
+        .iter()
+        .map(|key| config.aliases.get(key).unwrap_or(key).as_str())
+        .collect();
+    works.len()
+}
+
+/// `content` with the configured `citation-count` line, if `chapter` cites
+/// anything.
+fn with_count(config: &Config, chapter: &Pending, content: String) -> String {
+    match &config.citation_count {
+        Some(template) if chapter.references > 0 => badge::insert(
+            &content,
+            &badge::render(template, chapter.references),
+            &config.renderer,
+        ),
+        _ => content,
+    }
+}
+
+/// Rearranges the bibliography pandoc generated for a chapter with `input`
+/// as configured.
+fn arrange_bibliography(build: Build, input: &str, content: String) -> Result<String, Error> {
+    let config = build.config;
+    if config.bibliography_sort == BibliographySort::Style
+        && config.disambiguation == Scope::Chapter
+        && config.first_citation == Scope::Chapter
+        && config.bibliography_groups.is_empty()
+        && config.reference_heading_level.is_none()
+        && !config.numbered_bibliography
+        && !config.annotations
+        && build.template.is_none()
+        && config.url_policy.is_default()
+        && config.snapshots.is_empty()
+        && !config.coins
+    {
+        return Ok(content);
+    }
+    let Some(mut bibliography) = Bibliography::parse(&content) else {
+        return Ok(content);
+    };
+    let citations = citation::parse(input);
+    if config.disambiguation == Scope::Book || config.first_citation == Scope::Book {
+        // Pandoc was given every key in the book, or those cited before the
+        // chapter, so only keep the ones this chapter cites, in its text or
+        // its own `nocite`.
+        let nocite = config.metadata.get("nocite").map(|n| citation::parse(n));
+        bibliography.retain(
+            citations
+                .iter()
+                .chain(nocite.iter().flatten())
+                .flat_map(|citation| &citation.items)
+                .map(|item| item.key.as_str()),
+        );
+    }
+    if config.bibliography_sort == BibliographySort::Appearance {
+        bibliography.sort_by_appearance(
+            citations
+                .iter()
+                .flat_map(|citation| &citation.items)
+                .map(|item| item.key.as_str()),
+        );
+    }
+    if let Some(template) = build.template {
+        for entry in &mut bibliography.entries {
+            if let Some(source) = build.library.get(&entry.key) {
+                entry.set_body(&template.render(source)?);
+            }
+        }
+    }
+    if !config.url_policy.is_default() {
+        for entry in &mut bibliography.entries {
+            entry.html = urls::apply(&entry.html, &config.url_policy);
+        }
+    }
+    for entry in &mut bibliography.entries {
+        if let Some(snapshot) = config.snapshots.get(&entry.key) {
+            entry.append(&format!("[{}]({snapshot})", config.archive_link_text));
+        }
+    }
+    if config.coins && config.renderer == "html" {
+        for entry in &mut bibliography.entries {
+            if let Some(source) = build.library.get(&entry.key) {
+                entry.embed(&coins::span(source));
+            }
+        }
+    }
+    if config.annotations {
+        for entry in &mut bibliography.entries {
+            let annotation = build.library.get(&entry.key).and_then(|e| e.annotation());
+            if let Some(annotation) = annotation {
+                entry.annotate(&annotation);
+            }
+        }
+    }
+    if !config.bibliography_groups.is_empty() {
+        let groups = &config.bibliography_groups;
+        let titles: Vec<String> = groups.iter().map(|group| group.title.clone()).collect();
+        // There's always a group without types for the rest.
+        let rest = groups.iter().position(|group| group.types.is_none());
+        bibliography.group_by(&titles, |key| {
+            let kind = build.library.get(key).map(|entry| entry.kind.as_str());
+            groups
+                .iter()
+                .position(|group| {
+                    group.types.as_ref().is_some_and(|types| {
+                        kind.is_some_and(|kind| types.iter().any(|t| t == kind))
+                    })
+                })
+                .or(rest)
+                .unwrap_or(0)
+        });
+    }
+    bibliography.numbered = config.numbered_bibliography;
+    if let Some(level) = config.reference_heading_level {
+        bibliography.group_level = (level + 1).min(6);
+        // Otherwise the heading before the bibliography is the author's.
+        if config.metadata.contains_key("reference-section-title") {
+            bibliography.take_title();
+            if let Some(title) = &mut bibliography.title {
+                title.level = level;
+            }
+        }
+    }
+    Ok(bibliography.render())
+}
+
+/// Checks the translated bibliography `config` uses has every key of the
+/// `original`, since citations keep their keys (and so their `ref-`
+/// anchors) across translations. Files which can't be read are reported
+/// when they're loaded.
+fn translation_warnings(root: &Path, original: &str, config: &Config) -> Vec<String> {
+    let Some(translated) = &config.bibliography else {
+        return Vec::new();
+    };
+    let (Ok(original_entries), Ok(translated_entries)) = (
+        bibliography::load(&root.join(original)),
+        bibliography::load(&root.join(&translated.bibliography)),
+    ) else {
+        return Vec::new();
+    };
+    let translated_keys: BTreeSet<&str> = translated_entries
+        .iter()
+        .map(|entry| entry.key.as_str())
+        .collect();
+    let missing: Vec<&str> = original_entries
+        .iter()
+        .map(|entry| entry.key.as_str())
+        .filter(|key| !translated_keys.contains(key))
+        .collect();
+    match missing.as_slice() {
+        [] => Vec::new(),
+        missing => vec![format!(
+            "{} is missing {} of {original}'s entries: {}",
+            translated.bibliography,
+            missing.len(),
+            missing.join(", ")
+        )],
+    }
+}
+
+/// Aliases each duplicate entry's key to the key of the entry it
+/// duplicates, across the bibliography and any others, and reports the
+/// ones the book cites. A key already aliased keeps its alias.
+fn deduplicate(config: &mut Config, root: &Path, cited: &[String]) -> Result<(), Error> {
+    let Some(bibliography) = &config.bibliography else {
+        return Ok(());
+    };
+    let mut entries = bibliography::load(&root.join(&bibliography.bibliography))?;
+    for extra in &config.extra_bibliographies {
+        entries.extend(bibliography::load(extra)?);
+    }
+    for duplicate in bibliography::duplicates(&entries) {
+        if config.aliases.contains_key(&duplicate.key) {
+            continue;
+        }
+        if cited.contains(&duplicate.key) {
+            eprintln!(
+                "Warning: `{}` is the same work as `{}` (same {}), so it's cited as `{}`; \
+                 set deduplicate = false to keep them apart",
+                duplicate.key, duplicate.of, duplicate.by, duplicate.of
+            );
+        }
+        config.aliases.insert(duplicate.key, duplicate.of);
+    }
+    Ok(())
+}
+
+/// Points `config` at a copy of its bibliography with only the entries
+/// matching `bibliography-filter`, and each of the others rewritten:
+/// `overrides` merged in, web pages without one given the default accessed
+/// date, authors with an ORCID iD written one way, titles recased, literal
+/// names braced and markup in fields protected. Entries defined in
+/// `book.toml` are written to a bibliography of their own, and replace any
+/// entry with the same key.
+fn derive_bibliography(config: &mut Config, root: &Path) -> Result<(), Error> {
+    let Some(bibliography) = &mut config.bibliography else {
+        return Ok(());
+    };
+    let path = root.join(&bibliography.bibliography);
+    let filter = config.bibliography_filter.as_ref();
+    let inline: Vec<&str> = config
+        .inline_entries
+        .iter()
+        .filter_map(|item| item["id"].as_str())
+        .collect();
+    let accessed = match &config.default_accessed {
+        None => None,
+        Some(AccessedDefault::Date(date)) => Some(date.clone()),
+        Some(AccessedDefault::Modified) => Some(civil_date(fs::metadata(&path)?.modified()?)),
+    };
+    let bibtex = matches!(
+        path.extension().and_then(|e| e.to_str()),
+        Some("bib" | "bibtex")
+    );
+    let orcid_names = match config.orcid_names {
+        true => {
+            let mut entries = bibliography::load(&path)?;
+            for extra in &config.extra_bibliographies {
+                entries.extend(bibliography::load(extra)?);
+            }
+            let names = orcid::names(&entries);
+            for conflict in &names.conflicts {
+                eprintln!("Warning: {conflict}");
+            }
+            Some(names).filter(|names| !names.is_empty())
+        }
+        false => None,
+    };
+    let mut markup = FieldMarkup::default();
+    let mut overridden = Vec::new();
+    let derived = bibliography::rewrite(&path, |entry| {
+        if inline.contains(&entry.key.as_str()) {
+            return false;
+        }
+        if let Some(date) = &accessed {
+            let dated = ["urldate", "accessed"]
+                .iter()
+                .any(|field| entry.fields.contains_key(*field));
+            if entry.kind == "webpage" && !dated {
+                entry.fields.insert("urldate".into(), date.clone());
+            }
+        }
+        if let Some(fields) = config.overrides.get(&entry.key) {
+            overridden.push(entry.key.clone());
+            entry.fields.extend(fields.clone());
+        }
+        if let Some(names) = &orcid_names {
+            names.apply(entry);
+        }
+        if let Some(title) = entry.fields.get_mut("title") {
+            *title = casing::recase(title, config.title_case);
+        }
+        if config.literal_names.contains(&entry.key) {
+            for (field, value) in &mut entry.fields {
+                if bibliography::NAME_FIELDS.contains(&field.as_str()) {
+                    *value = bibliography::split_names(value)
+                        .iter()
+                        .map(|name| format!("{{{}}}", name.replace(['{', '}'], "")))
+                        .collect::<Vec<_>>()
+                        .join(" and ");
+                }
+            }
+        }
+        for (field, value) in &mut entry.fields {
+            if let Some(protected) = markup.protect(field, value, bibtex) {
+                *value = protected;
+            }
+        }
+        filter.is_none_or(|filter| filter.matches(entry))
+    })?
+    .ok_or_else(|| {
+        CiteprocError::config(format!(
+            "{} can't be filtered or overridden: only BibTeX and CSL-JSON bibliographies can",
+            bibliography.bibliography
+        ))
+    })?;
+    for key in config.overrides.keys() {
+        if !overridden.contains(key) {
+            eprintln!(
+                "Warning: overrides.{key} doesn't match any entry in {}",
+                bibliography.bibliography
+            );
+        }
+    }
+    let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("bib");
+    let dir = config.cache_root.join("bibliography");
+    let derived = cache::store(&dir, extension, &derived)?;
+    bibliography.bibliography = derived.display().to_string();
+    config.field_markup = markup;
+    if !config.inline_entries.is_empty() {
+        let entries = serde_json::to_string_pretty(&config.inline_entries)?;
+        config
+            .extra_bibliographies
+            .push(cache::store(&dir, "json", &entries)?);
+    }
+    Ok(())
+}
+
+/// `time` as a `YYYY-MM-DD` date in UTC.
+pub(crate) fn civil_date(time: SystemTime) -> String {
+    let days = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs() / 86_400) as i64;
+    // Howard Hinnant's `civil_from_days`, with eras starting on 1 March.
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!("{year:04}-{month:02}-{day:02}")
+}
+
+/// `config` pointed at a copy of its style with the `citation-options` and
+/// `accessed-format` applied, if there are any.
+fn citation_style(root: &Path, config: &Config) -> Result<Option<Config>, Error> {
+    let Some(bibliography) = &config.bibliography else {
+        return Ok(None);
+    };
+    if config.citation_options.is_empty() && config.accessed_format.is_none() {
+        return Ok(None);
+    }
+    let style = style::customized(
+        &root.join(&bibliography.bibliography_style),
+        &config.citation_options,
+        config.accessed_format.as_deref(),
+        &config.cache_root.join("styles"),
+    )?;
+    let mut config = config.clone();
+    if let Some(bibliography) = &mut config.bibliography {
+        bibliography.bibliography_style = style.display().to_string();
+    }
+    Ok(Some(config))
+}
+
+/// Renders the citations in a chapter or part title as plain text, for the
+/// sidebar and anywhere else mdbook shows titles.
+fn convert_title(build: Build, title: &str) -> Result<String, Error> {
+    let config = build.config;
+    let title = &config.rename_keys(title);
+    if let Some(package) = config.latex_package() {
+        return Ok(latex::convert(title, package));
+    }
+    let mut title_config = citation_style(build.root, config)?.unwrap_or_else(|| config.clone());
+    title_config.to = Format::new("plain");
+    title_config
+        .metadata
+        .insert("suppress-bibliography".into(), "true".into());
+    let (output, stderr) = run_backend(build.backend, &title_config, title, title)?;
+    eprint!("{stderr}");
+    let output = decode_output(title, title, output, config.encoding)?;
+    Ok(output.map_or_else(
+        || title.to_string(),
+        |output| {
+            let output = config.field_markup.restore(&output, "plain");
+            output.split_whitespace().collect::<Vec<_>>().join(" ")
+        },
+    ))
+}
+
+/// `content` with the math and code in bibliography fields put back.
+fn restore_field_markup(config: &Config, content: String) -> String {
+    match config.field_markup.is_empty() {
+        true => content,
+        false => config.field_markup.restore(&content, &config.renderer),
+    }
+}
+
+/// How many of a chapter's unexpected modifications are printed in full.
+const AUDIT_REPORT_LIMIT: usize = 5;
+
+/// Warns about anything pandoc changed in a chapter besides its citations.
+fn report_modifications(chapter_name: &str, input: &str, output: &str) {
+    // Front matter is either kept verbatim or stripped on purpose.
+    let body = |content| front_matter::split(content).map_or(content, |split| split.body);
+    let modifications = audit::audit(body(input), body(output));
+    // Lines are counted in the body, and reported in the whole file.
+    let front_matter_lines = input.lines().count() - body(input).lines().count();
+    for modification in modifications.iter().take(AUDIT_REPORT_LIMIT) {
+        eprintln!(
+            "Warning: chapter \"{chapter_name}\": line {} changed unexpectedly:",
+            modification.line + front_matter_lines
+        );
+        for line in &modification.before {
+            eprintln!("  - {line}");
+        }
+        for line in &modification.after {
+            eprintln!("  + {line}");
+        }
+    }
+    if modifications.len() > AUDIT_REPORT_LIMIT {
+        eprintln!(
+            "Warning: chapter \"{chapter_name}\": {} more unexpected changes",
+            modifications.len() - AUDIT_REPORT_LIMIT
+        );
+    }
+}
+
+/// The works each chapter cites which chapters before it cite too, by
+/// ordinal, under their current keys.
+fn cited_before(config: &Config, citations: &BookCitations) -> Vec<Vec<String>> {
+    let mut cited: Vec<String> = Vec::new();
+    let mut before = Vec::with_capacity(citations.chapters.len());
+    for chapter in &citations.chapters {
+        let keys: Vec<String> = chapter
+            .keys()
+            .into_iter()
+            .map(|key| config.aliases.get(&key).cloned().unwrap_or(key))
+            .collect();
+        before.push(
+            cited
+                .iter()
+                .filter(|key| keys.contains(key))
+                .cloned()
+                .collect(),
+        );
+        for key in keys {
+            if !cited.contains(&key) {
+                cited.push(key);
+            }
+        }
+    }
+    before
+}
+
+/// The key a chapter is cached under: its source path when it has one.
+/// Renderers which get different output, or chapters with content for
+/// some of them only, are cached separately, as are chapters citing works
+/// earlier chapters cite once those change.
+fn chapter_key(config: &Config, chapter: &Chapter, primed: &[String]) -> String {
+    let key = match &chapter.source_path {
+        Some(path) => path.display().to_string(),
+        None => chapter.name.clone(),
+    };
+    let key = match primed {
+        [] => key,
+        primed => format!("{key}+{}", &sha256_hex(primed.join(" "))[..16]),
+    };
+    if config.renderer_specific() || targets::is_conditional(&chapter.content) {
+        format!("{}:{key}", config.renderer)
+    } else {
+        key
+    }
+}
+
+/// Fingerprints every input besides the chapter text itself which can
+/// change pandoc's output, so editing the bibliography or style
+/// invalidates every cached chapter.
+fn dependencies(ctx: &PreprocessorContext, config: &Config) -> Result<Dependencies, Error> {
+    let mut dependencies = Dependencies::new();
+    if let Some(bibliography) = &config.bibliography {
+        dependencies.insert(
+            "bibliography".into(),
+            file_fingerprint(&ctx.root.join(&bibliography.bibliography)),
+        );
+        dependencies.insert(
+            "style".into(),
+            file_fingerprint(&ctx.root.join(&bibliography.bibliography_style)),
+        );
+    }
+    for (i, path) in config.extra_bibliographies.iter().enumerate() {
+        dependencies.insert(format!("bibliography-{}", i + 1), file_fingerprint(path));
+    }
+    if let Some(template) = &config.entry_template {
+        dependencies.insert("entry-template".into(), file_fingerprint(template));
+    }
+    if let Some(file) = &config.metadata_file {
+        dependencies.insert("metadata-file".into(), file_fingerprint(file));
+    }
+    if !config.snapshots.is_empty() {
+        dependencies.insert(
+            "snapshots".into(),
+            sha256_hex(serde_json::to_vec(&config.snapshots)?),
+        );
+    }
+    if let Some(locale) = &config.locale {
+        dependencies.insert("locale".into(), locale.clone());
+    }
+    let table = ctx.config.get_preprocessor("citeproc");
+    dependencies.insert("config".into(), sha256_hex(serde_json::to_vec(&table)?));
+    if let Some(profile) = &config.profile {
+        dependencies.insert("profile".into(), profile.clone());
+    }
+    Ok(dependencies)
+}
+
+/// Feeds `content` to the backend and returns its stdout and stderr.
+///
+/// In `strict` mode any citeproc warnings (e.g. unknown citation keys)
+/// are turned into an error.
+fn run_backend(
+    backend: &dyn CitationBackend,
+    config: &Config,
+    chapter_name: &str,
+    content: &str,
+) -> Result<(Vec<u8>, String), Error> {
+    let output = backend.convert(config, content)?;
+    if let Some(failure) = output.failure {
+        return Err(CiteprocError::pandoc_failed(format!(
+            "{} failed on chapter \"{chapter_name}\" ({failure}): {}",
+            backend.name(),
+            output.stderr.trim()
+        )));
+    }
+    if config.strict {
+        let warnings: Vec<&str> = output
+            .stderr
+            .lines()
+            .filter(|line| line.starts_with("[WARNING] Citeproc"))
+            .collect();
+        if !warnings.is_empty() {
+            return Err(CiteprocError::citation(format!(
+                "citation errors in chapter \"{chapter_name}\":\n{}",
+                warnings.join("\n")
+            )));
+        }
+    }
+    Ok((output.stdout, output.stderr))
+}
+
+/// Decodes pandoc's output for a chapter according to `policy`.
+///
+/// Returns `Ok(None)` when the chapter's original content should be kept.
+fn decode_output(
+    chapter_name: &str,
+    input: &str,
+    output: Vec<u8>,
+    policy: EncodingPolicy,
+) -> Result<Option<String>, Error> {
+    let (problem, lossy) = match String::from_utf8(output) {
+        // Valid output needs nothing replacing, so it's used as it is.
+        Ok(text) if policy == EncodingPolicy::Lossy => return Ok(Some(text)),
+        Ok(text) => {
+            // Replacement characters which were already in the chapter are
+            // fine; any extra ones were introduced somewhere along the way.
+            let existing = input.matches('\u{FFFD}').count();
+            match text.match_indices('\u{FFFD}').nth(existing) {
+                None => return Ok(Some(text)),
+                Some((offset, _)) => (
+                    format!("a replacement character at byte offset {offset}"),
+                    text,
+                ),
+            }
+        }
+        Err(e) => {
+            let offset = e.utf8_error().valid_up_to();
+            (
+                format!("invalid UTF-8 at byte offset {offset}"),
+                String::from_utf8_lossy(e.as_bytes()).into_owned(),
+            )
+        }
+    };
+
+    match policy {
+        EncodingPolicy::Error => Err(CiteprocError::pandoc_failed(format!(
+            "pandoc output for chapter \"{chapter_name}\" contains {problem}"
+        ))),
+        EncodingPolicy::Lossy => {
+            eprintln!(
+                "Warning: pandoc output for chapter \"{chapter_name}\" contains {problem}; \
+                 invalid sequences have been replaced"
+            );
+            Ok(Some(lossy))
+        }
+        EncodingPolicy::Original => {
+            eprintln!(
+                "Warning: pandoc output for chapter \"{chapter_name}\" contains {problem}; \
+                 keeping the original chapter content"
+            );
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_output_by_policy() {
+        let decode = |output: &[u8], policy| decode_output("One", "a", output.to_vec(), policy);
+        let replaced = "a \u{FFFD}".as_bytes();
+        assert_eq!(
+            decode(replaced, EncodingPolicy::Lossy).unwrap().as_deref(),
+            Some("a \u{FFFD}")
+        );
+        assert!(decode(replaced, EncodingPolicy::Error).is_err());
+        assert_eq!(decode(replaced, EncodingPolicy::Original).unwrap(), None);
+        assert_eq!(
+            decode(b"a \xff", EncodingPolicy::Lossy).unwrap().as_deref(),
+            Some("a \u{FFFD}")
+        );
+    }
+
+    #[test]
+    fn keeps_the_library_until_the_bibliography_changes() {
+        let root = std::env::temp_dir().join(format!("citeproc-hot-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("refs.bib"), "@book{a, title = {A}}\n").unwrap();
+        let table = toml::from_str(
+            "citations = \"transpile\"\nbibliography = \"refs.bib\"\ncsl = \"style.csl\"",
+        )
+        .unwrap();
+        let config = Config::from_table(&table, &root).unwrap();
+
+        let pre = Pandoc::new().in_memory_cache();
+        let first = pre.library(&config, &root).unwrap();
+        assert!(Arc::ptr_eq(&first, &pre.library(&config, &root).unwrap()));
+
+        fs::write(root.join("refs.bib"), "@book{b, title = {B}}\n").unwrap();
+        let changed = pre.library(&config, &root).unwrap();
+        assert!(!Arc::ptr_eq(&first, &changed));
+        assert!(changed.get("b").is_some());
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,205 @@
+//! Where the time goes in a build: `--profile` and `mdbook-citeproc bench`.
+
+use std::fmt::Write as _;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use mdbook::errors::Error;
+
+use crate::preprocessor::Pandoc;
+
+/// Time spent on one chapter.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Timings {
+    /// Preparing the chapter for pandoc: key aliases, front matter, styles
+    /// and protecting what pandoc shouldn't touch.
+    pub parse: Duration,
+    pub pandoc: Duration,
+    /// Putting pandoc's output back together: bibliography arrangement,
+    /// restyling and restoring what was protected.
+    pub splice: Duration,
+}
+
+impl Timings {
+    pub fn total(&self) -> Duration {
+        self.parse + self.pandoc + self.splice
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChapterProfile {
+    pub name: String,
+    /// Taken from a cache, so it wasn't converted at all.
+    pub cached: bool,
+    pub timings: Timings,
+}
+
+/// Timings of one preprocessor run.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Profile {
+    /// In book order.
+    pub chapters: Vec<ChapterProfile>,
+    /// The whole run, which with parallel chapters is less than the sum of
+    /// theirs.
+    pub run: Duration,
+    /// Reading the book from mdbook and writing it back.
+    pub serialization: Duration,
+}
+
+impl Profile {
+    /// A table of the chapters, slowest first.
+    pub fn report(&self) -> String {
+        let mut chapters: Vec<&ChapterProfile> = self.chapters.iter().collect();
+        chapters.sort_by_key(|chapter| std::cmp::Reverse(chapter.timings.total()));
+        let width = chapters
+            .iter()
+            .map(|chapter| chapter.name.chars().count())
+            .chain(["chapter".len()])
+            .max()
+            .unwrap_or_default();
+
+        let mut out = format!(
+            "{:width$}  {:>10}  {:>10}  {:>10}\n",
+            "chapter", "parse", "pandoc", "splice"
+        );
+        for chapter in chapters {
+            let timings = &chapter.timings;
+            match chapter.cached {
+                true => writeln!(out, "{:width$}  (cached)", chapter.name),
+                false => writeln!(
+                    out,
+                    "{:width$}  {:>10}  {:>10}  {:>10}",
+                    chapter.name,
+                    millis(timings.parse),
+                    millis(timings.pandoc),
+                    millis(timings.splice)
+                ),
+            }
+            .expect("writing to a string");
+        }
+        let _ = writeln!(
+            out,
+            "{} chapters in {}, plus {} serializing",
+            self.chapters.len(),
+            millis(self.run),
+            millis(self.serialization)
+        );
+        out
+    }
+}
+
+/// How long each of the `profiles` of a benchmark took, and the report of
+/// the median one.
+pub fn summary(profiles: &[Profile]) -> String {
+    let total = |profile: &Profile| profile.run + profile.serialization;
+    let mut out = String::new();
+    for (i, profile) in profiles.iter().enumerate() {
+        let _ = writeln!(out, "iteration {}: {}", i + 1, millis(total(profile)));
+    }
+    let mut sorted: Vec<&Profile> = profiles.iter().collect();
+    sorted.sort_by_key(|profile| total(profile));
+    let (Some(min), Some(max)) = (sorted.first(), sorted.last()) else {
+        return out;
+    };
+    let median = sorted[sorted.len() / 2];
+    let _ = writeln!(
+        out,
+        "min {}, median {}, max {}\n",
+        millis(total(min)),
+        millis(total(median)),
+        millis(total(max))
+    );
+    out + &median.report()
+}
+
+fn millis(duration: Duration) -> String {
+    format!("{:.1}ms", duration.as_secs_f64() * 1000.0)
+}
+
+/// Builds the book in `root` for `renderer` `iterations` times, with the
+/// chapter cache off so every chapter goes through pandoc every time.
+pub fn bench(root: &Path, renderer: &str, iterations: usize) -> Result<Vec<Profile>, Error> {
+    let input = crate::uncached_input(root, renderer)?;
+    let preprocessor = Pandoc::new().quiet(true).profile();
+    let mut profiles = Vec::with_capacity(iterations);
+    for _ in 0..iterations {
+        let profile = crate::profile_input(&preprocessor, &input, io::sink())?;
+        profiles.push(profile);
+    }
+    Ok(profiles)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_slowest_chapters_first() {
+        let chapter = |name: &str, pandoc: u64| ChapterProfile {
+            name: name.into(),
+            cached: pandoc == 0,
+            timings: Timings {
+                pandoc: Duration::from_millis(pandoc),
+                ..Timings::default()
+            },
+        };
+        let profile = Profile {
+            chapters: vec![
+                chapter("Intro", 5),
+                chapter("Cached", 0),
+                chapter("Methods", 40),
+            ],
+            run: Duration::from_millis(45),
+            serialization: Duration::from_millis(2),
+        };
+        let report = profile.report();
+        let lines: Vec<&str> = report.lines().collect();
+        assert!(lines[1].starts_with("Methods") && lines[1].contains("40.0ms"));
+        assert!(lines[2].starts_with("Intro"));
+        assert_eq!(lines[3], "Cached   (cached)");
+        assert_eq!(lines[4], "3 chapters in 45.0ms, plus 2.0ms serializing");
+    }
+
+    /// `bench` is given a book directory, which needn't be the current one.
+    #[cfg(unix)]
+    #[test]
+    fn benches_a_book_elsewhere() {
+        use std::fs;
+        use std::os::unix::fs::PermissionsExt;
+
+        let root = std::env::temp_dir().join(format!("citeproc-bench-{}", std::process::id()));
+        fs::create_dir_all(root.join("src")).unwrap();
+        // A fake pandoc which fails, as pandoc does, if it can't find a
+        // file it's given.
+        let pandoc = root.join("pandoc");
+        fs::write(
+            &pandoc,
+            "#!/bin/sh\nfor arg; do case $arg in\n\
+             --version) echo pandoc 3.1; exit;;\n\
+             --bibliography=*|--csl=*) test -f \"${arg#*=}\" || exit 4;;\n\
+             esac; done\ncat\n",
+        )
+        .unwrap();
+        fs::set_permissions(&pandoc, fs::Permissions::from_mode(0o755)).unwrap();
+        fs::write(
+            root.join("book.toml"),
+            format!(
+                "[preprocessor.citeproc]\ncitations = \"transpile\"\n\
+                 bibliography = \"refs.bib\"\ncsl = \"style.csl\"\n\
+                 pandoc-command = [{:?}]\n",
+                pandoc.display().to_string()
+            ),
+        )
+        .unwrap();
+        fs::write(root.join("refs.bib"), "@book{a, title = {A}}\n").unwrap();
+        fs::write(root.join("style.csl"), "").unwrap();
+        fs::write(root.join("src/SUMMARY.md"), "- [One](one.md)\n").unwrap();
+        fs::write(root.join("src/one.md"), "See [@a].\n").unwrap();
+
+        let profiles = bench(&root, "html", 2).unwrap();
+        assert_eq!(profiles.len(), 2);
+        assert_eq!(profiles[0].chapters.len(), 1);
+        fs::remove_dir_all(root).unwrap();
+    }
+}
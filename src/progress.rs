@@ -0,0 +1,50 @@
+use std::io::{self, IsTerminal, Write};
+
+/// Per-chapter progress reporting on stderr.
+///
+/// Progress is only shown when stderr is a terminal, so logs captured by CI
+/// or by `mdbook serve` aren't flooded with status lines.
+pub struct Progress {
+    total: usize,
+    done: usize,
+    enabled: bool,
+}
+
+impl Progress {
+    pub fn new(total: usize, quiet: bool) -> Self {
+        Self {
+            total,
+            done: 0,
+            enabled: !quiet && io::stderr().is_terminal(),
+        }
+    }
+
+    /// Announces that `chapter` is about to be handed to pandoc.
+    pub fn start(&mut self, chapter: &str) {
+        if !self.enabled {
+            return;
+        }
+        let remaining = self.total - self.done;
+        let mut stderr = io::stderr().lock();
+        // `\x1b[2K` clears the previous status line before redrawing it.
+        let _ = write!(
+            stderr,
+            "\r\x1b[2K[{}/{}] pandoc: {} ({} remaining)",
+            self.done + 1,
+            self.total,
+            chapter,
+            remaining - 1
+        );
+        let _ = stderr.flush();
+    }
+
+    /// Marks the chapter announced by the last [`Progress::start`] as done
+    /// and clears the status line, so that anything printed before the next
+    /// chapter starts gets a line of its own.
+    pub fn finish_one(&mut self) {
+        self.done += 1;
+        if self.enabled {
+            let _ = write!(io::stderr(), "\r\x1b[2K");
+        }
+    }
+}
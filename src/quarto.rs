@@ -0,0 +1,125 @@
+//! Compatibility with Quarto, so chapters can be shared between a Quarto
+//! site and a book without edits. Quarto cites the way pandoc does, and
+//! places the bibliography with a `::: {#refs}` div as pandoc does too; the
+//! rest of its conventions are:
+//!
+//! - `@fig-plot`, `@tbl-results`, `@sec-intro` and the like are
+//!   cross-references, not citations, so they're hidden from pandoc (and
+//!   never reported as missing) and left as they're written.
+//! - The project's `_quarto.yml` is read for `bibliography` and `csl`
+//!   where `book.toml` doesn't set them, as a pandoc defaults file is.
+//! - A chapter's `citation` front matter describes how to cite the chapter
+//!   itself, which is Quarto's business, so it's left alone.
+
+use mdbook::errors::Error;
+
+use crate::citation;
+use crate::error::CiteprocError;
+use crate::model::BookCitations;
+
+const PREFIX: &str = "CITEPROCQUARTO";
+const SUFFIX: &str = "X";
+
+/// The project file, whose citation settings are used as defaults.
+pub const PROJECT: &str = "_quarto.yml";
+
+/// The kinds of thing Quarto cross-references, as the prefixes of their
+/// ids.
+const CROSSREF_PREFIXES: &[&str] = &[
+    "fig", "tbl", "lst", "sec", "eq", "thm", "lem", "cor", "prp", "cnj", "def", "exm", "exr",
+    "sol", "rem", "alg",
+];
+
+/// Whether `key` is a Quarto cross-reference rather than a citation key.
+pub fn is_crossref(key: &str) -> bool {
+    key.split_once('-')
+        .is_some_and(|(prefix, id)| !id.is_empty() && CROSSREF_PREFIXES.contains(&prefix))
+}
+
+/// Leaves the cross-references out of `citations`.
+pub fn skip_crossrefs(citations: &mut BookCitations) {
+    for chapter in &mut citations.chapters {
+        for citation in &mut chapter.citations {
+            citation.items.retain(|item| !is_crossref(&item.key));
+        }
+        chapter
+            .citations
+            .retain(|citation| !citation.items.is_empty());
+    }
+}
+
+/// A chapter with its cross-references taken out.
+#[derive(Debug)]
+pub struct Protected {
+    pub content: String,
+    crossrefs: Vec<String>,
+}
+
+/// Replaces each cross-reference in `content` with a placeholder, leaving
+/// citations which cite anything else as they are.
+pub fn protect(content: &str) -> Protected {
+    let mut protected = Protected {
+        content: String::with_capacity(content.len()),
+        crossrefs: Vec::new(),
+    };
+    let mut last = 0;
+    for citation in citation::parse(content) {
+        if !citation.items.iter().all(|item| is_crossref(&item.key)) {
+            continue;
+        }
+        let placeholder = format!("{PREFIX}{}{SUFFIX}", protected.crossrefs.len());
+        protected
+            .content
+            .push_str(&content[last..citation.span.start]);
+        protected.content.push_str(&placeholder);
+        protected
+            .crossrefs
+            .push(content[citation.span.clone()].to_string());
+        last = citation.span.end;
+    }
+    protected.content.push_str(&content[last..]);
+    protected
+}
+
+impl Protected {
+    /// Puts the cross-references back into pandoc's `output`.
+    pub fn restore(&self, chapter_name: &str, output: &str) -> Result<String, Error> {
+        let mut restored = output.to_string();
+        for (index, crossref) in self.crossrefs.iter().enumerate() {
+            let placeholder = format!("{PREFIX}{index}{SUFFIX}");
+            let Some(start) = restored.find(&placeholder) else {
+                return Err(CiteprocError::pandoc_failed(format!(
+                    "pandoc dropped a cross-reference from chapter \"{chapter_name}\": {crossref}"
+                )));
+            };
+            restored.replace_range(start..start + placeholder.len(), crossref);
+        }
+        Ok(restored)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leaves_crossrefs_to_quarto() {
+        assert!(is_crossref("fig-plot"));
+        assert!(!is_crossref("smith2020"));
+        assert!(!is_crossref("fig-"));
+        assert!(!is_crossref("figueroa-2019"));
+
+        let content = "See @fig-plot and [@tbl-a; @tbl-b], as in [@smith, p. 3].\n";
+        let protected = protect(content);
+        assert_eq!(
+            protected.content,
+            "See CITEPROCQUARTO0X and CITEPROCQUARTO1X, as in [@smith, p. 3].\n"
+        );
+        let output = "See CITEPROCQUARTO0X and CITEPROCQUARTO1X, as in (Smith 2020, 3).\n";
+        assert_eq!(
+            protected.restore("Intro", output).unwrap(),
+            "See @fig-plot and [@tbl-a; @tbl-b], as in (Smith 2020, 3).\n"
+        );
+        assert!(protected.restore("Intro", "See.\n").is_err());
+    }
+}
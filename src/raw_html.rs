@@ -0,0 +1,265 @@
+//! `raw-html = "preserve"`: swaps raw HTML for placeholders before a chapter
+//! goes through pandoc and puts it back afterwards, so pandoc never gets the
+//! chance to escape, reformat or drop it.
+//!
+//! Block-level HTML (a line starting with a tag after a blank line, up to
+//! the next blank line) becomes a placeholder paragraph; inline tags and
+//! comments become placeholder words. HTML inside code is left alone.
+
+use mdbook::errors::Error;
+
+use crate::error::CiteprocError;
+
+/// Placeholders are `PREFIX` + index + `SUFFIX`. Being a single word of
+/// letters and digits, pandoc passes them through untouched.
+const PREFIX: &str = "CITEPROCRAWHTML";
+const SUFFIX: &str = "X";
+
+/// Tags whose blocks run to their closing tag rather than a blank line.
+const VERBATIM_TAGS: &[&str] = &["pre", "script", "style", "textarea"];
+
+/// A chapter with its raw HTML taken out.
+#[derive(Debug)]
+pub struct Protected {
+    pub content: String,
+    stash: Vec<String>,
+}
+
+/// Replaces the raw HTML in `content` with placeholders.
+pub fn protect(content: &str) -> Protected {
+    let mut protected = Protected {
+        content: String::with_capacity(content.len()),
+        stash: Vec::new(),
+    };
+    let lines: Vec<&str> = content.split_inclusive('\n').collect();
+    let mut fence: Option<String> = None;
+    let mut i = 0;
+    while i < lines.len() {
+        let line = lines[i];
+        let trimmed = line.trim_start_matches(' ');
+        let indent = line.len() - trimmed.len();
+
+        if let Some(marker) = &fence {
+            if closes_fence(trimmed, marker) {
+                fence = None;
+            }
+            protected.content.push_str(line);
+            i += 1;
+            continue;
+        }
+        if indent < 4 {
+            if let Some(marker) = fence_marker(trimmed) {
+                fence = Some(marker);
+                protected.content.push_str(line);
+                i += 1;
+                continue;
+            }
+        }
+
+        let block_start = i == 0 || lines[i - 1].trim().is_empty();
+        if block_start && indent < 4 && starts_html_block(trimmed) {
+            let end = block_end(&lines, i, trimmed);
+            let block: String = lines[i..end].concat();
+            let newline = if block.ends_with('\n') { "\n" } else { "" };
+            let placeholder = protected.stash(block.trim_end_matches('\n').to_string());
+            protected.content.push_str(&placeholder);
+            protected.content.push_str(newline);
+            i = end;
+            continue;
+        }
+
+        protect_inline(line, &mut protected);
+        i += 1;
+    }
+    protected
+}
+
+impl Protected {
+    fn stash(&mut self, html: String) -> String {
+        let placeholder = placeholder(self.stash.len());
+        self.stash.push(html);
+        placeholder
+    }
+
+    /// Puts the stashed HTML back into pandoc's `output`.
+    pub fn restore(&self, chapter_name: &str, output: &str) -> Result<String, Error> {
+        let mut restored = output.to_string();
+        for (index, html) in self.stash.iter().enumerate() {
+            let placeholder = placeholder(index);
+            if !restored.contains(&placeholder) {
+                return Err(CiteprocError::pandoc_failed(format!(
+                    "pandoc dropped raw HTML from chapter \"{chapter_name}\": {}",
+                    html.lines().next().unwrap_or_default()
+                )));
+            }
+            restored = restored.replace(&placeholder, html);
+        }
+        Ok(restored)
+    }
+}
+
+fn placeholder(index: usize) -> String {
+    format!("{PREFIX}{index}{SUFFIX}")
+}
+
+/// The backticks or tildes opening a fenced code block, if `line` is one.
+fn fence_marker(line: &str) -> Option<String> {
+    let c = line.chars().next().filter(|c| *c == '`' || *c == '~')?;
+    let len = line.chars().take_while(|x| *x == c).count();
+    (len >= 3).then(|| c.to_string().repeat(len))
+}
+
+fn closes_fence(line: &str, marker: &str) -> bool {
+    let line = line.trim_end();
+    line.len() >= marker.len() && line.chars().all(|c| marker.starts_with(c))
+}
+
+fn starts_html_block(line: &str) -> bool {
+    line.starts_with("<!--") || tag_len(line).is_some()
+}
+
+/// The index of the first line after the HTML block starting at `start`.
+fn block_end(lines: &[&str], start: usize, first: &str) -> usize {
+    let closing = if first.starts_with("<!--") {
+        Some("-->".to_string())
+    } else {
+        VERBATIM_TAGS
+            .iter()
+            .find(|tag| tag_name(first).is_some_and(|name| name.eq_ignore_ascii_case(tag)))
+            .map(|tag| format!("</{tag}>"))
+    };
+    match closing {
+        Some(closing) => lines[start..]
+            .iter()
+            .position(|line| line.to_ascii_lowercase().contains(&closing))
+            .map_or(lines.len(), |offset| start + offset + 1),
+        None => lines[start..]
+            .iter()
+            .position(|line| line.trim().is_empty())
+            .map_or(lines.len(), |offset| start + offset),
+    }
+}
+
+/// Replaces inline tags and comments in `line`, skipping code spans.
+fn protect_inline(line: &str, protected: &mut Protected) {
+    let mut rest = line;
+    while let Some(offset) = rest.find(['<', '`']) {
+        protected.content.push_str(&rest[..offset]);
+        rest = &rest[offset..];
+        if rest.starts_with('`') {
+            let ticks = rest.chars().take_while(|c| *c == '`').count();
+            let span = rest[ticks..]
+                .find(&"`".repeat(ticks))
+                .map_or(ticks, |end| ticks + end + ticks);
+            protected.content.push_str(&rest[..span]);
+            rest = &rest[span..];
+            continue;
+        }
+        let len = if rest.starts_with("<!--") {
+            rest.find("-->").map(|end| end + 3)
+        } else {
+            tag_len(rest)
+        };
+        match len {
+            Some(len) => {
+                let placeholder = protected.stash(rest[..len].to_string());
+                protected.content.push_str(&placeholder);
+                rest = &rest[len..];
+            }
+            None => {
+                protected.content.push('<');
+                rest = &rest[1..];
+            }
+        }
+    }
+    protected.content.push_str(rest);
+}
+
+/// The name of the tag `s` starts with, if it starts with an opening or
+/// closing tag.
+fn tag_name(s: &str) -> Option<&str> {
+    let name = s.strip_prefix("</").or_else(|| s.strip_prefix('<'))?;
+    let len = name
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '-'))
+        .unwrap_or(name.len());
+    name[..len]
+        .starts_with(|c: char| c.is_ascii_alphabetic())
+        .then(|| &name[..len])
+}
+
+/// The length of the tag `s` starts with. Autolinks like `<https://...>`
+/// and `<me@example.com>` aren't tags.
+fn tag_len(s: &str) -> Option<usize> {
+    let name = tag_name(s)?;
+    let closing = s.starts_with("</");
+    let mut i = name.len() + if closing { 2 } else { 1 };
+    let bytes = s.as_bytes();
+    match bytes.get(i) {
+        Some(b'>') => return Some(i + 1),
+        Some(b'/') if !closing && bytes.get(i + 1) == Some(&b'>') => return Some(i + 2),
+        Some(c) if c.is_ascii_whitespace() => {}
+        _ => return None,
+    }
+    let mut quote = None;
+    while let Some(&c) = bytes.get(i) {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, b'"' | b'\'') => quote = Some(c),
+            (None, b'>') => return Some(i + 1),
+            (None, b'\n') if closing => return None,
+            _ => {}
+        }
+        i += 1;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(content: &str) -> String {
+        let protected = protect(content);
+        protected.restore("test", &protected.content).unwrap()
+    }
+
+    #[test]
+    fn blocks_become_placeholder_paragraphs() {
+        let content =
+            "Intro\n\n<details>\n<summary>More</summary>\n\nHidden *markdown*\n\n</details>\n";
+        let protected = protect(content);
+        assert_eq!(
+            protected.content,
+            "Intro\n\nCITEPROCRAWHTML0X\n\nHidden *markdown*\n\nCITEPROCRAWHTML1X\n"
+        );
+        assert_eq!(round_trip(content), content);
+    }
+
+    #[test]
+    fn inline_html_is_preserved_byte_for_byte() {
+        let content = "A <video src=\"a>b.mp4\" controls/> and <my-widget data-x='1'>x</my-widget> [@smith].\n";
+        let protected = protect(content);
+        assert!(!protected.content.contains('<'));
+        assert!(protected.content.contains("[@smith]"));
+        assert_eq!(round_trip(content), content);
+    }
+
+    #[test]
+    fn verbatim_blocks_span_blank_lines() {
+        let content = "<pre>\n<b>a</b>\n\n  b\n</pre>\n\nText\n";
+        assert_eq!(protect(content).content, "CITEPROCRAWHTML0X\n\nText\n");
+    }
+
+    #[test]
+    fn code_and_autolinks_are_untouched() {
+        let content = "`<b>` and <https://example.com> and <me@example.com>\n\n```\n<div>\n```\n";
+        assert_eq!(protect(content).content, content);
+    }
+
+    #[test]
+    fn dropped_html_is_an_error() {
+        let protected = protect("<!-- note -->\n");
+        assert!(protected.restore("test", "").is_err());
+    }
+}
@@ -0,0 +1,46 @@
+//! `mdbook-citeproc refresh`: asks remote services again for everything
+//! which was cached from them, so corrections upstream reach the book.
+
+use std::fs;
+use std::path::Path;
+
+use mdbook::errors::Error;
+
+use crate::bibliography::Library;
+use crate::error::CiteprocError;
+use crate::{archive, check, graph, http};
+
+/// Refetches what the book in `root` caches from remote services, and
+/// returns how many archived links it has.
+pub fn refresh(root: &Path) -> Result<usize, Error> {
+    let config = check::load_config(root)?;
+    http::configure_network(&config)?;
+    if http::is_offline() {
+        return Err(CiteprocError::config("refresh can't be run offline"));
+    }
+
+    if let Some(remote) = &config.remote_bibliography {
+        remote.fetch(config.remote_ttl, true)?;
+    }
+
+    // Link checks are only remembered for a day anyway.
+    match fs::remove_file(config.cache_root.join("links.json")) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+
+    if !config.archive_links {
+        return Ok(0);
+    }
+    let citations = graph::load(root)?;
+    let library = Library::new(citations.entries.values().cloned());
+    let (archive_cache, ttl) = config.archive_cache();
+    let snapshots = archive::snapshots(
+        &library,
+        citations.entries.keys().map(String::as_str),
+        archive_cache,
+        ttl,
+        true,
+    )?;
+    Ok(snapshots.len())
+}
@@ -0,0 +1,484 @@
+//! The bibliography citeproc appends to a chapter, which pandoc writes as
+//! a `<div id="refs">` containing one `<div id="ref-KEY">` per entry.
+
+/// A chapter's output split around its bibliography.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bibliography<'a> {
+    /// Everything before the bibliography.
+    pub before: &'a str,
+    /// The opening `<div id="refs" ...>` tag.
+    pub open: &'a str,
+    pub entries: Vec<Entry>,
+    /// Headings to put before entries, by the index of the entry.
+    pub headings: Vec<(usize, String)>,
+    /// The level of the headings in `headings`.
+    pub group_level: usize,
+    /// Render the entries as ordered lists numbered as the style labels
+    /// them, rather than with the labels themselves.
+    pub numbered: bool,
+    /// The heading pandoc put before the bibliography, from
+    /// `reference-section-title`, once it's [taken](Bibliography::take_title).
+    pub title: Option<Title>,
+    /// Everything after the bibliography's closing `</div>`.
+    pub after: &'a str,
+}
+
+/// The bibliography's own heading.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Title {
+    pub level: usize,
+    pub text: String,
+    /// Whether pandoc wrote it underlined, as it does unless asked for ATX
+    /// headings.
+    pub setext: bool,
+}
+
+impl Title {
+    fn render(&self) -> String {
+        match (self.setext, self.level) {
+            (true, 1) => format!("{}\n{}", self.text, "=".repeat(self.text.chars().count())),
+            (true, 2) => format!("{}\n{}", self.text, "-".repeat(self.text.chars().count())),
+            // Underlines only go two levels deep.
+            (_, level) => format!("{} {}", "#".repeat(level), self.text),
+        }
+    }
+}
+
+/// One rendered reference.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// The citation key, from the entry's `ref-` id.
+    pub key: String,
+    /// The entry's `<div>` through its closing `</div>`.
+    pub html: String,
+}
+
+impl Entry {
+    /// Replaces the rendered reference with `body`, keeping the `<div>`
+    /// citation links point at.
+    pub fn set_body(&mut self, body: &str) {
+        let (Some(open_end), Some(close)) = (self.html.find('>'), self.html.rfind("</div>")) else {
+            return;
+        };
+        self.html = format!(
+            "{}\n\n{}\n\n{}",
+            &self.html[..open_end + 1],
+            body.trim(),
+            &self.html[close..]
+        );
+    }
+
+    /// Adds `markdown` to the end of the reference's last paragraph.
+    pub fn append(&mut self, markdown: &str) {
+        let Some(close) = self.html.rfind("</div>") else {
+            return;
+        };
+        let end = self.html[..close].trim_end().len();
+        self.html.insert_str(end, &format!(" {markdown}"));
+    }
+
+    /// Adds `html` at the end of the reference, inside its `<div>`.
+    pub fn embed(&mut self, html: &str) {
+        let Some(close) = self.html.rfind("</div>") else {
+            return;
+        };
+        self.html.insert_str(close, &format!("{html}\n"));
+    }
+
+    /// Adds `annotation` beneath the reference as indented paragraphs.
+    pub fn annotate(&mut self, annotation: &str) {
+        let Some(close) = self.html.rfind("</div>") else {
+            return;
+        };
+        let mut paragraphs = String::new();
+        for paragraph in annotation.split("\n\n") {
+            let text = paragraph.split_whitespace().collect::<Vec<_>>().join(" ");
+            if !text.is_empty() {
+                paragraphs += &format!(
+                    "<p class=\"csl-annotation\" style=\"margin-left: 2em\">{}</p>\n\n",
+                    escape_html(&text)
+                );
+            }
+        }
+        self.html.insert_str(close, &paragraphs);
+    }
+}
+
+impl Entry {
+    /// The number a numeric style labels the entry with, e.g. 3 for `[3]`.
+    pub fn number(&self) -> Option<u32> {
+        let (_, label) = self.html.split_once("class=\"csl-left-margin\">")?;
+        let label = &label[..label.find('<')?];
+        let digits: String = label.chars().filter(char::is_ascii_digit).collect();
+        digits.parse().ok()
+    }
+
+    /// The entry as an item of an ordered list, keeping its number and the
+    /// id citations link to, without the label.
+    fn list_item(&self, number: u32) -> String {
+        let (Some(open_end), Some(close)) = (self.html.find('>'), self.html.rfind("</div>")) else {
+            return self.html.clone();
+        };
+        let mut body = self.html[open_end + 1..close].to_string();
+        if let Some(start) = body.find("<span class=\"csl-left-margin\">") {
+            if let Some(end) = body[start..].find("</span>") {
+                body.replace_range(start..start + end + "</span>".len(), "");
+            }
+        }
+        format!(
+            "<li id=\"ref-{}\" class=\"csl-entry\" value=\"{number}\">\n\n{}\n\n</li>",
+            self.key,
+            body.trim()
+        )
+    }
+}
+
+impl<'a> Bibliography<'a> {
+    /// Finds the bibliography in `output`.
+    pub fn parse(output: &'a str) -> Option<Self> {
+        let start = output.find("<div id=\"refs\"")?;
+        let open_end = start + output[start..].find('>')? + 1;
+        let end = matching_close(output, start)?;
+        let inner = &output[open_end..end - "</div>".len()];
+
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        while let Some(entry_start) = inner[offset..].find("<div").map(|i| offset + i) {
+            let entry_end = matching_close(inner, entry_start)?;
+            let html = &inner[entry_start..entry_end];
+            let key = html
+                .split_once("id=\"ref-")
+                .and_then(|(_, rest)| rest.split_once('"'))
+                .map(|(key, _)| key.to_string())
+                .unwrap_or_default();
+            entries.push(Entry {
+                key,
+                html: html.to_string(),
+            });
+            offset = entry_end;
+        }
+
+        Some(Self {
+            before: &output[..start],
+            open: &output[start..open_end],
+            entries,
+            headings: Vec::new(),
+            group_level: 2,
+            numbered: false,
+            title: None,
+            after: &output[end..],
+        })
+    }
+
+    /// Puts the chapter back together. A bibliography without entries is
+    /// left out entirely.
+    pub fn render(&self) -> String {
+        if self.entries.is_empty() {
+            return format!("{}{}", self.before.trim_end(), self.after);
+        }
+        let mut out = self.before.to_string();
+        if let Some(title) = &self.title {
+            out += &format!("{}\n\n", title.render());
+        }
+        out += &format!("{}\n\n", self.open);
+        let hashes = "#".repeat(self.group_level);
+        // Only if every entry has a number; otherwise it's not a numeric
+        // style.
+        let numbers: Option<Vec<u32>> = match self.numbered {
+            true => self.entries.iter().map(Entry::number).collect(),
+            false => None,
+        };
+        let mut list_open = false;
+        for (i, entry) in self.entries.iter().enumerate() {
+            for (_, heading) in self.headings.iter().filter(|(at, _)| *at == i) {
+                if list_open {
+                    out += "</ol>\n\n";
+                    list_open = false;
+                }
+                out += &format!("{hashes} {heading}\n\n");
+            }
+            match &numbers {
+                Some(numbers) => {
+                    if !list_open {
+                        out += "<ol class=\"csl-numbered\">\n\n";
+                        list_open = true;
+                    }
+                    out += &entry.list_item(numbers[i]);
+                }
+                None => out += &entry.html,
+            }
+            out += "\n\n";
+        }
+        if list_open {
+            out += "</ol>\n\n";
+        }
+        out += "</div>";
+        out += self.after;
+        out
+    }
+
+    /// Orders the entries by where they're first cited in `keys`, with any
+    /// entry which isn't cited (e.g. from `nocite`) at the end.
+    pub fn sort_by_appearance<'k>(&mut self, keys: impl IntoIterator<Item = &'k str>) {
+        let mut order: Vec<&str> = Vec::new();
+        for key in keys {
+            if !order.contains(&key) {
+                order.push(key);
+            }
+        }
+        self.entries.sort_by_key(|entry| {
+            order
+                .iter()
+                .position(|key| *key == entry.key)
+                .unwrap_or(order.len())
+        });
+    }
+
+    /// Takes the heading directly before the bibliography out of `before`
+    /// into `title`, if there is one, so its level can be changed.
+    pub fn take_title(&mut self) {
+        let before = self.before.trim_end();
+        let (line_start, line) = match before.rfind('\n') {
+            Some(i) => (i + 1, &before[i + 1..]),
+            None => (0, before),
+        };
+        let hashes = line.chars().take_while(|c| *c == '#').count();
+        let (start, title) = if (1..=6).contains(&hashes) && line[hashes..].starts_with(' ') {
+            let title = Title {
+                level: hashes,
+                text: line[hashes..].trim().to_string(),
+                setext: false,
+            };
+            (line_start, title)
+        } else {
+            let underline = line.trim();
+            let level = match underline.chars().next() {
+                Some('=') => 1,
+                Some('-') => 2,
+                _ => return,
+            };
+            let first = underline.as_bytes()[0];
+            // The text is right above, or it's a `---` rule.
+            let above = before[..line_start].strip_suffix('\n').unwrap_or("");
+            let above_start = above.rfind('\n').map_or(0, |i| i + 1);
+            let text = above[above_start..].trim();
+            if text.is_empty() || !underline.bytes().all(|c| c == first) {
+                return;
+            }
+            let title = Title {
+                level,
+                text: text.to_string(),
+                setext: true,
+            };
+            (above_start, title)
+        };
+        self.before = &self.before[..start];
+        self.title = Some(title);
+    }
+
+    /// Drops every entry whose key isn't in `keys`.
+    pub fn retain<'k>(&mut self, keys: impl IntoIterator<Item = &'k str>) {
+        let keys: Vec<&str> = keys.into_iter().collect();
+        self.entries
+            .retain(|entry| keys.contains(&entry.key.as_str()));
+    }
+
+    /// Splits the entries into groups under a heading each, in the order of
+    /// `titles`. `group` gives the index of an entry's group by key; empty
+    /// groups are left out.
+    pub fn group_by(&mut self, titles: &[String], group: impl Fn(&str) -> usize) {
+        // A stable sort keeps each group in the order it was in.
+        self.entries.sort_by_key(|entry| group(&entry.key));
+        self.headings.clear();
+        let mut last = None;
+        for (i, entry) in self.entries.iter().enumerate() {
+            let index = group(&entry.key);
+            if last != Some(index) {
+                self.headings.push((i, titles[index].clone()));
+                last = Some(index);
+            }
+        }
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// The end of the `</div>` closing the `<div` at `start`.
+fn matching_close(html: &str, start: usize) -> Option<usize> {
+    let mut depth = 0usize;
+    let mut i = start;
+    loop {
+        let open = html[i..].find("<div").map(|o| i + o);
+        let close = html[i..].find("</div>").map(|c| i + c);
+        match (open, close) {
+            (Some(open), Some(close)) if open < close => {
+                depth += 1;
+                i = open + "<div".len();
+            }
+            (_, Some(close)) => {
+                depth -= 1;
+                i = close + "</div>".len();
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            (_, None) => return None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OUTPUT: &str = "Text [Smith](#ref-smith).\n\n\
+        <div id=\"refs\" class=\"references csl-bib-body\"\nrole=\"list\">\n\n\
+        <div id=\"ref-adams\" class=\"csl-entry\" role=\"listitem\">\n\nAdams.\n\n</div>\n\n\
+        <div id=\"ref-smith\" class=\"csl-entry\" role=\"listitem\">\n\nSmith.\n\n</div>\n\n\
+        </div>\n";
+
+    #[test]
+    fn round_trips() {
+        let bibliography = Bibliography::parse(OUTPUT).unwrap();
+        let keys: Vec<&str> = bibliography
+            .entries
+            .iter()
+            .map(|e| e.key.as_str())
+            .collect();
+        assert_eq!(keys, ["adams", "smith"]);
+        assert_eq!(bibliography.render(), OUTPUT);
+    }
+
+    #[test]
+    fn sorts_by_appearance() {
+        let mut bibliography = Bibliography::parse(OUTPUT).unwrap();
+        bibliography.sort_by_appearance(["smith", "smith"]);
+        let keys: Vec<&str> = bibliography
+            .entries
+            .iter()
+            .map(|e| e.key.as_str())
+            .collect();
+        assert_eq!(keys, ["smith", "adams"]);
+    }
+
+    #[test]
+    fn replaces_entry_bodies() {
+        let mut bibliography = Bibliography::parse(OUTPUT).unwrap();
+        bibliography.entries[1].set_body("SMITH, J.\n");
+        assert_eq!(
+            bibliography.entries[1].html,
+            "<div id=\"ref-smith\" class=\"csl-entry\" role=\"listitem\">\n\nSMITH, J.\n\n</div>"
+        );
+    }
+
+    #[test]
+    fn appends_to_entries() {
+        let mut bibliography = Bibliography::parse(OUTPUT).unwrap();
+        bibliography.entries[0].append("[Archived](https://web.archive.org/x)");
+        assert_eq!(
+            bibliography.entries[0].html,
+            "<div id=\"ref-adams\" class=\"csl-entry\" role=\"listitem\">\n\n\
+             Adams. [Archived](https://web.archive.org/x)\n\n</div>"
+        );
+    }
+
+    #[test]
+    fn annotates_entries() {
+        let mut bibliography = Bibliography::parse(OUTPUT).unwrap();
+        bibliography.entries[0].annotate("A classic\nread.\n\nSee also <Smith>.");
+        assert_eq!(
+            bibliography.entries[0].html,
+            "<div id=\"ref-adams\" class=\"csl-entry\" role=\"listitem\">\n\nAdams.\n\n\
+             <p class=\"csl-annotation\" style=\"margin-left: 2em\">A classic read.</p>\n\n\
+             <p class=\"csl-annotation\" style=\"margin-left: 2em\">See also &lt;Smith&gt;.</p>\n\n\
+             </div>"
+        );
+    }
+
+    #[test]
+    fn drops_uncited_entries() {
+        let mut bibliography = Bibliography::parse(OUTPUT).unwrap();
+        bibliography.retain(["smith"]);
+        assert_eq!(bibliography.entries.len(), 1);
+        bibliography.retain([]);
+        assert_eq!(bibliography.render(), "Text [Smith](#ref-smith).\n");
+    }
+
+    #[test]
+    fn groups_entries_under_headings() {
+        let mut bibliography = Bibliography::parse(OUTPUT).unwrap();
+        let titles = ["Books".to_string(), "Articles".to_string()];
+        bibliography.group_by(&titles, |key| usize::from(key == "adams"));
+        let rendered = bibliography.render();
+        let books = rendered.find("## Books\n\n<div id=\"ref-smith\"").unwrap();
+        let articles = rendered
+            .find("## Articles\n\n<div id=\"ref-adams\"")
+            .unwrap();
+        assert!(books < articles);
+    }
+
+    #[test]
+    fn numbers_entries_as_their_labels() {
+        let numeric = "<div id=\"refs\" class=\"references csl-bib-body\" role=\"list\">\n\n\
+            <div id=\"ref-smith\" class=\"csl-entry\" role=\"listitem\">\n\n\
+            <span class=\"csl-left-margin\">[1] </span><span class=\"csl-right-inline\">Smith.</span>\n\n\
+            </div>\n\n\
+            <div id=\"ref-adams\" class=\"csl-entry\" role=\"listitem\">\n\n\
+            <span class=\"csl-left-margin\">[2] </span><span class=\"csl-right-inline\">Adams.</span>\n\n\
+            </div>\n\n</div>\n";
+        let mut bibliography = Bibliography::parse(numeric).unwrap();
+        bibliography.numbered = true;
+        let titles = ["Books".to_string(), "Articles".to_string()];
+        bibliography.group_by(&titles, |key| usize::from(key == "smith"));
+        assert_eq!(
+            bibliography.render(),
+            "<div id=\"refs\" class=\"references csl-bib-body\" role=\"list\">\n\n\
+             ## Books\n\n<ol class=\"csl-numbered\">\n\n\
+             <li id=\"ref-adams\" class=\"csl-entry\" value=\"2\">\n\n\
+             <span class=\"csl-right-inline\">Adams.</span>\n\n</li>\n\n</ol>\n\n\
+             ## Articles\n\n<ol class=\"csl-numbered\">\n\n\
+             <li id=\"ref-smith\" class=\"csl-entry\" value=\"1\">\n\n\
+             <span class=\"csl-right-inline\">Smith.</span>\n\n</li>\n\n</ol>\n\n</div>\n"
+        );
+
+        // Not a numeric style.
+        let mut bibliography = Bibliography::parse(OUTPUT).unwrap();
+        bibliography.numbered = true;
+        assert_eq!(bibliography.render(), OUTPUT);
+    }
+
+    #[test]
+    fn changes_the_title_level() {
+        let output = format!("Text.\n\nReferences\n==========\n\n{}", &OUTPUT[27..]);
+        let mut bibliography = Bibliography::parse(&output).unwrap();
+        bibliography.take_title();
+        let title = bibliography.title.as_mut().unwrap();
+        assert_eq!((title.text.as_str(), title.setext), ("References", true));
+        title.level = 2;
+        assert_eq!(bibliography.before, "Text.\n\n");
+        assert!(bibliography
+            .render()
+            .starts_with("Text.\n\nReferences\n----------\n\n<div id=\"refs\""));
+        bibliography.title.as_mut().unwrap().level = 3;
+        assert!(bibliography
+            .render()
+            .starts_with("Text.\n\n### References\n\n"));
+
+        let output = format!("Text.\n\n# Works cited\n\n{}", &OUTPUT[27..]);
+        let mut bibliography = Bibliography::parse(&output).unwrap();
+        bibliography.take_title();
+        assert_eq!(bibliography.title.unwrap().text, "Works cited");
+
+        let mut bibliography = Bibliography::parse(OUTPUT).unwrap();
+        bibliography.take_title();
+        assert_eq!(bibliography.title, None);
+        let ruled = format!("Text.\n\n---\n\n{}", &OUTPUT[27..]);
+        let mut bibliography = Bibliography::parse(&ruled).unwrap();
+        bibliography.take_title();
+        assert_eq!(bibliography.title, None);
+    }
+}
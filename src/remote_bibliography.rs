@@ -0,0 +1,256 @@
+//! A bibliography kept in an online reference manager, fetched with an API
+//! token, for `[remote-bibliography]`:
+//!
+//! ```toml
+//! [preprocessor.citeproc.remote-bibliography]
+//! service = "mendeley"
+//! ```
+//!
+//! Mendeley's documents are fetched from its API as BibTeX. EndNote online
+//! has no public API of its own, so `service = "endnote"` needs `url` as
+//! well: where the library's BibTeX export is served (e.g. through an
+//! institution's gateway). The token is read from the environment variable
+//! `token-env` names, `MENDELEY_TOKEN` or `ENDNOTE_TOKEN` by default, so it
+//! never ends up in `book.toml`.
+//!
+//! The library is kept in the cache root as `remote/<service>.bib` and
+//! used like any other bibliography. It's fetched again once it's older
+//! than `remote-cache-days`, or by `mdbook-citeproc refresh`; offline, or
+//! without a token, the copy there is used. With `sha256` set, whatever
+//! copy is used must have that hash, or the build fails. A copy [vendored](crate::vendor)
+//! is used instead, and only fetched again by `refresh` or `vendor`.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use mdbook::errors::Error;
+use toml::value::Table;
+
+use crate::cache::{self, sha256_hex};
+use crate::config::get_str;
+use crate::error::CiteprocError;
+use crate::http;
+
+const MENDELEY_API: &str = "https://api.mendeley.com/documents?view=bib&limit=500";
+const BIBTEX: &str = "application/x-bibtex";
+/// More pages than any library has, in case an API keeps saying there's a
+/// next one.
+const MAX_PAGES: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Service {
+    Mendeley,
+    EndNote,
+}
+
+impl Service {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Mendeley => "mendeley",
+            Self::EndNote => "endnote",
+        }
+    }
+}
+
+/// The parsed `[remote-bibliography]` table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemoteBibliography {
+    pub service: Service,
+    pub url: String,
+    /// The environment variable with the API token.
+    pub token_env: String,
+    /// Where the fetched library is kept.
+    pub path: PathBuf,
+    /// Whether `path` is the vendored copy.
+    pub vendored: bool,
+    /// The hash the library must have, if it's pinned.
+    pub sha256: Option<String>,
+}
+
+impl RemoteBibliography {
+    pub fn from_table(table: &Table, cache_root: &Path) -> Result<Self, Error> {
+        let service = match get_str(table, "service")?.as_deref() {
+            Some("mendeley") => Service::Mendeley,
+            Some("endnote") => Service::EndNote,
+            _ => {
+                return Err(CiteprocError::config(
+                    "remote-bibliography.service must be \"mendeley\" or \"endnote\"",
+                ))
+            }
+        };
+        let url = match (service, get_str(table, "url")?) {
+            (_, Some(url)) => url,
+            (Service::Mendeley, None) => MENDELEY_API.to_string(),
+            (Service::EndNote, None) => {
+                return Err(CiteprocError::config(
+                    "remote-bibliography.url must be set for EndNote, which has no public API",
+                ))
+            }
+        };
+        let token_env = get_str(table, "token-env")?
+            .unwrap_or_else(|| format!("{}_TOKEN", service.name().to_uppercase()));
+        let path = cache_root
+            .join("remote")
+            .join(format!("{}.bib", service.name()));
+        Ok(Self {
+            service,
+            url,
+            token_env,
+            path,
+            vendored: false,
+            sha256: get_str(table, "sha256")?,
+        })
+    }
+
+    /// Where the library is kept in the vendor directory `dir`.
+    pub fn vendored_path(&self, dir: &Path) -> Option<PathBuf> {
+        Some(dir.join("remote").join(self.path.file_name()?))
+    }
+
+    /// Reads the library from the vendor directory `dir`, if it's there.
+    pub fn vendored_in(mut self, dir: &Path) -> Self {
+        if let Some(path) = self.vendored_path(dir).filter(|path| path.is_file()) {
+            self.path = path;
+            self.vendored = true;
+        }
+        self
+    }
+
+    /// Fetches the library unless the cached copy is younger than `ttl`
+    /// (with no `ttl` it's kept for good), or always with `refresh`, and
+    /// checks it against `sha256`.
+    pub fn fetch(&self, ttl: Option<Duration>, refresh: bool) -> Result<(), Error> {
+        self.fetch_unverified(ttl, refresh)?;
+        match fs::read(&self.path) {
+            Ok(library) => self.verify(&library),
+            Err(_) => Ok(()),
+        }
+    }
+
+    /// Fails unless `library` has the pinned hash.
+    fn verify(&self, library: &[u8]) -> Result<(), Error> {
+        let Some(expected) = &self.sha256 else {
+            return Ok(());
+        };
+        let actual = sha256_hex(library);
+        match expected.eq_ignore_ascii_case(&actual) {
+            true => Ok(()),
+            false => Err(CiteprocError::config(format!(
+                "the {} library doesn't match remote-bibliography.sha256: expected {expected}, \
+                 got {actual}",
+                self.service.name()
+            ))),
+        }
+    }
+
+    fn fetch_unverified(&self, ttl: Option<Duration>, refresh: bool) -> Result<(), Error> {
+        let age = fs::metadata(&self.path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .map(|modified| {
+                SystemTime::now()
+                    .duration_since(modified)
+                    .unwrap_or_default()
+            });
+        let fresh = age.is_some_and(|age| self.vendored || ttl.is_none_or(|ttl| age < ttl));
+        if fresh && !refresh {
+            return Ok(());
+        }
+        let stale = |reason: String| match age {
+            Some(_) => {
+                eprintln!(
+                    "Warning: {reason}; using the copy of the {} library fetched before",
+                    self.service.name()
+                );
+                Ok(())
+            }
+            None => Err(CiteprocError::config(format!(
+                "{reason}, and the {} library has never been fetched",
+                self.service.name()
+            ))),
+        };
+        if http::is_offline() {
+            return stale("offline".to_string());
+        }
+        let Some(token) = env::var(&self.token_env).ok().filter(|t| !t.is_empty()) else {
+            return stale(format!("{} isn't set", self.token_env));
+        };
+
+        let mut bibtex = String::new();
+        let mut url = Some(self.url.clone());
+        for _ in 0..MAX_PAGES {
+            let Some(next) = url.take() else {
+                break;
+            };
+            let page = match http::get_page(&next, &token, BIBTEX) {
+                Ok(page) => page,
+                Err(e) => return stale(e.to_string()),
+            };
+            bibtex.push_str(&String::from_utf8_lossy(&page.body));
+            bibtex.push('\n');
+            url = page.next;
+        }
+        // Keep the last good copy.
+        self.verify(bibtex.as_bytes())?;
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        cache::write_atomic(&self.path, bibtex)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_the_service_and_its_defaults() {
+        let table = |toml: &str| toml::from_str::<Table>(toml).unwrap();
+        let cache = Path::new("cache");
+        let mendeley =
+            RemoteBibliography::from_table(&table("service = \"mendeley\""), cache).unwrap();
+        assert_eq!(mendeley.url, MENDELEY_API);
+        assert_eq!(mendeley.token_env, "MENDELEY_TOKEN");
+        assert_eq!(mendeley.path, Path::new("cache/remote/mendeley.bib"));
+
+        assert!(RemoteBibliography::from_table(&table("service = \"endnote\""), cache).is_err());
+        let endnote = RemoteBibliography::from_table(
+            &table("service = \"endnote\"\nurl = \"https://e.example/x\"\ntoken-env = \"T\""),
+            cache,
+        )
+        .unwrap();
+        assert_eq!(
+            (endnote.service, endnote.token_env.as_str()),
+            (Service::EndNote, "T")
+        );
+        assert!(RemoteBibliography::from_table(&table("service = \"zotero\""), cache).is_err());
+    }
+
+    #[test]
+    fn vendored_libraries_are_never_fetched() {
+        let dir = std::env::temp_dir().join(format!("citeproc-vendored-{}", std::process::id()));
+        fs::create_dir_all(dir.join("remote")).unwrap();
+        fs::write(dir.join("remote/mendeley.bib"), "@book{a, title = {A}}\n").unwrap();
+        let table =
+            toml::from_str::<Table>("service = \"mendeley\"\ntoken-env = \"UNSET_TOKEN\"").unwrap();
+        let remote = RemoteBibliography::from_table(&table, Path::new("cache"))
+            .unwrap()
+            .vendored_in(&dir);
+        assert_eq!(remote.path, dir.join("remote/mendeley.bib"));
+        // Without a token anything but the vendored copy would warn or fail.
+        remote.fetch(Some(Duration::ZERO), false).unwrap();
+        let pinned = RemoteBibliography {
+            sha256: Some("0".repeat(64)),
+            ..remote
+        };
+        let e = pinned.fetch(None, false).unwrap_err().to_string();
+        assert!(
+            e.contains("doesn't match remote-bibliography.sha256"),
+            "{e}"
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,243 @@
+//! Rewrites the list and emphasis markers in pandoc's output to match the
+//! book's markdown style, since pandoc offers no options for either.
+//!
+//! Only markers pandoc actually wrote are touched: code, code spans and
+//! escaped characters are left alone.
+
+/// How a processed chapter's markdown should be written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct WriterStyle {
+    /// The bullet list marker: `-`, `*` or `+`.
+    pub list_marker: Option<char>,
+    /// The emphasis (and strong emphasis) marker: `*` or `_`.
+    pub emphasis_marker: Option<char>,
+}
+
+impl WriterStyle {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Rewrites `markdown` according to `style`.
+pub fn restyle(markdown: &str, style: &WriterStyle) -> String {
+    let mut out = String::with_capacity(markdown.len());
+    let mut paragraph = String::new();
+    let mut fence: Option<(char, usize)> = None;
+    let mut list_indent: Option<usize> = None;
+    let mut previous_blank = true;
+    let mut in_indented_code = false;
+
+    for line in markdown.split_inclusive('\n') {
+        let trimmed = line.trim_start_matches(' ');
+        let indent = line.len() - trimmed.len();
+        let blank = trimmed.trim().is_empty();
+
+        if let Some((c, len)) = fence {
+            let end = trimmed.trim_end();
+            if end.len() >= len && end.chars().all(|x| x == c) {
+                fence = None;
+            }
+            out.push_str(line);
+            continue;
+        }
+        if let Some(marker) = fence_marker(trimmed) {
+            flush(&mut paragraph, &mut out, style);
+            fence = Some(marker);
+            out.push_str(line);
+            previous_blank = false;
+            continue;
+        }
+        let code_indent = list_indent.unwrap_or(0) + 4;
+        if !blank && indent >= code_indent && (previous_blank || in_indented_code) {
+            flush(&mut paragraph, &mut out, style);
+            in_indented_code = true;
+            out.push_str(line);
+            previous_blank = false;
+            continue;
+        }
+        if blank {
+            flush(&mut paragraph, &mut out, style);
+            out.push_str(line);
+            previous_blank = true;
+            continue;
+        }
+        in_indented_code = false;
+
+        match bullet(trimmed) {
+            Some((marker, width)) => {
+                flush(&mut paragraph, &mut out, style);
+                list_indent = Some(indent + width);
+                out.push_str(&line[..indent]);
+                out.push(style.list_marker.unwrap_or(marker));
+                paragraph.push_str(&trimmed[1..]);
+            }
+            None => {
+                if indent == 0 && previous_blank {
+                    list_indent = None;
+                }
+                paragraph.push_str(line);
+            }
+        }
+        previous_blank = false;
+    }
+    flush(&mut paragraph, &mut out, style);
+    out
+}
+
+/// The fence character and length, if `line` opens a fenced code block.
+fn fence_marker(line: &str) -> Option<(char, usize)> {
+    let c = line.chars().next().filter(|c| *c == '`' || *c == '~')?;
+    let len = line.chars().take_while(|x| *x == c).count();
+    (len >= 3).then_some((c, len))
+}
+
+/// The marker and content offset of a bullet list item. Thematic breaks
+/// like `* * *` aren't list items.
+fn bullet(line: &str) -> Option<(char, usize)> {
+    let marker = line
+        .chars()
+        .next()
+        .filter(|c| matches!(c, '-' | '*' | '+'))?;
+    let spaces = line[1..].chars().take_while(|c| *c == ' ').count();
+    if spaces == 0 && !line[1..].trim().is_empty() {
+        return None;
+    }
+    let break_chars = line.trim().chars().filter(|c| *c != ' ');
+    if break_chars.clone().count() >= 3 && break_chars.into_iter().all(|c| c == marker) {
+        return None;
+    }
+    Some((marker, 1 + spaces))
+}
+
+fn flush(paragraph: &mut String, out: &mut String, style: &WriterStyle) {
+    match style.emphasis_marker {
+        Some(marker) => out.push_str(&rewrite_emphasis(paragraph, marker)),
+        None => out.push_str(paragraph),
+    }
+    paragraph.clear();
+}
+
+/// A run of `*` or `_` which may open or close emphasis.
+struct Delimiter {
+    start: usize,
+    c: char,
+    len: usize,
+    can_open: bool,
+    can_close: bool,
+}
+
+/// Rewrites every pair of emphasis delimiters in `text` to use `marker`,
+/// unless `marker` wouldn't be valid in that position (`_` can't be used
+/// inside a word).
+fn rewrite_emphasis(text: &str, marker: char) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let mut delimiters = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => i += 2,
+            '`' => {
+                let ticks = chars[i..].iter().take_while(|c| **c == '`').count();
+                let close = (i + ticks..chars.len().saturating_sub(ticks - 1))
+                    .find(|&j| chars[j..j + ticks].iter().all(|c| *c == '`'));
+                i = close.map_or(i + ticks, |j| j + ticks);
+            }
+            c @ ('*' | '_') => {
+                let len = chars[i..].iter().take_while(|x| **x == c).count();
+                let before = i.checked_sub(1).map(|j| chars[j]);
+                let after = chars.get(i + len).copied();
+                let space = |c: Option<char>| c.is_none_or(char::is_whitespace);
+                let word = |c: Option<char>| c.is_some_and(char::is_alphanumeric);
+                let (mut can_open, mut can_close) = (!space(after), !space(before));
+                if c == '_' {
+                    can_open &= !word(before);
+                    can_close &= !word(after);
+                }
+                delimiters.push(Delimiter {
+                    start: i,
+                    c,
+                    len,
+                    can_open,
+                    can_close,
+                });
+                i += len;
+            }
+            _ => i += 1,
+        }
+    }
+
+    let mut replace = vec![false; chars.len()];
+    let mut openers: Vec<&Delimiter> = Vec::new();
+    for delimiter in &delimiters {
+        let opener = delimiter
+            .can_close
+            .then(|| {
+                openers
+                    .iter()
+                    .rposition(|o| o.c == delimiter.c && o.len == delimiter.len)
+            })
+            .flatten();
+        if let Some(index) = opener {
+            let opener = openers[index];
+            openers.truncate(index);
+            let word = |c: Option<&char>| c.is_some_and(|c| c.is_alphanumeric());
+            let fits = marker == '*'
+                || (!word(opener.start.checked_sub(1).and_then(|j| chars.get(j)))
+                    && !word(chars.get(delimiter.start + delimiter.len)));
+            if fits {
+                for d in [opener, delimiter] {
+                    replace[d.start..d.start + d.len].fill(true);
+                }
+            }
+        } else if delimiter.can_open {
+            openers.push(delimiter);
+        }
+    }
+
+    chars
+        .iter()
+        .zip(replace)
+        .map(|(c, replace)| if replace { marker } else { *c })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STYLE: WriterStyle = WriterStyle {
+        list_marker: Some('-'),
+        emphasis_marker: Some('_'),
+    };
+
+    #[test]
+    fn rewrites_list_markers() {
+        let markdown = "*   one\n*   two\n    *   nested\n\n* * *\n";
+        assert_eq!(
+            restyle(markdown, &STYLE),
+            "-   one\n-   two\n    -   nested\n\n* * *\n"
+        );
+    }
+
+    #[test]
+    fn rewrites_emphasis_across_lines() {
+        let markdown = "Some *emphasis\nacross lines* and **strong** text.\n";
+        assert_eq!(
+            restyle(markdown, &STYLE),
+            "Some _emphasis\nacross lines_ and __strong__ text.\n"
+        );
+    }
+
+    #[test]
+    fn keeps_intraword_emphasis_and_escapes() {
+        let markdown = "foo*bar*baz \\*not\\* `*code*` snake_case\n";
+        assert_eq!(restyle(markdown, &STYLE), markdown);
+    }
+
+    #[test]
+    fn leaves_code_blocks_alone() {
+        let markdown = "```\n*   not a list *x*\n```\n\n    *   indented *code*\n";
+        assert_eq!(restyle(markdown, &STYLE), markdown);
+    }
+}
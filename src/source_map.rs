@@ -0,0 +1,147 @@
+//! Which line of a chapter as its author wrote it each line of the text
+//! pandoc is given came from, once keys are renamed, directives expanded,
+//! front matter split off and placeholders swapped in, so what pandoc says
+//! about a line can point at the author's.
+//!
+//! Each step of the pipeline is diffed word by word against the one before.
+//! A line starting with words a step left alone keeps their line's origin,
+//! and one a step wrote takes that of the words it replaced. Chapters are as mdbook hands them over,
+//! so lines are counted after any `{{#include}}`.
+
+use similar::{ChangeTag, TextDiff};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceMap {
+    /// For each line, counting from 0, the line it came from, counting
+    /// from 1.
+    origins: Vec<usize>,
+}
+
+impl SourceMap {
+    /// The map of the author's `text` to itself.
+    pub fn new(text: &str) -> Self {
+        Self {
+            origins: (1..=text.lines().count().max(1)).collect(),
+        }
+    }
+
+    /// The map for `after`, a step on from `before`, which this maps.
+    pub fn step(&self, before: &str, after: &str) -> Self {
+        if before == after {
+            return self.clone();
+        }
+        // The line in `before` of each line which starts with a word it
+        // kept, or else whose first new word stands in for words taken
+        // out, counting from 0.
+        let mut anchors: Vec<Option<usize>> = vec![None; after.lines().count().max(1)];
+        let (mut old_line, mut new_line) = (0, 0);
+        let mut removed: Option<usize> = None;
+        for change in TextDiff::from_words(before, after).iter_all_changes() {
+            let value = change.value();
+            let word = !value.trim().is_empty();
+            let anchor = anchors.get_mut(new_line);
+            match change.tag() {
+                ChangeTag::Equal if word => {
+                    removed = None;
+                    if let Some(anchor @ None) = anchor {
+                        *anchor = Some(old_line);
+                    }
+                }
+                ChangeTag::Delete if word => {
+                    removed.get_or_insert(old_line);
+                }
+                ChangeTag::Insert if word => {
+                    if let (Some(anchor @ None), Some(removed)) = (anchor, removed) {
+                        *anchor = Some(removed);
+                    }
+                }
+                _ => {}
+            }
+            let newlines = value.matches('\n').count();
+            match change.tag() {
+                ChangeTag::Equal => {
+                    old_line += newlines;
+                    new_line += newlines;
+                }
+                ChangeTag::Delete => old_line += newlines,
+                ChangeTag::Insert => new_line += newlines,
+            }
+        }
+
+        // Lines without an anchor, like blank ones and what a step adds,
+        // go with the line before, or at the top the first line after.
+        let first = anchors.iter().find_map(|anchor| *anchor).unwrap_or(0);
+        let mut last = first;
+        let origins = anchors
+            .into_iter()
+            .map(|anchor| {
+                last = anchor.unwrap_or(last);
+                self.line(last + 1)
+            })
+            .collect();
+        Self { origins }
+    }
+
+    /// The map for what follows the first `lines` lines, e.g. a chapter's
+    /// body after its front matter.
+    pub fn skip(&self, lines: usize) -> Self {
+        let origins = self.origins[lines.min(self.origins.len() - 1)..].to_vec();
+        Self { origins }
+    }
+
+    /// The author's line for `line`, counting from 1. Lines past the end
+    /// are the last line's.
+    pub fn line(&self, line: usize) -> usize {
+        let last = self.origins.len() - 1;
+        self.origins[line.saturating_sub(1).min(last)]
+    }
+
+    /// `message` with each `line N` in it, as pandoc reports positions,
+    /// pointing at the author's line instead.
+    pub fn relocate(&self, message: &str) -> String {
+        const LINE: &str = "line ";
+        let mut out = String::with_capacity(message.len());
+        let mut rest = message;
+        while let Some(at) = rest.find(LINE) {
+            let after = &rest[at + LINE.len()..];
+            let digits = after.bytes().take_while(u8::is_ascii_digit).count();
+            out.push_str(&rest[..at + LINE.len()]);
+            match after[..digits].parse() {
+                Ok(line) => out.push_str(&self.line(line).to_string()),
+                Err(_) => out.push_str(&after[..digits]),
+            }
+            rest = &after[digits..];
+        }
+        out.push_str(rest);
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn follows_lines_through_each_step() {
+        let source = "---\ntitle: One\n---\n# One\n\n{{#epigraph @a \"Q.\"}}\n\nText @old.\n";
+        let expanded =
+            "---\ntitle: One\n---\n# One\n\n\nOPENX\n\n> Q.\n>\n> — [@a]\n\nCLOSEX\n\n\n\
+                        Text @new.\n";
+        let map = SourceMap::new(source).step(source, expanded);
+        assert_eq!(map.line(9), 6);
+        assert_eq!(map.line(16), 8);
+
+        let body = &expanded[expanded.find("# One").unwrap()..];
+        let primed = format!("PRIMEDX [@a]\n\n{body}");
+        let map = map.skip(3).step(body, &primed);
+        assert_eq!(map.line(1), 4);
+        assert_eq!(map.line(3), 4);
+        assert_eq!(map.line(16), 8);
+        assert_eq!(map.line(100), 8);
+        assert_eq!(
+            map.relocate("[WARNING] Duplicate note reference '1' at line 16 column 5\n"),
+            "[WARNING] Duplicate note reference '1' at line 8 column 5\n"
+        );
+        assert_eq!(map.relocate("no position, line x"), "no position, line x");
+    }
+}
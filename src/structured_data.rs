@@ -0,0 +1,155 @@
+//! schema.org structured data for the works a chapter cites, for
+//! `structured-data`: the HTML renderer's chapters end with a JSON-LD
+//! `<script>` describing each of them, as a `ScholarlyArticle`, `Book` and
+//! so on, so search engines can tell what the book cites.
+
+use serde_json::{json, Map, Value};
+
+use crate::bibliography::{self, Entry, Name};
+
+/// The `<script>` describing `entries`, or nothing if there are none.
+pub fn script(entries: &[&Entry]) -> Option<String> {
+    if entries.is_empty() {
+        return None;
+    }
+    let graph: Vec<Value> = entries.iter().map(|entry| work(entry)).collect();
+    let data = json!({ "@context": "https://schema.org", "@graph": graph });
+    // `</script>` in a title mustn't end the script.
+    let data = data.to_string().replace("</", "<\\/");
+    Some(format!(
+        "<script type=\"application/ld+json\">{data}</script>"
+    ))
+}
+
+/// The schema.org type a CSL type is described as.
+fn schema_type(kind: &str) -> &'static str {
+    match kind {
+        "article" | "article-journal" | "article-magazine" | "paper-conference" => {
+            "ScholarlyArticle"
+        }
+        "article-newspaper" => "NewsArticle",
+        "book" => "Book",
+        "chapter" => "Chapter",
+        "dataset" => "Dataset",
+        "report" => "Report",
+        "software" => "SoftwareSourceCode",
+        "thesis" => "Thesis",
+        "webpage" | "post-weblog" => "WebPage",
+        _ => "CreativeWork",
+    }
+}
+
+/// `entry` as a schema.org object.
+fn work(entry: &Entry) -> Value {
+    let csl = entry.csl_json();
+    let get = |variable: &str| csl.get(variable).and_then(Value::as_str);
+    let kind = schema_type(&entry.kind);
+    let mut work = Map::new();
+    work.insert("@type".into(), kind.into());
+    work.insert("@id".into(), format!("#ref-{}", entry.key).into());
+    for (variable, property) in [
+        ("title", "name"),
+        ("issued", "datePublished"),
+        ("publisher", "publisher"),
+        ("page", "pagination"),
+        ("volume", "volumeNumber"),
+        ("abstract", "abstract"),
+        ("URL", "url"),
+    ] {
+        if let Some(value) = get(variable) {
+            work.insert(property.into(), value.into());
+        }
+    }
+    for (field, property) in [("author", "author"), ("editor", "editor")] {
+        if let Some(names) = entry.fields.get(field) {
+            let names: Vec<Value> = bibliography::names(names).iter().map(person).collect();
+            work.insert(property.into(), names.into());
+        }
+    }
+    if let Some(container) = get("container-title") {
+        let container_type = match kind {
+            "Chapter" => "Book",
+            "ScholarlyArticle" | "NewsArticle" => "Periodical",
+            _ => "CreativeWork",
+        };
+        work.insert(
+            "isPartOf".into(),
+            json!({ "@type": container_type, "name": container }),
+        );
+    }
+    if let Some(doi) = get("DOI") {
+        work.insert("sameAs".into(), format!("https://doi.org/{doi}").into());
+    }
+    if let (Some(isbn), "Book") = (get("ISBN"), kind) {
+        work.insert("isbn".into(), isbn.into());
+    }
+    Value::Object(work)
+}
+
+/// `name` as a `Person`, or an `Organization` if it's a literal.
+fn person(name: &Name) -> Value {
+    if let Some(literal) = &name.literal {
+        return json!({ "@type": "Organization", "name": literal });
+    }
+    let family = match name.particle.is_empty() {
+        true => name.family.clone(),
+        false => format!("{} {}", name.particle, name.family),
+    };
+    let full = [name.given.as_str(), &family, &name.suffix]
+        .iter()
+        .filter(|part| !part.is_empty())
+        .copied()
+        .collect::<Vec<_>>()
+        .join(" ");
+    let mut person = json!({ "@type": "Person", "name": full, "familyName": family });
+    if !name.given.is_empty() {
+        person["givenName"] = name.given.clone().into();
+    }
+    person
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn describes_cited_works() {
+        let entry = Entry {
+            key: "smith2020".into(),
+            kind: "article-journal".into(),
+            fields: [
+                ("author", "van Smith, Jo and {World Health Organization}"),
+                ("title", "On {Citing} </script>"),
+                ("journal", "Journal of Things"),
+                ("year", "2020"),
+                ("doi", "10.1/x"),
+            ]
+            .iter()
+            .map(|(field, value)| (field.to_string(), value.to_string()))
+            .collect(),
+        };
+        let script = script(&[&entry]).unwrap();
+        let data = script
+            .strip_prefix("<script type=\"application/ld+json\">")
+            .and_then(|data| data.strip_suffix("</script>"))
+            .unwrap();
+        assert!(!data.contains("</"));
+        let data: Value = serde_json::from_str(data).unwrap();
+        assert_eq!(
+            data["@graph"][0],
+            json!({
+                "@type": "ScholarlyArticle",
+                "@id": "#ref-smith2020",
+                "name": "On Citing </script>",
+                "datePublished": "2020",
+                "author": [
+                    { "@type": "Person", "name": "Jo van Smith", "familyName": "van Smith", "givenName": "Jo" },
+                    { "@type": "Organization", "name": "World Health Organization" },
+                ],
+                "isPartOf": { "@type": "Periodical", "name": "Journal of Things" },
+                "sameAs": "https://doi.org/10.1/x",
+            })
+        );
+        assert_eq!(super::script(&[]), None);
+    }
+}
@@ -0,0 +1,368 @@
+//! Overrides for the CSL style's `<citation>` options and access dates, so
+//! collapsing, disambiguation and date formats can be configured without
+//! forking the style.
+//!
+//! Pandoc has no options for these, so the style is copied with the
+//! attributes (or dates) replaced and pandoc is pointed at the copy.
+//!
+//! A style at a URL is fetched once, through [`http`](crate::http) like
+//! every other request, and kept in the cache root's `styles/` (or the
+//! vendor directory's), so pandoc is only ever given a local file and
+//! offline builds use the copy there.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use mdbook::errors::Error;
+
+use crate::config::Config;
+use crate::error::CiteprocError;
+use crate::{cache, http};
+
+/// The `<citation>` attributes which can be overridden, and the values
+/// each accepts (`None` for free text).
+pub const CITATION_OPTIONS: &[(&str, Option<&[&str]>)] = &[
+    ("after-collapse-delimiter", None),
+    ("cite-group-delimiter", None),
+    (
+        "collapse",
+        Some(&[
+            "citation-number",
+            "year",
+            "year-suffix",
+            "year-suffix-ranged",
+        ]),
+    ),
+    ("disambiguate-add-givenname", Some(&["true", "false"])),
+    ("disambiguate-add-names", Some(&["true", "false"])),
+    ("disambiguate-add-year-suffix", Some(&["true", "false"])),
+    ("year-suffix-delimiter", None),
+];
+
+/// The placeholders `accessed-format` can contain, and the `<date-part>`
+/// each stands for.
+const DATE_PARTS: &[(&str, &str)] = &[
+    ("day", "<date-part name=\"day\""),
+    ("day-ordinal", "<date-part name=\"day\" form=\"ordinal\""),
+    (
+        "day-padded",
+        "<date-part name=\"day\" form=\"numeric-leading-zeros\"",
+    ),
+    ("month", "<date-part name=\"month\""),
+    ("month-short", "<date-part name=\"month\" form=\"short\""),
+    (
+        "month-numeric",
+        "<date-part name=\"month\" form=\"numeric\"",
+    ),
+    (
+        "month-padded",
+        "<date-part name=\"month\" form=\"numeric-leading-zeros\"",
+    ),
+    ("year", "<date-part name=\"year\""),
+    ("year-short", "<date-part name=\"year\" form=\"short\""),
+];
+
+/// Writes a copy of the style at `style` with `options` applied and access
+/// dates rendered as `accessed` (see [`accessed_date`]) to `dir`, and
+/// returns its path.
+pub fn customized(
+    style: &Path,
+    options: &BTreeMap<String, String>,
+    accessed: Option<&str>,
+    dir: &Path,
+) -> Result<PathBuf, Error> {
+    let mut csl = fs::read_to_string(style).map_err(|e| {
+        CiteprocError::config(format!("style {} can't be read: {e}", style.display()))
+    })?;
+    if !options.is_empty() {
+        csl = rewrite(&csl, options).ok_or_else(|| {
+            CiteprocError::config(format!(
+                "style {} has no <citation> element",
+                style.display()
+            ))
+        })?;
+    }
+    if let Some(format) = accessed {
+        let date = accessed_date(format).map_err(CiteprocError::config)?;
+        csl = replace_dates(&csl, "accessed", &date);
+    }
+    Ok(cache::store(dir, "csl", &csl)?)
+}
+
+/// Points `config` at a local copy of its style if it's a URL: the one
+/// [vendored](crate::vendor) if there is one, or else the cached one,
+/// fetching it the first time. `offline`, only a copy fetched before can be
+/// used.
+pub fn fetch_remote(config: &mut Config, offline: bool) -> Result<(), Error> {
+    let Some(bibliography) = &mut config.bibliography else {
+        return Ok(());
+    };
+    let url = &bibliography.bibliography_style;
+    if !url.contains("://") {
+        return Ok(());
+    }
+    let vendored = config
+        .vendor_dir
+        .as_ref()
+        .map(|dir| remote_path(url, &dir.join("styles")))
+        .filter(|path| path.is_file());
+    let style = match vendored {
+        Some(path) => path,
+        None => {
+            let cached = remote_path(url, &config.cache_root.join("styles"));
+            if !cached.is_file() {
+                if offline {
+                    return Err(CiteprocError::config(format!(
+                        "style {url} isn't available offline; build once without offline mode \
+                         to cache it"
+                    )));
+                }
+                fetch(url, &cached)?;
+            }
+            cached
+        }
+    };
+    bibliography.bibliography_style = style.display().to_string();
+    Ok(())
+}
+
+/// Where the style at `url` is kept in `dir`.
+pub fn remote_path(url: &str, dir: &Path) -> PathBuf {
+    dir.join(format!("{}.csl", cache::sha256_hex(url)))
+}
+
+/// Fetches the style at `url` into `path`.
+pub fn fetch(url: &str, path: &Path) -> Result<(), Error> {
+    let csl = http::get(url)?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    Ok(cache::write_atomic(path, csl)?)
+}
+
+/// The CSL `<date>` element for an `accessed-format` such as
+/// `"Accessed {day} {month} {year}"`: text around the placeholders becomes
+/// the parts' affixes.
+pub fn accessed_date(format: &str) -> Result<String, String> {
+    let mut prefix = None;
+    let mut parts: Vec<(&str, String)> = Vec::new();
+    let mut text = String::new();
+    let mut rest = format;
+    while let Some(open) = rest.find('{') {
+        text += &rest[..open];
+        let close = rest[open..]
+            .find('}')
+            .ok_or_else(|| format!("accessed-format has an unclosed {{ in {format:?}"))?;
+        let name = &rest[open + 1..open + close];
+        let (_, part) = DATE_PARTS
+            .iter()
+            .find(|(placeholder, _)| *placeholder == name)
+            .ok_or_else(|| format!("accessed-format has an unknown placeholder {{{name}}}"))?;
+        match parts.last_mut() {
+            Some((_, suffix)) => *suffix = std::mem::take(&mut text),
+            None => prefix = Some(std::mem::take(&mut text)),
+        }
+        parts.push((part, String::new()));
+        rest = &rest[open + close + 1..];
+    }
+    text += rest;
+    let Some((_, suffix)) = parts.last_mut() else {
+        return Err(format!(
+            "accessed-format needs at least one of {}",
+            DATE_PARTS
+                .iter()
+                .map(|(placeholder, _)| format!("{{{placeholder}}}"))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ));
+    };
+    *suffix = text;
+
+    let mut date = String::from("<date variable=\"accessed\"");
+    if let Some(prefix) = prefix.filter(|prefix| !prefix.is_empty()) {
+        date += &format!(" prefix=\"{}\"", escape_attribute(&prefix));
+    }
+    date += ">";
+    for (part, suffix) in parts {
+        date += part;
+        if !suffix.is_empty() {
+            date += &format!(" suffix=\"{}\"", escape_attribute(&suffix));
+        }
+        date += "/>";
+    }
+    date += "</date>";
+    Ok(date)
+}
+
+/// `csl` with every `<date>` element for `variable` replaced by `date`.
+fn replace_dates(csl: &str, variable: &str, date: &str) -> String {
+    let mut out = String::with_capacity(csl.len());
+    let mut rest = csl;
+    while let Some(start) = rest.find("<date") {
+        let Some(end) = rest[start..].find('>').map(|end| start + end + 1) else {
+            break;
+        };
+        let tag = &rest[start..end];
+        let is_date = tag["<date".len()..].starts_with(|c: char| c.is_whitespace() || c == '>');
+        let matches =
+            is_date && find_attribute(tag, "variable").is_some_and(|value| &tag[value] == variable);
+        if !matches {
+            out += &rest[..end];
+            rest = &rest[end..];
+            continue;
+        }
+        let element_end = if tag.ends_with("/>") {
+            Some(end)
+        } else {
+            rest[end..]
+                .find("</date>")
+                .map(|close| end + close + "</date>".len())
+        };
+        let Some(element_end) = element_end else {
+            break;
+        };
+        out += &rest[..start];
+        out += date;
+        rest = &rest[element_end..];
+    }
+    out += rest;
+    out
+}
+
+/// `csl` with `options` set on its `<citation>` element.
+fn rewrite(csl: &str, options: &BTreeMap<String, String>) -> Option<String> {
+    let start = csl.match_indices("<citation").map(|(i, _)| i).find(|&i| {
+        csl[i + "<citation".len()..].starts_with(|c: char| c.is_whitespace() || c == '>')
+    })?;
+    let end = start + csl[start..].find('>')?;
+    let close = if csl[..end].ends_with('/') {
+        end - 1
+    } else {
+        end
+    };
+
+    let mut tag = csl[start..close].to_string();
+    for (name, value) in options {
+        let value = escape_attribute(value);
+        tag = match find_attribute(&tag, name) {
+            Some(range) => format!("{}{value}{}", &tag[..range.start], &tag[range.end..]),
+            None => format!("{tag} {name}=\"{value}\""),
+        };
+    }
+    Some(format!("{}{tag}{}", &csl[..start], &csl[close..]))
+}
+
+/// Where the value of attribute `name` is in `tag`.
+fn find_attribute(tag: &str, name: &str) -> Option<std::ops::Range<usize>> {
+    let mut offset = 0;
+    while let Some(i) = tag[offset..].find(name).map(|i| offset + i) {
+        offset = i + name.len();
+        if !tag[..i].ends_with(char::is_whitespace) {
+            continue;
+        }
+        let Some(rest) = tag[offset..].trim_start().strip_prefix('=') else {
+            continue;
+        };
+        let rest = rest.trim_start();
+        let Some(quote) = rest.chars().next().filter(|c| *c == '"' || *c == '\'') else {
+            continue;
+        };
+        let value_start = tag.len() - rest.len() + 1;
+        let value_end = value_start + tag[value_start..].find(quote)?;
+        return Some(value_start..value_end);
+    }
+    None
+}
+
+fn escape_attribute(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn styles_at_urls_are_only_used_offline_once_fetched() {
+        let root = std::env::temp_dir().join(format!("citeproc-styles-{}", std::process::id()));
+        let url = "https://example.org/style.csl";
+        let table = toml::from_str(&format!(
+            "offline = true\ncitations = \"transpile\"\nbibliography = \"refs.bib\"\n\
+             csl = \"{url}\""
+        ))
+        .unwrap();
+        let config = Config::from_table(&table, &root).unwrap();
+        let error = fetch_remote(&mut config.clone(), config.offline).unwrap_err();
+        assert!(
+            error.to_string().contains("isn't available offline"),
+            "{error}"
+        );
+
+        let cached = remote_path(url, &config.cache_root.join("styles"));
+        fs::create_dir_all(cached.parent().unwrap()).unwrap();
+        fs::write(&cached, "<style/>").unwrap();
+        let mut local = config.clone();
+        fetch_remote(&mut local, config.offline).unwrap();
+        assert_eq!(
+            local.bibliography.unwrap().bibliography_style,
+            cached.display().to_string()
+        );
+
+        let vendor_dir = root.join(crate::vendor::DIR);
+        let vendored = remote_path(url, &vendor_dir.join("styles"));
+        fs::create_dir_all(vendored.parent().unwrap()).unwrap();
+        fs::write(&vendored, "<style/>").unwrap();
+        let mut local = Config {
+            vendor_dir: Some(vendor_dir),
+            ..config.clone()
+        };
+        fetch_remote(&mut local, config.offline).unwrap();
+        assert_eq!(
+            local.bibliography.unwrap().bibliography_style,
+            vendored.display().to_string()
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn overrides_citation_attributes() {
+        let csl = "<style>\n<citation-number/>\n\
+                   <citation collapse=\"year\" et-al-min=\"3\">\n<layout/>\n</citation>\n</style>";
+        let options = BTreeMap::from([
+            ("collapse".to_string(), "citation-number".to_string()),
+            ("after-collapse-delimiter".to_string(), "; ".to_string()),
+        ]);
+        assert_eq!(
+            rewrite(csl, &options).unwrap(),
+            "<style>\n<citation-number/>\n\
+             <citation collapse=\"citation-number\" et-al-min=\"3\" after-collapse-delimiter=\"; \">\n\
+             <layout/>\n</citation>\n</style>"
+        );
+        assert_eq!(rewrite("<style/>", &options), None);
+    }
+
+    #[test]
+    fn formats_access_dates() {
+        let date = accessed_date("Accessed {day} {month} {year}.").unwrap();
+        assert_eq!(
+            date,
+            "<date variable=\"accessed\" prefix=\"Accessed \">\
+             <date-part name=\"day\" suffix=\" \"/>\
+             <date-part name=\"month\" suffix=\" \"/>\
+             <date-part name=\"year\" suffix=\".\"/></date>"
+        );
+        let csl = "<text term=\"accessed\"/><date variable=\"accessed\" form=\"text\"/>\
+                   <date variable=\"issued\"><date-part name=\"year\"/></date>\
+                   <date variable=\"accessed\">\n<date-part name=\"year\"/>\n</date>";
+        assert_eq!(
+            replace_dates(csl, "accessed", "D"),
+            "<text term=\"accessed\"/>D<date variable=\"issued\"><date-part name=\"year\"/></date>D"
+        );
+        assert!(accessed_date("{weekday}").is_err());
+        assert!(accessed_date("Accessed").is_err());
+    }
+}
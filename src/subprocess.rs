@@ -0,0 +1,449 @@
+//! Running pandoc as a subprocess the same way on every platform.
+//!
+//! Windows needs the most care: programs are often `.cmd` or `.bat` shims
+//! found through `PATHEXT`, spawning them from a GUI flashes a console
+//! window, and pandoc writes CRLF line endings.
+
+use std::borrow::Cow;
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use mdbook::errors::Error;
+
+use crate::config::Limits;
+use crate::error::CiteprocError;
+
+/// Finds `program` the way `which` (or `where` on Windows) would. Names
+/// with a directory in them are used as they are.
+pub fn find_program(program: &OsStr) -> Option<PathBuf> {
+    let path = env::var_os("PATH")?;
+    let extensions = match cfg!(windows) {
+        true => env::var_os("PATHEXT").unwrap_or_else(|| ".COM;.EXE;.BAT;.CMD".into()),
+        false => OsString::new(),
+    };
+    find_in(program, &path, &extensions)
+}
+
+/// Finds `program` in the directories of `path`, with each of the
+/// `;`-separated `extensions` if it has none.
+fn find_in(program: &OsStr, path: &OsStr, extensions: &OsStr) -> Option<PathBuf> {
+    let program = Path::new(program);
+    if program.components().count() > 1 {
+        return Some(program.to_path_buf());
+    }
+    let extensions: Vec<&str> = match program.extension() {
+        None if !extensions.is_empty() => extensions
+            .to_str()
+            .unwrap_or_default()
+            .split(';')
+            .filter(|extension| extension.starts_with('.'))
+            .collect(),
+        _ => vec![""],
+    };
+    env::split_paths(path).find_map(|dir| {
+        extensions.iter().find_map(|extension| {
+            let mut candidate = dir.join(program).into_os_string();
+            candidate.push(extension);
+            let candidate = PathBuf::from(candidate);
+            is_executable(&candidate).then_some(candidate)
+        })
+    })
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    path.metadata()
+        .is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+/// A command running `program` (a program followed by any leading
+/// arguments), found on the `PATH`.
+pub fn command(program: &[OsString]) -> Result<process::Command, Error> {
+    let path = find_program(&program[0]).ok_or_else(|| {
+        CiteprocError::pandoc_missing(format!(
+            "{} could not be found on the PATH",
+            program[0].to_string_lossy()
+        ))
+    })?;
+    // The standard library quotes arguments for `.cmd` and `.bat` files
+    // itself, refusing any which cmd.exe would misread.
+    let mut command = process::Command::new(path);
+    command.args(&program[1..]);
+    #[cfg(windows)]
+    {
+        use std::os::windows::process::CommandExt;
+        // Don't open a console window for every chapter.
+        const CREATE_NO_WINDOW: u32 = 0x0800_0000;
+        command.creation_flags(CREATE_NO_WINDOW);
+    }
+    Ok(command)
+}
+
+/// The variables [`clear_env`] always keeps: the `PATH`, for shims like
+/// `quarto pandoc`, and what Windows programs can't start without.
+const KEPT_ENV: &[&str] = &["PATH", "SYSTEMROOT", "WINDIR"];
+
+/// Empties the environment of `command`, but for the `PATH` and `pass`.
+pub fn clear_env(command: &mut process::Command, pass: &[String]) {
+    command.env_clear();
+    for name in KEPT_ENV
+        .iter()
+        .copied()
+        .chain(pass.iter().map(String::as_str))
+    {
+        if let Some(value) = env::var_os(name) {
+            command.env(name, value);
+        }
+    }
+}
+
+/// `program` with `args` as they'd be typed into a POSIX shell.
+pub fn display(program: &[OsString], args: &[String]) -> String {
+    let words = program
+        .iter()
+        .map(|word| word.to_string_lossy())
+        .chain(args.iter().map(|arg| Cow::Borrowed(arg.as_str())));
+    words
+        .map(|word| quote(&word).into_owned())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn quote(word: &str) -> Cow<'_, str> {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "_-+=./:,@%".contains(c);
+    match !word.is_empty() && word.chars().all(plain) {
+        true => Cow::Borrowed(word),
+        false => Cow::Owned(format!("'{}'", word.replace('\'', r"'\''"))),
+    }
+}
+
+/// Spawns `command` within `limits`, feeds it `content` on stdin and
+/// collects its output.
+pub fn run(
+    command: &mut process::Command,
+    content: &str,
+    limits: Limits,
+) -> Result<process::Output, Error> {
+    let program = command.get_program().to_string_lossy().into_owned();
+    #[cfg(unix)]
+    limit(command, limits);
+    let mut child = command
+        .stdin(process::Stdio::piped())
+        .stdout(process::Stdio::piped())
+        .stderr(process::Stdio::piped())
+        .spawn()
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => {
+                CiteprocError::pandoc_missing(format!("{program} could not be found on the PATH"))
+            }
+            _ => CiteprocError::pandoc_failed(format!("failed to spawn {program}: {e}")),
+        })?;
+    #[cfg(windows)]
+    let _job = job::assign(&child, limits).map_err(|e| {
+        let _ = child.kill();
+        CiteprocError::pandoc_failed(format!("failed to limit {program}: {e}"))
+    })?;
+    #[cfg(not(any(unix, windows)))]
+    let _ = limits;
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    // Write from a separate thread so a chapter larger than the pipe
+    // buffer can't deadlock against pandoc filling up stdout.
+    let output = thread::scope(|scope| {
+        let writer = scope.spawn(move || stdin.write_all(content.as_bytes()));
+        let output = child.wait_with_output();
+        (writer.join().expect("stdin writer panicked"), output)
+    });
+    match output {
+        (_, Err(e)) => Err(CiteprocError::pandoc_failed(format!(
+            "failed to wait on {program}: {e}"
+        ))),
+        (Err(e), Ok(output)) if output.status.success() => Err(CiteprocError::pandoc_failed(
+            format!("failed to write to {program} stdin: {e}"),
+        )),
+        (_, Ok(output)) => Ok(output),
+    }
+}
+
+/// Sets `limits` as rlimits of the child `command` spawns, lowering (never
+/// raising) any it already has.
+#[cfg(unix)]
+fn limit(command: &mut process::Command, limits: Limits) {
+    use std::os::unix::process::CommandExt;
+
+    if limits == Limits::default() {
+        return;
+    }
+    let set = |resource, value: u64| {
+        let mut limit = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        // SAFETY: `limit` is a valid rlimit to fill in and read from.
+        unsafe {
+            if libc::getrlimit(resource, &mut limit) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let value = (value as libc::rlim_t).min(limit.rlim_max);
+            limit.rlim_cur = value;
+            limit.rlim_max = value;
+            match libc::setrlimit(resource, &limit) {
+                0 => Ok(()),
+                _ => Err(io::Error::last_os_error()),
+            }
+        }
+    };
+    // SAFETY: getrlimit and setrlimit are async-signal-safe, and nothing is
+    // allocated between the fork and the exec.
+    unsafe {
+        command.pre_exec(move || {
+            if let Some(bytes) = limits.memory {
+                set(libc::RLIMIT_AS, bytes)?;
+            }
+            if let Some(cpu) = limits.cpu {
+                set(libc::RLIMIT_CPU, cpu.as_secs().max(1))?;
+            }
+            Ok(())
+        });
+    }
+}
+
+/// Job objects holding a child within its limits. Closing the job kills
+/// whatever is still in it.
+#[cfg(windows)]
+mod job {
+    use std::io;
+    use std::os::windows::io::AsRawHandle;
+    use std::process::Child;
+    use std::ptr;
+
+    use windows_sys::Win32::Foundation::{CloseHandle, HANDLE};
+    use windows_sys::Win32::System::JobObjects::{
+        AssignProcessToJobObject, CreateJobObjectW, JobObjectExtendedLimitInformation,
+        SetInformationJobObject, JOBOBJECT_EXTENDED_LIMIT_INFORMATION,
+        JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE, JOB_OBJECT_LIMIT_PROCESS_MEMORY,
+        JOB_OBJECT_LIMIT_PROCESS_TIME,
+    };
+
+    use crate::config::Limits;
+
+    pub struct Job(HANDLE);
+
+    impl Drop for Job {
+        fn drop(&mut self) {
+            // SAFETY: the handle is the job's, and only closed here.
+            unsafe { CloseHandle(self.0) };
+        }
+    }
+
+    /// Puts `child` in a job with `limits`, as soon as it's started.
+    pub fn assign(child: &Child, limits: Limits) -> io::Result<Option<Job>> {
+        if limits == Limits::default() {
+            return Ok(None);
+        }
+        // SAFETY: the job is created without a name or attributes, and
+        // `info` is a valid, fully initialized extended limit structure.
+        unsafe {
+            let handle = CreateJobObjectW(ptr::null(), ptr::null());
+            if handle.is_null() {
+                return Err(io::Error::last_os_error());
+            }
+            let job = Job(handle);
+            let mut info: JOBOBJECT_EXTENDED_LIMIT_INFORMATION = std::mem::zeroed();
+            info.BasicLimitInformation.LimitFlags = JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE;
+            if let Some(bytes) = limits.memory {
+                info.BasicLimitInformation.LimitFlags |= JOB_OBJECT_LIMIT_PROCESS_MEMORY;
+                info.ProcessMemoryLimit = bytes as usize;
+            }
+            if let Some(cpu) = limits.cpu {
+                // In 100 nanosecond ticks of user time.
+                info.BasicLimitInformation.LimitFlags |= JOB_OBJECT_LIMIT_PROCESS_TIME;
+                info.BasicLimitInformation.PerProcessUserTimeLimit =
+                    (cpu.as_nanos() / 100).min(i64::MAX as u128) as i64;
+            }
+            let set = SetInformationJobObject(
+                job.0,
+                JobObjectExtendedLimitInformation,
+                ptr::addr_of!(info).cast(),
+                std::mem::size_of_val(&info) as u32,
+            );
+            if set == 0 || AssignProcessToJobObject(job.0, child.as_raw_handle() as HANDLE) == 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Some(job))
+        }
+    }
+}
+
+/// A directory of our own in the system's temporary directory, for
+/// handing files to a subprocess. Only we can read it on Unix, and it's
+/// removed with whatever is in it when dropped.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new() -> io::Result<Self> {
+        static DIRS: AtomicUsize = AtomicUsize::new(0);
+        loop {
+            let path = env::temp_dir().join(format!(
+                "mdbook-citeproc-{}-{}",
+                process::id(),
+                DIRS.fetch_add(1, Ordering::Relaxed)
+            ));
+            let mut builder = fs::DirBuilder::new();
+            #[cfg(unix)]
+            std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+            // Never one which is already there, in case it was put there
+            // for us to write into.
+            match builder.create(&path) {
+                Ok(()) => return Ok(Self(path)),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// `output` with CRLF line endings turned into LF, as pandoc writes the
+/// platform's line endings.
+pub fn normalize_newlines(output: Vec<u8>) -> Vec<u8> {
+    if !output.windows(2).any(|pair| pair == b"\r\n") {
+        return output;
+    }
+    let mut normalized = Vec::with_capacity(output.len());
+    let mut bytes = output.iter().peekable();
+    while let Some(&byte) = bytes.next() {
+        if byte != b'\r' || bytes.peek() != Some(&&b'\n') {
+            normalized.push(byte);
+        }
+    }
+    normalized
+}
+
+/// `path` as an argument for pandoc. Windows' verbatim `\\?\` paths, which
+/// canonicalizing produces, are turned back into ordinary ones.
+pub fn path_arg(path: &Path) -> Cow<'_, str> {
+    let text = path.to_string_lossy();
+    match text.strip_prefix(r"\\?\UNC\") {
+        Some(share) => Cow::Owned(format!(r"\\{share}")),
+        None => match text.strip_prefix(r"\\?\") {
+            Some(local) => Cow::Owned(local.to_string()),
+            None => text,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_newlines_paths_and_quoting() {
+        assert_eq!(
+            normalize_newlines(b"a\r\nb\r\rc\n".to_vec()),
+            b"a\nb\r\rc\n"
+        );
+        assert_eq!(
+            path_arg(Path::new(r"\\?\C:\book\refs.bib")),
+            r"C:\book\refs.bib"
+        );
+        assert_eq!(
+            path_arg(Path::new(r"\\?\UNC\server\share\refs.bib")),
+            r"\\server\share\refs.bib"
+        );
+        assert_eq!(path_arg(Path::new("book/refs.bib")), "book/refs.bib");
+        assert_eq!(
+            display(
+                &["quarto".into(), "pandoc".into()],
+                &["--bibliography=my refs.bib".into(), "it's".into()]
+            ),
+            r"quarto pandoc '--bibliography=my refs.bib' 'it'\''s'"
+        );
+    }
+
+    /// A fake pandoc which prints its arguments on stderr and echoes stdin
+    /// with CRLF line endings, like pandoc on Windows.
+    #[cfg(unix)]
+    #[test]
+    fn runs_a_fake_pandoc_from_the_path() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("citeproc-subprocess-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let pandoc = dir.join("pandoc");
+        fs::write(&pandoc, "#!/bin/sh\necho \"$@\" >&2\nsed 's/$/\\r/'\n").unwrap();
+        fs::set_permissions(&pandoc, fs::Permissions::from_mode(0o755)).unwrap();
+        fs::write(dir.join("pandoc.txt"), "not a program").unwrap();
+
+        let path = env::join_paths([dir.join("missing"), dir.clone()]).unwrap();
+        assert_eq!(
+            find_in("pandoc".as_ref(), &path, "".as_ref()),
+            Some(pandoc.clone())
+        );
+        assert_eq!(find_in("pandoc.txt".as_ref(), &path, "".as_ref()), None);
+
+        let mut command = process::Command::new(&pandoc);
+        command.args(["--bibliography=my refs.bib", "it's"]);
+        let output = run(&mut command, "one\ntwo\n", Limits::default()).unwrap();
+        assert_eq!(normalize_newlines(output.stdout), b"one\ntwo\n");
+        assert_eq!(output.stderr, b"--bibliography=my refs.bib it's\n");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn limits_the_child() {
+        let mut command = process::Command::new("sh");
+        command.args(["-c", "ulimit -t; ulimit -v"]);
+        let limits = Limits {
+            memory: Some(256 * 1024 * 1024),
+            cpu: Some(std::time::Duration::from_secs(7)),
+        };
+        let output = run(&mut command, "", limits).unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "7\n262144\n");
+    }
+
+    /// The same for a `.cmd` shim, found through `PATHEXT`, with arguments
+    /// cmd.exe would split or mangle if they weren't quoted.
+    #[cfg(windows)]
+    #[test]
+    fn runs_a_fake_pandoc_shim_from_the_path() {
+        let dir = std::env::temp_dir().join(format!("citeproc-subprocess-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let pandoc = dir.join("pandoc.cmd");
+        fs::write(&pandoc, "@echo off\r\necho %* 1>&2\r\nmore\r\n").unwrap();
+
+        let found = find_in("pandoc".as_ref(), dir.as_os_str(), ".EXE;.CMD".as_ref());
+        assert_eq!(found, Some(pandoc.clone()));
+
+        let mut command = process::Command::new(&pandoc);
+        command.arg("--bibliography=C:\\my refs.bib");
+        let output = run(&mut command, "one\ntwo\n", Limits::default()).unwrap();
+        assert_eq!(normalize_newlines(output.stdout), b"one\ntwo\n");
+        assert_eq!(
+            String::from_utf8_lossy(&output.stderr).trim(),
+            "\"--bibliography=C:\\my refs.bib\""
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
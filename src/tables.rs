@@ -0,0 +1,314 @@
+//! Pipe tables, cell by cell. Unless pandoc reads pipe tables itself, each
+//! is an ordinary paragraph to it, which it reflows into a single line, and
+//! a table it does read it writes back with its own padding and widths.
+//!
+//! So tables are swapped for placeholder paragraphs before a chapter goes
+//! through pandoc. Each cell with a citation follows its table's
+//! placeholder as a paragraph of its own, so pandoc renders the citation
+//! where it is in the chapter, and the table is put back together around
+//! the rendered cells afterwards: on one line each, with any `|` escaped,
+//! and the rest of the table as it was written.
+
+use mdbook::errors::Error;
+
+use crate::citation;
+use crate::error::CiteprocError;
+
+const TABLE_PREFIX: &str = "CITEPROCTABLE";
+const CELL_PREFIX: &str = "CITEPROCCELL";
+const SUFFIX: &str = "X";
+
+/// A chapter with its tables taken out.
+#[derive(Debug)]
+pub struct Protected {
+    pub content: String,
+    tables: Vec<Table>,
+    cells: usize,
+}
+
+/// A table's lines, split into cells, with the cells pandoc renders
+/// numbered.
+#[derive(Debug)]
+struct Table {
+    rows: Vec<Vec<Cell>>,
+    /// Whether each row has a leading and a trailing `|`.
+    pipes: Vec<(bool, bool)>,
+}
+
+#[derive(Debug)]
+enum Cell {
+    Kept(String),
+    Rendered(usize),
+}
+
+/// Replaces the pipe tables in `content` with placeholders.
+pub fn protect(content: &str) -> Protected {
+    let mut protected = Protected {
+        content: String::with_capacity(content.len()),
+        tables: Vec::new(),
+        cells: 0,
+    };
+    let lines: Vec<&str> = content.split_inclusive('\n').collect();
+    let mut fence: Option<String> = None;
+    let mut i = 0;
+    while i < lines.len() {
+        let line = lines[i];
+        let trimmed = line.trim_start_matches(' ');
+        let indent = line.len() - trimmed.len();
+        if let Some(marker) = &fence {
+            if closes_fence(trimmed, marker) {
+                fence = None;
+            }
+            protected.content.push_str(line);
+            i += 1;
+            continue;
+        }
+        if indent < 4 {
+            if let Some(marker) = fence_marker(trimmed) {
+                fence = Some(marker);
+                protected.content.push_str(line);
+                i += 1;
+                continue;
+            }
+        }
+
+        let block_start = i == 0 || lines[i - 1].trim().is_empty();
+        let is_table = block_start
+            && indent < 4
+            && line.contains('|')
+            && lines.get(i + 1).is_some_and(|next| is_delimiter_row(next));
+        if !is_table {
+            protected.content.push_str(line);
+            i += 1;
+            continue;
+        }
+        let end = (i + 2..lines.len())
+            .find(|&j| lines[j].trim().is_empty() || !lines[j].contains('|'))
+            .unwrap_or(lines.len());
+        protected.take_table(&lines[i..end]);
+        // The placeholder is a paragraph of its own, whatever follows.
+        protected.content.push_str(match lines.get(end) {
+            Some(next) if !next.trim().is_empty() => "\n\n",
+            _ if lines[end - 1].ends_with('\n') => "\n",
+            _ => "",
+        });
+        i = end;
+    }
+    protected
+}
+
+impl Protected {
+    fn take_table(&mut self, lines: &[&str]) {
+        let index = self.tables.len();
+        let mut table = Table {
+            rows: Vec::new(),
+            pipes: Vec::new(),
+        };
+        let mut cells = String::new();
+        for (row, line) in lines.iter().enumerate() {
+            let line = line.trim_end_matches(['\n', '\r']);
+            let (leading, trailing, texts) = split_row(line);
+            table.pipes.push((leading, trailing));
+            table.rows.push(
+                texts
+                    .into_iter()
+                    .map(|text| {
+                        // The delimiter row has nothing to render.
+                        if row == 1 || citation::parse(text).is_empty() {
+                            return Cell::Kept(text.to_string());
+                        }
+                        let cell = self.cells;
+                        self.cells += 1;
+                        cells += &format!("\n\n{CELL_PREFIX}{cell}{SUFFIX} {}", text.trim());
+                        Cell::Rendered(cell)
+                    })
+                    .collect(),
+            );
+        }
+        self.tables.push(table);
+        self.content
+            .push_str(&format!("{TABLE_PREFIX}{index}{SUFFIX}{cells}"));
+    }
+
+    /// Puts the tables back into pandoc's `output`, with their cells as
+    /// pandoc rendered them.
+    pub fn restore(&self, chapter_name: &str, output: &str) -> Result<String, Error> {
+        let dropped = || {
+            CiteprocError::pandoc_failed(format!(
+                "pandoc dropped part of a table from chapter \"{chapter_name}\""
+            ))
+        };
+        let mut restored = output.to_string();
+        let mut rendered = vec![String::new(); self.cells];
+        // Cells come out as paragraphs, which end at a blank line.
+        for (cell, text) in rendered.iter_mut().enumerate() {
+            let placeholder = format!("{CELL_PREFIX}{cell}{SUFFIX}");
+            let start = restored.find(&placeholder).ok_or_else(dropped)?;
+            let end = restored[start..]
+                .find("\n\n")
+                .map_or(restored.len(), |i| start + i);
+            *text = escape_pipes(
+                &restored[start + placeholder.len()..end]
+                    .split_whitespace()
+                    .collect::<Vec<_>>()
+                    .join(" "),
+            );
+            // Along with the blank line before the paragraph.
+            let start = restored[..start].trim_end_matches('\n').len();
+            restored.replace_range(start..end, "");
+        }
+        for (index, table) in self.tables.iter().enumerate() {
+            let placeholder = format!("{TABLE_PREFIX}{index}{SUFFIX}");
+            let start = restored.find(&placeholder).ok_or_else(dropped)?;
+            restored.replace_range(start..start + placeholder.len(), &table.render(&rendered));
+        }
+        Ok(restored)
+    }
+}
+
+impl Table {
+    fn render(&self, rendered: &[String]) -> String {
+        let rows: Vec<String> = self
+            .rows
+            .iter()
+            .zip(&self.pipes)
+            .map(|(cells, &(leading, trailing))| {
+                let cells: Vec<String> = cells
+                    .iter()
+                    .map(|cell| match cell {
+                        Cell::Kept(text) => text.clone(),
+                        Cell::Rendered(cell) => format!(" {} ", rendered[*cell]),
+                    })
+                    .collect();
+                let mut row = cells.join("|");
+                if leading {
+                    row.insert(0, '|');
+                }
+                if trailing {
+                    row.push('|');
+                }
+                row
+            })
+            .collect();
+        rows.join("\n")
+    }
+}
+
+/// The cells of a table row, with whether it has a leading and a trailing
+/// `|`. Escaped pipes and pipes in code spans don't separate cells.
+fn split_row(line: &str) -> (bool, bool, Vec<&str>) {
+    let mut separators = Vec::new();
+    let bytes = line.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' => i += 1,
+            b'`' => {
+                let ticks = bytes[i..].iter().take_while(|b| **b == b'`').count();
+                let fence = "`".repeat(ticks);
+                i += line[i + ticks..]
+                    .find(&fence)
+                    .map_or(ticks, |end| ticks + end + ticks)
+                    - 1;
+            }
+            b'|' => separators.push(i),
+            _ => {}
+        }
+        i += 1;
+    }
+    let leading = line.trim_start().starts_with('|');
+    let trailing = line.trim_end().ends_with('|') && !line.trim_end().ends_with("\\|");
+    let mut cells = Vec::new();
+    let mut start = 0;
+    for (n, &at) in separators.iter().enumerate() {
+        if !(n == 0 && leading) {
+            cells.push(&line[start..at]);
+        }
+        start = at + 1;
+    }
+    if !trailing || separators.is_empty() {
+        cells.push(&line[start..]);
+    }
+    (leading, trailing, cells)
+}
+
+/// Whether `line` is the row of dashes under a table's header, e.g.
+/// `|:---|---:|`.
+fn is_delimiter_row(line: &str) -> bool {
+    let line = line.trim();
+    let line = line.strip_prefix('|').unwrap_or(line);
+    let line = line.strip_suffix('|').unwrap_or(line);
+    !line.is_empty()
+        && line.split('|').all(|cell| {
+            let cell = cell.trim();
+            let dashes = cell.trim_start_matches(':').trim_end_matches(':');
+            !dashes.is_empty() && dashes.chars().all(|c| c == '-')
+        })
+}
+
+/// `text` with any `|` not already escaped escaped, so it stays in its
+/// cell.
+fn escape_pipes(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut escaped = false;
+    for c in text.chars() {
+        if c == '|' && !escaped {
+            out.push('\\');
+        }
+        escaped = c == '\\' && !escaped;
+        out.push(c);
+    }
+    out
+}
+
+fn fence_marker(line: &str) -> Option<String> {
+    let c = line.chars().next().filter(|c| *c == '`' || *c == '~')?;
+    let len = line.chars().take_while(|x| *x == c).count();
+    (len >= 3).then(|| c.to_string().repeat(len))
+}
+
+fn closes_fence(line: &str, marker: &str) -> bool {
+    let line = line.trim_end();
+    line.len() >= marker.len() && line.chars().all(|c| marker.starts_with(c))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TABLE: &str = "| Claim | Source |\n|:------|-------:|\n\
+                         | Tables | [@smith2020, p. 3] |\n| `a|b` | none \\| here |\n";
+
+    #[test]
+    fn renders_cells_in_place() {
+        let chapter = format!("Intro @adams2018.\n\n{TABLE}\nAfter.\n");
+        let protected = protect(&chapter);
+        assert_eq!(
+            protected.content,
+            "Intro @adams2018.\n\nCITEPROCTABLE0X\n\nCITEPROCCELL0X [@smith2020, p. 3]\n\nAfter.\n"
+        );
+        // As pandoc would write it back, wrapped.
+        let output = "Intro (Adams 2018).\n\nCITEPROCTABLE0X\n\nCITEPROCCELL0X (Smith\n2020|3)\n\n\
+                      After.\n";
+        assert_eq!(
+            protected.restore("test", output).unwrap(),
+            "Intro (Adams 2018).\n\n| Claim | Source |\n|:------|-------:|\n\
+             | Tables | (Smith 2020\\|3) |\n| `a|b` | none \\| here |\n\nAfter.\n"
+        );
+    }
+
+    #[test]
+    fn keeps_tables_without_citations_as_they_are() {
+        let table = "a | b\n--- | ---\nc | d";
+        let protected = protect(table);
+        assert_eq!(protected.content, "CITEPROCTABLE0X");
+        assert_eq!(
+            protected.restore("test", "CITEPROCTABLE0X\n").unwrap(),
+            "a | b\n--- | ---\nc | d\n"
+        );
+
+        let code = "```\n| a |\n|---|\n```\n";
+        assert_eq!(protect(code).content, code);
+        assert!(protect(TABLE).restore("test", "").is_err());
+    }
+}
@@ -0,0 +1,230 @@
+//! Content for some outputs only, e.g. full citations in a footnote for
+//! print and a compact one on the web. Marked with pandoc's class syntax,
+//! as a fenced div for blocks or a bracketed span for inline text:
+//!
+//! ```markdown
+//! ::: {.only-print}
+//! The full account is in @doe99, pp. 4–9.
+//! :::
+//!
+//! See [@doe99]{.only-web}[^[@doe99, pp. 4–9.]]{.only-print}.
+//! ```
+//!
+//! A target is a renderer's name or a group from `targets`, which by
+//! default has `print` (latex, pdf and typst) and `web` (html). Content
+//! marked for several, e.g. `{.only-web .only-epub}`, is kept for any of
+//! them. What isn't for the current renderer is taken out before pandoc
+//! sees the chapter, and the markers around what is.
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+
+const CLASS: &str = ".only-";
+
+/// The groups of renderers `only-` classes can name besides a renderer.
+pub fn default_groups() -> BTreeMap<String, Vec<String>> {
+    let group = |renderers: &[&str]| renderers.iter().map(|r| r.to_string()).collect();
+    BTreeMap::from([
+        ("print".to_string(), group(&["latex", "pdf", "typst"])),
+        ("web".to_string(), group(&["html"])),
+    ])
+}
+
+/// Whether `content` has anything marked for some outputs only.
+pub fn is_conditional(content: &str) -> bool {
+    content.contains(CLASS)
+}
+
+/// `content` with only what's for `targets`.
+pub fn select<'a>(content: &'a str, targets: &[&str]) -> Cow<'a, str> {
+    if !is_conditional(content) {
+        return Cow::Borrowed(content);
+    }
+    let mut out = String::with_capacity(content.len());
+    let mut fence: Option<String> = None;
+    // For each div open around the current line, whether it's conditional
+    // and, if so, whether its content is kept.
+    let mut divs: Vec<Option<bool>> = Vec::new();
+    let mut paragraph = String::new();
+    for line in content.split_inclusive('\n') {
+        let trimmed = line.trim_start_matches(' ');
+        let dropped = divs.contains(&Some(false));
+        if let Some(marker) = &fence {
+            if closes_fence(trimmed, marker) {
+                fence = None;
+            }
+            if !dropped {
+                out.push_str(line);
+            }
+            continue;
+        }
+        if line.len() - trimmed.len() < 4 {
+            if let Some(marker) = fence_marker(trimmed) {
+                flush(&mut out, &mut paragraph, targets);
+                fence = Some(marker);
+                if !dropped {
+                    out.push_str(line);
+                }
+                continue;
+            }
+            if let Some(attributes) = div_fence(trimmed) {
+                flush(&mut out, &mut paragraph, targets);
+                match attributes {
+                    // A closing fence.
+                    "" => match divs.pop() {
+                        Some(Some(_)) => {}
+                        _ if !dropped => out.push_str(line),
+                        _ => {}
+                    },
+                    attributes => {
+                        let condition = condition(attributes)
+                            .map(|only| only.iter().any(|target| targets.contains(target)));
+                        if condition.is_none() && !dropped {
+                            out.push_str(line);
+                        }
+                        divs.push(condition);
+                    }
+                }
+                continue;
+            }
+        }
+        if dropped {
+            continue;
+        }
+        if line.trim().is_empty() {
+            flush(&mut out, &mut paragraph, targets);
+            out.push_str(line);
+        } else {
+            paragraph.push_str(line);
+        }
+    }
+    flush(&mut out, &mut paragraph, targets);
+    Cow::Owned(out)
+}
+
+/// Moves `paragraph` into `out` with its conditional spans resolved.
+fn flush(out: &mut String, paragraph: &mut String, targets: &[&str]) {
+    if paragraph.contains(CLASS) {
+        out.push_str(&select_spans(paragraph, targets));
+    } else {
+        out.push_str(paragraph);
+    }
+    paragraph.clear();
+}
+
+/// `text` with each `[...]{.only-x}` replaced by what's in the brackets,
+/// or nothing if it isn't for `targets`. Code spans are left alone.
+fn select_spans(text: &str, targets: &[&str]) -> String {
+    let mut out = String::with_capacity(text.len());
+    // Where each unclosed `[` is in `out`.
+    let mut open: Vec<usize> = Vec::new();
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        match c {
+            '\\' => {
+                let escaped = rest.chars().nth(1).map_or(1, |c| 1 + c.len_utf8());
+                out.push_str(&rest[..escaped]);
+                rest = &rest[escaped..];
+                continue;
+            }
+            '`' => {
+                let ticks = rest.chars().take_while(|c| *c == '`').count();
+                let end = rest[ticks..]
+                    .find(&"`".repeat(ticks))
+                    .map_or(ticks, |i| ticks + i + ticks);
+                out.push_str(&rest[..end]);
+                rest = &rest[end..];
+                continue;
+            }
+            '[' => open.push(out.len()),
+            ']' => {
+                let start = open.pop();
+                let attributes = rest[1..]
+                    .strip_prefix('{')
+                    .and_then(|after| after.find('}').map(|end| &after[..end]));
+                if let (Some(start), Some(attributes)) = (start, attributes) {
+                    if let Some(only) = condition(attributes) {
+                        let kept = out[start + 1..].to_string();
+                        out.truncate(start);
+                        if only.iter().any(|target| targets.contains(target)) {
+                            out.push_str(&kept);
+                        }
+                        rest = &rest[attributes.len() + 3..];
+                        continue;
+                    }
+                }
+            }
+            _ => {}
+        }
+        out.push(c);
+        rest = &rest[c.len_utf8()..];
+    }
+    out
+}
+
+/// The targets attributes like `.only-print .only-web` are for, or `None`
+/// if they say anything else and so aren't a condition.
+fn condition(attributes: &str) -> Option<Vec<&str>> {
+    let attributes = attributes.trim();
+    let attributes = attributes.strip_prefix('{').unwrap_or(attributes);
+    let attributes = attributes.strip_suffix('}').unwrap_or(attributes);
+    let only: Vec<&str> = attributes
+        .split_whitespace()
+        .map(|class| {
+            class
+                .strip_prefix(CLASS)
+                .filter(|target| !target.is_empty())
+        })
+        .collect::<Option<_>>()?;
+    (!only.is_empty()).then_some(only)
+}
+
+/// What follows the colons of a fenced div's fence, which is empty for a
+/// closing one.
+fn div_fence(line: &str) -> Option<&str> {
+    let colons = line.chars().take_while(|c| *c == ':').count();
+    (colons >= 3).then(|| line[colons..].trim().trim_end_matches(':').trim())
+}
+
+fn fence_marker(line: &str) -> Option<String> {
+    let c = line.chars().next().filter(|c| *c == '`' || *c == '~')?;
+    let len = line.chars().take_while(|x| *x == c).count();
+    (len >= 3).then(|| c.to_string().repeat(len))
+}
+
+fn closes_fence(line: &str, marker: &str) -> bool {
+    let line = line.trim_end();
+    line.len() >= marker.len() && line.chars().all(|c| marker.starts_with(c))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_what_is_for_the_target() {
+        let chapter = "Before.\n\n::: {.only-print}\nFull @doe99, pp. 4–9.\n:::\n\n\
+                       ::: {.only-web .only-epub}\n::: note\nShort.\n:::\n:::\n\n\
+                       See [@doe99]{.only-web}[^[@doe99, pp. 4–9.]]{.only-print}.\n";
+        assert_eq!(
+            select(chapter, &["latex", "print"]),
+            "Before.\n\nFull @doe99, pp. 4–9.\n\n\nSee ^[@doe99, pp. 4–9.].\n"
+        );
+        assert_eq!(
+            select(chapter, &["html", "web"]),
+            "Before.\n\n\n::: note\nShort.\n:::\n\nSee @doe99.\n"
+        );
+    }
+
+    #[test]
+    fn leaves_everything_else_alone() {
+        let chapter = "[link](a.md){.only-print} `[x]{.only-web}`\n\n\
+                       ```\n::: {.only-web}\n```\n\n::: {.only-web .wide}\nKept.\n:::\n";
+        assert_eq!(
+            select(chapter, &["typst", "print"]),
+            "[link](a.md){.only-print} `[x]{.only-web}`\n\n\
+             ```\n::: {.only-web}\n```\n\n::: {.only-web .wide}\nKept.\n:::\n"
+        );
+        assert!(matches!(select("No markers.", &["html"]), Cow::Borrowed(_)));
+    }
+}
@@ -0,0 +1,72 @@
+//! User templates for bibliography entries, for in-house formats CSL
+//! can't express.
+//!
+//! Each entry pandoc renders is replaced by the template rendered with the
+//! entry's CSL-JSON fields, e.g. `{{author}} ({{issued}}). {{title}}.`.
+//! Citations are still rendered by the style.
+
+use std::fs;
+use std::path::Path;
+
+use handlebars::Handlebars;
+use mdbook::errors::Error;
+
+use crate::bibliography::Entry;
+use crate::error::CiteprocError;
+
+const NAME: &str = "entry";
+
+pub struct EntryTemplate {
+    registry: Handlebars<'static>,
+}
+
+impl EntryTemplate {
+    /// Compiles the Handlebars template at `path`.
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let source = fs::read_to_string(path).map_err(|e| {
+            CiteprocError::config(format!(
+                "entry template {} can't be read: {e}",
+                path.display()
+            ))
+        })?;
+        Self::new(&source)
+            .map_err(|e| CiteprocError::config(format!("entry template {}: {e}", path.display())))
+    }
+
+    fn new(source: &str) -> Result<Self, handlebars::TemplateError> {
+        let mut registry = Handlebars::new();
+        registry.register_template_string(NAME, source)?;
+        Ok(Self { registry })
+    }
+
+    /// Renders `entry`, HTML-escaping its fields.
+    pub fn render(&self, entry: &Entry) -> Result<String, Error> {
+        self.registry.render(NAME, &entry.csl_json()).map_err(|e| {
+            CiteprocError::config(format!("entry template failed on `{}`: {e}", entry.key))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    #[test]
+    fn renders_csl_fields() {
+        let template = EntryTemplate::new(
+            "{{title}}, <i>{{container-title}}</i>{{#if DOI}} doi:{{DOI}}{{/if}}",
+        )
+        .unwrap();
+        let entry = Entry {
+            key: "smith".into(),
+            kind: "article-journal".into(),
+            fields: BTreeMap::from([
+                ("title".to_string(), "Q & A".to_string()),
+                ("journal".to_string(), "J".to_string()),
+            ]),
+        };
+        assert_eq!(template.render(&entry).unwrap(), "Q &amp; A, <i>J</i>");
+    }
+}
@@ -0,0 +1,326 @@
+//! Testing books and integrations without pandoc: a scriptable
+//! [`FakePandoc`] backend, [`TestBook`] fixtures and golden files.
+//!
+//! Only built with the `test-utils` feature.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use mdbook::errors::Error;
+use serde_json::{json, Value};
+use toml::value::Table;
+
+use crate::backend::{self, Capabilities, CitationBackend, Output};
+use crate::citation::{self, Mode};
+use crate::config::Config;
+use crate::preprocessor::Pandoc;
+
+type Respond = dyn Fn(&Config, &str) -> Output + Send + Sync;
+
+/// A chapter handed to a [`FakePandoc`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Call {
+    /// The arguments pandoc would have been run with.
+    pub args: Vec<String>,
+    pub input: String,
+}
+
+/// A [`CitationBackend`] which answers every chapter with a function
+/// instead of running pandoc, and remembers what it was asked.
+///
+/// Share it with an [`Arc`] to look at the calls after a build:
+///
+/// ```ignore
+/// let fake = Arc::new(FakePandoc::citeproc());
+/// let pandoc = Pandoc::new().backend(Box::new(fake.clone()));
+/// ```
+pub struct FakePandoc {
+    respond: Box<Respond>,
+    capabilities: Capabilities,
+    calls: Mutex<Vec<Call>>,
+}
+
+impl FakePandoc {
+    /// Answers each chapter with `respond`.
+    pub fn new(respond: impl Fn(&Config, &str) -> Output + Send + Sync + 'static) -> Self {
+        Self {
+            respond: Box::new(respond),
+            capabilities: Capabilities::default(),
+            calls: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Returns every chapter unchanged.
+    pub fn echo() -> Self {
+        Self::new(|_, input| success(input.to_string()))
+    }
+
+    /// Renders citations roughly like citeproc when pandoc would run it:
+    /// each cited key becomes a link to its entry, and a bibliography with
+    /// an entry per key, in key order, is appended as pandoc writes it.
+    pub fn citeproc() -> Self {
+        Self::new(|config, input| {
+            match backend::pandoc_args(config)
+                .iter()
+                .any(|arg| arg == "--citeproc")
+            {
+                true => success(render_citations(config, input)),
+                false => success(input.to_string()),
+            }
+        })
+    }
+
+    /// Fails every chapter with `message` on stderr.
+    pub fn failing(message: &str) -> Self {
+        let message = message.to_string();
+        Self::new(move |_, _| Output {
+            stdout: Vec::new(),
+            stderr: message.clone(),
+            failure: Some("exit status: 1".into()),
+        })
+    }
+
+    /// Claims `capabilities`, for testing `check`.
+    pub fn with_capabilities(mut self, capabilities: Capabilities) -> Self {
+        self.capabilities = capabilities;
+        self
+    }
+
+    /// Every chapter converted so far, in the order they were converted.
+    pub fn calls(&self) -> Vec<Call> {
+        self.calls.lock().expect("calls poisoned").clone()
+    }
+}
+
+impl CitationBackend for FakePandoc {
+    fn name(&self) -> &str {
+        "fake-pandoc"
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.capabilities.clone()
+    }
+
+    fn convert(&self, config: &Config, input: &str) -> Result<Output, Error> {
+        self.calls.lock().expect("calls poisoned").push(Call {
+            args: backend::pandoc_args(config),
+            input: input.to_string(),
+        });
+        Ok((self.respond)(config, input))
+    }
+}
+
+impl CitationBackend for Arc<FakePandoc> {
+    fn name(&self) -> &str {
+        self.as_ref().name()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.as_ref().capabilities()
+    }
+
+    fn convert(&self, config: &Config, input: &str) -> Result<Output, Error> {
+        self.as_ref().convert(config, input)
+    }
+}
+
+fn success(stdout: String) -> Output {
+    Output {
+        stdout: stdout.into_bytes(),
+        stderr: String::new(),
+        failure: None,
+    }
+}
+
+fn render_citations(config: &Config, input: &str) -> String {
+    let link = |key: &str| format!("[{key}](#ref-{key})");
+    let mut out = String::with_capacity(input.len());
+    let mut keys = config.nocite.clone();
+    let mut last = 0;
+    for cited in citation::parse(input) {
+        out += &input[last..cited.span.start];
+        last = cited.span.end;
+        let items: Vec<String> = cited
+            .items
+            .iter()
+            .map(|item| {
+                keys.push(item.key.clone());
+                let mut rendered = format!("{} {}", item.prefix, link(&item.key));
+                if !item.suffix.is_empty() {
+                    rendered += &format!(", {}", item.suffix);
+                }
+                rendered.trim().to_string()
+            })
+            .collect();
+        out += &match cited.mode {
+            Mode::Bracketed => format!("({})", items.join("; ")),
+            Mode::InText => items.join("; "),
+        };
+    }
+    out += &input[last..];
+
+    keys.sort();
+    keys.dedup();
+    if !keys.is_empty() {
+        if let Some(title) = config.metadata.get("reference-section-title") {
+            out += &format!("\n# {title}\n");
+        }
+        out += "\n<div id=\"refs\" class=\"references csl-bib-body\" role=\"list\">\n\n";
+        for key in keys {
+            out += &format!(
+                "<div id=\"ref-{key}\" class=\"csl-entry\" role=\"listitem\">\n\n{key}.\n\n</div>\n\n"
+            );
+        }
+        out += "</div>\n";
+    }
+    out
+}
+
+/// A book to run the preprocessor over, written to a directory of its own
+/// so bibliographies and styles can be read from it.
+#[derive(Debug, Clone)]
+pub struct TestBook {
+    root: PathBuf,
+    config: Table,
+    renderer: String,
+    files: Vec<(PathBuf, String)>,
+    chapters: Vec<(String, String)>,
+}
+
+impl TestBook {
+    /// A book in `root`, which is created when the book is run.
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: root.into(),
+            config: Table::new(),
+            renderer: "html".into(),
+            files: Vec::new(),
+            chapters: Vec::new(),
+        }
+    }
+
+    /// Sets `[preprocessor.citeproc]` to `toml`.
+    ///
+    /// # Panics
+    ///
+    /// If `toml` isn't a valid TOML table.
+    pub fn config(mut self, toml: &str) -> Self {
+        self.config = toml::from_str(toml).expect("invalid preprocessor configuration");
+        self
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn renderer(mut self, renderer: &str) -> Self {
+        self.renderer = renderer.into();
+        self
+    }
+
+    /// Adds a file to the book, e.g. its bibliography.
+    pub fn file(mut self, path: impl Into<PathBuf>, contents: &str) -> Self {
+        self.files.push((path.into(), contents.into()));
+        self
+    }
+
+    /// Adds a top-level chapter, as `<name>.md`.
+    pub fn chapter(mut self, name: &str, content: &str) -> Self {
+        self.chapters.push((name.into(), content.into()));
+        self
+    }
+
+    /// The JSON mdbook would send the preprocessor for the book.
+    pub fn input(&self) -> Vec<u8> {
+        let sections: Vec<Value> = self
+            .chapters
+            .iter()
+            .enumerate()
+            .map(|(i, (name, content))| {
+                let path = format!("{}.md", name.to_lowercase().replace(' ', "-"));
+                json!({"Chapter": {
+                    "name": name,
+                    "content": content,
+                    "number": [i + 1],
+                    "sub_items": [],
+                    "path": path,
+                    "source_path": path,
+                    "parent_names": [],
+                }})
+            })
+            .collect();
+        let context = json!({
+            "root": self.root,
+            "config": {
+                "book": {"authors": [], "language": "en", "multilingual": false, "src": "src"},
+                "preprocessor": {"citeproc": self.config},
+            },
+            "renderer": self.renderer,
+            "mdbook_version": mdbook::MDBOOK_VERSION,
+        });
+        let book = json!({"sections": sections, "__non_exhaustive": null});
+        serde_json::to_vec(&json!([context, book])).expect("books serialize")
+    }
+
+    /// Writes the book's files and runs `pandoc` over it, returning each
+    /// chapter's content.
+    pub fn run(&self, pandoc: &Pandoc) -> Result<Vec<String>, Error> {
+        fs::create_dir_all(&self.root)?;
+        for (path, contents) in &self.files {
+            let path = self.root.join(path);
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            fs::write(path, contents)?;
+        }
+        let output: Value = serde_json::from_str(&crate::process_input(pandoc, &self.input())?)?;
+        Ok(output["sections"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|section| section["Chapter"]["content"].as_str())
+            .map(str::to_string)
+            .collect())
+    }
+
+    /// Runs the book with a fresh [`FakePandoc::citeproc`].
+    pub fn run_fake(&self) -> Result<Vec<String>, Error> {
+        self.run(
+            &Pandoc::new()
+                .quiet(true)
+                .backend(Box::new(FakePandoc::citeproc())),
+        )
+    }
+}
+
+/// Checks that `actual` matches the golden file at `path`. With the
+/// `UPDATE_GOLDEN` environment variable set, the file is written instead.
+///
+/// # Panics
+///
+/// If they differ, with a diff of the two.
+pub fn assert_golden(path: impl AsRef<Path>, actual: &str) {
+    let path = path.as_ref();
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).expect("can't create the golden file's directory");
+        }
+        fs::write(path, actual).expect("can't write the golden file");
+        return;
+    }
+    let expected = fs::read_to_string(path).unwrap_or_else(|e| {
+        panic!(
+            "can't read {}: {e}; run with UPDATE_GOLDEN=1 to create it",
+            path.display()
+        )
+    });
+    if expected != actual {
+        let diff = similar::TextDiff::from_lines(expected.as_str(), actual);
+        panic!(
+            "{} doesn't match, run with UPDATE_GOLDEN=1 to update it:\n{}",
+            path.display(),
+            diff.unified_diff().header("expected", "actual")
+        );
+    }
+}
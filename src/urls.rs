@@ -0,0 +1,348 @@
+//! Link handling in rendered references: shortening long URLs, preferring
+//! DOIs over other links, removing tracking parameters and leaving links
+//! out altogether, e.g. from print, where a profile for its renderers can
+//! set `suppress-urls` and `suppress-dois`.
+//!
+//! Pandoc writes an entry's links as markdown (`<url>` or `[text](url)`),
+//! or as `<a>` tags when a template renders it, so all three are handled.
+
+use std::ops::Range;
+
+/// How links in the bibliography are rewritten.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct UrlPolicy {
+    /// Shorten URLs shown as link text to this many characters. The link
+    /// itself is kept whole.
+    pub max_length: Option<usize>,
+    /// Leave out other links from entries which link to a DOI.
+    pub prefer_doi: bool,
+    /// Remove `utm_*` and similar query parameters.
+    pub strip_tracking: bool,
+    /// Leave out links other than DOIs.
+    pub suppress_urls: bool,
+    /// Leave out DOIs.
+    pub suppress_dois: bool,
+}
+
+impl UrlPolicy {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Query parameters which only track where a visitor came from, besides
+/// any starting with `utm_`.
+const TRACKING_PARAMETERS: &[&str] = &[
+    "_ga", "_hsenc", "_hsmi", "dclid", "fbclid", "gclid", "igshid", "mc_cid", "mc_eid", "msclkid",
+    "yclid",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Syntax {
+    /// `<https://example.com>`
+    Autolink,
+    /// `[text](https://example.com)`
+    Markdown,
+    /// `<a href="https://example.com">text</a>`, with where the `href`
+    /// value is and where the text starts.
+    Html { href: (usize, usize), text: usize },
+}
+
+#[derive(Debug)]
+struct Link {
+    span: Range<usize>,
+    syntax: Syntax,
+    href: String,
+    text: String,
+}
+
+/// Applies `policy` to the links in a rendered entry.
+pub fn apply(entry: &str, policy: &UrlPolicy) -> String {
+    let links = links(entry);
+    let has_doi = policy.prefer_doi && links.iter().any(|link| is_doi(&link.href));
+    let mut out = String::with_capacity(entry.len());
+    let mut last = 0;
+    for link in links {
+        out += &entry[last..link.span.start];
+        last = link.span.end;
+        let doi = is_doi(&link.href);
+        let dropped = match doi {
+            true => policy.suppress_dois,
+            false => has_doi || policy.suppress_urls,
+        };
+        if dropped {
+            // Take the space before the link with it, and don't leave two
+            // full stops where it was.
+            out.truncate(out.trim_end().len());
+            if let Some(end) = out.chars().last().filter(|c| ".,;".contains(*c)) {
+                if entry[last..].starts_with(end) {
+                    last += end.len_utf8();
+                }
+            }
+            continue;
+        }
+
+        let shows_url = unescape(&link.text) == link.href;
+        let href = match policy.strip_tracking {
+            true => strip_tracking(&link.href),
+            false => link.href.clone(),
+        };
+        let text = match (shows_url, policy.max_length) {
+            (true, Some(max)) => shorten(&href, max),
+            (true, None) => href.clone(),
+            (false, _) => link.text.clone(),
+        };
+        if href == link.href && text == link.text {
+            out += &entry[link.span];
+            continue;
+        }
+        out += &match link.syntax {
+            Syntax::Autolink | Syntax::Markdown if text == href => format!("<{href}>"),
+            Syntax::Autolink => format!("[{}]({href})", escape_markdown(&text)),
+            Syntax::Markdown if shows_url => format!("[{}]({href})", escape_markdown(&text)),
+            Syntax::Markdown => format!("[{text}]({href})"),
+            Syntax::Html {
+                href: (href_start, href_end),
+                text: text_start,
+            } => {
+                let text = match shows_url {
+                    true => escape_html(&text),
+                    false => text,
+                };
+                format!(
+                    "{}{}{}{text}</a>",
+                    &entry[link.span.start..href_start],
+                    href.replace('&', "&amp;"),
+                    &entry[href_end..text_start]
+                )
+            }
+        };
+    }
+    out += &entry[last..];
+    out
+}
+
+/// Every external link in `entry`.
+fn links(entry: &str) -> Vec<Link> {
+    let mut links = Vec::new();
+    let mut i = 0;
+    while i < entry.len() {
+        let rest = &entry[i..];
+        let link = if rest.starts_with('\\') {
+            i += rest.chars().nth(1).map_or(1, |c| 1 + c.len_utf8());
+            continue;
+        } else if rest.starts_with("<a ") {
+            html_link(entry, i)
+        } else if rest.starts_with("<http://") || rest.starts_with("<https://") {
+            rest.find('>').map(|end| Link {
+                span: i..i + end + 1,
+                syntax: Syntax::Autolink,
+                href: rest[1..end].to_string(),
+                text: rest[1..end].to_string(),
+            })
+        } else if rest.starts_with('[') {
+            markdown_link(entry, i)
+        } else {
+            None
+        };
+        match link {
+            Some(link) => {
+                i = link.span.end;
+                if link.href.starts_with("http://") || link.href.starts_with("https://") {
+                    links.push(link);
+                }
+            }
+            None => i += rest.chars().next().map_or(1, char::len_utf8),
+        }
+    }
+    links
+}
+
+fn html_link(entry: &str, start: usize) -> Option<Link> {
+    let tag_end = start + entry[start..].find('>')?;
+    let href_start = start + entry[start..tag_end].find("href=\"")? + "href=\"".len();
+    let href_end = href_start + entry[href_start..tag_end].find('"')?;
+    let close = tag_end + entry[tag_end..].find("</a>")?;
+    Some(Link {
+        span: start..close + "</a>".len(),
+        syntax: Syntax::Html {
+            href: (href_start, href_end),
+            text: tag_end + 1,
+        },
+        href: entry[href_start..href_end].replace("&amp;", "&"),
+        text: entry[tag_end + 1..close].to_string(),
+    })
+}
+
+fn markdown_link(entry: &str, start: usize) -> Option<Link> {
+    let mut depth = 0usize;
+    let mut close = None;
+    let mut escaped = false;
+    for (offset, c) in entry[start + 1..].char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '[' => depth += 1,
+            ']' if depth == 0 => {
+                close = Some(start + 1 + offset);
+                break;
+            }
+            ']' => depth -= 1,
+            _ => {}
+        }
+    }
+    let close = close?;
+    let destination = entry[close + 1..].strip_prefix('(')?;
+    let end = destination.find(')')?;
+    // Drop a title, as in `[text](url "title")`.
+    let href = destination[..end].split_whitespace().next()?;
+    Some(Link {
+        span: start..close + 2 + end + 1,
+        syntax: Syntax::Markdown,
+        href: href.to_string(),
+        text: entry[start + 1..close].to_string(),
+    })
+}
+
+fn is_doi(href: &str) -> bool {
+    href.contains("://doi.org/") || href.contains("://dx.doi.org/")
+}
+
+/// `url` without its tracking query parameters.
+fn strip_tracking(url: &str) -> String {
+    let (url, fragment) = match url.find('#') {
+        Some(hash) => url.split_at(hash),
+        None => (url, ""),
+    };
+    let Some((base, query)) = url.split_once('?') else {
+        return format!("{url}{fragment}");
+    };
+    let kept: Vec<&str> = query
+        .split('&')
+        .filter(|parameter| {
+            let name = parameter.split('=').next().unwrap_or_default();
+            !name.starts_with("utm_") && !TRACKING_PARAMETERS.contains(&name)
+        })
+        .collect();
+    match kept.as_slice() {
+        [] => format!("{base}{fragment}"),
+        kept => format!("{base}?{}{fragment}", kept.join("&")),
+    }
+}
+
+/// `url` shown in at most `max` characters: without its scheme, and cut
+/// short with an ellipsis if that isn't enough.
+fn shorten(url: &str, max: usize) -> String {
+    if url.chars().count() <= max {
+        return url.to_string();
+    }
+    let url = url
+        .strip_prefix("https://")
+        .or_else(|| url.strip_prefix("http://"))
+        .unwrap_or(url);
+    if url.chars().count() <= max {
+        return url.to_string();
+    }
+    let mut short: String = url.chars().take(max.saturating_sub(1)).collect();
+    short.push('…');
+    short
+}
+
+fn unescape(markdown: &str) -> String {
+    let mut out = String::with_capacity(markdown.len());
+    let mut chars = markdown.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => out.extend(chars.next()),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn escape_markdown(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        if "\\[]*_`<>".contains(c) {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shortens_and_strips_urls() {
+        let policy = UrlPolicy {
+            max_length: Some(20),
+            strip_tracking: true,
+            ..UrlPolicy::default()
+        };
+        assert_eq!(
+            apply(
+                "Smith. <https://example.com/a/long/path?utm_source=x&page=2>.",
+                &policy
+            ),
+            "Smith. [example.com/a/long/…](https://example.com/a/long/path?page=2)."
+        );
+        assert_eq!(
+            apply(
+                "[Site](https://example.com/?fbclid=1#top) <https://x.org>",
+                &policy
+            ),
+            "[Site](https://example.com/#top) <https://x.org>"
+        );
+        assert_eq!(
+            apply(
+                "<a href=\"https://example.com/page?gclid=2\">https://example.com/page?gclid=2</a>",
+                &policy
+            ),
+            "<a href=\"https://example.com/page\">example.com/page</a>"
+        );
+    }
+
+    #[test]
+    fn prefers_dois() {
+        let policy = UrlPolicy {
+            prefer_doi: true,
+            ..UrlPolicy::default()
+        };
+        assert_eq!(
+            apply(
+                "Smith. J, 3. <https://doi.org/10.1/x>. <https://example.com/x>.",
+                &policy
+            ),
+            "Smith. J, 3. <https://doi.org/10.1/x>."
+        );
+        let without_doi = "Smith. <https://example.com/x>.";
+        assert_eq!(apply(without_doi, &policy), without_doi);
+    }
+
+    #[test]
+    fn suppresses_urls_and_dois() {
+        let entry = "Smith. J, 3. <https://doi.org/10.1/x>. [Page](https://example.com/x).";
+        let urls = UrlPolicy {
+            suppress_urls: true,
+            ..UrlPolicy::default()
+        };
+        assert_eq!(
+            apply(entry, &urls),
+            "Smith. J, 3. <https://doi.org/10.1/x>."
+        );
+        let both = UrlPolicy {
+            suppress_dois: true,
+            ..urls
+        };
+        assert_eq!(apply(entry, &both), "Smith. J, 3.");
+    }
+}
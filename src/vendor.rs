@@ -0,0 +1,99 @@
+//! `mdbook-citeproc vendor`: copies what the book fetches from remote
+//! services into `citeproc-vendor/` beside `book.toml`, which can be
+//! committed so the book builds offline, and the same way everywhere.
+//!
+//! Builds use what's vendored in place of the cache: the library
+//! `[remote-bibliography]` fetches is read from `remote/`, styles at URLs
+//! (the book's and its translations') from `styles/`, neither fetched
+//! again, and `archive-links` lookups are kept (for good) in
+//! `wayback.json`. Vendoring again fetches the library and styles afresh,
+//! and only looks up links which haven't been archived yet. Pandoc itself
+//! isn't vendored, being a different binary on every platform, and nor are
+//! locales, which it has built in.
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use mdbook::errors::Error;
+
+use crate::bibliography::Library;
+use crate::error::CiteprocError;
+use crate::{archive, check, graph, http, style};
+
+/// The vendor directory's name, in the book's root.
+pub const DIR: &str = "citeproc-vendor";
+
+/// What [`vendor`] copied.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Vendored {
+    /// Where the remote bibliography is, relative to the book's root.
+    pub remote_bibliography: Option<PathBuf>,
+    /// How many styles at URLs were vendored.
+    pub styles: usize,
+    /// How many archived links there are.
+    pub snapshots: usize,
+}
+
+/// Vendors the remote resources of the book in `root`.
+pub fn vendor(root: &Path) -> Result<Vendored, Error> {
+    let config = check::load_config(root)?;
+    http::configure_network(&config)?;
+    if http::is_offline() {
+        return Err(CiteprocError::config("vendor can't be run offline"));
+    }
+    let dir = root.join(DIR);
+    fs::create_dir_all(&dir)?;
+    let mut vendored = Vendored::default();
+
+    if let Some(remote) = &config.remote_bibliography {
+        remote.fetch(None, true)?;
+        let target = remote
+            .vendored_path(&dir)
+            .unwrap_or_else(|| remote.path.clone());
+        if remote.path != target {
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::copy(&remote.path, &target)?;
+        }
+        vendored.remote_bibliography = Some(target.strip_prefix(root).unwrap_or(&target).into());
+    }
+
+    let styles: BTreeSet<&str> = config
+        .bibliography
+        .iter()
+        .map(|bibliography| bibliography.bibliography_style.as_str())
+        .chain(
+            config
+                .languages
+                .values()
+                .filter_map(|language| language.bibliography_style.as_deref()),
+        )
+        .filter(|style| style.contains("://"))
+        .collect();
+    for url in &styles {
+        style::fetch(url, &style::remote_path(url, &dir.join("styles")))?;
+    }
+    vendored.styles = styles.len();
+
+    if config.archive_links {
+        // Start from what's been looked up already.
+        let known = dir.join("wayback.json");
+        let cached = config.cache_root.join("wayback.json");
+        if !known.exists() && cached.exists() {
+            fs::copy(cached, &known)?;
+        }
+        let citations = graph::load(root)?;
+        let library = Library::new(citations.entries.values().cloned());
+        vendored.snapshots = archive::snapshots(
+            &library,
+            citations.entries.keys().map(String::as_str),
+            &dir,
+            None,
+            false,
+        )?
+        .len();
+    }
+    Ok(vendored)
+}
@@ -0,0 +1,201 @@
+//! Runs a WASI build of pandoc (e.g. the official `pandoc.wasm`) in-process
+//! with wasmtime, for environments which forbid spawning binaries.
+//!
+//! The guest only sees the book root, read-only, as its working directory,
+//! so relative bibliography and style paths resolve exactly as they do for
+//! a pandoc subprocess. The cache root, where derived bibliographies and
+//! the nocite metadata are written, is mounted read-only at [`GUEST_CACHE`],
+//! and the absolute paths pandoc is given are mapped into the guest.
+
+use std::path::{Path, PathBuf};
+
+use mdbook::errors::Error;
+use wasmtime::{Engine, Linker, Module, Store};
+use wasmtime_wasi::pipe::{MemoryInputPipe, MemoryOutputPipe};
+use wasmtime_wasi::preview1::{self, WasiP1Ctx};
+use wasmtime_wasi::{DirPerms, FilePerms, I32Exit, WasiCtxBuilder};
+
+use crate::backend::{pandoc_args_for, Capabilities, CitationBackend, Output};
+use crate::config::Config;
+use crate::error::CiteprocError;
+use crate::subprocess::path_arg;
+
+/// Where the guest sees the cache root.
+const GUEST_CACHE: &str = "/citeproc-cache";
+
+/// A compiled pandoc module, reused for every chapter of a build.
+pub struct WasmPandoc {
+    engine: Engine,
+    module: Module,
+    linker: Linker<WasiP1Ctx>,
+    root: PathBuf,
+}
+
+impl WasmPandoc {
+    pub fn new(module: &Path, root: &Path) -> Result<Self, Error> {
+        let engine = Engine::default();
+        let module = Module::from_file(&engine, module).map_err(|e| {
+            CiteprocError::pandoc_missing(format!(
+                "failed to load wasm module {}: {e}",
+                module.display()
+            ))
+        })?;
+        let mut linker = Linker::new(&engine);
+        preview1::add_to_linker_sync(&mut linker, |ctx| ctx)?;
+        Ok(Self {
+            engine,
+            module,
+            linker,
+            root: root.to_path_buf(),
+        })
+    }
+
+    /// Runs the module with `args`, `input` on stdin and `cache_root`
+    /// mounted, returning its stdout, stderr and exit code.
+    pub fn run(
+        &self,
+        args: &[String],
+        input: &str,
+        cache_root: &Path,
+    ) -> Result<(Vec<u8>, Vec<u8>, i32), Error> {
+        let stdout = MemoryOutputPipe::new(usize::MAX);
+        let stderr = MemoryOutputPipe::new(usize::MAX);
+        let mut argv = vec!["pandoc".to_string()];
+        argv.extend_from_slice(args);
+        let mut wasi = WasiCtxBuilder::new();
+        wasi.stdin(MemoryInputPipe::new(input.to_string()))
+            .stdout(stdout.clone())
+            .stderr(stderr.clone())
+            .args(&argv)
+            .preopened_dir(&self.root, ".", DirPerms::READ, FilePerms::READ)?;
+        if cache_root.is_dir() {
+            wasi.preopened_dir(cache_root, GUEST_CACHE, DirPerms::READ, FilePerms::READ)?;
+        }
+        let wasi = wasi.build_p1();
+
+        let mut store = Store::new(&self.engine, wasi);
+        let instance = self.linker.instantiate(&mut store, &self.module)?;
+        let start = instance.get_typed_func::<(), ()>(&mut store, "_start")?;
+        let code = match start.call(&mut store, ()) {
+            Ok(()) => 0,
+            Err(e) => match e.downcast_ref::<I32Exit>() {
+                Some(exit) => exit.0,
+                None => {
+                    return Err(CiteprocError::pandoc_failed(format!(
+                        "wasm pandoc trapped: {e}"
+                    )))
+                }
+            },
+        };
+        drop(store);
+        Ok((stdout.contents().to_vec(), stderr.contents().to_vec(), code))
+    }
+}
+
+impl CitationBackend for WasmPandoc {
+    fn name(&self) -> &str {
+        "wasm"
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            reads_outside_root: false,
+            ..Capabilities::default()
+        }
+    }
+
+    fn convert(&self, config: &Config, input: &str) -> Result<Output, Error> {
+        let guest = guest_config(config, &self.root)?;
+        let version = self.capabilities().pandoc_version;
+        let args = pandoc_args_for(&guest, version.as_ref());
+        let (stdout, stderr, code) = self.run(&args, input, &config.cache_root)?;
+        Ok(Output {
+            stdout,
+            stderr: String::from_utf8_lossy(&stderr).into_owned(),
+            failure: (code != 0).then(|| format!("exit status: {code}")),
+        })
+    }
+}
+
+/// `config` with the files pandoc reads as the guest sees them: relative
+/// to the book root, or under [`GUEST_CACHE`]. Anything elsewhere is out of
+/// the guest's reach.
+fn guest_config(config: &Config, root: &Path) -> Result<Config, Error> {
+    let cache_root = config.cache_root.clone();
+    let guest_path = |path: &Path| -> Result<PathBuf, Error> {
+        if path.is_relative() {
+            return Ok(path.to_path_buf());
+        }
+        if let Ok(relative) = path.strip_prefix(root) {
+            return Ok(relative.to_path_buf());
+        }
+        if let Ok(relative) = path.strip_prefix(&cache_root) {
+            return Ok(Path::new(GUEST_CACHE).join(relative));
+        }
+        Err(CiteprocError::config(format!(
+            "the wasm backend can only read files in the book or its cache, not {}",
+            path.display()
+        )))
+    };
+    let mut config = config.clone();
+    if let Some(bibliography) = &mut config.bibliography {
+        bibliography.bibliography =
+            path_arg(&guest_path(bibliography.bibliography.as_ref())?).into_owned();
+        if !bibliography.bibliography_style.contains("://") {
+            bibliography.bibliography_style =
+                path_arg(&guest_path(bibliography.bibliography_style.as_ref())?).into_owned();
+        }
+    }
+    for path in config
+        .extra_bibliographies
+        .iter_mut()
+        .chain(&mut config.metadata_file)
+    {
+        *path = guest_path(path)?;
+    }
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_paths_into_the_guest() {
+        let root = Path::new("/book");
+        let table = toml::from_str(
+            r#"
+            citations = "transpile"
+            bibliography = ["refs.bib", "more.bib"]
+            csl = "style.csl"
+            "#,
+        )
+        .unwrap();
+        let mut config = Config::from_table(&table, root).unwrap();
+        config.cache_root = PathBuf::from("/tmp/cache");
+        config
+            .extra_bibliographies
+            .push(config.cache_root.join("derived/0123.json"));
+        config.metadata_file = Some(config.cache_root.join("nocite.yaml"));
+
+        let guest = guest_config(&config, root).unwrap();
+        assert_eq!(
+            guest.extra_bibliographies,
+            [
+                PathBuf::from("more.bib"),
+                PathBuf::from("/citeproc-cache/derived/0123.json")
+            ]
+        );
+        assert_eq!(
+            guest.metadata_file,
+            Some(PathBuf::from("/citeproc-cache/nocite.yaml"))
+        );
+        let bibliography = guest.bibliography.unwrap();
+        assert_eq!(bibliography.bibliography, "refs.bib");
+
+        config
+            .extra_bibliographies
+            .push(PathBuf::from("/elsewhere/refs.bib"));
+        assert!(guest_config(&config, root).is_err());
+    }
+}
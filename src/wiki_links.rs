@@ -0,0 +1,101 @@
+//! Citations written as wiki links, `[[@smith2020]]`, as Obsidian and
+//! Logseq notes have them. With `wiki-links` on they're rewritten as
+//! pandoc citations, `[@smith2020]`, before anything else reads the
+//! chapter, so notes can be published without rewriting them.
+//!
+//! A link's display text (`[[@smith2020|Smith]]`) and heading
+//! (`[[@smith2020#Method]]`) are dropped, since the style decides what a
+//! citation says. Wiki links to anything but a citation key are left
+//! alone, as is code.
+
+use mdbook::book::Book;
+use mdbook::BookItem;
+
+/// Rewrites the wiki-link citations in every chapter of `book`.
+pub fn normalize_book(book: &mut Book) {
+    book.for_each_mut(|item| {
+        if let BookItem::Chapter(chapter) = item {
+            if chapter.content.contains("[[@") {
+                chapter.content = normalize(&chapter.content);
+            }
+        }
+    });
+}
+
+/// `content` with its wiki-link citations rewritten as pandoc citations.
+pub fn normalize(content: &str) -> String {
+    let mut out = String::with_capacity(content.len());
+    let mut fence: Option<(char, usize)> = None;
+    for line in content.split_inclusive('\n') {
+        let trimmed = line.trim_start_matches(' ');
+        let c = trimmed.chars().next().filter(|c| *c == '`' || *c == '~');
+        let run = c.map_or(0, |c| trimmed.chars().take_while(|x| *x == c).count());
+        match (fence, c) {
+            (Some((open, len)), Some(c)) if c == open && run >= len => fence = None,
+            (Some(_), _) => {}
+            (None, Some(c)) if run >= 3 => fence = Some((c, run)),
+            _ => {
+                normalize_line(line, &mut out);
+                continue;
+            }
+        }
+        out.push_str(line);
+    }
+    out
+}
+
+fn normalize_line(line: &str, out: &mut String) {
+    let bytes = line.as_bytes();
+    let mut last = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'`' => {
+                let ticks = bytes[i..].iter().take_while(|b| **b == b'`').count();
+                let close = line[i + ticks..].find(&"`".repeat(ticks));
+                i = close.map_or(i + ticks, |end| i + ticks + end + ticks);
+            }
+            b'\\' => i += 2,
+            b'[' if line[i..].starts_with("[[@") => {
+                let inner = &line[i + 3..];
+                match inner.find("]]") {
+                    Some(close) => {
+                        let key = inner[..close]
+                            .split(['|', '#'])
+                            .next()
+                            .unwrap_or_default()
+                            .trim();
+                        if key.is_empty() || key.contains(char::is_whitespace) {
+                            i += 1;
+                            continue;
+                        }
+                        out.push_str(&line[last..i]);
+                        out.push_str(&format!("[@{key}]"));
+                        i += 3 + close + 2;
+                        last = i;
+                    }
+                    None => i += 1,
+                }
+            }
+            _ => i += 1,
+        }
+    }
+    out.push_str(&line[last.min(line.len())..]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rewrites_wiki_link_citations() {
+        let content = "As [[@smith2020]] and [[@jones|Jones]] show, \
+                       see [[Notes]] and `[[@code]]`.\n\
+                       ```\n[[@fenced]]\n```\n[[@doe#Method]]\n";
+        assert_eq!(
+            normalize(content),
+            "As [@smith2020] and [@jones] show, see [[Notes]] and `[[@code]]`.\n\
+             ```\n[[@fenced]]\n```\n[@doe]\n"
+        );
+    }
+}
@@ -0,0 +1,175 @@
+//! Golden-file tests: the fixture book in `tests/fixtures/book` built with
+//! each configuration in `tests/fixtures/configs`, against a fake pandoc,
+//! compared with `tests/golden`. Run with `UPDATE_GOLDEN=1` to accept new
+//! output.
+
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use mdbook_citeproc::testing::{assert_golden, FakePandoc, TestBook};
+use mdbook_citeproc::Pandoc;
+
+const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures");
+const GOLDEN: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/golden");
+
+fn read(path: &str) -> String {
+    fs::read_to_string(Path::new(FIXTURES).join(path)).unwrap()
+}
+
+/// The fixture book's bibliography and style with `config` as
+/// `[preprocessor.citeproc]`, and no chapters yet.
+fn fixture(name: &str, config: &str) -> TestBook {
+    let root = std::env::temp_dir().join(format!("citeproc-golden-{name}-{}", std::process::id()));
+    TestBook::new(root)
+        .config(config)
+        .file("refs.bib", &read("book/refs.bib"))
+        .file("style.csl", &read("book/style.csl"))
+}
+
+/// The fixture book with `[preprocessor.citeproc]` from
+/// `configs/<case>.toml`.
+fn book(case: &str) -> TestBook {
+    fixture(case, &read(&format!("configs/{case}.toml")))
+        .chapter("One", &read("book/one.md"))
+        .chapter("Two", &read("book/two.md"))
+}
+
+fn check(case: &str, renderer: &str) {
+    let book = book(case).renderer(renderer);
+    let chapters = book.run_fake().unwrap();
+    fs::remove_dir_all(book.root()).unwrap();
+    let actual: String = ["One", "Two"]
+        .iter()
+        .zip(chapters)
+        .map(|(name, content)| format!("<!-- {name} -->\n{content}"))
+        .collect();
+    assert_golden(Path::new(GOLDEN).join(format!("{case}.md")), &actual);
+}
+
+#[test]
+fn default() {
+    check("default", "html");
+}
+
+#[test]
+fn sorted_by_appearance() {
+    check("appearance", "html");
+}
+
+#[test]
+fn grouped() {
+    check("groups", "html");
+}
+
+#[test]
+fn annotated() {
+    check("annotations", "html");
+}
+
+#[test]
+fn disambiguated_across_the_book() {
+    check("book-disambiguation", "html");
+}
+
+#[test]
+fn aliased_keys() {
+    check("aliases", "html");
+}
+
+#[test]
+fn preserved_citations() {
+    check("preserve", "html");
+}
+
+#[test]
+fn further_reading() {
+    check("further-reading", "html");
+}
+
+#[test]
+fn first_citations_across_the_book() {
+    check("first-citation", "html");
+
+    let fake = Arc::new(FakePandoc::citeproc());
+    let pandoc = Pandoc::new().quiet(true).backend(Box::new(fake.clone()));
+    let book = book("first-citation");
+    book.run(&pandoc).unwrap();
+    fs::remove_dir_all(book.root()).unwrap();
+    let primed: Vec<String> = fake
+        .calls()
+        .into_iter()
+        .filter_map(|call| call.input.lines().next().map(str::to_string))
+        .filter(|line| line.starts_with("CITEPROCPRIMEDX"))
+        .collect();
+    assert_eq!(primed, ["CITEPROCPRIMEDX [@adams2018]"]);
+}
+
+#[test]
+fn latex_citations() {
+    check("latex", "latex");
+}
+
+#[test]
+fn records_what_pandoc_was_asked() {
+    let fake = Arc::new(FakePandoc::citeproc());
+    let pandoc = Pandoc::new().quiet(true).backend(Box::new(fake.clone()));
+    let book = book("default");
+    book.run(&pandoc).unwrap();
+    fs::remove_dir_all(book.root()).unwrap();
+    let calls = fake.calls();
+    assert_eq!(calls.len(), 2);
+    assert!(calls[0].args.iter().any(|arg| arg == "--citeproc"));
+    assert!(calls[1].input.contains("@adams2018"));
+}
+
+#[test]
+fn reports_pandoc_failures() {
+    let pandoc = Pandoc::new()
+        .quiet(true)
+        .backend(Box::new(FakePandoc::failing("pandoc: out of memory")));
+    let book = book("default");
+    let e = book.run(&pandoc).unwrap_err();
+    fs::remove_dir_all(book.root()).unwrap();
+    assert!(e.to_string().contains("out of memory"), "{e}");
+}
+
+#[test]
+fn citations_in_table_cells() {
+    let book = fixture("tables", &read("configs/default.toml")).chapter(
+        "Tables",
+        "# Tables\n\n| Claim | Source |\n|:------|-------:|\n\
+             | Cited | [@smith2020, p. 3] |\n| Not | `[@adams2018]` |\n\nAfter.\n",
+    );
+    let chapters = book.run_fake().unwrap();
+    fs::remove_dir_all(book.root()).unwrap();
+    assert!(
+        chapters[0].starts_with(
+            "# Tables\n\n| Claim | Source |\n|:------|-------:|\n\
+             | Cited | ([smith2020](#ref-smith2020), p. 3) |\n| Not | `[@adams2018]` |\n\nAfter.\n"
+        ),
+        "{}",
+        chapters[0]
+    );
+}
+
+#[test]
+fn reference_heading_levels() {
+    let config = format!(
+        "{}reference-heading-level = 2\n\
+         bibliography-groups = [{{ title = \"Books\", types = [\"book\"] }}]\n",
+        read("configs/default.toml")
+    );
+    let book = fixture("heading", &config).chapter(
+        "One",
+        "---\nreference-section-title: Works cited\n---\n# One\n\n[@adams2018; @smith2020]\n",
+    );
+    let chapters = book.run_fake().unwrap();
+    fs::remove_dir_all(book.root()).unwrap();
+    let chapter = &chapters[0];
+    let title = chapter
+        .find("\n## Works cited\n\n<div id=\"refs\"")
+        .unwrap();
+    let books = chapter.find("\n### Books\n\n").unwrap();
+    assert!(title < books, "{chapter}");
+}
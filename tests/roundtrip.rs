@@ -0,0 +1,152 @@
+//! Property tests: chapters without citations come back from the
+//! preprocessor byte for byte, whatever it protects from pandoc on the way.
+//!
+//! Pandoc is faked with one which echoes its input, so anything that
+//! changes is the preprocessor's own doing. The same properties are checked
+//! against a real pandoc by an ignored test, for CI to run with each pandoc
+//! version it supports: `CITEPROC_TEST_PANDOC=/path/to/pandoc cargo test
+//! --test roundtrip -- --ignored`.
+
+use std::fs;
+
+use mdbook_citeproc::testing::{FakePandoc, TestBook};
+use mdbook_citeproc::Pandoc;
+use proptest::prelude::*;
+
+/// The configurations which take parts of a chapter out before pandoc
+/// sees it and put them back afterwards.
+const CONFIGS: &[(&str, &str)] = &[
+    ("default", ""),
+    ("raw-html", "raw-html = \"preserve\""),
+    ("admonish", "admonish = true"),
+    ("both", "raw-html = \"preserve\"\nadmonish = true"),
+];
+
+/// Text without anything that could start a citation, a tag or code.
+fn text() -> impl Strategy<Value = String> {
+    "[a-zA-Z0-9][a-zA-Z0-9 .,;:!?'\"()éß漢-]{0,30}"
+}
+
+fn inline() -> impl Strategy<Value = String> {
+    prop_oneof![
+        4 => text(),
+        1 => text().prop_map(|t| format!("*{t}*")),
+        1 => text().prop_map(|t| format!("`{t} <b>`")),
+        1 => text().prop_map(|t| format!("[{t}](https://example.com/{})", t.len())),
+        1 => text().prop_map(|t| format!("<span class=\"note\">{t}</span>")),
+        1 => text().prop_map(|t| format!("<!-- {t} -->")),
+        1 => Just("<br>".to_string()),
+    ]
+}
+
+fn paragraph() -> impl Strategy<Value = String> {
+    prop::collection::vec(prop::collection::vec(inline(), 1..5), 1..4).prop_map(|lines| {
+        lines
+            .iter()
+            .map(|line| line.join(" "))
+            .collect::<Vec<_>>()
+            .join("\n")
+    })
+}
+
+fn block() -> impl Strategy<Value = String> {
+    let lines = || prop::collection::vec(text(), 0..4).prop_map(|lines| lines.join("\n"));
+    prop_oneof![
+        4 => paragraph(),
+        1 => (1..=6usize, text()).prop_map(|(level, t)| format!("{} {t}", "#".repeat(level))),
+        1 => prop::collection::vec(paragraph(), 1..4).prop_map(|items| {
+            items
+                .iter()
+                .map(|item| format!("- {}", item.replace('\n', "\n  ")))
+                .collect::<Vec<_>>()
+                .join("\n")
+        }),
+        1 => paragraph().prop_map(|p| format!("> {}", p.replace('\n', "\n> "))),
+        1 => ("[a-z]{0,6}", lines()).prop_map(|(lang, code)| format!("```{lang}\n{code}\n```")),
+        1 => lines().prop_map(|code| format!("~~~~\n<div>\n{code}\n```\n~~~~")),
+        1 => paragraph().prop_map(|p| format!("<div class=\"aside\">\n{p}\n</div>")),
+        1 => lines().prop_map(|text| format!("<pre>\n{text}\n\n{text}\n</pre>")),
+        1 => (text(), paragraph())
+            .prop_map(|(summary, p)| format!("<details>\n<summary>{summary}</summary>\n\n{p}\n\n</details>")),
+        1 => ("(note|warning|tip)", paragraph())
+            .prop_map(|(kind, p)| format!("```admonish {kind}\n{p}\n```")),
+        1 => (paragraph(), lines()).prop_map(|(p, code)| {
+            format!("````admonish example title=\"Code\"\n{p}\n\n```\n{code}\n```\n````")
+        }),
+        1 => Just("---".to_string()),
+        1 => Just("<hr>".to_string()),
+    ]
+}
+
+/// A chapter of blocks separated by blank lines, perhaps with front matter
+/// and perhaps without a final newline.
+fn chapter() -> impl Strategy<Value = String> {
+    (
+        prop::option::of(text()),
+        prop::collection::vec(block(), 0..8),
+        prop_oneof![Just("\n\n"), Just("\n\n\n")],
+        prop_oneof![Just(""), Just("\n"), Just("\n\n")],
+    )
+        .prop_map(|(title, blocks, separator, end)| {
+            let front_matter =
+                title.map_or(String::new(), |t| format!("---\ntitle: \"{t}\"\n---\n\n"));
+            format!("{front_matter}{}{end}", blocks.join(separator))
+        })
+}
+
+/// The pandoc the ignored tests run, from `CITEPROC_TEST_PANDOC`.
+fn real_pandoc() -> String {
+    std::env::var("CITEPROC_TEST_PANDOC").unwrap_or_else(|_| "pandoc".into())
+}
+
+fn run(config: &str, chapters: &[String], pandoc: &Pandoc) -> Vec<String> {
+    let root = std::env::temp_dir().join(format!(
+        "citeproc-roundtrip-{}-{:?}",
+        std::process::id(),
+        std::thread::current().id()
+    ));
+    let book = chapters.iter().enumerate().fold(
+        TestBook::new(&root).config(&format!("cache = false\n{config}")),
+        |book, (i, content)| book.chapter(&format!("Chapter {i}"), content),
+    );
+    let output = book.run(pandoc);
+    let _ = fs::remove_dir_all(&root);
+    output.unwrap()
+}
+
+proptest! {
+    #[test]
+    fn chapters_without_citations_are_unchanged(
+        chapters in prop::collection::vec(chapter(), 1..4)
+    ) {
+        let pandoc = Pandoc::new()
+            .quiet(true)
+            .backend(Box::new(FakePandoc::echo()));
+        for (name, config) in CONFIGS {
+            let output = run(config, &chapters, &pandoc);
+            for (input, output) in chapters.iter().zip(&output) {
+                prop_assert_eq!(input, output, "with the {} configuration", name);
+            }
+        }
+    }
+}
+
+proptest! {
+    // Each case runs pandoc for every chapter and configuration.
+    #![proptest_config(ProptestConfig::with_cases(32))]
+
+    #[test]
+    #[ignore = "needs pandoc; set CITEPROC_TEST_PANDOC to pick one"]
+    fn chapters_without_citations_are_unchanged_by_pandoc(
+        chapters in prop::collection::vec(chapter(), 1..4)
+    ) {
+        let pandoc = Pandoc::new().quiet(true);
+        let command = format!("pandoc-command = [{:?}]", real_pandoc());
+        for (name, config) in CONFIGS {
+            let output = run(&format!("{command}\n{config}"), &chapters, &pandoc);
+            for (input, output) in chapters.iter().zip(&output) {
+                prop_assert_eq!(input, output, "with the {} configuration", name);
+            }
+        }
+    }
+}